@@ -0,0 +1,73 @@
+// The parts of the daemon's fleet config that the api needs. The api reads the same file as the
+// daemon (FLEET_CONFIG, fleet.toml by default) so that it scores routes the way the traders do.
+use anyhow::Context;
+use serde::Deserialize;
+use spacemonger_core::scoring::ScoringConfig;
+use std::env;
+use std::fs;
+
+// Everything else in the fleet config belongs to the daemon and is ignored here
+#[derive(Debug, Default, Deserialize)]
+struct FleetConfig {
+    #[serde(default)]
+    trading: TradingConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TradingConfig {
+    #[serde(default)]
+    scoring: ScoringConfig,
+}
+
+pub fn parse_scoring_config(contents: &str) -> anyhow::Result<ScoringConfig> {
+    let config: FleetConfig = toml::from_str(contents)?;
+
+    Ok(config.trading.scoring)
+}
+
+/// The traders' scoring config. Falls back to the daemon's defaults when there isn't a fleet config
+/// to read
+pub fn scoring_config_from_env() -> anyhow::Result<ScoringConfig> {
+    let path = env::var("FLEET_CONFIG").unwrap_or_else(|_| "fleet.toml".to_string());
+
+    match fs::read_to_string(&path) {
+        Ok(contents) => parse_scoring_config(&contents)
+            .with_context(|| format!("Invalid fleet config {}", path)),
+        Err(e) => {
+            log::warn!("Unable to read fleet config {}, scoring routes with the default config. Error: {}", path, e);
+            Ok(ScoringConfig::default())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spacemonger_core::scoring::ScorerType;
+
+    #[test]
+    fn only_the_scoring_config_is_read() {
+        let scoring = parse_scoring_config("
+            username_base = \"bloveless\"
+
+            [trading]
+            blacklisted_goods = [\"NARCOTICS\"]
+
+            [trading.scoring]
+            scorer = \"profit_volume_time\"
+            slippage_impact = 0.25
+        ").unwrap();
+
+        assert_eq!(scoring.scorer, ScorerType::ProfitVolumeTime);
+        assert!((scoring.slippage_impact - 0.25).abs() < f64::EPSILON);
+        assert!(scoring.learn_slippage);
+
+        assert_eq!(parse_scoring_config("").unwrap().scorer, ScorerType::CreditsPerSecond);
+        assert!(parse_scoring_config("[trading.scoring]\nscorer = \"fastest\"").is_err());
+    }
+
+    #[test]
+    fn the_daemons_fleet_config_can_be_read() {
+        assert!(parse_scoring_config(include_str!("../../daemon/fleet.toml")).is_ok());
+    }
+}
@@ -0,0 +1,425 @@
+// Every endpoint run against a database migrated with daemon/migrations so that the api can't drift
+// away from the daemon's schema again. They need a postgres database (the one from docker-compose
+// works) so they are ignored by default. TEST_DATABASE_URL overrides the connection string.
+use crate::models::{LoanEvent, MarketData, ShipEvent, SystemLocation, User, UserShip, UserShipAssignment, UserStatsResponse, UserTransaction};
+use crate::planning::TradeRoute;
+use crate::views;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use actix_http::Request;
+use serde_json::json;
+use chrono::{Duration, SecondsFormat, Utc};
+use spacemonger_core::ledger::{LedgerPage, TradeSummary};
+use spacemonger_core::scoring::{ScorerType, ScoringConfig};
+use spacemonger_core::routes;
+use spacemonger_core::test_utils::{self, TestDatabase};
+use sqlx::PgPool;
+
+const USER_ID: &str = "6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91";
+
+/// A migrated database with one user in it and market data for two systems. OE has a planet and a
+/// moon that both trade metals and XV has a single location
+async fn get_test_db() -> TestDatabase {
+    let test_db = test_utils::get_test_db().await;
+
+    let seed = [
+        "INSERT INTO daemon_system_info (system, system_name, location, location_name, location_type, x, y) VALUES
+            ('OE', 'Omicron Eridani', 'OE-PM', 'Prime', 'Planet', 0, 0),
+            ('OE', 'Omicron Eridani', 'OE-PM-TR', 'Tritus', 'Moon', 3, 4),
+            ('XV', 'Xiav', 'XV-BN', 'Ben', 'Planet', 10, 10);",
+        "INSERT INTO daemon_market_data (location, good, price_per_unit, volume_per_unit, quantity_available, purchase_price_per_unit, sell_price_per_unit, created_at) VALUES
+            ('OE-PM', 'Metals', 9, 1, 1000, 10, 8, NOW() - INTERVAL '1 hour'),
+            ('OE-PM', 'Metals', 10, 1, 1000, 11, 9, NOW()),
+            ('OE-PM', 'Fuel', 2, 1, 5000, 2, 1, NOW()),
+            ('OE-PM-TR', 'Metals', 20, 1, 1000, 21, 19, NOW()),
+            ('XV-BN', 'Research', 50, 1, 1000, 51, 49, NOW());",
+        "INSERT INTO daemon_user (id, username, token, new_ship_assignment, new_ship_system) VALUES
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'tester', 'token', 'trader', 'OE');",
+        "INSERT INTO daemon_user_stats (user_id, credits, ship_count, ships) VALUES
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 1000, 1, '[]');",
+        "INSERT INTO daemon_user_ship (user_id, ship_id, type, class, max_cargo, speed, manufacturer, plating, weapons, system, assignment) VALUES
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'ship-1', 'GR-MK-I', 'MK-I', 100, 1, 'Gravager', 5, 5, 'OE', 'trader');",
+        "INSERT INTO daemon_user_transaction (user_id, ship_id, type, good, price_per_unit, quantity, total, location) VALUES
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'ship-1', 'purchase', 'Metals', 11, 10, 110, 'OE-PM');",
+        "INSERT INTO daemon_user_loan_event (user_id, loan_id, loan_type, event_type, amount, due_at, credits, created_at) VALUES
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'loan-1', 'STARTUP', 'requested', 200000, NOW() + INTERVAL '1 day', 200000, NOW() - INTERVAL '1 hour'),
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'loan-1', 'STARTUP', 'repaid', 200000, NOW() + INTERVAL '1 day', 1000, NOW());",
+        "INSERT INTO daemon_user_ship_history (user_id, ship_id, ship_type, event_type, location, created_at) VALUES
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'ship-1', 'GR-MK-I', 'purchased', 'OE-PM', NOW() - INTERVAL '1 hour'),
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'ship-1', 'GR-MK-I', 'retiring', 'OE-PM', NOW()),
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'ship-2', 'JW-MK-I', 'purchased', 'OE-PM', NOW());",
+    ];
+
+    for query in seed.iter() {
+        sqlx::query(query).execute(&test_db.pg_pool).await.unwrap();
+    }
+
+    test_db
+}
+
+async fn app(pg_pool: &PgPool) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+    app_with_scoring(pg_pool, ScoringConfig::default()).await
+}
+
+async fn app_with_scoring(pg_pool: &PgPool, scoring_config: ScoringConfig) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+    test::init_service(App::new()
+        .app_data(web::Data::new(pg_pool.clone()))
+        .app_data(web::Data::new(scoring_config))
+        .configure(views::init)
+    ).await
+}
+
+/// GETs the uri and checks that it succeeded before reading the body
+async fn get<S, T>(app: &S, uri: &str) -> T
+    where
+        S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+        T: serde::de::DeserializeOwned,
+{
+    let response = test::call_service(app, test::TestRequest::get().uri(uri).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK, "GET {}", uri);
+
+    test::read_body_json(response).await
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn index_responds() {
+    let test_db = get_test_db().await;
+    let app = app(&test_db.pg_pool).await;
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn systems_list_every_location() {
+    let test_db = get_test_db().await;
+    let app = app(&test_db.pg_pool).await;
+
+    let systems: Vec<SystemLocation> = get(&app, "/systems").await;
+    let locations: Vec<&str> = systems.iter().map(|s| s.location.as_str()).collect();
+    assert_eq!(locations, vec!["OE-PM", "OE-PM-TR", "XV-BN"]);
+    assert_eq!(systems[1].location_type, "Moon");
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn system_goods_are_for_the_requested_system() {
+    let test_db = get_test_db().await;
+    let app = app(&test_db.pg_pool).await;
+
+    let goods: Vec<String> = get(&app, "/systems/OE/goods").await;
+    assert_eq!(goods, vec!["Fuel", "Metals"]);
+
+    let goods: Vec<String> = get(&app, "/systems/XV/goods").await;
+    assert_eq!(goods, vec!["Research"]);
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn system_routes_stay_within_the_system() {
+    let test_db = get_test_db().await;
+    let app = app(&test_db.pg_pool).await;
+
+    let routes: Vec<TradeRoute> = get(&app, "/systems/OE/routes/Metals?fuel=10").await;
+    assert_eq!(routes.len(), 2);
+    // Buying on the planet and selling on the moon is the only profitable way around
+    assert_eq!(routes[0].plan.purchase_location, "OE-PM");
+    assert_eq!(routes[0].plan.sell_location, "OE-PM-TR");
+    assert_eq!(routes[0].plan.purchase_price_per_unit, 10);
+    assert_eq!(routes[0].plan.sell_price_per_unit, 20);
+    assert_eq!(routes[0].plan.legs.len(), 1);
+    assert_eq!(routes[0].plan.legs[0].distance, 5.0);
+    assert!(routes[0].score.expected_profit > 0.0);
+    assert!(routes[1].score.expected_profit < 0.0);
+
+    // The same fuel and flight time estimates that the daemon's traders use
+    let routes: Vec<TradeRoute> = get(&app, "/systems/OE/routes/Metals?fuel=10&ship_type=GR-MK-II&speed=2").await;
+    assert_eq!(routes[0].plan.fuel_required, routes::fuel_required("Planet", 5.0, "GR-MK-II"));
+    assert_eq!(routes[0].plan.flight_time, routes::flight_time(5.0, 2));
+
+    let routes: Vec<TradeRoute> = get(&app, "/systems/XV/routes/Research").await;
+    assert!(routes.is_empty());
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn latest_market_data_has_one_row_per_good_and_location() {
+    let test_db = get_test_db().await;
+    let app = app(&test_db.pg_pool).await;
+
+    let market_data: Vec<MarketData> = get(&app, "/market-data/latest").await;
+    let rows: Vec<(&str, &str, i32)> = market_data.iter()
+        .map(|md| (md.location.as_str(), md.good.as_str(), md.purchase_price_per_unit))
+        .collect();
+    assert_eq!(rows, vec![("OE-PM", "Fuel", 2), ("OE-PM", "Metals", 11), ("OE-PM-TR", "Metals", 21), ("XV-BN", "Research", 51)]);
+    assert_eq!(market_data[3].system, "XV");
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn location_goods_and_market_data() {
+    let test_db = get_test_db().await;
+    let app = app(&test_db.pg_pool).await;
+
+    let goods: Vec<String> = get(&app, "/locations/OE-PM/goods").await;
+    assert_eq!(goods, vec!["Fuel", "Metals"]);
+
+    let market_data: Vec<MarketData> = get(&app, "/locations/OE-PM/market-data").await;
+    assert_eq!(market_data.len(), 3);
+    assert!(market_data.iter().all(|md| md.system == "OE"));
+
+    let market_data: Vec<MarketData> = get(&app, "/locations/OE-PM/market-data/Metals?days_ago=1").await;
+    let prices: Vec<i32> = market_data.iter().map(|md| md.purchase_price_per_unit).collect();
+    assert_eq!(prices, vec![11, 10]);
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn location_routes_start_at_the_location() {
+    let test_db = get_test_db().await;
+    let app = app(&test_db.pg_pool).await;
+
+    let routes: Vec<TradeRoute> = get(&app, "/locations/OE-PM/routes").await;
+    assert_eq!(routes.len(), 1);
+    assert_eq!(routes[0].plan.sell_location, "OE-PM-TR");
+    assert_eq!(routes[0].plan.fuel_price_per_unit, Some(2));
+    assert!(routes[0].score.fuel_to_buy > 0);
+
+    // There's no fuel on the moon so an empty ship can't leave it
+    let routes: Vec<TradeRoute> = get(&app, "/locations/OE-PM-TR/routes").await;
+    assert!(routes.is_empty());
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn location_routes_are_scored_like_the_traders_score_them() {
+    let test_db = get_test_db().await;
+    let app = app(&test_db.pg_pool).await;
+
+    let configured: Vec<TradeRoute> = get(&app, "/locations/OE-PM/routes?cargo=1000").await;
+
+    // The traders have learned that buying metals on the planet moves the price a lot
+    sqlx::query("
+        INSERT INTO daemon_slippage_model (location, good, purchase_impact, sell_impact, purchase_samples, sell_samples) VALUES
+            ('OE-PM', 'Metals', 4.0, 0.5, 1000, 0);
+    ")
+        .execute(&test_db.pg_pool)
+        .await
+        .unwrap();
+
+    let learned: Vec<TradeRoute> = get(&app, "/locations/OE-PM/routes?cargo=1000").await;
+    assert!(learned[0].score.units < configured[0].score.units);
+    assert!(learned[0].score.expected_profit < configured[0].score.expected_profit);
+
+    let app = app_with_scoring(&test_db.pg_pool, ScoringConfig { scorer: ScorerType::ProfitVolumeTime, ..ScoringConfig::default() }).await;
+    let profit_volume_time: Vec<TradeRoute> = get(&app, "/locations/OE-PM/routes?cargo=1000").await;
+    assert_eq!(profit_volume_time[0].score.fuel_to_buy, 0);
+    assert!((profit_volume_time[0].score.credits_per_second - profit_volume_time[0].plan.profit_volume_time).abs() < 1e-9);
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn users_and_their_stats() {
+    let test_db = get_test_db().await;
+    let app = app(&test_db.pg_pool).await;
+
+    let users: Vec<User> = get(&app, "/users").await;
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].id, USER_ID);
+    assert_eq!(users[0].new_ship_system, Some("OE".to_string()));
+    assert_eq!(users[0].credits, 1000);
+    assert_eq!(users[0].ships, Some("[]".to_string()));
+
+    let stats: UserStatsResponse = get(&app, &format!("/users/{}", USER_ID)).await;
+    assert_eq!(stats.username, "tester");
+    assert!(stats.stats.iter().any(|s| s.credits == 1000 && s.ship_count == 1));
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/users/00000000-0000-0000-0000-000000000000").to_request()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn user_ships_and_their_transactions_and_history() {
+    let test_db = get_test_db().await;
+    let app = app(&test_db.pg_pool).await;
+
+    let ships: Vec<UserShip> = get(&app, &format!("/users/{}/ships", USER_ID)).await;
+    assert_eq!(ships.len(), 1);
+    assert_eq!(ships[0].ship_type, "GR-MK-I");
+    assert_eq!(ships[0].assignment, "trader");
+
+    let transactions: Vec<UserTransaction> = get(&app, &format!("/users/{}/ships/ship-1/transactions", USER_ID)).await;
+    assert_eq!(transactions.len(), 1);
+    assert_eq!(transactions[0].good, "Metals");
+    assert_eq!(transactions[0].location, "OE-PM");
+    assert_eq!(transactions[0].transaction_type, "purchase");
+
+    let history: Vec<ShipEvent> = get(&app, &format!("/users/{}/ships/ship-1/history", USER_ID)).await;
+    let events: Vec<&str> = history.iter().map(|e| e.event_type.as_str()).collect();
+    assert_eq!(events, vec!["retiring", "purchased"]);
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn user_loans_are_newest_first() {
+    let test_db = get_test_db().await;
+    let app = app(&test_db.pg_pool).await;
+
+    let loans: Vec<LoanEvent> = get(&app, &format!("/users/{}/loans", USER_ID)).await;
+    let events: Vec<&str> = loans.iter().map(|e| e.event_type.as_str()).collect();
+    assert_eq!(events, vec!["repaid", "requested"]);
+    assert_eq!(loans[0].credits, 1000);
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn ship_assignments_can_be_changed() {
+    let test_db = get_test_db().await;
+    let app = app(&test_db.pg_pool).await;
+
+    let put = |ship_id: &str, body: serde_json::Value| test::TestRequest::put()
+        .uri(&format!("/users/{}/ships/{}/assignment", USER_ID, ship_id))
+        .set_json(&body)
+        .to_request();
+
+    let response = test::call_service(&app, put("ship-1", json!({ "system": "XV", "assignment": "scout" }))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let assignment: UserShipAssignment = test::read_body_json(response).await;
+    assert_eq!(assignment.system, "XV");
+    assert_eq!(assignment.assignment, "scout");
+
+    let ships: Vec<UserShip> = get(&app, &format!("/users/{}/ships", USER_ID)).await;
+    assert_eq!(ships[0].assignment, "scout");
+
+    let response = test::call_service(&app, put("ship-1", json!({ "system": "XV", "assignment": "pirate" }))).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = test::call_service(&app, put("ship-1", json!({ "system": "XV", "assignment": "system_change" }))).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = test::call_service(&app, put("ship-1", json!({ "system": "ZZ", "assignment": "scout" }))).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = test::call_service(&app, put("ship-9", json!({ "system": "XV", "assignment": "scout" }))).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+const SHIP_1_TRADE_ID: &str = "0b5e7f3c-1c1d-4f7e-9a3b-2d6c8e4f5a61";
+
+/// Buys and sells on top of the seeded purchase along with the trades the daemon recorded for them.
+/// ship-1 finishes its trade a minute from now and ship-2 traded an hour ago
+async fn seed_trades(pg_pool: &PgPool) {
+    sqlx::query("
+        INSERT INTO daemon_user_transaction (user_id, ship_id, type, good, price_per_unit, quantity, total, location, created_at) VALUES
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'ship-1', 'purchase', 'Fuel', 2, 5, 10, 'OE-PM', NOW() - INTERVAL '2 hours'),
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'ship-2', 'purchase', 'Metals', 10, 20, 200, 'OE-PM', NOW() - INTERVAL '90 minutes'),
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'ship-2', 'sell', 'Metals', 20, 20, 400, 'OE-PM-TR', NOW() - INTERVAL '60 minutes'),
+            ('6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'ship-1', 'sell', 'Metals', 20, 10, 200, 'OE-PM-TR', NOW() + INTERVAL '1 minute');
+    ")
+        .execute(pg_pool)
+        .await
+        .unwrap();
+
+    sqlx::query("
+        INSERT INTO daemon_user_trade (trade_id, user_id, ship_id, good, purchase_location, sell_location, quantity, cost, revenue, fuel_cost, profit, predicted_profit, duration_seconds, started_at, completed_at) VALUES
+            ('0b5e7f3c-1c1d-4f7e-9a3b-2d6c8e4f5a61', '6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'ship-1', 'Metals', 'OE-PM', 'OE-PM-TR', 10, 110, 200, 10, 80, 120, 7260, NOW() - INTERVAL '2 hours', NOW() + INTERVAL '1 minute'),
+            ('7c2d9a41-5e8b-4c3f-b1a6-9f0e2d4c6b83', '6d3b2f6e-8d0c-4d2b-9a55-1f0e4c2a7b91', 'ship-2', 'Metals', 'OE-PM', 'OE-PM-TR', 20, 200, 400, 0, 200, 180, 1800, NOW() - INTERVAL '90 minutes', NOW() - INTERVAL '60 minutes');
+    ")
+        .execute(pg_pool)
+        .await
+        .unwrap();
+}
+
+fn summarize(page: &LedgerPage) -> Vec<(&str, &str, i32)> {
+    page.transactions.iter()
+        .map(|t| (t.ship_id.as_str(), t.transaction_type.as_str(), t.total))
+        .collect()
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn the_ledger_is_paged_with_a_cursor() {
+    let test_db = get_test_db().await;
+    seed_trades(&test_db.pg_pool).await;
+    let app = app(&test_db.pg_pool).await;
+
+    let uri = format!("/users/{}/transactions?limit=2", USER_ID);
+    let first: LedgerPage = get(&app, &uri).await;
+    assert_eq!(summarize(&first), vec![("ship-1", "sell", 200), ("ship-1", "purchase", 110)]);
+
+    let second: LedgerPage = get(&app, &format!("{}&cursor={}", uri, first.next_cursor.unwrap())).await;
+    assert_eq!(summarize(&second), vec![("ship-2", "sell", 400), ("ship-2", "purchase", 200)]);
+
+    let last: LedgerPage = get(&app, &format!("{}&cursor={}", uri, second.next_cursor.unwrap())).await;
+    assert_eq!(summarize(&last), vec![("ship-1", "purchase", 10)]);
+    assert_eq!(last.next_cursor, None);
+
+    // Paging by total goes through the same rows in a different order. Ties are split across the
+    // pages by the newest transaction first
+    let uri = format!("/users/{}/transactions?limit=2&sort=-total", USER_ID);
+    let first: LedgerPage = get(&app, &uri).await;
+    assert_eq!(summarize(&first), vec![("ship-2", "sell", 400), ("ship-1", "sell", 200)]);
+    let second: LedgerPage = get(&app, &format!("{}&cursor={}", uri, first.next_cursor.unwrap())).await;
+    assert_eq!(summarize(&second), vec![("ship-2", "purchase", 200), ("ship-1", "purchase", 110)]);
+
+    for bad_request in ["sort=price", "cursor=nope"].iter() {
+        let request = test::TestRequest::get().uri(&format!("/users/{}/transactions?{}", USER_ID, bad_request)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::BAD_REQUEST);
+    }
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn the_ledger_can_be_filtered() {
+    let test_db = get_test_db().await;
+    seed_trades(&test_db.pg_pool).await;
+    let app = app(&test_db.pg_pool).await;
+
+    let ledger = |query: String| format!("/users/{}/transactions?{}", USER_ID, query);
+
+    let page: LedgerPage = get(&app, &ledger("ship_id=ship-2&sort=created_at".to_string())).await;
+    assert_eq!(summarize(&page), vec![("ship-2", "purchase", 200), ("ship-2", "sell", 400)]);
+
+    let page: LedgerPage = get(&app, &ledger("good=Metals&type=sell".to_string())).await;
+    assert_eq!(summarize(&page), vec![("ship-1", "sell", 200), ("ship-2", "sell", 400)]);
+
+    let page: LedgerPage = get(&app, &ledger("location=OE-PM".to_string())).await;
+    assert_eq!(page.transactions.len(), 3);
+
+    let since = (Utc::now() - Duration::minutes(75)).to_rfc3339_opts(SecondsFormat::Secs, true);
+    let until = (Utc::now() - Duration::minutes(30)).to_rfc3339_opts(SecondsFormat::Secs, true);
+    let page: LedgerPage = get(&app, &ledger(format!("since={}&until={}", since, until))).await;
+    assert_eq!(summarize(&page), vec![("ship-2", "sell", 400)]);
+
+    // The ship's own transactions are still all there
+    let transactions: Vec<UserTransaction> = get(&app, &format!("/users/{}/ships/ship-1/transactions", USER_ID)).await;
+    assert_eq!(transactions.len(), 3);
+}
+
+#[actix_rt::test]
+#[ignore]
+async fn trades_add_up_to_the_realized_profit() {
+    let test_db = get_test_db().await;
+    seed_trades(&test_db.pg_pool).await;
+    let app = app(&test_db.pg_pool).await;
+
+    let summary: TradeSummary = get(&app, &format!("/users/{}/trades", USER_ID)).await;
+    assert_eq!(summary.trades.len(), 2);
+    assert_eq!(summary.trades[0].trade_id, SHIP_1_TRADE_ID);
+    assert_eq!(summary.trades[0].ship_id, "ship-1");
+    assert_eq!(summary.trades[0].profit, 80);
+    assert_eq!(summary.trades[0].predicted_profit, 120);
+    assert_eq!(summary.trades[0].purchase_location, "OE-PM");
+    assert_eq!(summary.trades[0].sell_location, "OE-PM-TR");
+    assert_eq!(summary.trades[1].profit, 200);
+    assert_eq!(summary.realized_profit, 280);
+    assert_eq!(summary.predicted_profit, 300);
+
+    let summary: TradeSummary = get(&app, &format!("/users/{}/trades?ship_id=ship-2", USER_ID)).await;
+    assert_eq!(summary.realized_profit, 200);
+
+    let summary: TradeSummary = get(&app, &format!("/users/{}/trades?trade_id={}", USER_ID, SHIP_1_TRADE_ID)).await;
+    assert_eq!(summary.trades.len(), 1);
+    assert_eq!(summary.predicted_profit, 120);
+}
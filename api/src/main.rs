@@ -1,6 +1,9 @@
-mod db;
+mod config;
 mod views;
 mod models;
+mod planning;
+#[cfg(test)]
+mod endpoint_tests;
 
 use actix_web::{web, App, HttpServer, middleware};
 use actix_web::middleware::Logger;
@@ -8,6 +11,7 @@ use anyhow::Result;
 use std::env;
 use dotenv::dotenv;
 use actix_cors::Cors;
+use spacemonger_core::db;
 
 #[actix_web::main]
 async fn main() -> Result<()> {
@@ -22,6 +26,7 @@ async fn main() -> Result<()> {
     let postgres_database = env::var("POSTGRES_DATABASE").unwrap();
 
     let pg_pool = db::get_db_pool(postgres_host, postgres_port, postgres_username, postgres_password, postgres_database).await?;
+    let scoring_config = config::scoring_config_from_env()?;
 
     HttpServer::new(move || App::new()
         .wrap(Cors::permissive())
@@ -29,6 +34,7 @@ async fn main() -> Result<()> {
         .wrap(Logger::default())
         .service(web::scope("/api/").configure(views::init))
         .app_data(web::Data::new(pg_pool.clone()))
+        .app_data(web::Data::new(scoring_config.clone()))
     )
         .bind("0.0.0.0:8080")?
         .run()
@@ -0,0 +1,66 @@
+// Plans and scores trades for the dashboard with the same planner and scorer that the daemon's
+// traders use, so the routes shown are the ones a trader would actually take.
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use spacemonger_core::models::SystemLocation;
+use spacemonger_core::planner::{RoutePlanner, TradePlan};
+use spacemonger_core::queries;
+use spacemonger_core::scoring::{self, RouteScore, ScoringConfig, ShipProfile};
+
+/// The ship to plan trades for. I.E. /locations/OE-PM/routes?ship_type=GR-MK-II&speed=2&cargo=300&fuel=10
+#[derive(Deserialize)]
+pub struct ShipQuery {
+    ship_type: Option<String>,
+    speed: Option<i32>,
+    cargo: Option<i32>,
+    fuel: Option<i32>,
+}
+
+impl ShipQuery {
+    fn profile(&self) -> ShipProfile {
+        let fuel = self.fuel.unwrap_or(0);
+
+        ShipProfile {
+            ship_type: self.ship_type.clone().unwrap_or_else(|| "JW-MK-I".to_string()),
+            speed: self.speed.unwrap_or(1).max(1),
+            space_available: self.cargo.unwrap_or(100) - fuel,
+            fuel,
+        }
+    }
+}
+
+/// A trade along with what it is expected to earn the ship
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TradeRoute {
+    #[serde(flatten)]
+    pub plan: TradePlan,
+    #[serde(flatten)]
+    pub score: RouteScore,
+}
+
+/// Every trade that starts at one of the accepted locations scored for the ship with the traders'
+/// scoring config and the slippage they have learned. Trades the ship can't make are dropped and
+/// the best are first
+pub async fn trade_routes(pg_pool: &PgPool, scoring_config: &ScoringConfig, ship: &ShipQuery, accept: impl Fn(&SystemLocation) -> bool) -> anyhow::Result<Vec<TradeRoute>> {
+    let locations = queries::system_locations(pg_pool).await?;
+    let market_data = queries::trading_market_data(pg_pool).await?;
+
+    let origins: Vec<String> = locations.iter()
+        .filter(|l| accept(l))
+        .map(|l| l.location.clone())
+        .collect();
+
+    let ship = ship.profile();
+    let planner = RoutePlanner::new(locations, &market_data);
+    let plans = origins.iter()
+        .flat_map(|origin| planner.plan_trades(origin, ship.speed, &ship.ship_type, &market_data))
+        .collect();
+
+    let scorer = scoring::scorer(scoring_config, queries::slippage_models(pg_pool).await?);
+
+    Ok(
+        scoring::rank_plans(plans, &ship, scorer.as_ref()).into_iter()
+            .map(|(plan, score)| TradeRoute { plan, score })
+            .collect()
+    )
+}
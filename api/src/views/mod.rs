@@ -15,7 +15,12 @@ pub fn init(cfg: &mut web::ServiceConfig) {
     cfg.service(users::users);
     cfg.service(users::user_stats);
     cfg.service(users::user_ships);
+    cfg.service(users::update_user_ship_assignment);
     cfg.service(users::user_ship_transactions);
+    cfg.service(users::user_transactions);
+    cfg.service(users::user_trades);
+    cfg.service(users::user_ship_history);
+    cfg.service(users::user_loans);
 
     // market data
     cfg.service(market_data::latest);
@@ -1,52 +1,14 @@
-use actix_web::{web, HttpResponse, Responder, get};
-use sqlx::{PgPool, Row, Error};
-use sqlx::postgres::PgRow;
-use crate::models::{User, UserStats, UserStatsResponse, UserShip, UserTransaction};
+use actix_web::{web, HttpResponse, Responder, get, put};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::PgPool;
+use spacemonger_core::ledger::{Cursor, LedgerFilter, LedgerSort, DEFAULT_PAGE_SIZE};
+use spacemonger_core::queries;
+use crate::models::{ShipAssignment, UserStatsResponse, UserShipAssignmentRequest};
 
 #[get("/users")]
 pub async fn users(pg_pool: web::Data<PgPool>) -> impl Responder {
-    let results = sqlx::query("
-            ;WITH user_stats AS (
-                SELECT
-                     user_id
-                    ,credits
-                    ,ship_count
-                    ,ships
-                    ,created_at
-                    ,ROW_NUMBER() OVER (PARTITION BY user_id ORDER BY created_at DESC) as rank
-                FROM daemon_user_stats
-            )
-            SELECT
-                 u.id::text
-                ,u.username
-                ,u.new_ship_assignment
-                ,u.new_ship_system_symbol
-                ,u.location_symbol
-                ,us.credits
-                ,us.ship_count
-                ,us.ships::text
-                ,us.created_at as stats_updated_at
-            FROM daemon_user u
-            INNER JOIN user_stats us
-                ON u.id = us.user_id
-            WHERE us.rank = 1;
-        ")
-        .map(|row: PgRow| {
-            User {
-                id: row.get("id"),
-                username: row.get("username"),
-                new_ship_assignment: row.get("new_ship_assignment"),
-                new_ship_system_symbol: row.get("new_ship_system_symbol"),
-                credits: row.get("credits"),
-                ship_count: row.get("ship_count"),
-                ships: row.get("ships"),
-                stats_updated_at: row.get("stats_updated_at"),
-            }
-        })
-        .fetch_all(pg_pool.get_ref())
-        .await;
-
-    match results {
+    match queries::users(pg_pool.get_ref()).await {
         Ok(results) => HttpResponse::Ok().json(results),
         _ => HttpResponse::BadRequest().body("Error trying to do something"),
     }
@@ -54,70 +16,16 @@ pub async fn users(pg_pool: web::Data<PgPool>) -> impl Responder {
 
 #[get("/users/{user_id}")]
 pub async fn user_stats(user_id: web::Path<String>, pg_pool: web::Data<PgPool>) -> impl Responder {
-    let username: Result<String, Error> = sqlx::query("
-        SELECT
-            u.username
-        FROM daemon_user u
-        WHERE u.id = $1::uuid
-        LIMIT 1;
-    ")
-        .bind(user_id.as_str())
-        .map(|row: PgRow| {
-            row.get("username")
-        })
-        .fetch_one(pg_pool.get_ref())
-        .await;
-
-    if username.is_err() {
-        return HttpResponse::InternalServerError().body("Unable to get username");
-    }
+    let username = match queries::username(pg_pool.get_ref(), user_id.as_str()).await {
+        Ok(Some(username)) => username,
+        Ok(None) => return HttpResponse::NotFound().body("User not found"),
+        Err(_) => return HttpResponse::InternalServerError().body("Unable to get username"),
+    };
 
-    let user_stats = sqlx::query("
-        ;WITH earliest_date AS (
-            SELECT MIN(created_at) AS earliest_date
-            FROM daemon_user_stats dus
-            WHERE dus.user_id = $1::uuid
-            LIMIT 1
-        ), time_group AS (
-            SELECT
-                 row_number() over (order by series) as id
-                ,series as end_date
-                ,series - '15 minutes'::interval as start_date
-            FROM generate_series(
-                date_trunc('hour', (SELECT earliest_date FROM earliest_date LIMIT 1)),
-                date_trunc('hour', NOW()) + '1 hour',
-                '15 minutes'::interval
-            ) as series
-        )
-        SELECT
-             tg.id
-            ,COALESCE(MAX(dus.credits), 0) as credits
-            ,COALESCE(MAX(dus.ship_count), 0) as ship_count
-            ,MAX(tg.end_date) as created_at
-        FROM time_group tg
-        LEFT JOIN daemon_user_stats dus
-            ON dus.created_at >= tg.start_date
-            AND dus.created_at < tg.end_date
-            AND dus.user_id = $1::uuid
-        GROUP BY tg.id
-        ORDER BY tg.id;
-    ")
-        .bind(user_id.as_str())
-        .map(|row: PgRow| {
-            UserStats {
-                user_id: user_id.as_str().to_string(),
-                credits: row.get("credits"),
-                ship_count: row.get("ship_count"),
-                created_at: row.get("created_at"),
-            }
-        })
-        .fetch_all(pg_pool.as_ref())
-        .await;
-
-    match user_stats {
+    match queries::user_stats(pg_pool.get_ref(), user_id.as_str()).await {
         Ok(user_stats) => {
             HttpResponse::Ok().json(UserStatsResponse {
-                username: username.unwrap(),
+                username,
                 stats: user_stats,
             })
         },
@@ -127,47 +35,34 @@ pub async fn user_stats(user_id: web::Path<String>, pg_pool: web::Data<PgPool>)
 
 #[get("/users/{user_id}/ships")]
 pub async fn user_ships(user_id: web::Path<String>, pg_pool: web::Data<PgPool>) -> impl Responder {
-    let ships = sqlx::query("
-        SELECT
-             user_id::text
-            ,ship_id
-            ,type
-            ,class
-            ,max_cargo
-            ,speed
-            ,manufacturer
-            ,plating
-            ,weapons
-            ,modified_at
-            ,created_at
-        FROM daemon_user_ship dus
-        WHERE dus.user_id = $1::uuid
-        ORDER BY created_at DESC;
-    ")
-        .bind(user_id.as_str())
-        .map(|row: PgRow| {
-            UserShip {
-                user_id: row.get("user_id"),
-                ship_id: row.get("ship_id"),
-                ship_type: row.get("type"),
-                class: row.get("class"),
-                max_cargo: row.get("max_cargo"),
-                speed: row.get("speed"),
-                manufacturer: row.get("manufacturer"),
-                plating: row.get("plating"),
-                weapons: row.get("weapons"),
-                modified_at: row.get("modified_at"),
-                created_at: row.get("created_at"),
-            }
-        })
-        .fetch_all(pg_pool.as_ref())
-        .await;
-
-    match ships {
-        Ok(ships) => {
-            HttpResponse::Ok().json(ships)
-        },
-        _ => HttpResponse::BadRequest().body("Error trying to get user stats"),
+    match queries::user_ships(pg_pool.get_ref(), user_id.as_str()).await {
+        Ok(ships) => HttpResponse::Ok().json(ships),
+        _ => HttpResponse::BadRequest().body("Error trying to get user ships"),
+    }
+}
+
+// The daemon picks up the new assignment the next time the ship is between jobs. I.E. a trader
+// will finish its current trade before it heads to another system.
+#[put("/users/{user_id}/ships/{ship_id}/assignment")]
+pub async fn update_user_ship_assignment(params: web::Path<(String, String)>, request: web::Json<UserShipAssignmentRequest>, pg_pool: web::Data<PgPool>) -> impl Responder {
+    let (user_id, ship_id) = params.into_inner();
+
+    // Ships are moved between systems by changing their system. system_change is only how the
+    // daemon gets them there
+    if request.assignment == ShipAssignment::SystemChange {
+        return HttpResponse::BadRequest().body("A ship can't be assigned to system_change. Expected one of trader or scout");
+    }
+
+    match queries::system_exists(pg_pool.get_ref(), &request.system).await {
+        Ok(true) => {},
+        Ok(false) => return HttpResponse::BadRequest().body(format!("Unknown system {}", request.system)),
+        Err(_) => return HttpResponse::InternalServerError().body("Unable to check the system"),
+    }
+
+    match queries::update_ship_assignment(pg_pool.get_ref(), &user_id, &ship_id, &request.system, &request.assignment.to_string()).await {
+        Ok(Some(assignment)) => HttpResponse::Ok().json(assignment),
+        Ok(None) => HttpResponse::NotFound().body("Ship not found"),
+        Err(_) => HttpResponse::InternalServerError().body("Error trying to update the ship assignment"),
     }
 }
 
@@ -175,44 +70,86 @@ pub async fn user_ships(user_id: web::Path<String>, pg_pool: web::Data<PgPool>)
 pub async fn user_ship_transactions(params: web::Path<(String, String)>, pg_pool: web::Data<PgPool>) -> impl Responder {
     let (user_id, ship_id) = params.into_inner();
 
-    let ship_transactions = sqlx::query("
-        SELECT
-             user_id::text
-            ,ship_id
-            ,type
-            ,good_symbol
-            ,price_per_unit
-            ,quantity
-            ,total
-            ,location_symbol
-            ,created_at
-        FROM daemon_user_transaction dut
-        WHERE dut.user_id = $1::uuid
-            AND dut.ship_id = $2
-        ORDER BY created_at DESC;
-    ")
-        .bind(user_id.as_str())
-        .bind(ship_id.as_str())
-        .map(|row: PgRow| {
-            UserTransaction {
-                user_id: row.get("user_id"),
-                ship_id: row.get("ship_id"),
-                transaction_type: row.get("type"),
-                good_symbol: row.get("good_symbol"),
-                price_per_unit: row.get("price_per_unit"),
-                quantity: row.get("quantity"),
-                total: row.get("total"),
-                location_symbol: row.get("location_symbol"),
-                created_at: row.get("created_at"),
-            }
-        })
-        .fetch_all(pg_pool.as_ref())
-        .await;
-
-    match ship_transactions {
-        Ok(ship_transactions) => {
-            HttpResponse::Ok().json(ship_transactions)
-        },
-        _ => HttpResponse::BadRequest().body("Error trying to get user stats"),
+    match queries::ship_transactions(pg_pool.get_ref(), &user_id, &ship_id).await {
+        Ok(ship_transactions) => HttpResponse::Ok().json(ship_transactions),
+        _ => HttpResponse::BadRequest().body("Error trying to get ship transactions"),
+    }
+}
+
+/// I.E. /users/{user_id}/transactions?ship_id=abc&good=Metals&type=sell&since=2021-07-01T00:00:00Z&sort=-total&limit=100
+#[derive(Deserialize)]
+pub struct LedgerQuery {
+    ship_id: Option<String>,
+    good: Option<String>,
+    location: Option<String>,
+    #[serde(rename = "type")]
+    transaction_type: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    trade_id: Option<String>,
+    sort: Option<String>,
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+impl LedgerQuery {
+    fn filter(&self) -> LedgerFilter {
+        LedgerFilter {
+            ship_id: self.ship_id.clone(),
+            good: self.good.clone(),
+            location: self.location.clone(),
+            transaction_type: self.transaction_type.clone(),
+            since: self.since,
+            until: self.until,
+            trade_id: self.trade_id.clone(),
+        }
+    }
+}
+
+// Every purchase and sale across the user's fleet a page at a time. Follow next_cursor, along with
+// the same filters and sort, to get the next page
+#[get("/users/{user_id}/transactions")]
+pub async fn user_transactions(user_id: web::Path<String>, web::Query(query): web::Query<LedgerQuery>, pg_pool: web::Data<PgPool>) -> impl Responder {
+    let sort = match query.sort.as_deref().map(LedgerSort::parse).transpose() {
+        Ok(sort) => sort.unwrap_or_default(),
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+
+    let cursor = match query.cursor.as_deref().map(Cursor::decode).transpose() {
+        Ok(cursor) => cursor,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+
+    match queries::ledger_page(pg_pool.get_ref(), user_id.as_str(), &query.filter(), sort, cursor, query.limit.unwrap_or(DEFAULT_PAGE_SIZE)).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        _ => HttpResponse::BadRequest().body("Error trying to get user transactions"),
+    }
+}
+
+// Every trade the daemon completed within the filters with its realized and predicted profit. Type,
+// sort, cursor and limit are ignored
+#[get("/users/{user_id}/trades")]
+pub async fn user_trades(user_id: web::Path<String>, web::Query(query): web::Query<LedgerQuery>, pg_pool: web::Data<PgPool>) -> impl Responder {
+    match queries::trade_summary(pg_pool.get_ref(), user_id.as_str(), &query.filter()).await {
+        Ok(trades) => HttpResponse::Ok().json(trades),
+        _ => HttpResponse::BadRequest().body("Error trying to get user trades"),
+    }
+}
+
+#[get("/users/{user_id}/ships/{ship_id}/history")]
+pub async fn user_ship_history(params: web::Path<(String, String)>, pg_pool: web::Data<PgPool>) -> impl Responder {
+    let (user_id, ship_id) = params.into_inner();
+
+    match queries::ship_events(pg_pool.get_ref(), &user_id, &ship_id).await {
+        Ok(ship_history) => HttpResponse::Ok().json(ship_history),
+        _ => HttpResponse::BadRequest().body("Error trying to get the ship history"),
+    }
+}
+
+#[get("/users/{user_id}/loans")]
+pub async fn user_loans(user_id: web::Path<String>, pg_pool: web::Data<PgPool>) -> impl Responder {
+    match queries::loan_events(pg_pool.get_ref(), user_id.as_str()).await {
+        Ok(loan_events) => HttpResponse::Ok().json(loan_events),
+        _ => HttpResponse::BadRequest().body("Error trying to get user loans"),
     }
 }
@@ -0,0 +1,33 @@
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+
+/// Every migration in daemon/migrations. Embedded at compile time
+pub static MIGRATOR: Migrator = sqlx::migrate!("../daemon/migrations");
+
+pub async fn get_db_pool(host: String, port: i32, username: String, password: String, database: String) -> anyhow::Result<PgPool> {
+    let pg_pool = PgPoolOptions::new()
+        .max_connections(5)
+        .connect(&format!("postgresql://{}:{}@{}:{}/{}", username, password, host, port, database))
+        .await?;
+
+    Ok(pg_pool)
+}
+
+pub async fn run_migrations(pg_pool: &PgPool) -> anyhow::Result<()> {
+    // sqlx assumes that a database without any applied migrations is already at version 0 so on a
+    // brand new database 000_init.sql would be validated rather than applied. Apply it ourselves
+    // before handing the rest off to the migrator.
+    let mut conn = pg_pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    if conn.version().await?.is_none() {
+        if let Some(init) = MIGRATOR.iter().find(|m| m.version == 0) {
+            conn.apply(init).await?;
+        }
+    }
+    drop(conn);
+
+    MIGRATOR.run(pg_pool).await?;
+
+    Ok(())
+}
@@ -0,0 +1,189 @@
+// A user's purchases and sales. Transactions are paged with a cursor rather than an offset so that
+// pages don't shift while the fleet keeps trading. Completed trades are the ones the daemon recorded
+// when a trader sold everything it bought for a trade.
+use crate::models::{UserTrade, UserTransaction};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+/// Transactions are only ever shown for one user. Everything else narrows them down further
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LedgerFilter {
+    pub ship_id: Option<String>,
+    pub good: Option<String>,
+    pub location: Option<String>,
+    /// purchase or sell
+    pub transaction_type: Option<String>,
+    /// Inclusive
+    pub since: Option<DateTime<Utc>>,
+    /// Exclusive
+    pub until: Option<DateTime<Utc>>,
+    pub trade_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LedgerSort {
+    #[default]
+    Newest,
+    Oldest,
+    LargestTotal,
+    SmallestTotal,
+}
+
+impl LedgerSort {
+    /// created_at and total sort ascending and -created_at and -total sort descending
+    pub fn parse(sort: &str) -> anyhow::Result<LedgerSort> {
+        match sort {
+            "-created_at" => Ok(LedgerSort::Newest),
+            "created_at" => Ok(LedgerSort::Oldest),
+            "-total" => Ok(LedgerSort::LargestTotal),
+            "total" => Ok(LedgerSort::SmallestTotal),
+            _ => Err(anyhow::anyhow!("Unknown sort {}. Expected one of created_at, -created_at, total or -total", sort)),
+        }
+    }
+
+    pub fn is_by_total(&self) -> bool {
+        matches!(self, LedgerSort::LargestTotal | LedgerSort::SmallestTotal)
+    }
+
+    pub fn is_descending(&self) -> bool {
+        matches!(self, LedgerSort::Newest | LedgerSort::LargestTotal)
+    }
+}
+
+/// Where the previous page left off. The value of the sort column and the id of the last
+/// transaction on the page. Times are in microseconds, the precision postgres keeps them at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    pub sort_value: i64,
+    pub id: i64,
+}
+
+impl Cursor {
+    pub fn after(transaction: &UserTransaction, sort: LedgerSort) -> Cursor {
+        let sort_value = if sort.is_by_total() {
+            i64::from(transaction.total)
+        } else {
+            transaction.created_at.timestamp() * 1_000_000 + i64::from(transaction.created_at.timestamp_subsec_micros())
+        };
+
+        Cursor {
+            sort_value,
+            id: transaction.id,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{}_{}", self.sort_value, self.id)
+    }
+
+    pub fn decode(cursor: &str) -> anyhow::Result<Cursor> {
+        let mut parts = cursor.splitn(2, '_');
+        let parsed = match (parts.next(), parts.next()) {
+            (Some(sort_value), Some(id)) => sort_value.parse::<i64>().ok().zip(id.parse::<i64>().ok()),
+            _ => None,
+        };
+
+        match parsed {
+            Some((sort_value, id)) => Ok(Cursor { sort_value, id }),
+            None => Err(anyhow::anyhow!("Invalid cursor {}", cursor)),
+        }
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        Utc.timestamp(self.sort_value.div_euclid(1_000_000), (self.sort_value.rem_euclid(1_000_000) * 1_000) as u32)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerPage {
+    pub transactions: Vec<UserTransaction>,
+    /// Pass this back to get the next page. None on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// The trades that match a filter, newest first, along with what they made and what the route
+/// scorer expected them to make when they were picked
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TradeSummary {
+    pub trades: Vec<UserTrade>,
+    pub realized_profit: i64,
+    pub predicted_profit: i64,
+}
+
+impl TradeSummary {
+    pub fn new(trades: Vec<UserTrade>) -> TradeSummary {
+        let realized_profit = trades.iter().map(|t| i64::from(t.profit)).sum();
+        let predicted_profit = trades.iter().map(|t| i64::from(t.predicted_profit)).sum();
+
+        TradeSummary {
+            trades,
+            realized_profit,
+            predicted_profit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_round_trip() {
+        let transaction = UserTransaction {
+            id: 42,
+            user_id: "user".to_string(),
+            ship_id: "ship-1".to_string(),
+            transaction_type: "purchase".to_string(),
+            good: "Metals".to_string(),
+            price_per_unit: 10,
+            quantity: 10,
+            total: 100,
+            location: "OE-PM".to_string(),
+            trade_id: None,
+            created_at: Utc.timestamp(1_625_000_000, 0),
+        };
+
+        let cursor = Cursor::decode(&Cursor::after(&transaction, LedgerSort::Newest).encode()).unwrap();
+        assert_eq!(cursor.id, 42);
+        assert_eq!(cursor.created_at(), transaction.created_at);
+
+        let cursor = Cursor::decode(&Cursor::after(&transaction, LedgerSort::LargestTotal).encode()).unwrap();
+        assert_eq!(cursor.sort_value, 100);
+
+        assert!(Cursor::decode("nope").is_err());
+        assert!(Cursor::decode("1_x").is_err());
+        assert!(LedgerSort::parse("price").is_err());
+    }
+
+    fn trade(trade_id: &str, profit: i32, predicted_profit: i32) -> UserTrade {
+        UserTrade {
+            trade_id: trade_id.to_string(),
+            user_id: "user".to_string(),
+            ship_id: "ship-1".to_string(),
+            good: "Metals".to_string(),
+            purchase_location: "OE-PM".to_string(),
+            sell_location: "OE-UC".to_string(),
+            quantity: 100,
+            cost: 1_000,
+            revenue: 1_000 + profit,
+            fuel_cost: 0,
+            profit,
+            predicted_profit,
+            duration_seconds: 300,
+            started_at: Utc.timestamp(1_625_000_000, 0),
+            completed_at: Utc.timestamp(1_625_000_300, 0),
+        }
+    }
+
+    #[test]
+    fn trade_summaries_add_up_realized_and_predicted_profit() {
+        let summary = TradeSummary::new(vec![trade("trade-1", 950, 1_000), trade("trade-2", -100, 200)]);
+
+        assert_eq!(summary.trades.len(), 2);
+        assert_eq!(summary.realized_profit, 850);
+        assert_eq!(summary.predicted_profit, 1_200);
+    }
+}
@@ -0,0 +1,12 @@
+// The parts of the database that the daemon and the api share. The schema belongs to
+// daemon/migrations and every query that both binaries run lives here so that renaming a column
+// breaks both builds (and the api's endpoint tests) rather than turning into a 500 in the api.
+pub mod db;
+pub mod ledger;
+pub mod models;
+pub mod planner;
+pub mod queries;
+pub mod routes;
+pub mod scoring;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
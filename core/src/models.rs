@@ -0,0 +1,207 @@
+// The rows that the api serves and the daemon reads back. Field names follow the columns in
+// daemon/migrations.
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use spacetraders::shared::Good;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketData {
+    pub id: i32,
+    pub location: String,
+    pub system: String,
+    pub good: String,
+    pub price_per_unit: i32,
+    pub volume_per_unit: i32,
+    pub quantity_available: i32,
+    pub created_at: DateTime<Utc>,
+    pub purchase_price_per_unit: i32,
+    pub sell_price_per_unit: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    pub new_ship_assignment: String,
+    pub new_ship_system: Option<String>,
+    pub credits: i32,
+    pub ship_count: i32,
+    pub ships: Option<String>,
+    pub stats_updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserStats {
+    pub user_id: String,
+    pub credits: i32,
+    pub ship_count: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserShip {
+    pub user_id: String,
+    pub ship_id: String,
+    pub ship_type: String,
+    pub class: String,
+    pub max_cargo: i32,
+    pub speed: i32,
+    pub manufacturer: String,
+    pub plating: i32,
+    pub weapons: i32,
+    pub system: String,
+    pub assignment: String,
+    pub modified_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What a ship is doing. Stored as the snake case name in daemon_user_ship.assignment and
+/// daemon_user.new_ship_assignment
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShipAssignment {
+    Trader,
+    Scout,
+    /// Moving to the ship's assigned system. The daemon switches ships to this on its own, it isn't
+    /// something a ship can be assigned to do
+    SystemChange,
+}
+
+impl Display for ShipAssignment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ShipAssignment::Trader => write!(f, "trader"),
+            ShipAssignment::Scout => write!(f, "scout"),
+            ShipAssignment::SystemChange => write!(f, "system_change"),
+        }
+    }
+}
+
+impl FromStr for ShipAssignment {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trader" => Ok(ShipAssignment::Trader),
+            "scout" => Ok(ShipAssignment::Scout),
+            "system_change" => Ok(ShipAssignment::SystemChange),
+            other => Err(anyhow::anyhow!("Unknown ship assignment {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserShipAssignment {
+    pub user_id: String,
+    pub ship_id: String,
+    pub system: String,
+    pub assignment: String,
+    pub modified_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserTransaction {
+    pub id: i64,
+    pub user_id: String,
+    pub ship_id: String,
+    pub transaction_type: String,
+    pub good: String,
+    pub price_per_unit: i32,
+    pub quantity: i32,
+    pub total: i32,
+    pub location: String,
+    /// The trade the order was made for. None for orders made outside of a trade
+    pub trade_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A trade that a ship bought, carried and sold. Every order and flight plan made for it carries
+/// its trade_id
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserTrade {
+    pub trade_id: String,
+    pub user_id: String,
+    pub ship_id: String,
+    pub good: String,
+    pub purchase_location: String,
+    pub sell_location: String,
+    /// Units sold
+    pub quantity: i32,
+    pub cost: i32,
+    pub revenue: i32,
+    /// Fuel bought along the way less any fuel sold back at the end
+    pub fuel_cost: i32,
+    /// revenue - cost - fuel_cost
+    pub profit: i32,
+    /// What the route scorer expected the trade to make when it was picked
+    pub predicted_profit: i32,
+    pub duration_seconds: i64,
+    pub started_at: DateTime<Utc>,
+    pub completed_at: DateTime<Utc>,
+}
+
+/// Something that happened to one of a user's loans
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoanEvent {
+    pub user_id: String,
+    pub loan_id: String,
+    pub loan_type: String,
+    /// requested or repaid
+    pub event_type: String,
+    /// What the loan costs to pay back
+    pub amount: i32,
+    pub due_at: DateTime<Utc>,
+    /// The user's credits after the event
+    pub credits: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Something that happened to one of a user's ships
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShipEvent {
+    pub user_id: String,
+    pub ship_id: String,
+    pub ship_type: String,
+    /// purchased, retiring or retired
+    pub event_type: String,
+    /// Where the ship was bought or where it is parked
+    pub location: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SystemLocation {
+    pub system: String,
+    pub system_name: String,
+    pub location: String,
+    pub location_name: String,
+    pub location_type: String,
+    pub x: i32,
+    pub y: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The latest market data for a good at a location. See `queries::trading_market_data`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LatestMarketData {
+    pub location: String,
+    pub good: Good,
+    pub price_per_unit: i32,
+    pub volume_per_unit: i32,
+    pub quantity_available: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// How much our own orders move the price of a good at a location. The daemon learns these from
+/// its past orders, see `scoring::LearnedSlippage` for how they are used
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlippageModel {
+    pub location: String,
+    pub good: Good,
+    pub purchase_impact: f64,
+    pub sell_impact: f64,
+    pub purchase_samples: i32,
+    pub sell_samples: i32,
+}
@@ -0,0 +1,331 @@
+// Plans trades that can span several systems. Every known location is a node. Locations in the
+// same system are connected by a flight and each pair of wormholes connects two systems with a
+// warp jump. Trades are found by buying at the ship's location and finding the quickest path to
+// every location that buys the same good. The daemon's traders pick their trades from these and
+// the api shows the same plans on the dashboard.
+use crate::models::{LatestMarketData, SystemLocation};
+use crate::routes::{self, flight_time, fuel_required};
+use serde::{Deserialize, Serialize};
+use spacetraders::shared::Good;
+use std::cmp::Ordering::Equal;
+use std::collections::{HashMap, HashSet};
+
+/// Warp jumps don't use any fuel but take about three minutes regardless of the ship
+pub const WARP_JUMP_SECONDS: f64 = 180.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LegType {
+    Flight,
+    Warp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Leg {
+    pub leg_type: LegType,
+    pub origin: String,
+    pub destination: String,
+    pub distance: f64,
+    pub fuel_required: f64,
+    pub flight_time: f64,
+}
+
+/// Buy a good at the purchase location and carry it over one or more legs to the sell location
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TradePlan {
+    pub good: Good,
+    pub purchase_location: String,
+    pub sell_location: String,
+    pub purchase_quantity: i32,
+    pub sell_quantity: i32,
+    pub purchase_price_per_unit: i32,
+    pub sell_price_per_unit: i32,
+    pub volume_per_unit: i32,
+    pub legs: Vec<Leg>,
+    pub fuel_required: f64,
+    pub flight_time: f64,
+    pub profit_volume_time: f64,
+    /// What fuel costs at the purchase location. None when it isn't sold there
+    #[serde(default)]
+    pub fuel_price_per_unit: Option<i32>,
+    /// What the fuel bought along the way after the first leg costs at the markets it is bought at
+    #[serde(default)]
+    pub fuel_cost_after_first_leg: f64,
+}
+
+impl TradePlan {
+    /// Fuel that has to be bought along the way after the first leg. Space for this needs to be
+    /// left in the hold when buying the goods.
+    pub fn fuel_required_after_first_leg(&self) -> i32 {
+        self.legs.iter().skip(1).fold(0.0, |acc, l| acc + l.fuel_required).ceil() as i32
+    }
+}
+
+fn distance_between(origin: &SystemLocation, destination: &SystemLocation) -> f64 {
+    routes::distance(origin.x, origin.y, destination.x, destination.y)
+}
+
+/// Wormholes are named after the system they lead to (OE-W-XV leads to XV) and are paired with
+/// the wormhole on the other side that leads back (XV-W-OE).
+fn is_wormhole_pair(from: &SystemLocation, to: &SystemLocation) -> bool {
+    from.location_type == "Wormhole"
+        && to.location_type == "Wormhole"
+        && from.system != to.system
+        && from.location.ends_with(&format!("-{}", to.system))
+        && to.location.ends_with(&format!("-{}", from.system))
+}
+
+#[derive(Debug, Clone)]
+pub struct RoutePlanner {
+    locations: Vec<SystemLocation>,
+    // Locations where a ship can fill up before flying on
+    refuel_locations: HashSet<String>,
+}
+
+impl RoutePlanner {
+    pub fn new(locations: Vec<SystemLocation>, market_data: &[LatestMarketData]) -> RoutePlanner {
+        let refuel_locations = market_data.iter()
+            .filter(|m| m.good == Good::Fuel && m.quantity_available > 0)
+            .map(|m| m.location.clone())
+            .collect();
+
+        RoutePlanner {
+            locations,
+            refuel_locations,
+        }
+    }
+
+    fn location(&self, location: &str) -> Option<&SystemLocation> {
+        self.locations.iter().find(|l| l.location == location)
+    }
+
+    fn leg(&self, from: &SystemLocation, to: &SystemLocation, ship_speed: i32, ship_type: &str) -> Option<Leg> {
+        if from.location == to.location {
+            return None;
+        }
+
+        if is_wormhole_pair(from, to) {
+            return Some(Leg {
+                leg_type: LegType::Warp,
+                origin: from.location.clone(),
+                destination: to.location.clone(),
+                distance: 0.0,
+                fuel_required: 0.0,
+                flight_time: WARP_JUMP_SECONDS,
+            });
+        }
+
+        if from.system != to.system {
+            return None;
+        }
+
+        let distance = distance_between(from, to);
+
+        Some(Leg {
+            leg_type: LegType::Flight,
+            origin: from.location.clone(),
+            destination: to.location.clone(),
+            distance,
+            fuel_required: fuel_required(&from.location_type, distance, ship_type),
+            flight_time: flight_time(distance, ship_speed),
+        })
+    }
+
+    /// The quickest path from the origin to every reachable location. A ship can only fly on from
+    /// a location where it can buy more fuel, the origin itself or after a warp jump into a
+    /// wormhole with a fuel market.
+    pub fn paths_from(&self, origin: &str, ship_speed: i32, ship_type: &str) -> HashMap<String, Vec<Leg>> {
+        let mut best_time: HashMap<String, f64> = HashMap::new();
+        let mut paths: HashMap<String, Vec<Leg>> = HashMap::new();
+        let mut visited: HashSet<String> = HashSet::new();
+
+        if self.location(origin).is_none() {
+            return paths;
+        }
+
+        best_time.insert(origin.to_string(), 0.0);
+        paths.insert(origin.to_string(), Vec::new());
+
+        // There are only a few dozen locations so a simple dijkstra without a heap is plenty
+        loop {
+            let next = best_time.iter()
+                .filter(|(location, _)| !visited.contains(*location))
+                .min_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(Equal))
+                .map(|(location, time)| (location.clone(), *time));
+
+            let (current, current_time) = match next {
+                Some(next) => next,
+                None => break,
+            };
+
+            visited.insert(current.clone());
+
+            let from = self.location(&current).unwrap();
+            let can_fly_on = current == origin || self.refuel_locations.contains(&current);
+
+            for to in &self.locations {
+                if visited.contains(&to.location) {
+                    continue;
+                }
+
+                let leg = match self.leg(from, to, ship_speed, ship_type) {
+                    Some(leg) => leg,
+                    None => continue,
+                };
+
+                if leg.leg_type == LegType::Flight && !can_fly_on {
+                    continue;
+                }
+
+                let time = current_time + leg.flight_time;
+                if best_time.get(&to.location).is_none_or(|t| time < *t) {
+                    let mut path = paths[&current].clone();
+                    path.push(leg);
+
+                    best_time.insert(to.location.clone(), time);
+                    paths.insert(to.location.clone(), path);
+                }
+            }
+        }
+
+        paths.remove(origin);
+        paths
+    }
+
+    /// Every trade that starts by buying at the origin, most profitable first
+    pub fn plan_trades(&self, origin: &str, ship_speed: i32, ship_type: &str, market_data: &[LatestMarketData]) -> Vec<TradePlan> {
+        let paths = self.paths_from(origin, ship_speed, ship_type);
+        let fuel_prices: HashMap<&str, i32> = market_data.iter()
+            .filter(|m| m.good == Good::Fuel)
+            .map(|m| (m.location.as_str(), m.price_per_unit))
+            .collect();
+        let fuel_price_per_unit = fuel_prices.get(origin).copied();
+
+        let mut plans: Vec<TradePlan> = Vec::new();
+        for purchase in market_data.iter().filter(|m| m.location == origin) {
+            for sell in market_data.iter().filter(|m| m.good == purchase.good && m.location != origin) {
+                let legs = match paths.get(&sell.location) {
+                    Some(legs) => legs.clone(),
+                    None => continue,
+                };
+
+                let fuel_required = legs.iter().fold(0.0, |acc, l| acc + l.fuel_required);
+                let flight_time = legs.iter().fold(0.0, |acc, l| acc + l.flight_time);
+                // Ships only fly on from places that sell fuel so every later flight has a price
+                let fuel_cost_after_first_leg = legs.iter().skip(1)
+                    .map(|l| l.fuel_required * f64::from(fuel_prices.get(l.origin.as_str()).copied().unwrap_or(0)))
+                    .sum();
+                let profit = f64::from(sell.price_per_unit - purchase.price_per_unit);
+
+                plans.push(TradePlan {
+                    good: purchase.good,
+                    purchase_location: purchase.location.clone(),
+                    sell_location: sell.location.clone(),
+                    purchase_quantity: purchase.quantity_available,
+                    sell_quantity: sell.quantity_available,
+                    purchase_price_per_unit: purchase.price_per_unit,
+                    sell_price_per_unit: sell.price_per_unit,
+                    volume_per_unit: purchase.volume_per_unit,
+                    legs,
+                    fuel_required,
+                    flight_time,
+                    profit_volume_time: profit / f64::from(purchase.volume_per_unit) / flight_time,
+                    fuel_price_per_unit,
+                    fuel_cost_after_first_leg,
+                });
+            }
+        }
+
+        plans.sort_by(|a, b| b.profit_volume_time.partial_cmp(&a.profit_volume_time).unwrap_or(Equal));
+        plans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn location(system: &str, location: &str, location_type: &str, x: i32, y: i32) -> SystemLocation {
+        SystemLocation {
+            system: system.to_string(),
+            system_name: system.to_string(),
+            location: location.to_string(),
+            location_name: location.to_string(),
+            location_type: location_type.to_string(),
+            x,
+            y,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn market(location: &str, good: Good, price_per_unit: i32) -> LatestMarketData {
+        LatestMarketData {
+            location: location.to_string(),
+            good,
+            price_per_unit,
+            volume_per_unit: 1,
+            quantity_available: 1_000,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn locations() -> Vec<SystemLocation> {
+        vec![
+            location("OE", "OE-PM", "Planet", 0, 0),
+            location("OE", "OE-CR", "Planet", 40, 0),
+            location("OE", "OE-W-XV", "Wormhole", 100, 0),
+            location("XV", "XV-W-OE", "Wormhole", 0, 0),
+            location("XV", "XV-BN", "Planet", 20, 0),
+            location("XV", "XV-CB", "Asteroid", 80, 0),
+        ]
+    }
+
+    #[test]
+    fn paths_cross_systems_through_the_wormholes() {
+        let market_data = vec![market("XV-W-OE", Good::Fuel, 5)];
+        let planner = RoutePlanner::new(locations(), &market_data);
+
+        let paths = planner.paths_from("OE-PM", 2, "GR-MK-I");
+        let legs: Vec<(LegType, &str)> = paths["XV-BN"].iter()
+            .map(|l| (l.leg_type, l.destination.as_str()))
+            .collect();
+
+        assert_eq!(legs, vec![(LegType::Flight, "OE-W-XV"), (LegType::Warp, "XV-W-OE"), (LegType::Flight, "XV-BN")]);
+        assert!(paths["XV-BN"][1].fuel_required.abs() < f64::EPSILON);
+        assert!((paths["XV-BN"][1].flight_time - WARP_JUMP_SECONDS).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn ships_cannot_fly_on_from_somewhere_without_fuel() {
+        let planner = RoutePlanner::new(locations(), &[]);
+
+        let paths = planner.paths_from("OE-PM", 2, "GR-MK-I");
+
+        assert!(paths.contains_key("OE-W-XV"));
+        assert!(paths.contains_key("XV-W-OE"));
+        assert!(!paths.contains_key("XV-BN"));
+    }
+
+    #[test]
+    fn plans_include_trades_into_other_systems() {
+        let market_data = vec![
+            market("OE-PM", Good::Metals, 10),
+            market("OE-CR", Good::Metals, 12),
+            market("XV-W-OE", Good::Fuel, 5),
+            market("XV-BN", Good::Metals, 200),
+        ];
+        let planner = RoutePlanner::new(locations(), &market_data);
+
+        let plans = planner.plan_trades("OE-PM", 2, "GR-MK-I", &market_data);
+
+        assert_eq!(plans.len(), 2);
+        assert_eq!(plans[0].sell_location, "XV-BN");
+        assert_eq!(plans[0].legs.len(), 3);
+        assert!((plans[0].flight_time - plans[0].legs.iter().fold(0.0, |acc, l| acc + l.flight_time)).abs() < f64::EPSILON);
+        assert_eq!(plans[0].fuel_required_after_first_leg(), plans[0].legs[2].fuel_required.ceil() as i32);
+        assert!((plans[0].fuel_cost_after_first_leg - plans[0].legs[2].fuel_required * 5.0).abs() < f64::EPSILON);
+        assert_eq!(plans[1].sell_location, "OE-CR");
+        assert!(plans[1].fuel_cost_after_first_leg.abs() < f64::EPSILON);
+        assert_eq!(plans[1].legs.len(), 1);
+    }
+}
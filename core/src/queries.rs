@@ -0,0 +1,893 @@
+// Every query that the api serves along with the ones the daemon shares with it.
+use crate::models::{LatestMarketData, LoanEvent, MarketData, ShipEvent, SlippageModel, SystemLocation, User, UserShip, UserShipAssignment, UserStats, UserTrade, UserTransaction};
+use crate::ledger::{Cursor, LedgerFilter, LedgerPage, LedgerSort, TradeSummary, MAX_PAGE_SIZE};
+use crate::routes::{LocationGood, MARKET_DATA_MAX_AGE_MINUTES};
+use chrono::{DateTime, Utc};
+use sqlx::postgres::PgRow;
+use spacetraders::shared::Good;
+use sqlx::{PgPool, Row};
+
+fn market_data_from_row(row: PgRow) -> MarketData {
+    MarketData {
+        id: row.get("id"),
+        location: row.get("location"),
+        system: row.get("system"),
+        good: row.get("good"),
+        price_per_unit: row.get("price_per_unit"),
+        volume_per_unit: row.get("volume_per_unit"),
+        quantity_available: row.get("quantity_available"),
+        created_at: row.get("created_at"),
+        purchase_price_per_unit: row.get("purchase_price_per_unit"),
+        sell_price_per_unit: row.get("sell_price_per_unit"),
+    }
+}
+
+// systems
+
+pub async fn system_locations(pg_pool: &PgPool) -> anyhow::Result<Vec<SystemLocation>> {
+    Ok(sqlx::query("
+        SELECT
+             system
+            ,system_name
+            ,location
+            ,location_name
+            ,location_type
+            ,x
+            ,y
+            ,created_at
+        FROM daemon_system_info
+        ORDER BY system, location;
+    ")
+        .map(|row: PgRow| {
+            SystemLocation {
+                system: row.get("system"),
+                system_name: row.get("system_name"),
+                location: row.get("location"),
+                location_name: row.get("location_name"),
+                location_type: row.get("location_type"),
+                x: row.get("x"),
+                y: row.get("y"),
+                created_at: row.get("created_at"),
+            }
+        })
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+pub async fn system_exists(pg_pool: &PgPool, system: &str) -> anyhow::Result<bool> {
+    Ok(sqlx::query("
+        SELECT EXISTS (
+            SELECT 1
+            FROM daemon_system_info dsi
+            WHERE dsi.system = $1
+        ) AS system_exists;
+    ")
+        .bind(system)
+        .map(|row: PgRow| row.get("system_exists"))
+        .fetch_one(pg_pool)
+        .await?
+    )
+}
+
+/// Every good that has been seen in a market in the system
+pub async fn system_goods(pg_pool: &PgPool, system: &str) -> anyhow::Result<Vec<String>> {
+    Ok(sqlx::query("
+        SELECT DISTINCT
+            dmd.good
+        FROM daemon_market_data dmd
+        INNER JOIN daemon_system_info dsi
+            ON dmd.location = dsi.location
+        WHERE dsi.system = $1
+        ORDER BY dmd.good;
+    ")
+        .bind(system)
+        .map(|row: PgRow| row.get("good"))
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+// market data
+
+/// The latest market data for each good in each location
+pub async fn latest_market_data(pg_pool: &PgPool) -> anyhow::Result<Vec<MarketData>> {
+    Ok(sqlx::query("
+        WITH ranked_location_goods AS (
+            SELECT
+                 md.id
+                ,ROW_NUMBER() OVER (
+                    PARTITION BY md.location, md.good
+                    ORDER BY md.created_at DESC
+                ) AS rank
+            FROM daemon_market_data md
+        )
+        SELECT
+             md.id
+            ,md.location
+            ,si.system
+            ,md.good
+            ,md.price_per_unit
+            ,md.volume_per_unit
+            ,md.quantity_available
+            ,md.created_at
+            ,md.purchase_price_per_unit
+            ,md.sell_price_per_unit
+        FROM daemon_market_data md
+        INNER JOIN ranked_location_goods rlg ON md.id = rlg.id
+        INNER JOIN daemon_system_info si ON md.location = si.location
+        WHERE rlg.rank = 1
+        ORDER BY md.location, md.good;
+    ")
+        .map(market_data_from_row)
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+/// Every good that has been seen in the location's market
+pub async fn location_goods(pg_pool: &PgPool, location: &str) -> anyhow::Result<Vec<String>> {
+    Ok(sqlx::query("
+        SELECT DISTINCT
+            md.good
+        FROM daemon_market_data md
+        WHERE md.location = $1
+        ORDER BY md.good;
+    ")
+        .bind(location)
+        .map(|row: PgRow| row.get("good"))
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+/// All of the market data ever recorded for the location
+pub async fn location_market_data(pg_pool: &PgPool, location: &str) -> anyhow::Result<Vec<MarketData>> {
+    Ok(sqlx::query("
+        SELECT
+             md.id
+            ,md.location
+            ,si.system
+            ,md.good
+            ,md.price_per_unit
+            ,md.volume_per_unit
+            ,md.quantity_available
+            ,md.created_at
+            ,md.purchase_price_per_unit
+            ,md.sell_price_per_unit
+        FROM daemon_market_data md
+        INNER JOIN daemon_system_info si ON si.location = md.location
+        WHERE md.location = $1
+        ORDER BY md.location, md.good, md.created_at;
+    ")
+        .bind(location)
+        .map(market_data_from_row)
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+/// The market data for a good in the location since the start of the day days_ago days ago, newest
+/// first
+pub async fn location_good_market_data(pg_pool: &PgPool, location: &str, good: &str, days_ago: i32) -> anyhow::Result<Vec<MarketData>> {
+    Ok(sqlx::query("
+        SELECT
+             md.id
+            ,md.location
+            ,si.system
+            ,md.good
+            ,md.price_per_unit
+            ,md.volume_per_unit
+            ,md.quantity_available
+            ,md.created_at
+            ,md.purchase_price_per_unit
+            ,md.sell_price_per_unit
+        FROM daemon_market_data md
+        INNER JOIN daemon_system_info si ON md.location = si.location
+        WHERE md.location = $1
+            AND md.good = $2
+            AND md.created_at > date_trunc('day', NOW()) - make_interval(days => $3)
+        ORDER BY md.created_at DESC;
+    ")
+        .bind(location)
+        .bind(good)
+        .bind(days_ago)
+        .map(market_data_from_row)
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+// routes
+
+/// The latest market data for each good in each location, ignoring anything too old to trade on.
+/// See `routes`
+pub async fn latest_location_goods(pg_pool: &PgPool) -> anyhow::Result<Vec<LocationGood>> {
+    Ok(sqlx::query("
+        WITH ranked_location_goods AS (
+            SELECT
+                 id
+                ,ROW_NUMBER() OVER (
+                    PARTITION BY location, good
+                    ORDER BY created_at DESC
+                ) AS rank
+            FROM daemon_market_data
+        )
+        SELECT
+             dsi.system
+            ,dmd.location
+            ,dsi.location_type
+            ,dsi.x
+            ,dsi.y
+            ,dmd.good
+            ,dmd.quantity_available
+            ,dmd.price_per_unit
+            ,dmd.volume_per_unit
+            ,dmd.created_at
+        FROM daemon_market_data dmd
+        INNER JOIN ranked_location_goods rlg ON dmd.id = rlg.id
+        INNER JOIN daemon_system_info dsi ON dmd.location = dsi.location
+        WHERE rlg.rank = 1
+            AND dmd.created_at > NOW() - make_interval(mins => $1)
+        ORDER BY dmd.good, dmd.location;
+    ")
+        .bind(MARKET_DATA_MAX_AGE_MINUTES as i32)
+        .map(|row: PgRow| {
+            LocationGood {
+                system: row.get("system"),
+                location: row.get("location"),
+                location_type: row.get("location_type"),
+                x: row.get("x"),
+                y: row.get("y"),
+                good: row.get("good"),
+                quantity_available: row.get("quantity_available"),
+                price_per_unit: row.get("price_per_unit"),
+                volume_per_unit: row.get("volume_per_unit"),
+                created_at: row.get("created_at"),
+            }
+        })
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+/// The latest market data for each good in each location, ignoring anything too old to trade on.
+/// This is what `planner::RoutePlanner` plans trades from
+pub async fn trading_market_data(pg_pool: &PgPool) -> anyhow::Result<Vec<LatestMarketData>> {
+    Ok(sqlx::query("
+        WITH ranked_location_goods AS (
+            SELECT
+                 id
+                ,ROW_NUMBER() OVER (
+                    PARTITION BY location, good
+                    ORDER BY created_at DESC
+                ) AS rank
+            FROM daemon_market_data
+        )
+        SELECT
+             dmd.location
+            ,dmd.good
+            ,dmd.price_per_unit
+            ,dmd.volume_per_unit
+            ,dmd.quantity_available
+            ,dmd.created_at
+        FROM daemon_market_data dmd
+        INNER JOIN ranked_location_goods rlg ON dmd.id = rlg.id
+        WHERE rlg.rank = 1
+            AND dmd.created_at > NOW() - make_interval(mins => $1)
+        ORDER BY dmd.good, dmd.location;
+    ")
+        .bind(MARKET_DATA_MAX_AGE_MINUTES as i32)
+        .map(|row: PgRow| {
+            LatestMarketData {
+                location: row.get("location"),
+                good: Good::from(row.get::<String, &str>("good")),
+                price_per_unit: row.get("price_per_unit"),
+                volume_per_unit: row.get("volume_per_unit"),
+                quantity_available: row.get("quantity_available"),
+                created_at: row.get("created_at"),
+            }
+        })
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+/// How much the daemon has learned our orders move the price of each good at each location
+pub async fn slippage_models(pg_pool: &PgPool) -> anyhow::Result<Vec<SlippageModel>> {
+    Ok(sqlx::query("
+        SELECT
+             location
+            ,good
+            ,purchase_impact
+            ,sell_impact
+            ,purchase_samples
+            ,sell_samples
+        FROM daemon_slippage_model
+    ")
+        .map(|row: PgRow| {
+            SlippageModel {
+                location: row.get("location"),
+                good: Good::from(row.get::<String, &str>("good")),
+                purchase_impact: row.get("purchase_impact"),
+                sell_impact: row.get("sell_impact"),
+                purchase_samples: row.get("purchase_samples"),
+                sell_samples: row.get("sell_samples"),
+            }
+        })
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+// users
+
+/// Every user that has recorded stats along with their latest stats
+pub async fn users(pg_pool: &PgPool) -> anyhow::Result<Vec<User>> {
+    Ok(sqlx::query("
+        WITH user_stats AS (
+            SELECT
+                 user_id
+                ,credits
+                ,ship_count
+                ,ships
+                ,created_at
+                ,ROW_NUMBER() OVER (PARTITION BY user_id ORDER BY created_at DESC) as rank
+            FROM daemon_user_stats
+        )
+        SELECT
+             u.id::text
+            ,u.username
+            ,u.new_ship_assignment
+            ,u.new_ship_system
+            ,us.credits
+            ,us.ship_count
+            ,us.ships::text
+            ,us.created_at as stats_updated_at
+        FROM daemon_user u
+        INNER JOIN user_stats us
+            ON u.id = us.user_id
+        WHERE us.rank = 1
+        ORDER BY u.username;
+    ")
+        .map(|row: PgRow| {
+            User {
+                id: row.get("id"),
+                username: row.get("username"),
+                new_ship_assignment: row.get("new_ship_assignment"),
+                new_ship_system: row.get("new_ship_system"),
+                credits: row.get("credits"),
+                ship_count: row.get("ship_count"),
+                ships: row.get("ships"),
+                stats_updated_at: row.get("stats_updated_at"),
+            }
+        })
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+pub async fn username(pg_pool: &PgPool, user_id: &str) -> anyhow::Result<Option<String>> {
+    Ok(sqlx::query("
+        SELECT
+            u.username
+        FROM daemon_user u
+        WHERE u.id = $1::uuid
+        LIMIT 1;
+    ")
+        .bind(user_id)
+        .map(|row: PgRow| row.get("username"))
+        .fetch_optional(pg_pool)
+        .await?
+    )
+}
+
+/// The user's highest credits and ship count in each 15 minute window since their first stats
+pub async fn user_stats(pg_pool: &PgPool, user_id: &str) -> anyhow::Result<Vec<UserStats>> {
+    Ok(sqlx::query("
+        WITH earliest_date AS (
+            SELECT MIN(created_at) AS earliest_date
+            FROM daemon_user_stats dus
+            WHERE dus.user_id = $1::uuid
+            LIMIT 1
+        ), time_group AS (
+            SELECT
+                 row_number() over (order by series) as id
+                ,series as end_date
+                ,series - '15 minutes'::interval as start_date
+            FROM generate_series(
+                date_trunc('hour', (SELECT earliest_date FROM earliest_date LIMIT 1)),
+                date_trunc('hour', NOW()) + '1 hour',
+                '15 minutes'::interval
+            ) as series
+        )
+        SELECT
+             tg.id
+            ,COALESCE(MAX(dus.credits), 0) as credits
+            ,COALESCE(MAX(dus.ship_count), 0) as ship_count
+            ,MAX(tg.end_date) as created_at
+        FROM time_group tg
+        LEFT JOIN daemon_user_stats dus
+            ON dus.created_at >= tg.start_date
+            AND dus.created_at < tg.end_date
+            AND dus.user_id = $1::uuid
+        GROUP BY tg.id
+        ORDER BY tg.id;
+    ")
+        .bind(user_id)
+        .map(|row: PgRow| {
+            UserStats {
+                user_id: user_id.to_string(),
+                credits: row.get("credits"),
+                ship_count: row.get("ship_count"),
+                created_at: row.get("created_at"),
+            }
+        })
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+pub async fn user_ships(pg_pool: &PgPool, user_id: &str) -> anyhow::Result<Vec<UserShip>> {
+    Ok(sqlx::query("
+        SELECT
+             user_id::text
+            ,ship_id
+            ,type
+            ,class
+            ,max_cargo
+            ,speed
+            ,manufacturer
+            ,plating
+            ,weapons
+            ,system
+            ,assignment
+            ,modified_at
+            ,created_at
+        FROM daemon_user_ship dus
+        WHERE dus.user_id = $1::uuid
+        ORDER BY created_at DESC;
+    ")
+        .bind(user_id)
+        .map(|row: PgRow| {
+            UserShip {
+                user_id: row.get("user_id"),
+                ship_id: row.get("ship_id"),
+                ship_type: row.get("type"),
+                class: row.get("class"),
+                max_cargo: row.get("max_cargo"),
+                speed: row.get("speed"),
+                manufacturer: row.get("manufacturer"),
+                plating: row.get("plating"),
+                weapons: row.get("weapons"),
+                system: row.get("system"),
+                assignment: row.get("assignment"),
+                modified_at: row.get("modified_at"),
+                created_at: row.get("created_at"),
+            }
+        })
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+/// None when the user doesn't have the ship
+pub async fn update_ship_assignment(pg_pool: &PgPool, user_id: &str, ship_id: &str, system: &str, assignment: &str) -> anyhow::Result<Option<UserShipAssignment>> {
+    Ok(sqlx::query("
+        UPDATE daemon_user_ship
+        SET
+             system = $3
+            ,assignment = $4
+            ,modified_at = timezone('utc', NOW())
+        WHERE user_id = $1::uuid
+            AND ship_id = $2
+        RETURNING
+             user_id::text
+            ,ship_id
+            ,system
+            ,assignment
+            ,modified_at;
+    ")
+        .bind(user_id)
+        .bind(ship_id)
+        .bind(system)
+        .bind(assignment)
+        .map(|row: PgRow| {
+            UserShipAssignment {
+                user_id: row.get("user_id"),
+                ship_id: row.get("ship_id"),
+                system: row.get("system"),
+                assignment: row.get("assignment"),
+                modified_at: row.get("modified_at"),
+            }
+        })
+        .fetch_optional(pg_pool)
+        .await?
+    )
+}
+
+fn transaction_from_row(row: PgRow) -> UserTransaction {
+    UserTransaction {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        ship_id: row.get("ship_id"),
+        transaction_type: row.get("type"),
+        good: row.get("good"),
+        price_per_unit: row.get("price_per_unit"),
+        quantity: row.get("quantity"),
+        total: row.get("total"),
+        location: row.get("location"),
+        trade_id: row.get("trade_id"),
+        created_at: row.get("created_at"),
+    }
+}
+
+/// Every purchase and sale the ship has made, newest first
+pub async fn ship_transactions(pg_pool: &PgPool, user_id: &str, ship_id: &str) -> anyhow::Result<Vec<UserTransaction>> {
+    let filter = LedgerFilter {
+        ship_id: Some(ship_id.to_string()),
+        ..LedgerFilter::default()
+    };
+
+    transactions(pg_pool, user_id, &filter, LedgerSort::Newest, None, None).await
+}
+
+// transactions
+
+/// The user's transactions that match the filter in the order asked for, starting after the cursor.
+/// Everything that matches when there's no limit
+pub async fn transactions(pg_pool: &PgPool, user_id: &str, filter: &LedgerFilter, sort: LedgerSort, cursor: Option<Cursor>, limit: Option<i64>) -> anyhow::Result<Vec<UserTransaction>> {
+    let sort_column = if sort.is_by_total() { "total" } else { "created_at" };
+    let (direction, comparison) = if sort.is_descending() { ("DESC", "<") } else { ("ASC", ">") };
+    let cursor_value = if sort.is_by_total() { "$8::bigint" } else { "$9::timestamptz" };
+
+    Ok(sqlx::query(&format!("
+        SELECT
+             id
+            ,user_id::text
+            ,ship_id
+            ,type
+            ,good
+            ,price_per_unit
+            ,quantity
+            ,total
+            ,location
+            ,trade_id::text
+            ,created_at
+        FROM daemon_user_transaction dut
+        WHERE dut.user_id = $1::uuid
+            AND ($2::text IS NULL OR dut.ship_id = $2)
+            AND ($3::text IS NULL OR dut.good = $3)
+            AND ($4::text IS NULL OR dut.location = $4)
+            AND ($5::text IS NULL OR dut.type = $5)
+            AND ($6::timestamptz IS NULL OR dut.created_at >= $6)
+            AND ($7::timestamptz IS NULL OR dut.created_at < $7)
+            AND ($10::bigint IS NULL OR (dut.{column}, dut.id) {comparison} ({cursor_value}, $10))
+            AND ($12::uuid IS NULL OR dut.trade_id = $12::uuid)
+        ORDER BY dut.{column} {direction}, dut.id {direction}
+        LIMIT $11;
+    ", column = sort_column, comparison = comparison, cursor_value = cursor_value, direction = direction))
+        .bind(user_id)
+        .bind(&filter.ship_id)
+        .bind(&filter.good)
+        .bind(&filter.location)
+        .bind(&filter.transaction_type)
+        .bind(filter.since)
+        .bind(filter.until)
+        .bind(cursor.map(|c| c.sort_value))
+        .bind(cursor.map(|c| c.created_at()))
+        .bind(cursor.map(|c| c.id))
+        .bind(limit)
+        .bind(&filter.trade_id)
+        .map(transaction_from_row)
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+/// One page of the user's transactions. page_size is kept between 1 and MAX_PAGE_SIZE
+pub async fn ledger_page(pg_pool: &PgPool, user_id: &str, filter: &LedgerFilter, sort: LedgerSort, cursor: Option<Cursor>, page_size: i64) -> anyhow::Result<LedgerPage> {
+    let page_size = page_size.clamp(1, MAX_PAGE_SIZE);
+
+    // Ask for one more than fits on the page to find out whether there is another page
+    let mut transactions = transactions(pg_pool, user_id, filter, sort, cursor, Some(page_size + 1)).await?;
+    let next_cursor = if transactions.len() as i64 > page_size {
+        transactions.truncate(page_size as usize);
+        transactions.last().map(|t| Cursor::after(t, sort).encode())
+    } else {
+        None
+    };
+
+    Ok(LedgerPage {
+        transactions,
+        next_cursor,
+    })
+}
+
+// trades
+
+fn trade_from_row(row: PgRow) -> UserTrade {
+    UserTrade {
+        trade_id: row.get("trade_id"),
+        user_id: row.get("user_id"),
+        ship_id: row.get("ship_id"),
+        good: row.get("good"),
+        purchase_location: row.get("purchase_location"),
+        sell_location: row.get("sell_location"),
+        quantity: row.get("quantity"),
+        cost: row.get("cost"),
+        revenue: row.get("revenue"),
+        fuel_cost: row.get("fuel_cost"),
+        profit: row.get("profit"),
+        predicted_profit: row.get("predicted_profit"),
+        duration_seconds: row.get("duration_seconds"),
+        started_at: row.get("started_at"),
+        completed_at: row.get("completed_at"),
+    }
+}
+
+pub async fn persist_trade(pg_pool: &PgPool, trade: &UserTrade) -> anyhow::Result<()> {
+    sqlx::query("
+        INSERT INTO daemon_user_trade (
+             trade_id
+            ,user_id
+            ,ship_id
+            ,good
+            ,purchase_location
+            ,sell_location
+            ,quantity
+            ,cost
+            ,revenue
+            ,fuel_cost
+            ,profit
+            ,predicted_profit
+            ,duration_seconds
+            ,started_at
+            ,completed_at
+        ) VALUES ($1::uuid, $2::uuid, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15);
+    ")
+        .bind(&trade.trade_id)
+        .bind(&trade.user_id)
+        .bind(&trade.ship_id)
+        .bind(&trade.good)
+        .bind(&trade.purchase_location)
+        .bind(&trade.sell_location)
+        .bind(trade.quantity)
+        .bind(trade.cost)
+        .bind(trade.revenue)
+        .bind(trade.fuel_cost)
+        .bind(trade.profit)
+        .bind(trade.predicted_profit)
+        .bind(trade.duration_seconds)
+        .bind(trade.started_at)
+        .bind(trade.completed_at)
+        .execute(pg_pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Every trade a user's ships have completed since `since`, oldest first
+pub async fn trades(pg_pool: &PgPool, user_id: &str, since: DateTime<Utc>) -> anyhow::Result<Vec<UserTrade>> {
+    Ok(sqlx::query("
+        SELECT
+             trade_id::text
+            ,user_id::text
+            ,ship_id
+            ,good
+            ,purchase_location
+            ,sell_location
+            ,quantity
+            ,cost
+            ,revenue
+            ,fuel_cost
+            ,profit
+            ,predicted_profit
+            ,duration_seconds
+            ,started_at
+            ,completed_at
+        FROM daemon_user_trade
+        WHERE user_id = $1::uuid
+            AND completed_at >= $2
+        ORDER BY completed_at;
+    ")
+        .bind(user_id)
+        .bind(since)
+        .map(trade_from_row)
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+/// The user's completed trades that match the filter, newest first. since and until are compared
+/// against when the trade completed, location matches either end of the trade and the transaction
+/// type is ignored
+pub async fn trade_summary(pg_pool: &PgPool, user_id: &str, filter: &LedgerFilter) -> anyhow::Result<TradeSummary> {
+    let trades = sqlx::query("
+        SELECT
+             trade_id::text
+            ,user_id::text
+            ,ship_id
+            ,good
+            ,purchase_location
+            ,sell_location
+            ,quantity
+            ,cost
+            ,revenue
+            ,fuel_cost
+            ,profit
+            ,predicted_profit
+            ,duration_seconds
+            ,started_at
+            ,completed_at
+        FROM daemon_user_trade dut
+        WHERE dut.user_id = $1::uuid
+            AND ($2::text IS NULL OR dut.ship_id = $2)
+            AND ($3::text IS NULL OR dut.good = $3)
+            AND ($4::text IS NULL OR dut.purchase_location = $4 OR dut.sell_location = $4)
+            AND ($5::timestamptz IS NULL OR dut.completed_at >= $5)
+            AND ($6::timestamptz IS NULL OR dut.completed_at < $6)
+            AND ($7::uuid IS NULL OR dut.trade_id = $7::uuid)
+        ORDER BY dut.completed_at DESC, dut.trade_id;
+    ")
+        .bind(user_id)
+        .bind(&filter.ship_id)
+        .bind(&filter.good)
+        .bind(&filter.location)
+        .bind(filter.since)
+        .bind(filter.until)
+        .bind(&filter.trade_id)
+        .map(trade_from_row)
+        .fetch_all(pg_pool)
+        .await?;
+
+    Ok(TradeSummary::new(trades))
+}
+
+// loans
+
+/// created_at is set by the database
+pub async fn persist_loan_event(pg_pool: &PgPool, loan_event: &LoanEvent) -> anyhow::Result<()> {
+    sqlx::query("
+        INSERT INTO daemon_user_loan_event (
+             user_id
+            ,loan_id
+            ,loan_type
+            ,event_type
+            ,amount
+            ,due_at
+            ,credits
+        ) VALUES ($1::uuid, $2, $3, $4, $5, $6, $7);
+    ")
+        .bind(&loan_event.user_id)
+        .bind(&loan_event.loan_id)
+        .bind(&loan_event.loan_type)
+        .bind(&loan_event.event_type)
+        .bind(loan_event.amount)
+        .bind(loan_event.due_at)
+        .bind(loan_event.credits)
+        .execute(pg_pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Every loan event for a user, newest first
+pub async fn loan_events(pg_pool: &PgPool, user_id: &str) -> anyhow::Result<Vec<LoanEvent>> {
+    Ok(sqlx::query("
+        SELECT
+             user_id::text
+            ,loan_id
+            ,loan_type
+            ,event_type
+            ,amount
+            ,due_at
+            ,credits
+            ,created_at
+        FROM daemon_user_loan_event
+        WHERE user_id = $1::uuid
+        ORDER BY created_at DESC;
+    ")
+        .bind(user_id)
+        .map(|row: PgRow| {
+            LoanEvent {
+                user_id: row.get("user_id"),
+                loan_id: row.get("loan_id"),
+                loan_type: row.get("loan_type"),
+                event_type: row.get("event_type"),
+                amount: row.get("amount"),
+                due_at: row.get("due_at"),
+                credits: row.get("credits"),
+                created_at: row.get("created_at"),
+            }
+        })
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+// ship history
+
+/// created_at is set by the database
+pub async fn persist_ship_event(pg_pool: &PgPool, ship_event: &ShipEvent) -> anyhow::Result<()> {
+    sqlx::query("
+        INSERT INTO daemon_user_ship_history (
+             user_id
+            ,ship_id
+            ,ship_type
+            ,event_type
+            ,location
+        ) VALUES ($1::uuid, $2, $3, $4, $5);
+    ")
+        .bind(&ship_event.user_id)
+        .bind(&ship_event.ship_id)
+        .bind(&ship_event.ship_type)
+        .bind(&ship_event.event_type)
+        .bind(&ship_event.location)
+        .execute(pg_pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Everything that has happened to a user's ships, oldest first
+pub async fn ship_history(pg_pool: &PgPool, user_id: &str) -> anyhow::Result<Vec<ShipEvent>> {
+    Ok(sqlx::query("
+        SELECT
+             user_id::text
+            ,ship_id
+            ,ship_type
+            ,event_type
+            ,location
+            ,created_at
+        FROM daemon_user_ship_history
+        WHERE user_id = $1::uuid
+        ORDER BY created_at;
+    ")
+        .bind(user_id)
+        .map(|row: PgRow| {
+            ShipEvent {
+                user_id: row.get("user_id"),
+                ship_id: row.get("ship_id"),
+                ship_type: row.get("ship_type"),
+                event_type: row.get("event_type"),
+                location: row.get("location"),
+                created_at: row.get("created_at"),
+            }
+        })
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
+
+/// Everything that has happened to one of the user's ships, newest first
+pub async fn ship_events(pg_pool: &PgPool, user_id: &str, ship_id: &str) -> anyhow::Result<Vec<ShipEvent>> {
+    Ok(sqlx::query("
+        SELECT
+             user_id::text
+            ,ship_id
+            ,ship_type
+            ,event_type
+            ,location
+            ,created_at
+        FROM daemon_user_ship_history
+        WHERE user_id = $1::uuid
+            AND ship_id = $2
+        ORDER BY created_at DESC;
+    ")
+        .bind(user_id)
+        .bind(ship_id)
+        .map(|row: PgRow| {
+            ShipEvent {
+                user_id: row.get("user_id"),
+                ship_id: row.get("ship_id"),
+                ship_type: row.get("ship_type"),
+                event_type: row.get("event_type"),
+                location: row.get("location"),
+                created_at: row.get("created_at"),
+            }
+        })
+        .fetch_all(pg_pool)
+        .await?
+    )
+}
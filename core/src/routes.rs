@@ -0,0 +1,192 @@
+// The one place distances, the fuel needed and flight times are worked out. The route planner
+// builds every leg from these so that the daemon's traders and the api's dashboard always agree on
+// them. Single system routes are still handy for inspecting a location from the command line.
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Market data older than this isn't worth trading on
+pub const MARKET_DATA_MAX_AGE_MINUTES: i64 = 30;
+
+/// The latest market data for a good at a location along with where the location is
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocationGood {
+    pub system: String,
+    pub location: String,
+    pub location_type: String,
+    pub x: i32,
+    pub y: i32,
+    pub good: String,
+    pub quantity_available: i32,
+    pub price_per_unit: i32,
+    pub volume_per_unit: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Buy a good at one location and sell it at another in the same system
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    pub purchase_location: String,
+    pub purchase_location_type: String,
+    pub sell_location: String,
+    pub good: String,
+    pub distance: f64,
+    pub purchase_quantity: i32,
+    pub sell_quantity: i32,
+    pub purchase_price_per_unit: i32,
+    pub sell_price_per_unit: i32,
+    pub volume_per_unit: i32,
+    pub fuel_required: f64,
+    pub flight_time: f64,
+    /// What fuel costs at the purchase location. None when it isn't sold there
+    pub fuel_price_per_unit: Option<i32>,
+    // Filled in once the route has been scored for a ship
+    pub units: i32,
+    pub expected_profit: f64,
+    pub credits_per_second: f64,
+}
+
+impl Route {
+    /// Fill in the fuel and flight time estimates for a ship
+    pub fn with_ship(mut self, ship_type: &str, ship_speed: i32) -> Route {
+        self.fuel_required = fuel_required(&self.purchase_location_type, self.distance, ship_type);
+        self.flight_time = flight_time(self.distance, ship_speed);
+
+        self
+    }
+}
+
+pub fn distance(x1: i32, y1: i32, x2: i32, y2: i32) -> f64 {
+    (f64::from(x1 - x2).powi(2) + f64::from(y1 - y2).powi(2)).sqrt()
+}
+
+/// The estimated fuel needed to fly a distance. Leaving a planet costs a little extra and the
+/// larger gravager ships burn more.
+pub fn fuel_required(origin_location_type: &str, distance: f64, ship_type: &str) -> f64 {
+    let planet_penalty = if origin_location_type == "Planet" { 2.0 } else { 0.0 };
+    let ship_penalty = match ship_type {
+        "GR-MK-II" => 1.0,
+        "GR-MK-III" => 2.0,
+        _ => 0.0,
+    };
+
+    (distance.round() / 4.0).round() + planet_penalty + 1.0 + ship_penalty
+}
+
+/// https://discord.com/channels/792864705139048469/792864705139048472/836090525307371541
+/// time = distance * (2 / speed) + 60
+pub fn flight_time(distance: f64, ship_speed: i32) -> f64 {
+    (distance * (2.0 / f64::from(ship_speed)).round()) + 60.0
+}
+
+/// Every route that buys somewhere is_purchase_location accepts and sells the same good somewhere
+/// else in the same system. Routes aren't ranked until they are scored for a ship so they are
+/// kept in a stable order until then
+pub fn routes(location_goods: &[LocationGood], ship_type: &str, ship_speed: i32, is_purchase_location: impl Fn(&LocationGood) -> bool) -> Vec<Route> {
+    let mut routes = Vec::new();
+    for purchase in location_goods.iter().filter(|lg| is_purchase_location(lg)) {
+        let fuel_price_per_unit = location_goods.iter()
+            .find(|lg| lg.location == purchase.location && lg.good == "Fuel")
+            .map(|lg| lg.price_per_unit);
+
+        for sell in location_goods {
+            if sell.good != purchase.good || sell.system != purchase.system || sell.location == purchase.location {
+                continue;
+            }
+
+            routes.push(
+                Route {
+                    purchase_location: purchase.location.clone(),
+                    purchase_location_type: purchase.location_type.clone(),
+                    sell_location: sell.location.clone(),
+                    good: purchase.good.clone(),
+                    distance: distance(purchase.x, purchase.y, sell.x, sell.y),
+                    purchase_quantity: purchase.quantity_available,
+                    sell_quantity: sell.quantity_available,
+                    purchase_price_per_unit: purchase.price_per_unit,
+                    sell_price_per_unit: sell.price_per_unit,
+                    volume_per_unit: purchase.volume_per_unit,
+                    fuel_required: 0.0,
+                    flight_time: 0.0,
+                    fuel_price_per_unit,
+                    units: 0,
+                    expected_profit: 0.0,
+                    credits_per_second: 0.0,
+                }
+                    .with_ship(ship_type, ship_speed)
+            );
+        }
+    }
+
+    routes.sort_by(|a, b| (&a.purchase_location, &a.sell_location, &a.good).cmp(&(&b.purchase_location, &b.sell_location, &b.good)));
+    routes
+}
+
+/// Every route that starts at the location
+pub fn routes_from_location(location_goods: &[LocationGood], location: &str, ship_type: &str, ship_speed: i32) -> Vec<Route> {
+    routes(location_goods, ship_type, ship_speed, |lg| lg.location == location)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location_good(system: &str, location: &str, location_type: &str, x: i32, y: i32, good: &str, price_per_unit: i32) -> LocationGood {
+        LocationGood {
+            system: system.to_string(),
+            location: location.to_string(),
+            location_type: location_type.to_string(),
+            x,
+            y,
+            good: good.to_string(),
+            quantity_available: 1_000,
+            price_per_unit,
+            volume_per_unit: 1,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn location_goods() -> Vec<LocationGood> {
+        vec![
+            location_good("OE", "OE-PM", "Planet", 0, 0, "Metals", 10),
+            location_good("OE", "OE-PM", "Planet", 0, 0, "Fuel", 2),
+            location_good("OE", "OE-PM-TR", "Moon", 30, 40, "Metals", 20),
+            location_good("OE", "OE-PM-TR", "Moon", 30, 40, "Chemicals", 20),
+            location_good("XV", "XV-BN", "Planet", 0, 0, "Metals", 50),
+        ]
+    }
+
+    #[test]
+    fn routes_pair_up_the_same_good_within_a_system() {
+        let routes = routes_from_location(&location_goods(), "OE-PM", "GR-MK-II", 2);
+
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].sell_location, "OE-PM-TR");
+        assert_eq!(routes[0].sell_price_per_unit, 20);
+        assert!((routes[0].distance - 50.0).abs() < f64::EPSILON);
+        assert!((routes[0].fuel_required - fuel_required("Planet", 50.0, "GR-MK-II")).abs() < f64::EPSILON);
+        assert!((routes[0].flight_time - flight_time(50.0, 2)).abs() < f64::EPSILON);
+        assert_eq!(routes[0].fuel_price_per_unit, Some(2));
+
+        // Nothing sells fuel on the moon
+        let routes = routes_from_location(&location_goods(), "OE-PM-TR", "GR-MK-II", 2);
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].fuel_price_per_unit, None);
+    }
+
+    #[test]
+    fn routes_can_start_anywhere_that_is_accepted() {
+        let routes = routes(&location_goods(), "GR-MK-I", 1, |lg| lg.system == "OE" && lg.good == "Metals");
+        let pairs: Vec<(&str, &str)> = routes.iter()
+            .map(|r| (r.purchase_location.as_str(), r.sell_location.as_str()))
+            .collect();
+
+        assert_eq!(pairs, vec![("OE-PM", "OE-PM-TR"), ("OE-PM-TR", "OE-PM")]);
+    }
+
+    #[test]
+    fn fuel_and_flight_time_match_the_single_system_formulas() {
+        assert!((fuel_required("Planet", 40.0, "GR-MK-I") - 13.0).abs() < f64::EPSILON);
+        assert!((fuel_required("Moon", 40.0, "GR-MK-III") - 13.0).abs() < f64::EPSILON);
+        assert!((flight_time(40.0, 1) - 140.0).abs() < f64::EPSILON);
+    }
+}
@@ -0,0 +1,542 @@
+// Scores trades by what they are worth to a specific ship. A route that looks great on paper is
+// worth a lot less to a small ship, to a ship that has to fill up on expensive fuel first or when
+// the market is so thin that our own order moves the price. Scorers are pluggable so that
+// different strategies can be compared by changing the fleet config. The api scores the routes it
+// shows with the same scorer and config so the dashboard ranks them the way the traders do.
+use crate::models::SlippageModel;
+use crate::planner::TradePlan;
+use crate::routes::Route;
+use serde::{Deserialize, Serialize};
+use spacetraders::shared::{self, Good};
+use std::cmp::Ordering::Equal;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// How many different order sizes are tried when looking for the most profitable one
+const ORDER_SIZE_STEPS: i32 = 20;
+
+/// How many orders the configured impact is worth. Until a market has seen more orders than this
+/// the configured impact has more say than what was learned
+const PRIOR_SAMPLES: f64 = 5.0;
+
+/// How traders decide which trade is best
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScoringConfig {
+    #[serde(default)]
+    pub scorer: ScorerType,
+    /// How far buying (or selling) a market's entire quantity moves its price. I.E. 0.5 means the
+    /// last unit costs half again as much as the first
+    #[serde(default = "slippage_impact")]
+    pub slippage_impact: f64,
+    /// Learn how much our orders move prices at each market from past trades. slippage_impact is
+    /// used until there are enough of them
+    #[serde(default = "enabled")]
+    pub learn_slippage: bool,
+}
+
+impl Default for ScoringConfig {
+    fn default() -> Self {
+        ScoringConfig {
+            scorer: ScorerType::default(),
+            slippage_impact: slippage_impact(),
+            learn_slippage: enabled(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScorerType {
+    /// Expected credits per second for the ship after fuel and slippage
+    #[default]
+    CreditsPerSecond,
+    /// The original profit per volume per second
+    ProfitVolumeTime,
+}
+
+fn slippage_impact() -> f64 {
+    0.5
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// The parts of a ship that decide how much a trade is worth to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShipProfile {
+    pub ship_type: String,
+    pub speed: i32,
+    /// Room in the hold once everything other than fuel has been sold
+    pub space_available: i32,
+    /// Fuel already in the hold
+    pub fuel: i32,
+}
+
+impl ShipProfile {
+    pub fn from_ship(ship: &shared::Ship) -> ShipProfile {
+        let fuel = ship.cargo.iter().filter(|c| c.good == Good::Fuel).fold(0, |acc, c| acc + c.quantity);
+        let other_cargo = ship.cargo.iter().filter(|c| c.good != Good::Fuel).fold(0, |acc, c| acc + c.total_volume);
+
+        ShipProfile {
+            ship_type: ship.ship_type.clone(),
+            speed: ship.speed,
+            space_available: ship.space_available + other_cargo,
+            fuel,
+        }
+    }
+}
+
+/// Everything a scorer needs to know about a trade
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub good: Good,
+    pub purchase_location: String,
+    pub sell_location: String,
+    pub purchase_price_per_unit: i32,
+    pub sell_price_per_unit: i32,
+    pub purchase_quantity: i32,
+    pub sell_quantity: i32,
+    pub volume_per_unit: i32,
+    /// Fuel for the first leg. This is bought where the goods are bought
+    pub first_leg_fuel_required: f64,
+    /// Fuel bought along the way for the rest of the trip. The hold has to have room for it
+    pub fuel_required_after_first_leg: i32,
+    pub fuel_cost_after_first_leg: f64,
+    pub flight_time: f64,
+    /// What fuel costs where the goods are bought. None when fuel isn't sold there
+    pub fuel_price_per_unit: Option<i32>,
+}
+
+impl From<&TradePlan> for Trade {
+    fn from(plan: &TradePlan) -> Trade {
+        Trade {
+            good: plan.good,
+            purchase_location: plan.purchase_location.clone(),
+            sell_location: plan.sell_location.clone(),
+            purchase_price_per_unit: plan.purchase_price_per_unit,
+            sell_price_per_unit: plan.sell_price_per_unit,
+            purchase_quantity: plan.purchase_quantity,
+            sell_quantity: plan.sell_quantity,
+            volume_per_unit: plan.volume_per_unit,
+            first_leg_fuel_required: plan.legs.first().map(|l| l.fuel_required).unwrap_or(0.0),
+            fuel_required_after_first_leg: plan.fuel_required_after_first_leg(),
+            fuel_cost_after_first_leg: plan.fuel_cost_after_first_leg,
+            flight_time: plan.flight_time,
+            fuel_price_per_unit: plan.fuel_price_per_unit,
+        }
+    }
+}
+
+impl From<&Route> for Trade {
+    fn from(route: &Route) -> Trade {
+        Trade {
+            good: Good::from(route.good.clone()),
+            purchase_location: route.purchase_location.clone(),
+            sell_location: route.sell_location.clone(),
+            purchase_price_per_unit: route.purchase_price_per_unit,
+            sell_price_per_unit: route.sell_price_per_unit,
+            purchase_quantity: route.purchase_quantity,
+            sell_quantity: route.sell_quantity,
+            volume_per_unit: route.volume_per_unit,
+            first_leg_fuel_required: route.fuel_required,
+            fuel_required_after_first_leg: 0,
+            fuel_cost_after_first_leg: 0.0,
+            flight_time: route.flight_time,
+            fuel_price_per_unit: route.fuel_price_per_unit,
+        }
+    }
+}
+
+/// What a trade is expected to earn a ship
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteScore {
+    /// How many units to buy
+    pub units: i32,
+    /// Fuel that has to be bought before leaving
+    pub fuel_to_buy: i32,
+    pub expected_profit: f64,
+    pub credits_per_second: f64,
+}
+
+pub trait RouteScorer: Debug + Send + Sync {
+    /// None when the ship can't make the trade at all
+    fn score(&self, ship: &ShipProfile, trade: &Trade) -> Option<RouteScore>;
+}
+
+/// How much our own order moves the price. Prices returned are the average paid or received per
+/// unit over the whole order.
+pub trait Slippage: Debug + Send + Sync {
+    fn purchase_price(&self, trade: &Trade, units: i32) -> f64;
+    fn sell_price(&self, trade: &Trade, units: i32) -> f64;
+}
+
+/// Assumes each unit moves the price by the same amount and that buying everything the market
+/// has moves it by `impact` of the listed price. Thin markets get expensive quickly.
+#[derive(Debug, Clone)]
+pub struct DepthSlippage {
+    pub impact: f64,
+}
+
+impl DepthSlippage {
+    fn average_move(&self, units: i32, depth: i32) -> f64 {
+        self.impact * f64::from(units) / (2.0 * f64::from(depth.max(1)))
+    }
+}
+
+impl Slippage for DepthSlippage {
+    fn purchase_price(&self, trade: &Trade, units: i32) -> f64 {
+        f64::from(trade.purchase_price_per_unit) * (1.0 + self.average_move(units, trade.purchase_quantity))
+    }
+
+    fn sell_price(&self, trade: &Trade, units: i32) -> f64 {
+        f64::from(trade.sell_price_per_unit) * (1.0 - self.average_move(units, trade.sell_quantity)).max(0.0)
+    }
+}
+
+/// Expected credits per second for the whole trip. The order is sized to make the most profit
+/// after slippage and the fuel that has to be bought is paid for out of the profit. Like the
+/// traders only the first leg's fuel is bought up front, the hold keeps room for the rest which is
+/// bought along the way.
+#[derive(Debug, Clone)]
+pub struct CreditsPerSecond {
+    pub slippage: Arc<dyn Slippage>,
+}
+
+impl CreditsPerSecond {
+    fn profit(&self, trade: &Trade, units: i32) -> f64 {
+        f64::from(units) * (self.slippage.sell_price(trade, units) - self.slippage.purchase_price(trade, units))
+    }
+}
+
+impl RouteScorer for CreditsPerSecond {
+    fn score(&self, ship: &ShipProfile, trade: &Trade) -> Option<RouteScore> {
+        let fuel_to_buy = (trade.first_leg_fuel_required.ceil() as i32 - ship.fuel).max(0);
+        let fuel_cost = match (fuel_to_buy, trade.fuel_price_per_unit) {
+            (0, _) => 0.0,
+            (_, Some(fuel_price_per_unit)) => f64::from(fuel_to_buy * fuel_price_per_unit),
+            // The ship can't fly without buying fuel somewhere else first
+            (_, None) => return None,
+        } + trade.fuel_cost_after_first_leg;
+
+        let space_for_goods = ship.space_available - (fuel_to_buy + trade.fuel_required_after_first_leg) * Good::Fuel.get_volume();
+        let max_units = (space_for_goods / trade.volume_per_unit.max(1)).min(trade.purchase_quantity);
+        if max_units <= 0 || trade.flight_time <= 0.0 {
+            return None;
+        }
+
+        // Profit isn't linear once prices move so try a range of order sizes
+        let step = (max_units / ORDER_SIZE_STEPS).max(1);
+        let units = (1..=ORDER_SIZE_STEPS)
+            .map(|i| (i * step).min(max_units))
+            .chain(std::iter::once(max_units))
+            .max_by(|a, b| self.profit(trade, *a).partial_cmp(&self.profit(trade, *b)).unwrap_or(Equal))
+            .unwrap();
+
+        let expected_profit = self.profit(trade, units) - fuel_cost;
+
+        Some(RouteScore {
+            units,
+            fuel_to_buy,
+            expected_profit,
+            credits_per_second: expected_profit / trade.flight_time,
+        })
+    }
+}
+
+/// The original ranking. Profit per unit of volume per second, ignoring fuel, the size of the
+/// ship and the depth of the market. Kept around to compare against.
+#[derive(Debug, Clone)]
+pub struct ProfitVolumeTime;
+
+impl RouteScorer for ProfitVolumeTime {
+    fn score(&self, ship: &ShipProfile, trade: &Trade) -> Option<RouteScore> {
+        if trade.flight_time <= 0.0 {
+            return None;
+        }
+
+        let profit = f64::from(trade.sell_price_per_unit - trade.purchase_price_per_unit);
+        let units = (ship.space_available / trade.volume_per_unit.max(1)).min(trade.purchase_quantity);
+
+        Some(RouteScore {
+            units,
+            fuel_to_buy: 0,
+            expected_profit: profit * f64::from(units),
+            credits_per_second: profit / f64::from(trade.volume_per_unit.max(1)) / trade.flight_time,
+        })
+    }
+}
+
+/// Expected prices using the impact learned for each market, falling back to the configured
+/// impact where we haven't traded yet
+#[derive(Debug, Clone)]
+pub struct LearnedSlippage {
+    pub prior: DepthSlippage,
+    slippage_models: Vec<SlippageModel>,
+}
+
+impl LearnedSlippage {
+    pub fn new(prior: DepthSlippage, slippage_models: Vec<SlippageModel>) -> LearnedSlippage {
+        LearnedSlippage { prior, slippage_models }
+    }
+
+    fn slippage_model(&self, location: &str, good: Good) -> Option<&SlippageModel> {
+        self.slippage_models.iter().find(|m| m.location == location && m.good == good)
+    }
+
+    fn blend(&self, learned: f64, samples: i32) -> DepthSlippage {
+        let samples = f64::from(samples);
+
+        DepthSlippage {
+            impact: (learned * samples + self.prior.impact * PRIOR_SAMPLES) / (samples + PRIOR_SAMPLES),
+        }
+    }
+
+    pub fn purchase_slippage(&self, location: &str, good: Good) -> DepthSlippage {
+        match self.slippage_model(location, good) {
+            Some(m) => self.blend(m.purchase_impact, m.purchase_samples),
+            None => self.prior.clone(),
+        }
+    }
+
+    pub fn sell_slippage(&self, location: &str, good: Good) -> DepthSlippage {
+        match self.slippage_model(location, good) {
+            Some(m) => self.blend(m.sell_impact, m.sell_samples),
+            None => self.prior.clone(),
+        }
+    }
+}
+
+impl Slippage for LearnedSlippage {
+    fn purchase_price(&self, trade: &Trade, units: i32) -> f64 {
+        self.purchase_slippage(&trade.purchase_location, trade.good).purchase_price(trade, units)
+    }
+
+    fn sell_price(&self, trade: &Trade, units: i32) -> f64 {
+        self.sell_slippage(&trade.sell_location, trade.good).sell_price(trade, units)
+    }
+}
+
+/// Build the configured scorer. `slippage_models` are the impacts the daemon has learned so far
+pub fn scorer(config: &ScoringConfig, slippage_models: Vec<SlippageModel>) -> Arc<dyn RouteScorer> {
+    let depth_slippage = DepthSlippage { impact: config.slippage_impact };
+    let slippage: Arc<dyn Slippage> = if config.learn_slippage {
+        Arc::new(LearnedSlippage::new(depth_slippage, slippage_models))
+    } else {
+        Arc::new(depth_slippage)
+    };
+
+    match config.scorer {
+        ScorerType::CreditsPerSecond => Arc::new(CreditsPerSecond { slippage }),
+        ScorerType::ProfitVolumeTime => Arc::new(ProfitVolumeTime),
+    }
+}
+
+/// Score routes for a ship. Routes the ship can't fly are dropped and the best are first
+pub fn rank_routes(routes: Vec<Route>, ship: &ShipProfile, scorer: &dyn RouteScorer) -> Vec<Route> {
+    let mut routes: Vec<Route> = routes.into_iter()
+        .filter_map(|mut route| {
+            let score = scorer.score(ship, &Trade::from(&route))?;
+            route.units = score.units;
+            route.expected_profit = score.expected_profit;
+            route.credits_per_second = score.credits_per_second;
+
+            Some(route)
+        })
+        .collect();
+
+    routes.sort_by(|a, b| b.credits_per_second.partial_cmp(&a.credits_per_second).unwrap_or(Equal));
+    routes
+}
+
+/// Score trade plans for a ship. Plans the ship can't make are dropped and the best are first
+pub fn rank_plans(plans: Vec<TradePlan>, ship: &ShipProfile, scorer: &dyn RouteScorer) -> Vec<(TradePlan, RouteScore)> {
+    let mut plans: Vec<(TradePlan, RouteScore)> = plans.into_iter()
+        .filter_map(|plan| {
+            let score = scorer.score(ship, &Trade::from(&plan))?;
+            Some((plan, score))
+        })
+        .collect();
+
+    plans.sort_by(|a, b| b.1.credits_per_second.partial_cmp(&a.1.credits_per_second).unwrap_or(Equal));
+    plans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(purchase_price_per_unit: i32, sell_price_per_unit: i32, purchase_quantity: i32) -> Trade {
+        Trade {
+            good: Good::Electronics,
+            purchase_location: "OE-PM".to_string(),
+            sell_location: "OE-UC".to_string(),
+            purchase_price_per_unit,
+            sell_price_per_unit,
+            purchase_quantity,
+            sell_quantity: 10_000,
+            volume_per_unit: 1,
+            first_leg_fuel_required: 20.0,
+            fuel_required_after_first_leg: 0,
+            fuel_cost_after_first_leg: 0.0,
+            flight_time: 200.0,
+            fuel_price_per_unit: Some(2),
+        }
+    }
+
+    fn ship(max_cargo: i32, fuel: i32) -> ShipProfile {
+        ShipProfile {
+            ship_type: "GR-MK-I".to_string(),
+            speed: 1,
+            space_available: max_cargo - fuel,
+            fuel,
+        }
+    }
+
+    fn credits_per_second(impact: f64) -> CreditsPerSecond {
+        CreditsPerSecond { slippage: Arc::new(DepthSlippage { impact }) }
+    }
+
+    #[test]
+    fn fuel_comes_out_of_the_profit_and_the_hold() {
+        let scorer = credits_per_second(0.0);
+
+        let empty = scorer.score(&ship(100, 0), &trade(10, 20, 5_000)).unwrap();
+        assert_eq!(empty.fuel_to_buy, 20);
+        assert_eq!(empty.units, 80);
+        assert!((empty.expected_profit - (80.0 * 10.0 - 40.0)).abs() < f64::EPSILON);
+        assert!((empty.credits_per_second - 760.0 / 200.0).abs() < f64::EPSILON);
+
+        let fueled = scorer.score(&ship(100, 20), &trade(10, 20, 5_000)).unwrap();
+        assert_eq!(fueled.fuel_to_buy, 0);
+        assert!(fueled.credits_per_second > empty.credits_per_second);
+    }
+
+    #[test]
+    fn only_the_first_leg_is_fueled_up_front() {
+        let scorer = credits_per_second(0.0);
+        let mut warp = trade(10, 20, 5_000);
+        warp.fuel_required_after_first_leg = 15;
+        warp.fuel_cost_after_first_leg = 45.0;
+
+        let score = scorer.score(&ship(100, 0), &warp).unwrap();
+
+        assert_eq!(score.fuel_to_buy, 20);
+        assert_eq!(score.units, 65);
+        assert!((score.expected_profit - (65.0 * 10.0 - 40.0 - 45.0)).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn ships_that_cant_buy_fuel_or_goods_cant_trade() {
+        let scorer = credits_per_second(0.0);
+
+        let mut no_fuel_market = trade(10, 20, 5_000);
+        no_fuel_market.fuel_price_per_unit = None;
+        assert!(scorer.score(&ship(100, 0), &no_fuel_market).is_none());
+        assert!(scorer.score(&ship(100, 20), &no_fuel_market).is_some());
+
+        assert!(scorer.score(&ship(20, 0), &trade(10, 20, 5_000)).is_none());
+        assert!(scorer.score(&ship(100, 0), &trade(10, 20, 0)).is_none());
+    }
+
+    #[test]
+    fn big_ships_earn_more_from_deep_markets() {
+        let scorer = credits_per_second(0.0);
+        let small = scorer.score(&ship(100, 20), &trade(10, 20, 5_000)).unwrap();
+        let large = scorer.score(&ship(3_000, 20), &trade(10, 20, 5_000)).unwrap();
+        let shallow = scorer.score(&ship(3_000, 20), &trade(10, 20, 100)).unwrap();
+
+        assert_eq!(large.units, 2_980);
+        assert_eq!(shallow.units, 100);
+        assert!(large.credits_per_second > small.credits_per_second);
+        assert!(shallow.credits_per_second < large.credits_per_second);
+    }
+
+    #[test]
+    fn orders_are_sized_to_what_the_market_can_take() {
+        let scorer = credits_per_second(0.5);
+        let score = scorer.score(&ship(3_000, 20), &trade(10, 12, 2_000)).unwrap();
+
+        // Buying the whole market would push the price past the sell price
+        assert!(score.units < 2_000);
+        assert!(score.expected_profit > 0.0);
+        assert!(score.expected_profit < f64::from(score.units * 2));
+    }
+
+    #[test]
+    fn ship_profiles_count_sold_cargo_as_free_space() {
+        let cargo = |good: Good, quantity: i32| shared::Cargo { good, quantity, total_volume: quantity * good.get_volume() };
+        let ship = shared::Ship {
+            id: "ship-1".to_string(),
+            location: Some("OE-PM".to_string()),
+            cargo: vec![cargo(Good::Fuel, 15), cargo(Good::Metals, 20)],
+            space_available: 100 - 15 - 20,
+            ship_type: "GR-MK-I".to_string(),
+            class: "MK-I".to_string(),
+            max_cargo: 100,
+            speed: 1,
+            manufacturer: "Gravager".to_string(),
+            plating: 10,
+            weapons: 5,
+            x: None,
+            y: None,
+            flight_plan_id: None,
+        };
+
+        let profile = ShipProfile::from_ship(&ship);
+
+        assert_eq!(profile.fuel, 15);
+        assert_eq!(profile.space_available, 100 - 15);
+    }
+
+    #[test]
+    fn learned_impact_takes_over_from_the_prior_as_orders_come_in() {
+        let slippage_model = |purchase_samples: i32| SlippageModel {
+            location: "OE-PM".to_string(),
+            good: Good::Metals,
+            purchase_impact: 0.0,
+            sell_impact: 2.0,
+            purchase_samples,
+            sell_samples: 5,
+        };
+        let prior = DepthSlippage { impact: 0.5 };
+
+        let few = LearnedSlippage::new(prior.clone(), vec![slippage_model(1)]);
+        let many = LearnedSlippage::new(prior.clone(), vec![slippage_model(95)]);
+
+        assert!((few.purchase_slippage("OE-PM", Good::Metals).impact - 0.5 * 5.0 / 6.0).abs() < 1e-9);
+        assert!((many.purchase_slippage("OE-PM", Good::Metals).impact - 0.025).abs() < 1e-9);
+        assert!((many.sell_slippage("OE-PM", Good::Metals).impact - 1.25).abs() < 1e-9);
+        assert!((many.purchase_slippage("OE-UC", Good::Metals).impact - 0.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn routes_are_ranked_for_the_ship() {
+        let route = |good: Good, purchase_price_per_unit: i32, purchase_quantity: i32| Route {
+            purchase_location: "OE-PM".to_string(),
+            purchase_location_type: "Planet".to_string(),
+            sell_location: "OE-UC".to_string(),
+            good: good.to_string(),
+            distance: 80.0,
+            purchase_quantity,
+            sell_quantity: 5_000,
+            purchase_price_per_unit,
+            sell_price_per_unit: 30,
+            volume_per_unit: 1,
+            fuel_required: 23.0,
+            flight_time: 220.0,
+            fuel_price_per_unit: Some(2),
+            units: 0,
+            expected_profit: 0.0,
+            credits_per_second: 0.0,
+        };
+        let routes = vec![route(Good::Metals, 25, 5_000), route(Good::Chemicals, 22, 40), route(Good::Textiles, 28, 5_000)];
+
+        let ranked = rank_routes(routes, &ship(100, 0), &credits_per_second(0.0));
+
+        // Chemicals have the biggest margin but there are only 40 of them to buy
+        assert_eq!(ranked.iter().map(|r| Good::from(r.good.clone())).collect::<Vec<Good>>(), vec![Good::Metals, Good::Chemicals, Good::Textiles]);
+        assert_eq!(ranked[1].units, 40);
+    }
+}
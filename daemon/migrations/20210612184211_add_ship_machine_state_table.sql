@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE daemon_ship_machine_state (
+     user_id uuid NOT NULL
+    ,ship_id VARCHAR(50) NOT NULL
+    ,machine_type VARCHAR(50) NOT NULL
+    ,state JSONB NOT NULL
+    ,created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+    ,modified_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE UNIQUE INDEX daemon_ship_machine_state_user_id_ship_id ON daemon_ship_machine_state(user_id, ship_id);
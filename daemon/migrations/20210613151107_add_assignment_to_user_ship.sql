@@ -0,0 +1,12 @@
+-- Add migration script here
+ALTER TABLE daemon_user_ship ADD COLUMN assignment VARCHAR(100);
+
+-- Existing ships keep doing whatever their user assigns to new ships
+UPDATE daemon_user_ship dus
+SET assignment = du.new_ship_assignment
+FROM daemon_user du
+WHERE du.id = dus.user_id;
+
+UPDATE daemon_user_ship SET assignment = 'trader' WHERE assignment IS NULL;
+
+ALTER TABLE daemon_user_ship ALTER COLUMN assignment SET NOT NULL;
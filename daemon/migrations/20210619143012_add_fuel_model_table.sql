@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE daemon_fuel_model (
+     ship_type VARCHAR(100) NOT NULL
+    ,location_type VARCHAR(100) NOT NULL
+    ,fuel_intercept DOUBLE PRECISION NOT NULL
+    ,fuel_per_distance DOUBLE PRECISION NOT NULL
+    ,fuel_max_error DOUBLE PRECISION NOT NULL
+    ,time_intercept DOUBLE PRECISION NOT NULL
+    ,time_per_distance DOUBLE PRECISION NOT NULL
+    ,min_distance INT NOT NULL
+    ,max_distance INT NOT NULL
+    ,samples INT NOT NULL
+    ,created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+    ,modified_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+    ,PRIMARY KEY(ship_type, location_type)
+);
@@ -0,0 +1,14 @@
+-- Add migration script here
+CREATE TABLE daemon_slippage_model (
+     location VARCHAR(100) NOT NULL
+    ,good VARCHAR(100) NOT NULL
+    ,purchase_impact DOUBLE PRECISION NOT NULL
+    ,sell_impact DOUBLE PRECISION NOT NULL
+    ,purchase_samples INT NOT NULL
+    ,sell_samples INT NOT NULL
+    ,created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+    ,modified_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+    ,PRIMARY KEY(location, good)
+);
+
+CREATE INDEX IF NOT EXISTS daemon_user_transaction_location_good_created_at ON daemon_user_transaction (location, good, created_at);
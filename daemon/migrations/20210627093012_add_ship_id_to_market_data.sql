@@ -0,0 +1,3 @@
+-- Add migration script here
+-- The ship that recorded the snapshot. Snapshots from before ships were tagged don't have one
+ALTER TABLE daemon_market_data ADD COLUMN ship_id VARCHAR(100);
@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE daemon_user_loan_event (
+     user_id uuid NOT NULL
+    ,loan_id VARCHAR(100) NOT NULL
+    ,loan_type VARCHAR(50) NOT NULL
+    ,event_type VARCHAR(50) NOT NULL
+    ,amount INT NOT NULL
+    ,due_at TIMESTAMP WITH TIME ZONE NOT NULL
+    ,credits INT NOT NULL
+    ,created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS daemon_user_loan_event_user_id_created_at ON daemon_user_loan_event (user_id, created_at);
@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE daemon_user_ship_history (
+     user_id uuid NOT NULL
+    ,ship_id VARCHAR(100) NOT NULL
+    ,ship_type VARCHAR(50) NOT NULL
+    ,event_type VARCHAR(50) NOT NULL
+    ,location VARCHAR(20) NOT NULL
+    ,created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS daemon_user_ship_history_user_id_created_at ON daemon_user_ship_history (user_id, created_at);
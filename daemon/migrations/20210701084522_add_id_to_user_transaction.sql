@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE daemon_user_transaction ADD COLUMN id BIGSERIAL PRIMARY KEY;
+
+CREATE INDEX IF NOT EXISTS daemon_user_transaction_user_id_created_at ON daemon_user_transaction (user_id, created_at, id);
@@ -0,0 +1,26 @@
+-- Add migration script here
+ALTER TABLE daemon_user_transaction ADD COLUMN trade_id uuid NULL;
+ALTER TABLE daemon_flight_plan ADD COLUMN trade_id uuid NULL;
+
+CREATE INDEX IF NOT EXISTS daemon_user_transaction_trade_id ON daemon_user_transaction (trade_id);
+CREATE INDEX IF NOT EXISTS daemon_flight_plan_trade_id ON daemon_flight_plan (trade_id);
+
+CREATE TABLE daemon_user_trade (
+     trade_id uuid NOT NULL PRIMARY KEY
+    ,user_id uuid NOT NULL
+    ,ship_id VARCHAR(100) NOT NULL
+    ,good VARCHAR(50) NOT NULL
+    ,purchase_location VARCHAR(20) NOT NULL
+    ,sell_location VARCHAR(20) NOT NULL
+    ,quantity INT NOT NULL
+    ,cost INT NOT NULL
+    ,revenue INT NOT NULL
+    ,fuel_cost INT NOT NULL
+    ,profit INT NOT NULL
+    ,predicted_profit INT NOT NULL
+    ,duration_seconds BIGINT NOT NULL
+    ,started_at TIMESTAMP WITH TIME ZONE NOT NULL
+    ,completed_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS daemon_user_trade_user_id_completed_at ON daemon_user_trade (user_id, completed_at);
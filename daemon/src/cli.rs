@@ -0,0 +1,410 @@
+// Everything spacemongerd can do from the command line. Running without a subcommand is the same
+// as `spacemongerd run` so existing deployments keep working.
+use crate::config::FleetConfig;
+use crate::db::{self, DbMarketFreshness, DbRoute, DbUserSummary};
+use crate::funcs;
+use crate::game::{self, GameBackend};
+use spacemonger_core::scoring::{self, ShipProfile};
+use crate::storage::{self, StorageClient};
+use anyhow::bail;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "spacemongerd", about = "Trades, scouts and explores the SpaceTraders universe")]
+pub struct Opt {
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+pub enum Command {
+    /// Run the fleet described by the fleet config. This is the default
+    Run,
+    /// Scan the marketplace of every location in a system where one of our ships is docked
+    ScanSystem {
+        system: String,
+    },
+    /// Print the most profitable routes from a location for a ship using the latest market data
+    Routes {
+        location: String,
+        /// Type of the ship flying the routes
+        #[structopt(long, default_value = "GR-MK-I")]
+        ship_type: String,
+        /// Speed of the ship flying the routes
+        #[structopt(long, default_value = "1")]
+        speed: i32,
+        /// Size of the ship's hold
+        #[structopt(long, default_value = "100")]
+        cargo: i32,
+        /// Fuel already in the ship's hold
+        #[structopt(long, default_value = "0")]
+        fuel: i32,
+    },
+    /// Run any pending database migrations
+    Migrate,
+    /// Move every table into a backup schema. The next run starts from a clean database
+    Reset,
+    /// List every user with their latest credit balance
+    Users,
+    /// Show what a ship is doing right now
+    Ship {
+        id: String,
+    },
+    /// Show how old the market data at every location is and whether scouts or traders recorded
+    /// it over the last hour
+    Markets,
+}
+
+/// What a system scan found at a single location. Locations without a docked ship can't be
+/// scanned so they have no goods.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScannedLocation {
+    pub location: String,
+    pub goods: Option<usize>,
+}
+
+/// Run every subcommand other than `run`
+pub async fn execute(command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Run => unreachable!("run is handled by main"),
+        Command::ScanSystem { system } => {
+            let storage = storage::get_storage_from_env().await?;
+            let backend = get_backend().await?;
+
+            for scanned in scan_system(backend, storage, &system).await? {
+                match scanned.goods {
+                    Some(goods) => println!("{:<12} {} goods", scanned.location, goods),
+                    None => println!("{:<12} no ship docked", scanned.location),
+                }
+            }
+        }
+        Command::Routes { location, ship_type, speed, cargo, fuel } => {
+            let config = FleetConfig::from_env()?;
+            let storage = storage::get_storage_from_env().await?;
+            let ship = ShipProfile { ship_type, speed, space_available: cargo - fuel, fuel };
+
+            let routes = storage.get_routes_from_location(&location, &ship).await?;
+            let scorer = scoring::scorer(&config.trading.scoring, storage.get_slippage_models().await?);
+            let routes = scoring::rank_routes(routes, &ship, scorer.as_ref());
+
+            print!("{}", format_routes(&routes));
+        }
+        Command::Migrate => {
+            let pg_pool = storage::get_db_pool_from_env().await?;
+            db::run_migrations(pg_pool).await?;
+
+            println!("Database is up to date");
+        }
+        Command::Reset => {
+            let pg_pool = storage::get_db_pool_from_env().await?;
+            db::reset_db(pg_pool).await?;
+
+            println!("Moved every table into a backup schema. Run migrate to recreate them");
+        }
+        Command::Users => {
+            let storage = storage::get_storage_from_env().await?;
+            let users = storage.get_user_summaries().await?;
+
+            print!("{}", format_users(&users));
+        }
+        Command::Ship { id } => {
+            let storage = storage::get_storage_from_env().await?;
+            let backend = get_backend().await?;
+
+            show_ship(backend, storage, &id).await?;
+        }
+        Command::Markets => {
+            let storage = storage::get_storage_from_env().await?;
+            let now = Utc::now();
+            let markets = storage.get_market_freshness(now - Duration::hours(1)).await?;
+
+            print!("{}", format_markets(&markets, now));
+        }
+    }
+
+    Ok(())
+}
+
+async fn get_backend() -> anyhow::Result<Arc<dyn GameBackend>> {
+    let http_proxy: Option<String> = env::var("HTTP_PROXY").map(Some).unwrap_or(None);
+
+    game::get_backend_from_env(http_proxy).await
+}
+
+/// Marketplaces can only be read by a ship docked at the location. Look through every ship that
+/// every user owns for one docked at each location in the system and record whatever it sees.
+pub async fn scan_system(backend: Arc<dyn GameBackend>, storage: StorageClient, system: &str) -> anyhow::Result<Vec<ScannedLocation>> {
+    let locations: Vec<String> = storage.get_system_locations().await?
+        .into_iter()
+        .filter(|l| l.system == system)
+        .map(|l| l.location)
+        .collect();
+
+    if locations.is_empty() {
+        bail!("Unknown system {}. Run the daemon first so that the systems are saved", system);
+    }
+
+    let mut clients = HashMap::new();
+    for summary in storage.get_user_summaries().await? {
+        let user = match storage.get_user(summary.username.clone()).await? {
+            Some(user) => user,
+            None => continue,
+        };
+
+        let client = backend.client(user.username.clone(), user.token.clone());
+        let ships = match client.get_my_ships().await {
+            Ok(ships) => ships,
+            Err(e) => {
+                log::warn!("{} -- Unable to get ships. Skipping this user. Error: {}", user.username, e);
+                continue;
+            }
+        };
+
+        for ship in ships.ships {
+            if let Some(location) = ship.location.clone() {
+                if funcs::get_system_from_location(&location) == system {
+                    clients.entry(location).or_insert_with(|| (client.clone(), ship.id));
+                }
+            }
+        }
+    }
+
+    let mut scanned = Vec::new();
+    for location in locations {
+        let goods = match clients.get(&location) {
+            Some((client, ship_id)) => {
+                let marketplace = client.get_location_marketplace(&location).await?;
+                for datum in &marketplace.marketplace {
+                    storage.persist_market_data(&location, ship_id, datum).await?;
+                }
+
+                Some(marketplace.marketplace.len())
+            }
+            None => None,
+        };
+
+        scanned.push(ScannedLocation { location, goods });
+    }
+
+    Ok(scanned)
+}
+
+async fn show_ship(backend: Arc<dyn GameBackend>, storage: StorageClient, ship_id: &str) -> anyhow::Result<()> {
+    let owner = match storage.get_ship_owner(ship_id).await? {
+        Some(owner) => owner,
+        None => bail!("No user owns ship {}", ship_id),
+    };
+
+    let db_ship = storage.get_ship(&owner.id, ship_id).await?;
+    let ship = backend.client(owner.username.clone(), owner.token.clone())
+        .get_my_ship(ship_id).await?
+        .ship;
+
+    println!("Ship:       {} ({} {})", ship.id, ship.manufacturer, ship.ship_type);
+    println!("Owner:      {}", owner.username);
+    println!("Assignment: {} in {}", db_ship.assignment, db_ship.system);
+    println!("Location:   {}", ship.location.as_deref().unwrap_or("in transit"));
+    println!("Cargo:      {}/{}", ship.max_cargo - ship.space_available, ship.max_cargo);
+    for cargo in &ship.cargo {
+        println!("  {:<20} {}", cargo.good, cargo.quantity);
+    }
+
+    if let Some(flight_plan) = storage.get_active_flight_plan(ship_id).await? {
+        println!("Flight:     {} -> {} arriving at {}", flight_plan.departure, flight_plan.destination, flight_plan.arrives_at);
+    }
+
+    match storage.get_ship_machine_state(&owner.id, ship_id).await? {
+        Some(state) => println!("Machine:    {} {} (saved {})", state.machine_type, state.state, state.modified_at),
+        None => println!("Machine:    no checkpoint"),
+    }
+
+    Ok(())
+}
+
+fn format_routes(routes: &[DbRoute]) -> String {
+    let mut output = format!(
+        "{:<14} {:<14} {:<20} {:>8} {:>8} {:>8} {:>8} {:>8} {:>10} {:>10}\n",
+        "purchase", "sell", "good", "distance", "buy", "sell", "quantity", "units", "profit", "credits/s",
+    );
+
+    for route in routes {
+        output.push_str(&format!(
+            "{:<14} {:<14} {:<20} {:>8.2} {:>8} {:>8} {:>8} {:>8} {:>10.0} {:>10.2}\n",
+            route.purchase_location,
+            route.sell_location,
+            route.good.to_string(),
+            route.distance,
+            route.purchase_price_per_unit,
+            route.sell_price_per_unit,
+            route.purchase_quantity,
+            route.units,
+            route.expected_profit,
+            route.credits_per_second,
+        ));
+    }
+
+    output
+}
+
+fn format_markets(markets: &[DbMarketFreshness], now: DateTime<Utc>) -> String {
+    let mut output = format!("{:<14} {:>8} {:<12} {:>8} {:>8}\n", "location", "age (s)", "recorded by", "scouts", "traders");
+
+    for market in markets {
+        output.push_str(&format!(
+            "{:<14} {:>8} {:<12} {:>8} {:>8}\n",
+            market.location,
+            market.surveyed_at.map(|s| (now - s).num_seconds().to_string()).unwrap_or_else(|| "never".to_string()),
+            market.surveyed_by.as_deref().unwrap_or("-"),
+            market.scout_records,
+            market.trader_records,
+        ));
+    }
+
+    output
+}
+
+fn format_users(users: &[DbUserSummary]) -> String {
+    let mut output = format!("{:<40} {:<8} {:<8} {:>12} {:>6}\n", "username", "assigned", "system", "credits", "ships");
+
+    for user in users {
+        output.push_str(&format!(
+            "{:<40} {:<8} {:<8} {:>12} {:>6}\n",
+            user.username,
+            user.new_ship_assignment,
+            user.new_ship_system,
+            user.credits.map(|c| c.to_string()).unwrap_or_else(|| "-".to_string()),
+            user.ship_count.map(|c| c.to_string()).unwrap_or_else(|| "-".to_string()),
+        ));
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{LoanConfig, PurchasingConfig, TradingConfig, UserConfig};
+    use crate::route_reservations::RouteReservations;
+    use crate::game::simulator::Simulator;
+    use crate::ship_machines::ShipAssignment;
+    use crate::storage::memory::MemoryStorage;
+    use crate::user::User;
+
+    fn parse(args: &[&str]) -> Option<Command> {
+        Opt::from_iter_safe(args).unwrap().command
+    }
+
+    #[test]
+    fn subcommands_are_parsed() {
+        assert_eq!(parse(&["spacemongerd"]), None);
+        assert_eq!(parse(&["spacemongerd", "run"]), Some(Command::Run));
+        assert_eq!(parse(&["spacemongerd", "scan-system", "OE"]), Some(Command::ScanSystem { system: "OE".to_string() }));
+        assert_eq!(
+            parse(&["spacemongerd", "routes", "OE-PM", "--ship-type", "TD-MK-I", "--speed", "3", "--cargo", "3000", "--fuel", "40"]),
+            Some(Command::Routes { location: "OE-PM".to_string(), ship_type: "TD-MK-I".to_string(), speed: 3, cargo: 3_000, fuel: 40 }),
+        );
+        assert_eq!(
+            parse(&["spacemongerd", "routes", "OE-PM"]),
+            Some(Command::Routes { location: "OE-PM".to_string(), ship_type: "GR-MK-I".to_string(), speed: 1, cargo: 100, fuel: 0 }),
+        );
+        assert_eq!(parse(&["spacemongerd", "ship", "ship-1"]), Some(Command::Ship { id: "ship-1".to_string() }));
+        assert_eq!(parse(&["spacemongerd", "markets"]), Some(Command::Markets));
+        assert!(Opt::from_iter_safe(&["spacemongerd", "watch"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn scan_system_reads_markets_where_ships_are_docked() {
+        let storage: StorageClient = Arc::new(MemoryStorage::new());
+        let backend: Arc<dyn GameBackend> = Arc::new(Simulator::new(0.0));
+
+        let config = UserConfig {
+            name: "main".to_string(),
+            assignment: ShipAssignment::Trader,
+            system: "OE".to_string(),
+            location: None,
+            enabled: true,
+            purchasing: PurchasingConfig::default(),
+            loans: LoanConfig::default(),
+        };
+        let mut user = User::new(backend.clone(), storage.clone(), "cli-main".to_string(), &config, TradingConfig::default(), RouteReservations::new()).await.unwrap();
+        for system in &user.get_systems().await.unwrap().systems {
+            for location in &system.locations {
+                storage.persist_system_location(system, location).await.unwrap();
+            }
+        }
+        user.manage_loans().await.unwrap();
+        user.purchase_best_ship().await.unwrap();
+        let docked_at = user.get_my_ships().await.unwrap().ships[0].location.clone().unwrap();
+
+        let scanned = scan_system(backend, storage.clone(), "OE").await.unwrap();
+
+        assert!(scanned.len() > 1);
+        for location in &scanned {
+            assert_eq!(location.goods.is_some(), location.location == docked_at, "{:?}", location);
+        }
+        assert!(!storage.get_latest_market_data().await.unwrap().is_empty());
+
+        let ship_id = user.get_my_ships().await.unwrap().ships[0].id.clone();
+        let markets = storage.get_market_freshness(Utc::now() - Duration::hours(1)).await.unwrap();
+        let docked = markets.iter().find(|m| m.location == docked_at).unwrap();
+        assert_eq!(docked.surveyed_by, Some(ship_id));
+        assert!(docked.trader_records > 0);
+        assert_eq!(docked.scout_records, 0);
+    }
+
+    #[tokio::test]
+    async fn scan_system_rejects_unknown_systems() {
+        let storage: StorageClient = Arc::new(MemoryStorage::new());
+        let backend: Arc<dyn GameBackend> = Arc::new(Simulator::new(0.0));
+
+        assert!(scan_system(backend, storage, "ZZ").await.is_err());
+    }
+
+    #[test]
+    fn markets_that_were_never_recorded_are_listed() {
+        let now = Utc::now();
+        let markets = vec![
+            DbMarketFreshness {
+                location: "OE-PM".to_string(),
+                surveyed_at: Some(now - Duration::seconds(90)),
+                surveyed_by: Some("ship-1".to_string()),
+                scout_records: 0,
+                trader_records: 12,
+            },
+            DbMarketFreshness {
+                location: "OE-UC".to_string(),
+                surveyed_at: None,
+                surveyed_by: None,
+                scout_records: 0,
+                trader_records: 0,
+            },
+        ];
+
+        let output = format_markets(&markets, now);
+
+        assert_eq!(output.lines().count(), 3);
+        assert!(output.lines().nth(1).unwrap().contains("90 ship-1"));
+        assert!(output.lines().nth(2).unwrap().contains("never"));
+    }
+
+    #[test]
+    fn users_without_stats_are_listed() {
+        let users = vec![DbUserSummary {
+            id: "1".to_string(),
+            username: "bloveless-main".to_string(),
+            new_ship_assignment: "trader".to_string(),
+            new_ship_system: "OE".to_string(),
+            credits: None,
+            ship_count: None,
+        }];
+
+        let output = format_users(&users);
+
+        assert_eq!(output.lines().count(), 2);
+        assert!(output.lines().nth(1).unwrap().starts_with("bloveless-main"));
+    }
+}
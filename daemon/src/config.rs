@@ -0,0 +1,501 @@
+// The shape of the fleet. Which users to run, what their ships do, where they do it and when they
+// are allowed to spend credits on more ships. Read from a TOML file (FLEET_CONFIG, fleet.toml by
+// default) and validated before the daemon does anything.
+use crate::ship_machines::ShipAssignment;
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use spacetraders::responses::SystemsInfo;
+use spacetraders::shared::Good;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use spacemonger_core::scoring::ScoringConfig;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FleetConfig {
+    /// Every username is built from this. I.E. bloveless-main or bloveless-scout-OE-PM
+    pub username_base: String,
+    /// Clear out the database when the main user can no longer log in (the api was reset)
+    #[serde(default)]
+    pub enable_reset: bool,
+    /// The first user is the main user. It is always logged in since it is used to detect api
+    /// resets and to scan the systems.
+    pub users: Vec<UserConfig>,
+    #[serde(default)]
+    pub scouts: ScoutConfig,
+    #[serde(default)]
+    pub trading: TradingConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    /// Appended to the username base
+    pub name: String,
+    /// What the user's ships do. Either trader or scout.
+    pub assignment: ShipAssignment,
+    /// Where the user's new ships are sent
+    pub system: String,
+    /// Scouts watch this location. Scouts without one roam their system surveying whichever
+    /// market needs it most
+    pub location: Option<String>,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub purchasing: PurchasingConfig,
+    #[serde(default)]
+    pub loans: LoanConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScoutConfig {
+    /// Scout users are created for every system that isn't excluded
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub excluded_systems: Vec<String>,
+    /// Parked scouts are one user per location that watches it forever. Roaming scouts are a pool
+    /// of users per system that go wherever the market data is most out of date
+    #[serde(default)]
+    pub mode: ScoutMode,
+    /// How many roaming scout users each system gets
+    #[serde(default = "scout_pool_size")]
+    pub pool_size: usize,
+    #[serde(default)]
+    pub purchasing: PurchasingConfig,
+    #[serde(default)]
+    pub loans: LoanConfig,
+}
+
+impl Default for ScoutConfig {
+    fn default() -> Self {
+        ScoutConfig {
+            enabled: false,
+            excluded_systems: Vec::new(),
+            mode: ScoutMode::default(),
+            pool_size: scout_pool_size(),
+            purchasing: PurchasingConfig::default(),
+            loans: LoanConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoutMode {
+    #[default]
+    Roaming,
+    Parked,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PurchasingConfig {
+    /// A user will never own more ships than this
+    #[serde(default = "max_fleet_size")]
+    pub max_fleet_size: usize,
+    /// Another ship is bought once the user has this many credits for each ship they own. As
+    /// the fleet grows it gets more costly to fill every ship with goods so this keeps us from
+    /// going broke.
+    #[serde(default = "credits_per_ship")]
+    pub credits_per_ship: i32,
+    /// Loans are paid off once the user has more credits than this
+    #[serde(default = "pay_off_loans_above")]
+    pub pay_off_loans_above: i32,
+    /// Credits that have to be left over after buying a ship so the fleet can keep trading
+    #[serde(default)]
+    pub reserve_credits: i32,
+    /// Never spend more than this on a single ship
+    #[serde(default)]
+    pub max_ship_price: Option<i32>,
+    /// Once the fleet has stopped growing a trader is replaced by a ship that is expected to earn
+    /// this many times what it does. Ships are never replaced when unset. See `ship_upgrades`
+    #[serde(default)]
+    pub upgrade_ratio: Option<f64>,
+}
+
+impl Default for PurchasingConfig {
+    fn default() -> Self {
+        PurchasingConfig {
+            max_fleet_size: max_fleet_size(),
+            credits_per_ship: credits_per_ship(),
+            pay_off_loans_above: pay_off_loans_above(),
+            reserve_credits: 0,
+            max_ship_price: None,
+            upgrade_ratio: None,
+        }
+    }
+}
+
+impl PurchasingConfig {
+    fn validate(&self, name: &str) -> anyhow::Result<()> {
+        if self.max_fleet_size == 0 {
+            bail!("{} has a max_fleet_size of zero", name);
+        }
+
+        if self.credits_per_ship <= 0 {
+            bail!("{} needs a positive credits_per_ship", name);
+        }
+
+        if self.reserve_credits < 0 {
+            bail!("{} can't have a negative reserve_credits", name);
+        }
+
+        if matches!(self.max_ship_price, Some(price) if price <= 0) {
+            bail!("{} needs a positive max_ship_price", name);
+        }
+
+        if matches!(self.upgrade_ratio, Some(ratio) if ratio <= 1.0) {
+            bail!("{} needs an upgrade_ratio above 1", name);
+        }
+
+        Ok(())
+    }
+
+    pub fn should_purchase_ship(&self, credits: i32, ship_count: usize) -> bool {
+        ship_count < self.max_fleet_size && i64::from(credits) > ship_count as i64 * i64::from(self.credits_per_ship)
+    }
+
+    /// Whether a ship at this price fits the budget and leaves the reserve untouched
+    pub fn can_afford(&self, credits: i32, price: i32) -> bool {
+        self.max_ship_price.is_none_or(|max| price <= max) && i64::from(credits) - i64::from(price) >= i64::from(self.reserve_credits)
+    }
+}
+
+/// When a user borrows credits and when they are paid back. See `loan_manager`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoanConfig {
+    /// Credits to keep on hand. A loan is taken out when the user has less than this and nothing
+    /// outstanding. Loans are only paid off early when this much is left over
+    #[serde(default)]
+    pub cash_reserve: i32,
+    /// Loans are paid off this long before they are due, reserve or not
+    #[serde(default = "repay_hours_before_due")]
+    pub repay_hours_before_due: i64,
+}
+
+impl Default for LoanConfig {
+    fn default() -> Self {
+        LoanConfig {
+            cash_reserve: 0,
+            repay_hours_before_due: repay_hours_before_due(),
+        }
+    }
+}
+
+impl LoanConfig {
+    fn validate(&self, name: &str) -> anyhow::Result<()> {
+        if self.cash_reserve < 0 {
+            bail!("{} can't have a negative cash_reserve", name);
+        }
+
+        if self.repay_hours_before_due < 0 {
+            bail!("{} can't have a negative repay_hours_before_due", name);
+        }
+
+        Ok(())
+    }
+}
+
+/// Places and goods that traders stay away from
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TradingConfig {
+    #[serde(default)]
+    pub blacklisted_locations: Vec<String>,
+    #[serde(default)]
+    pub blacklisted_goods: Vec<Good>,
+    #[serde(default)]
+    pub scoring: ScoringConfig,
+}
+
+impl TradingConfig {
+    pub fn allows(&self, good: Good, location: &str) -> bool {
+        !self.blacklisted_goods.contains(&good) && !self.blacklisted_locations.iter().any(|l| l == location)
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn scout_pool_size() -> usize {
+    2
+}
+
+fn max_fleet_size() -> usize {
+    50
+}
+
+fn credits_per_ship() -> i32 {
+    1_000_000
+}
+
+fn pay_off_loans_above() -> i32 {
+    1_000_000
+}
+
+fn repay_hours_before_due() -> i64 {
+    12
+}
+
+impl FleetConfig {
+    pub fn from_env() -> anyhow::Result<FleetConfig> {
+        let path = env::var("FLEET_CONFIG").unwrap_or_else(|_| "fleet.toml".to_string());
+
+        FleetConfig::from_file(&path)
+    }
+
+    pub fn from_file(path: &str) -> anyhow::Result<FleetConfig> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read fleet config {}", path))?;
+
+        FleetConfig::parse(&contents)
+            .with_context(|| format!("Invalid fleet config {}", path))
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<FleetConfig> {
+        let config: FleetConfig = toml::from_str(contents)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Everything that can be checked without talking to the api
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.username_base.trim().is_empty() {
+            bail!("username_base can't be empty");
+        }
+
+        if self.users.is_empty() {
+            bail!("At least one user is required. The first user is the main user");
+        }
+
+        let mut names = HashSet::new();
+        for user in &self.users {
+            if !names.insert(&user.name) {
+                bail!("User {} is declared more than once", user.name);
+            }
+
+            match user.assignment {
+                ShipAssignment::Trader | ShipAssignment::Scout => {},
+                ShipAssignment::SystemChange => bail!("User {} can't be assigned to system_change. Use trader or scout", user.name),
+            }
+
+            user.purchasing.validate(&user.name)?;
+            user.loans.validate(&user.name)?;
+        }
+
+        if self.scouts.mode == ScoutMode::Roaming && self.scouts.pool_size == 0 {
+            bail!("Roaming scouts need a pool_size of at least one");
+        }
+
+        self.scouts.purchasing.validate("scouts")?;
+        self.scouts.loans.validate("scouts")
+    }
+
+    /// Make sure that every system and location in the config actually exists
+    pub fn validate_systems(&self, systems: &SystemsInfo) -> anyhow::Result<()> {
+        let system_symbols: HashSet<&str> = systems.systems.iter().map(|s| s.symbol.as_str()).collect();
+        let location_symbols: HashSet<&str> = systems.systems.iter()
+            .flat_map(|s| s.locations.iter().map(|l| l.symbol.as_str()))
+            .collect();
+
+        for user in &self.users {
+            if !system_symbols.contains(user.system.as_str()) {
+                bail!("User {} is assigned to unknown system {}", user.name, user.system);
+            }
+
+            if let Some(location) = &user.location {
+                if !location_symbols.contains(location.as_str()) {
+                    bail!("User {} is assigned to unknown location {}", user.name, location);
+                }
+            }
+        }
+
+        for system in &self.scouts.excluded_systems {
+            if !system_symbols.contains(system.as_str()) {
+                bail!("Scouts exclude unknown system {}", system);
+            }
+        }
+
+        for location in &self.trading.blacklisted_locations {
+            if !location_symbols.contains(location.as_str()) {
+                bail!("Trading blacklists unknown location {}", location);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn main_user(&self) -> &UserConfig {
+        &self.users[0]
+    }
+
+    pub fn username(&self, name: &str) -> String {
+        format!("{}-{}", self.username_base, name)
+    }
+
+    pub fn scouts_system(&self, system: &str) -> bool {
+        self.scouts.enabled && !self.scouts.excluded_systems.iter().any(|s| s == system)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::system;
+    use spacemonger_core::scoring::ScorerType;
+    use spacetraders::shared::LocationType;
+
+    const FLEET: &str = r#"
+        username_base = "bloveless"
+        enable_reset = true
+
+        [[users]]
+        name = "main"
+        assignment = "trader"
+        system = "OE"
+
+        [scouts]
+        enabled = true
+        excluded_systems = ["NA7"]
+
+        [trading]
+        blacklisted_locations = ["OE-XV-91-2"]
+        blacklisted_goods = ["RESEARCH"]
+    "#;
+
+    #[test]
+    fn defaults_match_the_original_fleet() {
+        let config = FleetConfig::parse(FLEET).unwrap();
+
+        assert_eq!(config.username(&config.main_user().name), "bloveless-main");
+        assert!(config.main_user().enabled);
+        assert_eq!(config.main_user().purchasing.max_fleet_size, 50);
+        assert_eq!(config.main_user().purchasing.credits_per_ship, 1_000_000);
+        assert_eq!(config.main_user().loans.cash_reserve, 0);
+        assert_eq!(config.main_user().loans.repay_hours_before_due, 12);
+        assert!(config.scouts_system("OE"));
+        assert!(!config.scouts_system("NA7"));
+        assert!(!config.trading.allows(Good::Metals, "OE-XV-91-2"));
+        assert!(!config.trading.allows(Good::Research, "OE-PM"));
+        assert!(config.trading.allows(Good::Metals, "OE-PM"));
+        assert_eq!(config.trading.scoring.scorer, ScorerType::CreditsPerSecond);
+        assert!((config.trading.scoring.slippage_impact - 0.5).abs() < f64::EPSILON);
+        assert!(config.trading.scoring.learn_slippage);
+        assert_eq!(config.scouts.mode, ScoutMode::Roaming);
+        assert_eq!(config.scouts.pool_size, 2);
+    }
+
+    #[test]
+    fn scouts_can_still_be_parked_one_per_location() {
+        let config = FleetConfig::parse(&FLEET.replace("enabled = true", "enabled = true\nmode = \"parked\"")).unwrap();
+        assert_eq!(config.scouts.mode, ScoutMode::Parked);
+
+        let roaming_scout = r#"
+            username_base = "bloveless"
+            [[users]]
+            name = "main"
+            assignment = "scout"
+            system = "OE"
+        "#;
+        assert!(FleetConfig::parse(roaming_scout).unwrap().main_user().location.is_none());
+    }
+
+    #[test]
+    fn scorers_are_chosen_by_name() {
+        let config = FleetConfig::parse(&format!("{}\n[trading.scoring]\nscorer = \"profit_volume_time\"", FLEET)).unwrap();
+        assert_eq!(config.trading.scoring.scorer, ScorerType::ProfitVolumeTime);
+
+        assert!(FleetConfig::parse(&format!("{}\n[trading.scoring]\nscorer = \"vibes\"", FLEET)).is_err());
+    }
+
+    #[test]
+    fn the_example_fleet_config_is_valid() {
+        let config = FleetConfig::from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/fleet.toml")).unwrap();
+
+        assert_eq!(config.main_user().assignment, ShipAssignment::Trader);
+        assert!(!config.scouts_system("NA7"));
+    }
+
+    #[test]
+    fn ships_are_bought_once_there_are_enough_credits_per_ship() {
+        let purchasing = PurchasingConfig { max_fleet_size: 3, credits_per_ship: 100, pay_off_loans_above: 0, ..PurchasingConfig::default() };
+
+        assert!(!purchasing.should_purchase_ship(200, 2));
+        assert!(purchasing.should_purchase_ship(201, 2));
+        assert!(!purchasing.should_purchase_ship(1_000, 3));
+    }
+
+    #[test]
+    fn ships_are_only_bought_within_budget() {
+        let purchasing = PurchasingConfig { reserve_credits: 100, max_ship_price: Some(500), ..PurchasingConfig::default() };
+
+        assert!(purchasing.can_afford(600, 500));
+        assert!(!purchasing.can_afford(599, 500));
+        assert!(!purchasing.can_afford(10_000, 501));
+        assert!(PurchasingConfig::default().can_afford(500, 500));
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let without_users = r#"username_base = "bloveless""#;
+        assert!(FleetConfig::parse(without_users).is_err());
+
+        let empty_scout_pool = r#"
+            username_base = "bloveless"
+            [[users]]
+            name = "main"
+            assignment = "trader"
+            system = "OE"
+            [scouts]
+            enabled = true
+            pool_size = 0
+        "#;
+        assert!(FleetConfig::parse(empty_scout_pool).is_err());
+
+        let unknown_field = r#"
+            username_base = "bloveless"
+            max_ships = 10
+            [[users]]
+            name = "main"
+            assignment = "trader"
+            system = "OE"
+        "#;
+        assert!(FleetConfig::parse(unknown_field).is_err());
+
+        let duplicate_users = r#"
+            username_base = "bloveless"
+            [[users]]
+            name = "main"
+            assignment = "trader"
+            system = "OE"
+            [[users]]
+            name = "main"
+            assignment = "trader"
+            system = "XV"
+        "#;
+        assert!(FleetConfig::parse(duplicate_users).is_err());
+    }
+
+    #[test]
+    fn systems_and_locations_must_exist() {
+        let config = FleetConfig::parse(FLEET).unwrap();
+        let systems = SystemsInfo {
+            systems: vec![
+                system("OE", &[("OE-PM", LocationType::Planet, 0, 0), ("OE-XV-91-2", LocationType::Asteroid, 10, 10)]),
+                system("NA7", &[("NA7-A", LocationType::Planet, 0, 0)]),
+            ],
+        };
+        assert!(config.validate_systems(&systems).is_ok());
+
+        let systems = SystemsInfo {
+            systems: vec![system("OE", &[("OE-PM", LocationType::Planet, 0, 0)])],
+        };
+        assert!(config.validate_systems(&systems).is_err());
+    }
+}
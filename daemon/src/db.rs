@@ -1,82 +1,136 @@
-use serde::Serialize;
 use spacetraders::{shared, responses};
-use sqlx::postgres::{PgPoolOptions, PgRow};
+use sqlx::postgres::PgRow;
 use sqlx::{Row, PgPool};
 use chrono::{Utc, DateTime};
-use std::cmp::Ordering::Equal;
 use spacetraders::shared::Good;
-use std::collections::HashMap;
-use spacetraders::errors::SpaceTradersClientError;
 use crate::ship_machines::ShipAssignment;
+use spacemonger_core::ledger::{LedgerFilter, LedgerSort};
+use spacemonger_core::routes;
+use spacemonger_core::scoring::ShipProfile;
 
 #[derive(Debug, Clone)]
-pub struct Ship {
+pub struct DbUser {
     pub id: String,
     pub username: String,
     pub token: String,
+    pub new_ship_assignment: String,
+    pub new_ship_system: String,
 }
 
-#[derive(Debug, Clone)]
-pub struct DbUser {
+/// A user along with their most recently recorded credits and ship count. Users that haven't
+/// recorded any stats yet have neither.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbUserSummary {
     pub id: String,
     pub username: String,
-    pub token: String,
     pub new_ship_assignment: String,
     pub new_ship_system: String,
+    pub credits: Option<i32>,
+    pub ship_count: Option<i32>,
 }
 
+pub use spacemonger_core::models::SystemLocation as DbSystemLocation;
+
+pub use spacemonger_core::routes::Route as DbRoute;
+
 #[derive(Debug, Clone)]
-pub struct DbSystemLocation {
-    pub system: String,
-    pub system_name: String,
-    pub location: String,
-    pub location_name: String,
+pub struct DbDistanceBetweenLocations {
+    pub origin_location_type: String,
+    pub distance: f64,
+}
+
+/// A flight within a single system that the fuel model can learn from
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbFlightSample {
+    pub distance: i32,
+    pub fuel_consumed: i32,
+    pub flight_time: i32,
+}
+
+/// The fuel and flight time coefficients learned for a ship type leaving a type of location.
+/// See `fuel_model` for how they are fitted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbFuelModel {
+    pub ship_type: String,
     pub location_type: String,
-    pub x: i32,
-    pub y: i32,
-    pub created_at: DateTime<Utc>,
+    pub fuel_intercept: f64,
+    pub fuel_per_distance: f64,
+    pub fuel_max_error: f64,
+    pub time_intercept: f64,
+    pub time_per_distance: f64,
+    pub min_distance: i32,
+    pub max_distance: i32,
+    pub samples: i32,
 }
 
-#[derive(Debug, Clone)]
-pub struct DbRoute {
-    pub purchase_location: String,
-    pub purchase_location_type: String,
-    pub sell_location: String,
+/// One of our orders along with what the market looked like just before and just after it. See
+/// `slippage_model` for how the price impact is learned from these
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbTradeSample {
+    pub location: String,
     pub good: Good,
-    pub distance: f64,
-    pub purchase_quantity: i32,
-    pub sell_quantity: i32,
-    pub purchase_price_per_unit: i32,
-    pub sell_price_per_unit: i32,
-    pub volume_per_unit: i32,
-    pub fuel_required: f64,
-    pub flight_time: f64,
-    pub cost_volume_distance: f64,
-    pub profit_speed_volume_distance: f64,
+    pub transaction_type: String,
+    pub quantity: i32,
+    pub price_per_unit: i32,
+    /// The price listed for our side of the trade before the order
+    pub quoted_price_per_unit: i32,
+    pub quantity_available: i32,
+    /// The price listed for our side of the trade after the order. None when nobody looked at the
+    /// market again soon enough
+    pub quoted_price_per_unit_after: Option<i32>,
 }
 
-#[derive(Debug, Clone)]
-pub struct DbDistanceBetweenLocations {
-    pub origin_location_type: String,
-    pub distance: f64,
+/// How much our own orders move the price of a good at a location
+pub use spacemonger_core::models::SlippageModel as DbSlippageModel;
+
+/// How stale, how changeable and how busy the market at a location is. See `survey_scheduler` for
+/// how these decide where the scouts go next
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbLocationSurvey {
+    pub location: String,
+    /// When the market was last recorded. None if it never has been
+    pub surveyed_at: Option<DateTime<Utc>>,
+    /// When one of our ships last arrived there. A location that has been visited but never
+    /// surveyed doesn't have a market
+    pub visited_at: Option<DateTime<Utc>>,
+    /// The average relative change in a good's price from one snapshot to the next
+    pub volatility: f64,
+    /// How many of our orders were placed there
+    pub trades: i32,
+    /// How many of our ships are on their way there right now
+    pub ships_arriving: i32,
 }
 
-pub async fn get_db_pool(host: String, port: i32, username: String, password: String, database: String) -> anyhow::Result<PgPool> {
-    let pg_pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&format!("postgresql://{}:{}@{}:{}/{}", username, password, host, port, database))
-        .await?;
+/// How fresh the market data at a location is and which ships have been keeping it that way
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbMarketFreshness {
+    pub location: String,
+    /// When the market was last recorded. None if it never has been
+    pub surveyed_at: Option<DateTime<Utc>>,
+    /// The ship that took the latest snapshot. None for snapshots taken before they were tagged
+    pub surveyed_by: Option<String>,
+    /// Goods recorded by scouts and by traders. Snapshots from ships we don't know aren't counted
+    pub scout_records: i32,
+    pub trader_records: i32,
+}
+
+/// Something that happened to one of a user's loans. See `loan_manager`
+pub use spacemonger_core::models::LoanEvent as DbLoanEvent;
 
-    Ok(pg_pool)
+/// Something that happened to one of a user's ships. See `ship_upgrades`
+pub use spacemonger_core::models::ShipEvent as DbShipEvent;
+
+/// A trade that a trader bought, carried and sold. See `Trader`
+pub use spacemonger_core::models::UserTrade as DbUserTrade;
+
+pub use spacemonger_core::models::UserTransaction as DbUserTransaction;
+
+pub async fn get_db_pool(host: String, port: i32, username: String, password: String, database: String) -> anyhow::Result<PgPool> {
+    spacemonger_core::db::get_db_pool(host, port, username, password, database).await
 }
 
 pub async fn run_migrations(pg_pool: PgPool) -> anyhow::Result<()> {
-    sqlx::migrate!("./migrations")
-        .run(&pg_pool)
-        .await
-        .expect("Failed to migrate database");
-
-    Ok(())
+    spacemonger_core::db::run_migrations(&pg_pool).await
 }
 
 pub async fn reset_db(pg_pool: PgPool) -> anyhow::Result<()> {
@@ -136,12 +190,68 @@ pub async fn get_user(pg_pool: PgPool, username: String) -> anyhow::Result<Optio
     )
 }
 
+pub async fn get_user_summaries(pg_pool: PgPool) -> anyhow::Result<Vec<DbUserSummary>> {
+    Ok(
+        sqlx::query("
+            SELECT
+                 du.id::text
+                ,du.username
+                ,du.new_ship_assignment
+                ,du.new_ship_system
+                ,dus.credits
+                ,dus.ship_count
+            FROM daemon_user du
+            LEFT JOIN LATERAL (
+                SELECT credits, ship_count
+                FROM daemon_user_stats
+                WHERE user_id = du.id
+                ORDER BY created_at DESC
+                LIMIT 1
+            ) dus ON true
+            ORDER BY du.username;
+        ")
+            .map(|row: PgRow| {
+                DbUserSummary {
+                    id: row.get("id"),
+                    username: row.get("username"),
+                    new_ship_assignment: row.get("new_ship_assignment"),
+                    new_ship_system: row.get("new_ship_system"),
+                    credits: row.get("credits"),
+                    ship_count: row.get("ship_count"),
+                }
+            })
+            .fetch_all(&pg_pool)
+            .await?
+    )
+}
+
+/// The user that owns a ship. Ship ids are unique across every user
+pub async fn get_ship_owner(pg_pool: PgPool, ship_id: &str) -> anyhow::Result<Option<DbUser>> {
+    Ok(
+        sqlx::query("
+            SELECT du.id::text, du.username, du.token, du.new_ship_assignment, du.new_ship_system
+            FROM daemon_user du
+            INNER JOIN daemon_user_ship dus ON dus.user_id = du.id
+            WHERE dus.ship_id = $1
+            LIMIT 1;
+        ")
+            .bind(ship_id)
+            .map(|row: PgRow| {
+                DbUser {
+                    id: row.get("id"),
+                    username: row.get("username"),
+                    token: row.get("token"),
+                    new_ship_assignment: row.get("new_ship_assignment"),
+                    new_ship_system: row.get("new_ship_system"),
+                }
+            })
+            .fetch_optional(&pg_pool)
+            .await?
+    )
+}
+
 pub async fn persist_user(pg_pool: PgPool, username: String, token: String, new_ship_assignment: &ShipAssignment, new_ship_system: &str) -> anyhow::Result<DbUser> {
-    let new_ship_assignment = match new_ship_assignment {
-        ShipAssignment::Scout => "scout",
-        ShipAssignment::Trader => "trader",
-        ShipAssignment::SystemChange => "system_change",
-    };
+    let new_ship_assignment = new_ship_assignment.to_string();
 
     Ok(
         sqlx::query("
@@ -151,8 +261,8 @@ pub async fn persist_user(pg_pool: PgPool, username: String, token: String, new_
         ")
             .bind(&username)
             .bind(&token)
-            .bind(&new_ship_assignment)
-            .bind(&new_ship_system)
+            .bind(new_ship_assignment)
+            .bind(new_ship_system)
             .map(|row: PgRow| {
                 DbUser {
                     id: row.get("id"),
@@ -184,9 +294,9 @@ pub async fn persist_system_location(pg_pool: PgPool, system: &shared::SystemsIn
         .bind(&system.name)
         .bind(&location.symbol)
         .bind(&location.name)
-        .bind(&location.systems_info_type.to_string())
-        .bind(&location.x)
-        .bind(&location.y)
+        .bind(location.systems_info_type.to_string())
+        .bind(location.x)
+        .bind(location.y)
         .execute(&pg_pool)
         .await?;
 
@@ -212,7 +322,7 @@ pub async fn get_system_locations_from_location(pg_pool: PgPool, location: &str)
     )
 }
 
-pub async fn persist_flight_plan(pg_pool: PgPool, user_id: &str, ship_id: &str, flight_plan: &responses::FlightPlan) -> anyhow::Result<()> {
+pub async fn persist_flight_plan(pg_pool: PgPool, user_id: &str, ship_id: &str, flight_plan: &responses::FlightPlan, trade_id: Option<&str>) -> anyhow::Result<()> {
     sqlx::query("
         INSERT INTO daemon_flight_plan (
              id
@@ -225,58 +335,27 @@ pub async fn persist_flight_plan(pg_pool: PgPool, user_id: &str, ship_id: &str,
             ,fuel_remaining
             ,time_remaining_in_seconds
             ,arrives_at
-        ) VALUES ($1, $2::uuid, $3, $4, $5, $6, $7, $8, $9, $10);
+            ,trade_id
+        ) VALUES ($1, $2::uuid, $3, $4, $5, $6, $7, $8, $9, $10, $11::uuid);
     ")
         .bind(&flight_plan.flight_plan.id)
-        .bind(&user_id)
-        .bind(&ship_id)
+        .bind(user_id)
+        .bind(ship_id)
         .bind(&flight_plan.flight_plan.departure)
         .bind(&flight_plan.flight_plan.destination)
-        .bind(&flight_plan.flight_plan.distance)
-        .bind(&flight_plan.flight_plan.fuel_consumed)
-        .bind(&flight_plan.flight_plan.fuel_remaining)
-        .bind(&flight_plan.flight_plan.time_remaining_in_seconds)
-        .bind(&flight_plan.flight_plan.arrives_at)
+        .bind(flight_plan.flight_plan.distance)
+        .bind(flight_plan.flight_plan.fuel_consumed)
+        .bind(flight_plan.flight_plan.fuel_remaining)
+        .bind(flight_plan.flight_plan.time_remaining_in_seconds)
+        .bind(flight_plan.flight_plan.arrives_at)
+        .bind(trade_id)
         .execute(&pg_pool)
         .await?;
 
     Ok(())
 }
 
-pub async fn get_system_location(pg_pool: PgPool, location: String) -> anyhow::Result<DbSystemLocation> {
-    Ok(
-        sqlx::query("
-            SELECT
-                 system
-                ,system_name
-                ,location
-                ,location_name
-                ,location_type
-                ,x
-                ,y
-                ,created_at
-            FROM daemon_system_info
-            WHERE location = $1;
-        ")
-            .bind(&location)
-            .map(|row: PgRow| {
-                DbSystemLocation {
-                    system: row.get("system"),
-                    system_name: row.get("system_name"),
-                    location: row.get("location"),
-                    location_name: row.get("location_name"),
-                    location_type: row.get("location_type"),
-                    x: row.get("x"),
-                    y: row.get("y"),
-                    created_at: row.get("created_at"),
-                }
-            })
-            .fetch_one(&pg_pool)
-            .await?
-    )
-}
-
-pub async fn get_distance_between_locations(pg_pool: PgPool, origin: &str, destination: &str) -> anyhow::Result<DbDistanceBetweenLocations> {
+pub async fn get_distance_between_locations(pg_pool: PgPool, origin: &str, destination: &str) -> anyhow::Result<Option<DbDistanceBetweenLocations>> {
     Ok(
         sqlx::query("
             SELECT
@@ -284,8 +363,8 @@ pub async fn get_distance_between_locations(pg_pool: PgPool, origin: &str, desti
                 ,SQRT(POW(dsi1.x - dsi2.x, 2) + POW(dsi1.y - dsi2.y, 2)) AS distance
             FROM daemon_system_info dsi1
             INNER JOIN daemon_system_info dsi2
-                -- for now we are going to restrict this to the same system since we don't have
-                -- multiple stops built yet
+                -- distances only make sense within a system. Trips between systems are planned
+                -- leg by leg in the route planner
                 ON dsi1.system = dsi2.system
             WHERE dsi1.location = $1
                 AND dsi2.location = $2;
@@ -298,7 +377,7 @@ pub async fn get_distance_between_locations(pg_pool: PgPool, origin: &str, desti
                     distance: row.get("distance"),
                 }
             })
-            .fetch_one(&pg_pool)
+            .fetch_optional(&pg_pool)
             .await?
     )
 }
@@ -323,7 +402,7 @@ pub async fn get_active_flight_plan(pg_pool: PgPool, ship_id: &str) -> anyhow::R
                 AND arrives_at > $2
         ")
             .bind(ship_id)
-            .bind(&Utc::now())
+            .bind(Utc::now())
             .map(|row: PgRow| {
                 shared::FlightPlanData {
                     id: row.get("id"),
@@ -344,156 +423,42 @@ pub async fn get_active_flight_plan(pg_pool: PgPool, ship_id: &str) -> anyhow::R
     )
 }
 
-pub async fn persist_market_data(pg_pool: PgPool, location: &str, marketplace_data: &shared::MarketplaceData) -> anyhow::Result<()> {
+pub async fn persist_market_data(pg_pool: PgPool, location: &str, ship_id: &str, marketplace_data: &shared::MarketplaceData) -> anyhow::Result<()> {
     sqlx::query("
-        INSERT INTO daemon_market_data(location, good, price_per_unit, volume_per_unit, quantity_available, purchase_price_per_unit, sell_price_per_unit)
-        VALUES ($1, $2, $3, $4, $5, $6, $7);
+        INSERT INTO daemon_market_data(location, good, price_per_unit, volume_per_unit, quantity_available, purchase_price_per_unit, sell_price_per_unit, ship_id)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8);
     ")
         .bind(location)
-        .bind(&marketplace_data.symbol.to_string())
-        .bind(&marketplace_data.price_per_unit)
-        .bind(&marketplace_data.volume_per_unit)
-        .bind(&marketplace_data.quantity_available)
-        .bind(&marketplace_data.purchase_price_per_unit)
-        .bind(&marketplace_data.sell_price_per_unit)
+        .bind(marketplace_data.symbol.to_string())
+        .bind(marketplace_data.price_per_unit)
+        .bind(marketplace_data.volume_per_unit)
+        .bind(marketplace_data.quantity_available)
+        .bind(marketplace_data.purchase_price_per_unit)
+        .bind(marketplace_data.sell_price_per_unit)
+        .bind(ship_id)
         .execute(&pg_pool)
         .await?;
 
     Ok(())
 }
 
-pub async fn get_routes_from_location(pg_pool: PgPool, location: &str, ship_speed: i32) -> anyhow::Result<Vec<DbRoute>> {
-    let mut transaction = pg_pool.begin().await.unwrap();
-
-    sqlx::query("DROP TABLE IF EXISTS tmp_latest_location_goods;")
-        .execute(&mut transaction)
-        .await?;
-
-    sqlx::query("
-        CREATE TEMPORARY TABLE tmp_latest_location_goods (
-             location VARCHAR(100) NOT NULL
-            ,location_type VARCHAR(100) NOT NULL
-            ,x INT NOT NULL
-            ,y INT NOT NULL
-            ,good VARCHAR(100) NOT NULL
-            ,quantity_available INT NOT NULL
-            ,price_per_unit INT NOT NULL
-            ,volume_per_unit INT NOT NULL
-            ,created_at TIMESTAMP WITH TIME ZONE NOT NULL
-        );
-    ")
-        .execute(&mut transaction)
-        .await?;
-
-    sqlx::query("
-        -- Get the latest market data from each good in each location
-        WITH ranked_location_goods AS (
-            SELECT
-                 id
-                ,ROW_NUMBER() OVER (
-                    PARTITION BY location, good
-                    ORDER BY created_at DESC
-                ) AS rank
-            FROM daemon_market_data
-        )
-        INSERT INTO tmp_latest_location_goods (
-             location
-            ,location_type
-            ,x
-            ,y
-            ,good
-            ,quantity_available
-            ,price_per_unit
-            ,volume_per_unit
-            ,created_at
-        )
-        SELECT
-             dmd.location
-            ,dsi.location_type
-            ,dsi.x
-            ,dsi.y
-            ,dmd.good
-            ,dmd.quantity_available
-            ,dmd.price_per_unit
-            ,dmd.volume_per_unit
-            ,dmd.created_at
-        FROM daemon_market_data dmd
-        INNER JOIN ranked_location_goods rlg ON dmd.id = rlg.id
-        INNER JOIN daemon_system_info dsi on dmd.location = dsi.location
-        WHERE rlg.rank = 1
-            AND dmd.created_at > (now() at time zone 'utc' - INTERVAL '30 min')
-        ORDER BY dmd.good, dmd.location;
-    ")
-        .execute(&mut transaction)
-        .await?;
-
-    let mut routes = sqlx::query("
-        -- calculate the route from each location to each location per good
-        -- limited to routes which will actually turn a profit
-        SELECT
-             llg1.location AS purchase_location
-            ,llg1.location_type AS purchase_location_type
-            ,llg2.location AS sell_location
-            ,llg2.good
-            ,SQRT(POW(llg1.x - llg2.x, 2) + POW(llg2.y - llg1.y, 2)) AS distance
-            ,llg1.quantity_available AS purchase_quantity
-            ,llg2.quantity_available AS sell_quantity
-            ,llg1.price_per_unit AS purchase_price_per_unit
-            ,llg2.price_per_unit AS sell_price_per_unit
-            ,llg1.volume_per_unit AS volume_per_unit
-        FROM tmp_latest_location_goods llg1
-        CROSS JOIN tmp_latest_location_goods llg2
-        INNER JOIN daemon_system_info from_dsi
-            ON from_dsi.location = llg1.location
-        INNER JOIN daemon_system_info to_dsi
-            ON to_dsi.location = llg2.location
-        WHERE from_dsi.location = $1
-            AND from_dsi.system = to_dsi.system
-            AND llg1.good = llg2.good
-            AND llg1.location != llg2.location
-    ")
-        .bind(location)
-        .map(|row: PgRow| {
-            let distance: f64 = row.get("distance");
-            let location_type: String = row.get("purchase_location_type");
-            let purchase_price_per_unit: i32 = row.get("purchase_price_per_unit");
-            let sell_price_per_unit: i32 = row.get("sell_price_per_unit");
-            let volume_per_unit: i32 = row.get("volume_per_unit");
-
-            let planet_penalty = if location_type == "Planet" { 2.0 } else { 0.0 };
-            let fuel_required: f64 = (distance.round() / 4.0).round() + planet_penalty + 1.0;
-
-            let flight_time = (distance * (2.0 / f64::from(ship_speed)).round()) + 60.0;
+pub use spacemonger_core::models::LatestMarketData as DbMarketData;
 
-            let profit = f64::from(sell_price_per_unit - purchase_price_per_unit);
-            let cost_volume_distance = profit / f64::from(volume_per_unit) / distance;
-            let profit_speed_volume_distance = (profit * f64::from(ship_speed)) / (f64::from(volume_per_unit) * distance);
+pub async fn get_system_locations(pg_pool: PgPool) -> anyhow::Result<Vec<DbSystemLocation>> {
+    spacemonger_core::queries::system_locations(&pg_pool).await
+}
 
-            DbRoute {
-                purchase_location: row.get("purchase_location"),
-                purchase_location_type: row.get("purchase_location_type"),
-                sell_location: row.get("sell_location"),
-                good: Good::from(row.get::<String, &str>("good")),
-                distance: row.get("distance"),
-                purchase_quantity: row.get("purchase_quantity"),
-                sell_quantity: row.get("sell_quantity"),
-                purchase_price_per_unit,
-                sell_price_per_unit,
-                volume_per_unit,
-                fuel_required,
-                flight_time,
-                cost_volume_distance,
-                profit_speed_volume_distance,
-            }
-        })
-        .fetch_all(&mut transaction)
-        .await?;
+/// The latest market data for each good in each location, ignoring anything too old to trade on
+pub async fn get_latest_market_data(pg_pool: PgPool) -> anyhow::Result<Vec<DbMarketData>> {
+    spacemonger_core::queries::trading_market_data(&pg_pool).await
+}
 
-    routes.sort_by(|a, b|
-        b.profit_speed_volume_distance.partial_cmp(&a.profit_speed_volume_distance).unwrap_or(Equal)
-    );
+// Single system routes. Traders use the route planner now but this is still handy for inspecting
+// a location from the command line
+pub async fn get_routes_from_location(pg_pool: PgPool, location: &str, ship: &ShipProfile) -> anyhow::Result<Vec<DbRoute>> {
+    let location_goods = spacemonger_core::queries::latest_location_goods(&pg_pool).await?;
 
-    Ok(routes)
+    Ok(routes::routes_from_location(&location_goods, location, &ship.ship_type, ship.speed))
 }
 
 pub async fn persist_user_stats(pg_pool: PgPool, user_id: &str, credits: i32, ships: &[shared::Ship]) -> anyhow::Result<()> {
@@ -510,58 +475,7 @@ pub async fn persist_user_stats(pg_pool: PgPool, user_id: &str, credits: i32, sh
     Ok(())
 }
 
-#[derive(Serialize)]
-struct DbRequest<'a> {
-    method: &'a str,
-    url: &'a str,
-    body: Option<&'a str>,
-}
-
-#[derive(Serialize)]
-struct DbResponse<'a> {
-    status_code: Option<u16>,
-    headers: Option<&'a HashMap<String, String>>,
-    body: Option<&'a str>,
-}
-
-pub async fn persist_request_response(
-    pg_pool: PgPool,
-    method: &str, url: &str, request_body: Option<&str>,
-    response_status_code: Option<u16>,
-    response_headers: Option<&HashMap<String, String>>,
-    response_body: Option<&str>,
-    error: Option<&SpaceTradersClientError>,
-) -> anyhow::Result<()> {
-    let db_request = DbRequest {
-        method,
-        url,
-        body: request_body,
-    };
-
-    let db_response = DbResponse {
-        status_code: response_status_code,
-        headers: response_headers,
-        body: response_body,
-    };
-
-    let mut db_error = None;
-    if let Some(error) = error {
-        db_error = Some(format!("Error: {}", error));
-    }
-
-    sqlx::query("
-        INSERT INTO http_log (request, response, error) VALUES ($1, $2, $3);
-    ")
-        .bind(serde_json::to_string(&db_request).unwrap())
-        .bind(serde_json::to_string(&db_response).unwrap())
-        .bind(db_error)
-        .execute(&pg_pool)
-        .await?;
-
-    Ok(())
-}
-
-pub async fn persist_ship(pg_pool: PgPool, user_id: &str, system: &str, ship: &shared::Ship) -> anyhow::Result<()> {
+pub async fn persist_ship(pg_pool: PgPool, user_id: &str, system: &str, assignment: &ShipAssignment, ship: &shared::Ship) -> anyhow::Result<()> {
     sqlx::query("
         INSERT INTO daemon_user_ship (
              user_id
@@ -574,6 +488,7 @@ pub async fn persist_ship(pg_pool: PgPool, user_id: &str, system: &str, ship: &s
             ,plating
             ,weapons
             ,system
+            ,assignment
         ) VALUES (
              $1::uuid
             ,$2
@@ -585,6 +500,7 @@ pub async fn persist_ship(pg_pool: PgPool, user_id: &str, system: &str, ship: &s
             ,$8
             ,$9
             ,$10
+            ,$11
         )
         ON CONFLICT (user_id, ship_id)
         DO UPDATE SET
@@ -595,19 +511,19 @@ pub async fn persist_ship(pg_pool: PgPool, user_id: &str, system: &str, ship: &s
             ,manufacturer = $7
             ,plating = $8
             ,weapons = $9
-            ,system = $10
             ,modified_at = timezone('utc', NOW());
     ")
         .bind(user_id)
         .bind(&ship.id)
         .bind(&ship.ship_type)
         .bind(&ship.class)
-        .bind(&ship.max_cargo)
-        .bind(&ship.speed)
+        .bind(ship.max_cargo)
+        .bind(ship.speed)
         .bind(&ship.manufacturer)
-        .bind(&ship.plating)
-        .bind(&ship.weapons)
-        .bind(&system)
+        .bind(ship.plating)
+        .bind(ship.weapons)
+        .bind(system)
+        .bind(assignment.to_string())
         .execute(&pg_pool)
         .await?;
 
@@ -619,13 +535,26 @@ pub struct DbShip {
     pub user_id: String,
     pub ship_id: String,
     pub ship_type: String,
-    pub class: String,
-    pub max_cargo: i32,
-    pub speed: i32,
-    pub manufacturer: String,
-    pub plating: i32,
-    pub weapons: i32,
     pub system: String,
+    pub assignment: String,
+}
+
+pub async fn update_ship_system(pg_pool: PgPool, user_id: &str, ship_id: &str, system: &str) -> anyhow::Result<()> {
+    sqlx::query("
+        UPDATE daemon_user_ship
+        SET
+             system = $3
+            ,modified_at = timezone('utc', NOW())
+        WHERE user_id = $1::uuid
+            AND ship_id = $2;
+    ")
+        .bind(user_id)
+        .bind(ship_id)
+        .bind(system)
+        .execute(&pg_pool)
+        .await?;
+
+    Ok(())
 }
 
 pub async fn get_ship(pg_pool: PgPool, user_id: &str, ship_id: &str) -> anyhow::Result<DbShip> {
@@ -635,13 +564,8 @@ pub async fn get_ship(pg_pool: PgPool, user_id: &str, ship_id: &str) -> anyhow::
              user_id::text
             ,ship_id
             ,type
-            ,class
-            ,max_cargo
-            ,speed
-            ,manufacturer
-            ,plating
-            ,weapons
             ,system
+            ,assignment
         FROM daemon_user_ship dus
         WHERE dus.user_id = $1::uuid
             AND dus.ship_id = $2
@@ -654,13 +578,8 @@ pub async fn get_ship(pg_pool: PgPool, user_id: &str, ship_id: &str) -> anyhow::
                 user_id: row.get("user_id"),
                 ship_id: row.get("ship_id"),
                 ship_type: row.get("type"),
-                class: row.get("class"),
-                max_cargo: row.get("max_cargo"),
-                speed: row.get("speed"),
-                manufacturer: row.get("manufacturer"),
-                plating: row.get("plating"),
-                weapons: row.get("weapons"),
                 system: row.get("system"),
+                assignment: row.get("assignment"),
             }
         })
         .fetch_one(&pg_pool)
@@ -668,7 +587,7 @@ pub async fn get_ship(pg_pool: PgPool, user_id: &str, ship_id: &str) -> anyhow::
     )
 }
 
-pub async fn persist_transaction(pg_pool: PgPool, transaction_type: &str, user_id: &str, order: &responses::PurchaseOrder) -> anyhow::Result<()> {
+pub async fn persist_transaction(pg_pool: PgPool, transaction_type: &str, user_id: &str, order: &responses::PurchaseOrder, trade_id: Option<&str>) -> anyhow::Result<()> {
     sqlx::query("
         INSERT INTO daemon_user_transaction (
              user_id
@@ -679,6 +598,7 @@ pub async fn persist_transaction(pg_pool: PgPool, transaction_type: &str, user_i
             ,quantity
             ,total
             ,location
+            ,trade_id
         ) VALUES (
              $1::uuid
             ,$2
@@ -688,44 +608,313 @@ pub async fn persist_transaction(pg_pool: PgPool, transaction_type: &str, user_i
             ,$6
             ,$7
             ,$8
+            ,$9::uuid
         )
     ")
         .bind(user_id)
         .bind(&order.ship.id)
         .bind(transaction_type)
-        .bind(&order.order.good.to_string())
-        .bind(&order.order.price_per_unit)
-        .bind(&order.order.quantity)
-        .bind(&order.order.total)
-        .bind(&order.ship.location.clone().unwrap_or_else(|| "UNKNOWN".to_string()))
+        .bind(order.order.good.to_string())
+        .bind(order.order.price_per_unit)
+        .bind(order.order.quantity)
+        .bind(order.order.total)
+        .bind(order.ship.location.clone().unwrap_or_else(|| "UNKNOWN".to_string()))
+        .bind(trade_id)
         .execute(&pg_pool)
         .await?;
 
     Ok(())
 }
 
-pub async fn get_fuel_required(pg_pool: PgPool, origin: &str, destination: &str, ship_type: &str) -> anyhow::Result<Option<i32>> {
+pub async fn get_flight_samples(pg_pool: PgPool, ship_type: &str, location_type: &str) -> anyhow::Result<Vec<DbFlightSample>> {
     Ok(sqlx::query("
-        SELECT fuel_consumed
+        SELECT
+             dfp.distance
+            ,dfp.fuel_consumed
+            ,dfp.time_remaining_in_seconds AS flight_time
         FROM daemon_flight_plan dfp
         INNER JOIN daemon_user_ship dus
             ON dus.ship_id = dfp.ship_id
-        WHERE dfp.origin = $1
-            AND dfp.destination = $2
-            AND dus.type = $3
-        LIMIT 1
+        INNER JOIN daemon_system_info dsi1
+            ON dsi1.location = dfp.origin
+        INNER JOIN daemon_system_info dsi2
+            -- warp jumps are flights between systems and don't use any fuel
+            ON dsi2.location = dfp.destination
+            AND dsi2.system = dsi1.system
+        WHERE dus.type = $1
+            AND dsi1.location_type = $2
+        ORDER BY dfp.created_at
+    ")
+        .bind(ship_type)
+        .bind(location_type)
+        .map(|row: PgRow| {
+            DbFlightSample {
+                distance: row.get("distance"),
+                fuel_consumed: row.get("fuel_consumed"),
+                flight_time: row.get("flight_time"),
+            }
+        })
+        .fetch_all(&pg_pool)
+        .await?
+    )
+}
+
+pub async fn persist_fuel_model(pg_pool: PgPool, fuel_model: &DbFuelModel) -> anyhow::Result<()> {
+    sqlx::query("
+        INSERT INTO daemon_fuel_model (
+             ship_type
+            ,location_type
+            ,fuel_intercept
+            ,fuel_per_distance
+            ,fuel_max_error
+            ,time_intercept
+            ,time_per_distance
+            ,min_distance
+            ,max_distance
+            ,samples
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        ON CONFLICT (ship_type, location_type)
+        DO UPDATE SET
+             fuel_intercept = $3
+            ,fuel_per_distance = $4
+            ,fuel_max_error = $5
+            ,time_intercept = $6
+            ,time_per_distance = $7
+            ,min_distance = $8
+            ,max_distance = $9
+            ,samples = $10
+            ,modified_at = timezone('utc', NOW());
+    ")
+        .bind(&fuel_model.ship_type)
+        .bind(&fuel_model.location_type)
+        .bind(fuel_model.fuel_intercept)
+        .bind(fuel_model.fuel_per_distance)
+        .bind(fuel_model.fuel_max_error)
+        .bind(fuel_model.time_intercept)
+        .bind(fuel_model.time_per_distance)
+        .bind(fuel_model.min_distance)
+        .bind(fuel_model.max_distance)
+        .bind(fuel_model.samples)
+        .execute(&pg_pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_fuel_model(pg_pool: PgPool, ship_type: &str, location_type: &str) -> anyhow::Result<Option<DbFuelModel>> {
+    Ok(sqlx::query("
+        SELECT
+             ship_type
+            ,location_type
+            ,fuel_intercept
+            ,fuel_per_distance
+            ,fuel_max_error
+            ,time_intercept
+            ,time_per_distance
+            ,min_distance
+            ,max_distance
+            ,samples
+        FROM daemon_fuel_model
+        WHERE ship_type = $1
+            AND location_type = $2
     ")
-        .bind(origin)
-        .bind(destination)
         .bind(ship_type)
+        .bind(location_type)
         .map(|row: PgRow| {
-            row.get("fuel_consumed")
+            DbFuelModel {
+                ship_type: row.get("ship_type"),
+                location_type: row.get("location_type"),
+                fuel_intercept: row.get("fuel_intercept"),
+                fuel_per_distance: row.get("fuel_per_distance"),
+                fuel_max_error: row.get("fuel_max_error"),
+                time_intercept: row.get("time_intercept"),
+                time_per_distance: row.get("time_per_distance"),
+                min_distance: row.get("min_distance"),
+                max_distance: row.get("max_distance"),
+                samples: row.get("samples"),
+            }
         })
         .fetch_optional(&pg_pool)
         .await?
     )
 }
 
+pub async fn get_trade_samples(pg_pool: PgPool, location: &str) -> anyhow::Result<Vec<DbTradeSample>> {
+    Ok(sqlx::query("
+        SELECT
+             dut.location
+            ,dut.good
+            ,dut.type
+            ,dut.quantity
+            ,dut.price_per_unit
+            ,CASE WHEN dut.type = 'purchase' THEN before.purchase_price_per_unit ELSE before.sell_price_per_unit END AS quoted_price_per_unit
+            ,before.quantity_available
+            ,CASE WHEN dut.type = 'purchase' THEN after.purchase_price_per_unit ELSE after.sell_price_per_unit END AS quoted_price_per_unit_after
+        FROM daemon_user_transaction dut
+        -- the market snapshots surrounding the order. Anything older than 15 minutes says more
+        -- about the market drifting than about our order
+        INNER JOIN LATERAL (
+            SELECT
+                 dmd.purchase_price_per_unit
+                ,dmd.sell_price_per_unit
+                ,dmd.quantity_available
+            FROM daemon_market_data dmd
+            WHERE dmd.location = dut.location
+                AND dmd.good = dut.good
+                AND dmd.created_at <= dut.created_at
+                AND dmd.created_at > dut.created_at - INTERVAL '15 minutes'
+            ORDER BY dmd.created_at DESC
+            LIMIT 1
+        ) before ON TRUE
+        LEFT JOIN LATERAL (
+            SELECT
+                 dmd.purchase_price_per_unit
+                ,dmd.sell_price_per_unit
+            FROM daemon_market_data dmd
+            WHERE dmd.location = dut.location
+                AND dmd.good = dut.good
+                AND dmd.created_at > dut.created_at
+                AND dmd.created_at < dut.created_at + INTERVAL '15 minutes'
+            ORDER BY dmd.created_at
+            LIMIT 1
+        ) after ON TRUE
+        WHERE dut.location = $1
+        ORDER BY dut.created_at
+    ")
+        .bind(location)
+        .map(|row: PgRow| {
+            DbTradeSample {
+                location: row.get("location"),
+                good: Good::from(row.get::<String, &str>("good")),
+                transaction_type: row.get("type"),
+                quantity: row.get("quantity"),
+                price_per_unit: row.get("price_per_unit"),
+                quoted_price_per_unit: row.get("quoted_price_per_unit"),
+                quantity_available: row.get("quantity_available"),
+                quoted_price_per_unit_after: row.get("quoted_price_per_unit_after"),
+            }
+        })
+        .fetch_all(&pg_pool)
+        .await?
+    )
+}
+
+pub async fn persist_slippage_model(pg_pool: PgPool, slippage_model: &DbSlippageModel) -> anyhow::Result<()> {
+    sqlx::query("
+        INSERT INTO daemon_slippage_model (
+             location
+            ,good
+            ,purchase_impact
+            ,sell_impact
+            ,purchase_samples
+            ,sell_samples
+        ) VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (location, good)
+        DO UPDATE SET
+             purchase_impact = $3
+            ,sell_impact = $4
+            ,purchase_samples = $5
+            ,sell_samples = $6
+            ,modified_at = timezone('utc', NOW());
+    ")
+        .bind(&slippage_model.location)
+        .bind(slippage_model.good.to_string())
+        .bind(slippage_model.purchase_impact)
+        .bind(slippage_model.sell_impact)
+        .bind(slippage_model.purchase_samples)
+        .bind(slippage_model.sell_samples)
+        .execute(&pg_pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_slippage_models(pg_pool: PgPool) -> anyhow::Result<Vec<DbSlippageModel>> {
+    spacemonger_core::queries::slippage_models(&pg_pool).await
+}
+
+/// Every location in a system along with how its market has behaved since `since`
+pub async fn get_location_surveys(pg_pool: PgPool, system: &str, since: DateTime<Utc>) -> anyhow::Result<Vec<DbLocationSurvey>> {
+    Ok(sqlx::query("
+        WITH price_changes AS (
+            SELECT
+                 dmd.location
+                ,ABS(dmd.price_per_unit - LAG(dmd.price_per_unit) OVER w)::DOUBLE PRECISION
+                    / NULLIF(LAG(dmd.price_per_unit) OVER w, 0) AS price_change
+            FROM daemon_market_data dmd
+            INNER JOIN daemon_system_info dsi ON dsi.location = dmd.location
+            WHERE dsi.system = $1
+                AND dmd.created_at > $2
+            WINDOW w AS (PARTITION BY dmd.location, dmd.good ORDER BY dmd.created_at)
+        )
+        SELECT
+             dsi.location
+            ,(SELECT MAX(dmd.created_at) FROM daemon_market_data dmd WHERE dmd.location = dsi.location) AS surveyed_at
+            ,(SELECT MAX(dfp.arrives_at) FROM daemon_flight_plan dfp WHERE dfp.destination = dsi.location AND dfp.arrives_at <= $3) AS visited_at
+            ,COALESCE((SELECT AVG(pc.price_change) FROM price_changes pc WHERE pc.location = dsi.location), 0) AS volatility
+            ,(SELECT COUNT(*) FROM daemon_user_transaction dut WHERE dut.location = dsi.location AND dut.created_at > $2)::INT AS trades
+            ,(SELECT COUNT(*) FROM daemon_flight_plan dfp WHERE dfp.destination = dsi.location AND dfp.arrives_at > $3)::INT AS ships_arriving
+        FROM daemon_system_info dsi
+        WHERE dsi.system = $1
+        ORDER BY dsi.location
+    ")
+        .bind(system)
+        .bind(since)
+        .bind(Utc::now())
+        .map(|row: PgRow| {
+            DbLocationSurvey {
+                location: row.get("location"),
+                surveyed_at: row.get("surveyed_at"),
+                visited_at: row.get("visited_at"),
+                volatility: row.get("volatility"),
+                trades: row.get("trades"),
+                ships_arriving: row.get("ships_arriving"),
+            }
+        })
+        .fetch_all(&pg_pool)
+        .await?
+    )
+}
+
+/// Every location with its latest snapshot and who has recorded its market since `since`
+pub async fn get_market_freshness(pg_pool: PgPool, since: DateTime<Utc>) -> anyhow::Result<Vec<DbMarketFreshness>> {
+    Ok(sqlx::query("
+        SELECT
+             dsi.location
+            ,latest.created_at AS surveyed_at
+            ,latest.ship_id AS surveyed_by
+            ,COUNT(*) FILTER (WHERE dus.assignment = 'scout')::INT AS scout_records
+            ,COUNT(*) FILTER (WHERE dus.assignment = 'trader')::INT AS trader_records
+        FROM daemon_system_info dsi
+        LEFT JOIN LATERAL (
+            SELECT
+                 dmd.created_at
+                ,dmd.ship_id
+            FROM daemon_market_data dmd
+            WHERE dmd.location = dsi.location
+            ORDER BY dmd.created_at DESC
+            LIMIT 1
+        ) latest ON TRUE
+        LEFT JOIN daemon_market_data dmd ON dmd.location = dsi.location AND dmd.created_at > $1
+        LEFT JOIN daemon_user_ship dus ON dus.ship_id = dmd.ship_id
+        GROUP BY dsi.location, latest.created_at, latest.ship_id
+        ORDER BY dsi.location
+    ")
+        .bind(since)
+        .map(|row: PgRow| {
+            DbMarketFreshness {
+                location: row.get("location"),
+                surveyed_at: row.get("surveyed_at"),
+                surveyed_by: row.get("surveyed_by"),
+                scout_records: row.get("scout_records"),
+                trader_records: row.get("trader_records"),
+            }
+        })
+        .fetch_all(&pg_pool)
+        .await?
+    )
+}
+
 pub async fn get_wormhole_from_location_to_system(pg_pool: PgPool, location: &str, system: &str) -> anyhow::Result<String> {
     Ok(sqlx::query("
         SELECT
@@ -747,3 +936,92 @@ pub async fn get_wormhole_from_location_to_system(pg_pool: PgPool, location: &st
         .await?
     )
 }
+
+#[derive(Debug, Clone)]
+pub struct DbShipMachineState {
+    pub user_id: String,
+    pub ship_id: String,
+    pub machine_type: String,
+    pub state: String,
+    pub modified_at: DateTime<Utc>,
+}
+
+pub async fn persist_ship_machine_state(pg_pool: PgPool, user_id: &str, ship_id: &str, machine_type: &str, state: &str) -> anyhow::Result<()> {
+    sqlx::query("
+        INSERT INTO daemon_ship_machine_state (user_id, ship_id, machine_type, state)
+        VALUES ($1::uuid, $2, $3, $4::jsonb)
+        ON CONFLICT (user_id, ship_id)
+        DO UPDATE SET
+             machine_type = $3
+            ,state = $4::jsonb
+            ,modified_at = timezone('utc', NOW());
+    ")
+        .bind(user_id)
+        .bind(ship_id)
+        .bind(machine_type)
+        .bind(state)
+        .execute(&pg_pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_ship_machine_state(pg_pool: PgPool, user_id: &str, ship_id: &str) -> anyhow::Result<Option<DbShipMachineState>> {
+    Ok(
+        sqlx::query("
+            SELECT user_id::text, ship_id, machine_type, state::text, modified_at
+            FROM daemon_ship_machine_state
+            WHERE user_id = $1::uuid
+                AND ship_id = $2
+            LIMIT 1;
+        ")
+            .bind(user_id)
+            .bind(ship_id)
+            .map(|row: PgRow| {
+                DbShipMachineState {
+                    user_id: row.get("user_id"),
+                    ship_id: row.get("ship_id"),
+                    machine_type: row.get("machine_type"),
+                    state: row.get("state"),
+                    modified_at: row.get("modified_at"),
+                }
+            })
+            .fetch_optional(&pg_pool)
+            .await?
+    )
+}
+
+/// Every order made for a trade, oldest first
+pub async fn get_trade_transactions(pg_pool: PgPool, user_id: &str, trade_id: &str) -> anyhow::Result<Vec<DbUserTransaction>> {
+    let filter = LedgerFilter {
+        trade_id: Some(trade_id.to_string()),
+        ..LedgerFilter::default()
+    };
+
+    spacemonger_core::queries::transactions(&pg_pool, user_id, &filter, LedgerSort::Oldest, None, None).await
+}
+
+pub async fn persist_trade(pg_pool: PgPool, trade: &DbUserTrade) -> anyhow::Result<()> {
+    spacemonger_core::queries::persist_trade(&pg_pool, trade).await
+}
+
+/// Every trade a user's ships have completed since `since`, oldest first
+pub async fn get_trades(pg_pool: PgPool, user_id: &str, since: DateTime<Utc>) -> anyhow::Result<Vec<DbUserTrade>> {
+    spacemonger_core::queries::trades(&pg_pool, user_id, since).await
+}
+
+/// created_at is set by the database
+pub async fn persist_loan_event(pg_pool: PgPool, loan_event: &DbLoanEvent) -> anyhow::Result<()> {
+    spacemonger_core::queries::persist_loan_event(&pg_pool, loan_event).await
+}
+
+/// Every loan event for a user, oldest first
+/// created_at is set by the database
+pub async fn persist_ship_event(pg_pool: PgPool, ship_event: &DbShipEvent) -> anyhow::Result<()> {
+    spacemonger_core::queries::persist_ship_event(&pg_pool, ship_event).await
+}
+
+/// Everything that has happened to a user's ships, oldest first
+pub async fn get_ship_history(pg_pool: PgPool, user_id: &str) -> anyhow::Result<Vec<DbShipEvent>> {
+    spacemonger_core::queries::ship_history(&pg_pool, user_id).await
+}
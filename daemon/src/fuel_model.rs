@@ -0,0 +1,266 @@
+// Learns how much fuel and time a flight takes from every flight plan the fleet has flown. The
+// game's formulas have never been published but within a system both grow linearly with the
+// distance, with an offset that depends on the ship type and what kind of location the ship is
+// leaving (planets have a gravity penalty). So each ship type and origin location type gets its
+// own least squares fit.
+use crate::db::{DbDistanceBetweenLocations, DbFlightSample, DbFuelModel};
+use crate::storage::StorageClient;
+use spacemonger_core::routes;
+use spacetraders::shared;
+
+/// Predictions below this are from too few flights to be trusted on their own. Three flights at
+/// distances that cover the one being predicted
+pub const MIN_CONFIDENCE: f64 = 0.75;
+
+/// What the model expects a flight to take
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuelPrediction {
+    pub fuel: i32,
+    pub flight_time: i32,
+    /// Between 0 and 1. Grows with the number of flights seen and drops off for distances outside
+    /// of the ones that have been flown
+    pub confidence: f64,
+    pub within_flown_distances: bool,
+}
+
+impl FuelPrediction {
+    pub fn is_reliable(&self) -> bool {
+        self.within_flown_distances && self.confidence >= MIN_CONFIDENCE
+    }
+}
+
+/// Fit fuel and flight time against distance. Returns None when there is nothing to learn from
+pub fn fit(ship_type: &str, location_type: &str, samples: &[DbFlightSample]) -> Option<DbFuelModel> {
+    if samples.is_empty() {
+        return None;
+    }
+
+    let distances: Vec<f64> = samples.iter().map(|s| s.distance as f64).collect();
+    let fuel: Vec<f64> = samples.iter().map(|s| s.fuel_consumed as f64).collect();
+    let flight_times: Vec<f64> = samples.iter().map(|s| s.flight_time as f64).collect();
+
+    let (fuel_intercept, fuel_per_distance) = least_squares(&distances, &fuel);
+    let (time_intercept, time_per_distance) = least_squares(&distances, &flight_times);
+
+    // Fuel is always bought in whole units and the game rounds, so remember how far off the fit
+    // has been. Predictions add this on so that a ship never leaves short
+    let fuel_max_error = distances.iter().zip(&fuel)
+        .map(|(d, f)| (f - (fuel_intercept + fuel_per_distance * d)).abs())
+        .fold(0.0, f64::max);
+
+    Some(DbFuelModel {
+        ship_type: ship_type.to_string(),
+        location_type: location_type.to_string(),
+        fuel_intercept,
+        fuel_per_distance,
+        fuel_max_error,
+        time_intercept,
+        time_per_distance,
+        min_distance: samples.iter().map(|s| s.distance).min().unwrap(),
+        max_distance: samples.iter().map(|s| s.distance).max().unwrap(),
+        samples: samples.len() as i32,
+    })
+}
+
+/// Returns the (intercept, slope) of the line that best fits the points. When every point is at
+/// the same x there is no slope to learn so the line is flat.
+fn least_squares(xs: &[f64], ys: &[f64]) -> (f64, f64) {
+    let n = xs.len() as f64;
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = ys.iter().sum::<f64>() / n;
+
+    let sxx: f64 = xs.iter().map(|x| (x - mean_x).powi(2)).sum();
+    let sxy: f64 = xs.iter().zip(ys).map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+
+    let slope = if sxx > f64::EPSILON { sxy / sxx } else { 0.0 };
+
+    (mean_y - slope * mean_x, slope)
+}
+
+pub fn predict(fuel_model: &DbFuelModel, distance: f64) -> FuelPrediction {
+    let fuel = fuel_model.fuel_intercept + fuel_model.fuel_per_distance * distance + fuel_model.fuel_max_error;
+    let flight_time = fuel_model.time_intercept + fuel_model.time_per_distance * distance;
+
+    let sample_confidence = fuel_model.samples as f64 / (fuel_model.samples as f64 + 1.0);
+    let span = (fuel_model.max_distance - fuel_model.min_distance) as f64;
+    let outside = (fuel_model.min_distance as f64 - distance).max(distance - fuel_model.max_distance as f64).max(0.0);
+    let range_confidence = if outside > 0.0 { span / (span + outside) } else { 1.0 };
+
+    FuelPrediction {
+        // Shave a little off before rounding up so that float noise doesn't cost a unit of fuel
+        fuel: (fuel - 1e-6).ceil().max(0.0) as i32,
+        flight_time: flight_time.round().max(0.0) as i32,
+        confidence: sample_confidence * range_confidence,
+        within_flown_distances: outside <= 0.0,
+    }
+}
+
+/// What the game's published formula says a flight takes. See `routes::fuel_required`
+pub fn baseline_fuel(distance: &DbDistanceBetweenLocations, ship_type: &str) -> i32 {
+    routes::fuel_required(&distance.origin_location_type, distance.distance, ship_type).ceil() as i32
+}
+
+/// The fuel to carry for a flight. A prediction the model isn't sure of is never allowed to be less
+/// than the baseline. With one flight there is no slope to go on at all
+pub fn fuel_to_carry(prediction: &FuelPrediction, distance: &DbDistanceBetweenLocations, ship_type: &str) -> i32 {
+    if prediction.is_reliable() {
+        return prediction.fuel;
+    }
+
+    prediction.fuel.max(baseline_fuel(distance, ship_type))
+}
+
+/// Refit the model for a ship type leaving a type of location from all of its flights and store
+/// the new coefficients
+pub async fn learn(storage: StorageClient, ship_type: &str, location_type: &str) -> anyhow::Result<Option<DbFuelModel>> {
+    let samples = storage.get_flight_samples(ship_type, location_type).await?;
+    let fuel_model = fit(ship_type, location_type, &samples);
+
+    if let Some(fuel_model) = &fuel_model {
+        log::debug!(
+            "Learned fuel model for {} leaving a {} from {} flights. fuel = {:.3} + {:.3} * distance (+/- {:.2}), time = {:.1} + {:.3} * distance",
+            ship_type,
+            location_type,
+            fuel_model.samples,
+            fuel_model.fuel_intercept,
+            fuel_model.fuel_per_distance,
+            fuel_model.fuel_max_error,
+            fuel_model.time_intercept,
+            fuel_model.time_per_distance,
+        );
+        storage.persist_fuel_model(fuel_model).await?;
+    }
+
+    Ok(fuel_model)
+}
+
+/// Fold a flight that was just created into the model for its ship type
+pub async fn learn_from_flight(storage: StorageClient, ship_type: &str, flight_plan: &shared::FlightPlanData) -> anyhow::Result<()> {
+    if let Some(distance) = storage.get_distance_between_locations(&flight_plan.departure, &flight_plan.destination).await? {
+        learn(storage, ship_type, &distance.origin_location_type).await?;
+    }
+
+    Ok(())
+}
+
+/// Predict the fuel and time for a flight within a system. Returns None when nothing like the
+/// flight has been flown yet
+pub async fn estimate(storage: StorageClient, ship_type: &str, distance: &DbDistanceBetweenLocations) -> anyhow::Result<Option<FuelPrediction>> {
+    let fuel_model = match storage.get_fuel_model(ship_type, &distance.origin_location_type).await? {
+        Some(fuel_model) => Some(fuel_model),
+        // Flights recorded before the model existed haven't been learned from yet
+        None => learn(storage, ship_type, &distance.origin_location_type).await?,
+    };
+
+    Ok(fuel_model.map(|fuel_model| predict(&fuel_model, distance.distance)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spacemonger_core::routes;
+    use crate::ship_machines::ShipAssignment;
+    use crate::storage::memory::MemoryStorage;
+    use crate::test_utils::{self, system};
+    use spacetraders::shared::LocationType;
+    use std::sync::Arc;
+
+    const USER_ID: &str = "00000000-0000-0000-0000-000000000001";
+
+    fn sample(distance: i32, fuel_consumed: i32, flight_time: i32) -> DbFlightSample {
+        DbFlightSample { distance, fuel_consumed, flight_time }
+    }
+
+    #[test]
+    fn fit_learns_the_game_formulas() {
+        let samples: Vec<DbFlightSample> = (10..150).step_by(7)
+            .map(|d| sample(
+                d,
+                routes::fuel_required("Planet", d as f64, "GR-MK-II") as i32,
+                routes::flight_time(d as f64, 2) as i32,
+            ))
+            .collect();
+
+        let fuel_model = fit("GR-MK-II", "Planet", &samples).unwrap();
+
+        assert!((fuel_model.fuel_per_distance - 0.25).abs() < 0.01, "{:?}", fuel_model);
+        assert!(fuel_model.fuel_max_error <= 1.0);
+        for s in &samples {
+            let prediction = predict(&fuel_model, s.distance as f64);
+            assert!(prediction.fuel >= s.fuel_consumed && prediction.fuel <= s.fuel_consumed + 1, "{:?} {:?}", s, prediction);
+            assert!((prediction.flight_time - s.flight_time).abs() <= 1, "{:?} {:?}", s, prediction);
+        }
+    }
+
+    #[test]
+    fn confidence_grows_with_samples_and_drops_outside_the_flown_distances() {
+        let one = fit("GR-MK-I", "Moon", &[sample(40, 11, 140)]).unwrap();
+        let many = fit("GR-MK-I", "Moon", &[sample(20, 6, 100), sample(40, 11, 140), sample(60, 16, 180)]).unwrap();
+
+        assert_eq!(predict(&one, 40.0).fuel, 11);
+        assert!((predict(&one, 40.0).confidence - 0.5).abs() < f64::EPSILON);
+        assert!(predict(&one, 80.0).confidence < f64::EPSILON);
+
+        assert!((predict(&many, 30.0).confidence - 0.75).abs() < f64::EPSILON);
+        assert!(predict(&many, 100.0).confidence < predict(&many, 30.0).confidence);
+        assert_eq!(predict(&many, 100.0).fuel, 26);
+        assert!(fit("GR-MK-I", "Moon", &[]).is_none());
+    }
+
+    #[test]
+    fn unreliable_predictions_fall_back_to_the_formula() {
+        let one = fit("GR-MK-I", "Moon", &[sample(40, 11, 140)]).unwrap();
+        let many = fit("GR-MK-I", "Moon", &[sample(20, 6, 100), sample(40, 11, 140), sample(60, 16, 180)]).unwrap();
+        let distance = |distance: f64| DbDistanceBetweenLocations { origin_location_type: "Moon".to_string(), distance };
+
+        // One flight has no slope so it would leave a ship flying twice as far with the same fuel
+        let prediction = predict(&one, 80.0);
+        assert!(!prediction.is_reliable());
+        assert_eq!(prediction.fuel, 11);
+        assert_eq!(fuel_to_carry(&prediction, &distance(80.0), "GR-MK-I"), 21);
+
+        // Never less than the model predicts though
+        assert_eq!(fuel_to_carry(&predict(&one, 40.0), &distance(40.0), "GR-MK-I"), 11);
+
+        // Past the flown distances
+        let prediction = predict(&many, 200.0);
+        assert!(!prediction.is_reliable());
+        assert_eq!(fuel_to_carry(&prediction, &distance(200.0), "GR-MK-I"), 51);
+
+        // Enough flights that cover the distance are trusted as is
+        let prediction = predict(&many, 30.0);
+        assert!(prediction.is_reliable());
+        assert_eq!(fuel_to_carry(&prediction, &distance(30.0), "GR-MK-I"), prediction.fuel);
+    }
+
+    #[tokio::test]
+    async fn estimate_learns_from_recorded_flights() {
+        let storage: StorageClient = Arc::new(MemoryStorage::new());
+        let oe = system("OE", &[
+            ("OE-PM", LocationType::Planet, 20, -25),
+            ("OE-PM-TR", LocationType::Moon, 22, -28),
+            ("OE-UC", LocationType::GasGiant, -75, 80),
+        ]);
+        for location in &oe.locations {
+            storage.persist_system_location(&oe, location).await.unwrap();
+        }
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+
+        let planet = storage.get_distance_between_locations("OE-PM", "OE-UC").await.unwrap().unwrap();
+        let moon = storage.get_distance_between_locations("OE-PM-TR", "OE-UC").await.unwrap().unwrap();
+        assert!(estimate(storage.clone(), "GR-MK-I", &planet).await.unwrap().is_none());
+
+        let flight_plan = test_utils::flight_plan("OE-PM", "OE-UC", 60);
+        storage.persist_flight_plan(USER_ID, "ship-1", &flight_plan, None).await.unwrap();
+        learn_from_flight(storage.clone(), "GR-MK-I", &flight_plan.flight_plan).await.unwrap();
+
+        let prediction = estimate(storage.clone(), "GR-MK-I", &moon).await.unwrap();
+        // Only leaving planets has been seen so far
+        assert!(prediction.is_none());
+
+        let prediction = estimate(storage.clone(), "GR-MK-I", &planet).await.unwrap().unwrap();
+        assert_eq!(prediction.fuel, 10);
+        assert_eq!(prediction.flight_time, 60);
+        assert!(storage.get_fuel_model("GR-MK-I", "Planet").await.unwrap().is_some());
+    }
+}
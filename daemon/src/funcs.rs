@@ -1,15 +1,17 @@
 use anyhow::anyhow;
-use spacetraders::client::{self, HttpClient, Client};
 use spacetraders::errors::SpaceTradersClientError;
-use sqlx::PgPool;
+use crate::storage::StorageClient;
 use spacetraders::{responses, shared};
-use crate::db;
 use spacetraders::shared::Good;
-use crate::db::DbRoute;
-use regex::Regex;
+use crate::game::{GameBackend, GameClient};
+use crate::game::errors::GameError;
+use crate::fuel_model;
+use spacemonger_core::planner::{RoutePlanner, TradePlan};
+use std::cmp::min;
+use std::sync::Arc;
 
-pub async fn is_api_in_maintenance_mode(http_client: HttpClient) -> bool {
-    let game_status = client::get_game_status(http_client.clone()).await;
+pub async fn is_api_in_maintenance_mode(backend: Arc<dyn GameBackend>) -> bool {
+    let game_status = backend.get_game_status().await;
 
     if game_status.is_err() {
         let game_status_error = game_status.err().unwrap();
@@ -20,7 +22,13 @@ pub async fn is_api_in_maintenance_mode(http_client: HttpClient) -> bool {
     false
 }
 
-pub async fn create_flight_plan(client: Client, pg_pool: PgPool, user_id: &str, destination: &str, ship: &mut shared::Ship) -> anyhow::Result<responses::FlightPlan> {
+/// Locations are always prefixed with their system. I.E. OE-PM-TR is in OE
+pub fn get_system_from_location(location: &str) -> &str {
+    location.split('-').next().unwrap_or(location)
+}
+
+/// trade_id is the trade the ship is flying for. See `DbUserTrade`
+pub async fn create_flight_plan(client: GameClient, storage: StorageClient, user_id: &str, trade_id: Option<&str>, destination: &str, ship: &mut shared::Ship) -> anyhow::Result<responses::FlightPlan> {
     let flight_plan = client.create_flight_plan(ship.id.clone(), destination.to_string()).await?;
 
     ship.location = None;
@@ -32,19 +40,36 @@ pub async fn create_flight_plan(client: Client, pg_pool: PgPool, user_id: &str,
         c
     }).collect();
 
-    db::persist_flight_plan(pg_pool, user_id, &ship.id, &flight_plan).await?;
+    storage.persist_flight_plan(user_id, &ship.id, &flight_plan, trade_id).await?;
+
+    // The flight has already been made so a model that can't be updated shouldn't stop the ship
+    if let Err(e) = fuel_model::learn_from_flight(storage, &ship.ship_type, &flight_plan.flight_plan).await {
+        log::warn!("Unable to update the fuel model for {}. Error: {}", ship.ship_type, e);
+    }
+
+    Ok(flight_plan)
+}
+
+pub async fn attempt_warp_jump(client: GameClient, storage: StorageClient, user_id: &str, trade_id: Option<&str>, ship: &mut shared::Ship) -> anyhow::Result<responses::FlightPlan> {
+    let flight_plan = client.attempt_warp_jump(ship.id.clone()).await?;
+
+    // Warp jumps don't use any fuel
+    ship.location = None;
+
+    storage.persist_flight_plan(user_id, &ship.id, &flight_plan, trade_id).await?;
 
     Ok(flight_plan)
 }
 
-pub async fn create_purchase_order(client: Client, pg_pool: PgPool, user_id: &str, good: Good, quantity: i32, ship: &mut shared::Ship) -> anyhow::Result<responses::PurchaseOrder> {
+/// trade_id is the trade the order is made for. See `DbUserTrade`
+pub async fn create_purchase_order(client: GameClient, storage: StorageClient, user_id: &str, trade_id: Option<&str>, good: Good, quantity: i32, ship: &mut shared::Ship) -> anyhow::Result<responses::PurchaseOrder> {
     if quantity > 0 {
         let purchase_order = client.create_purchase_order(ship.id.clone(), good, quantity).await?;
 
         ship.cargo = purchase_order.ship.cargo.clone();
         ship.space_available = purchase_order.ship.space_available;
 
-        db::persist_transaction(pg_pool.clone(), "purchase", user_id, &purchase_order).await?;
+        storage.persist_transaction("purchase", user_id, &purchase_order, trade_id).await?;
 
         Ok(purchase_order)
     } else {
@@ -52,14 +77,14 @@ pub async fn create_purchase_order(client: Client, pg_pool: PgPool, user_id: &st
     }
 }
 
-pub async fn create_sell_order(client: Client, pg_pool: PgPool, user_id: &str, good: Good, quantity: i32, ship: &mut shared::Ship) -> anyhow::Result<responses::PurchaseOrder> {
+pub async fn create_sell_order(client: GameClient, storage: StorageClient, user_id: &str, trade_id: Option<&str>, good: Good, quantity: i32, ship: &mut shared::Ship) -> anyhow::Result<responses::PurchaseOrder> {
     if quantity > 0 {
         let sell_order = client.create_sell_order(ship.id.to_string(), good, quantity).await?;
 
         ship.cargo = sell_order.ship.cargo.clone();
         ship.space_available = sell_order.ship.space_available;
 
-        db::persist_transaction(pg_pool.clone(), "sell", user_id, &sell_order).await?;
+        storage.persist_transaction("sell", user_id, &sell_order, trade_id).await?;
 
         Ok(sell_order)
     } else {
@@ -67,67 +92,128 @@ pub async fn create_sell_order(client: Client, pg_pool: PgPool, user_id: &str, g
     }
 }
 
-pub async fn get_additional_fuel_required_for_trip(pg_pool: PgPool, http_client: Client, ship_id: &str, ship_type: &str, current_fuel: i32, origin: &str, destination: &str) -> anyhow::Result<i32> {
-    let db_fuel_required = db::get_fuel_required(pg_pool.clone(), origin, destination, ship_type).await?;
-
-    // if we already have already made this flight before then we know exactly how much fuel is required
-    if let Some(db_fuel_required) = db_fuel_required {
-        return Ok(db_fuel_required - current_fuel)
-    }
+/// Top the ship up with the fuel that a failed flight plan said it was missing. A ship that filled
+/// its hold expecting to need less fuel sells some of its cargo back to make room. Returns the
+/// user's new credits if anything was bought or sold
+pub async fn buy_missing_fuel(client: GameClient, storage: StorageClient, user_id: &str, trade_id: Option<&str>, required: i32, ship: &mut shared::Ship) -> anyhow::Result<Option<i32>> {
+    let mut credits = None;
 
-    match http_client.create_flight_plan(ship_id.to_string(), destination.to_string()).await {
-        Ok(_) => {
-            log::error!("This request should have failed. This method should only be called with ships that have zero fuel");
+    for cargo in ship.cargo.clone() {
+        let missing_space = required - ship.space_available;
+        if missing_space <= 0 {
+            break;
+        }
 
-            Err(anyhow!("Request shouldn't have succeeded. Ship is now in motion"))
-        },
-        Err(e) => match e {
-            SpaceTradersClientError::ApiError(error_message) => {
-                let re = Regex::new(r"You require (\d+?) more FUEL").unwrap();
-
-                let mut additional_fuel_required = 0;
-                for cap in re.captures_iter(&error_message.error.message) {
-                    additional_fuel_required = cap[1].parse::<i32>().unwrap();
-                }
-
-                Ok(additional_fuel_required)
-            },
-            e => {
-                Err(anyhow!("Expected a \"not enough fuel\" error, but got {} instead", e))
-            },
+        if cargo.good == Good::Fuel {
+            continue;
         }
+
+        let volume = cargo.good.get_volume();
+        let quantity = min((missing_space + volume - 1) / volume, cargo.quantity);
+
+        log::info!("{} -- Selling {} {} to make room for fuel", ship.id, quantity, cargo.good);
+        let sell_order = create_sell_order(client.clone(), storage.clone(), user_id, trade_id, cargo.good, quantity, ship).await?;
+        credits = Some(sell_order.credits);
     }
 
-    // TODO: Rather than try and guess the formula lets attempt to create the flight plan
-    //       (after ensuring that the ship has no fuel) and then parse the response to get the
-    //       actual fuel requirements. After the flight plan is written to the db then we will
-    //       just use that value instead of this process again.
+    // Don't ever try and buy more fuel than the ship can hold
+    let quantity = min(required, ship.space_available);
+    if quantity <= 0 {
+        return Ok(credits);
+    }
+
+    let purchase_order = create_purchase_order(client, storage, user_id, trade_id, Good::Fuel, quantity, ship).await?;
+    *ship = purchase_order.ship;
+
+    Ok(Some(purchase_order.credits))
+}
+
+/// Every trade that starts at the origin, planned from the latest market data. See
+/// `spacemonger_core::planner`
+pub async fn plan_trades_from_location(storage: StorageClient, origin: &str, ship_speed: i32, ship_type: &str) -> anyhow::Result<Vec<TradePlan>> {
+    let market_data = storage.get_latest_market_data().await?;
+    let planner = RoutePlanner::new(storage.get_system_locations().await?, &market_data);
 
-    // let distance_between = db::get_distance_between_locations(pg_pool, origin, destination).await?;
+    Ok(planner.plan_trades(origin, ship_speed, ship_type, &market_data))
+}
 
-    // https://discord.com/channels/792864705139048469/792864705139048472/839919413742272572
-    // floor((cargo - fuelRequired) / volume) * (sell - buy) / time
-    // https://discord.com/channels/792864705139048469/792864705139048472/836090525307371541
-    // time = distance * (2 / speed) + 60
-    // let planet_penalty = if distance_between.origin_location_type == "Planet" { 2.0 } else { 0.0 };
-    // let fuel_required: f64 = (distance_between.distance.round() / 4.0) + planet_penalty + 1.0;
+/// Record everything the marketplace at a location has to offer right now. Each snapshot is tagged
+/// with the ship docked there
+pub async fn harvest_market_data(client: GameClient, storage: StorageClient, ship_id: &str, location: &str) -> anyhow::Result<()> {
+    let marketplace_data = client.get_location_marketplace(location).await?;
 
-    // let ship_fuel_penalty = match ship_type {
-    //     "GR-MK-II" => 1.0,
-    //     "GR-MK-III" => 2.0,
-    //     _ => 0.0,
-    // };
+    for datum in marketplace_data.marketplace {
+        storage.persist_market_data(location, ship_id, &datum).await?;
+    }
 
-    // Ok((fuel_required + ship_fuel_penalty).ceil() as i32)
+    Ok(())
 }
 
-pub async fn get_routes_for_ship(pg_pool: PgPool, location: &str, ship_speed: i32) -> anyhow::Result<Vec<DbRoute>> {
-    // TODO: Getting the best route only from the location that the ship currently is in locks
-    //       the ship into trade loops. It might be better to search the entire system for the
-    //       best route and then find the best trade to that location before beginning a trade
-    //       route. That way we move around the system a little more
-    match db::get_routes_from_location(pg_pool.clone(), location, ship_speed).await {
-        Ok(routes) => return Ok(routes),
-        Err(e) => panic!("Unable to get routes for ship {:?}", e),
+/// Throw away only the cargo that can't be sold where the ship is docked
+pub async fn jettison_unlisted_cargo(client: GameClient, ship: &mut shared::Ship) -> anyhow::Result<()> {
+    let location = match &ship.location {
+        Some(location) => location.clone(),
+        None => return Ok(()),
     };
+
+    let marketplace = client.get_location_marketplace(&location).await?;
+
+    for cargo in ship.cargo.clone() {
+        if cargo.good != Good::Fuel && !marketplace.marketplace.iter().any(|m| m.symbol == cargo.good) {
+            log::info!("{} -- Jettisoning {} {} that can't be sold at {}", ship.id, cargo.quantity, cargo.good, location);
+            client.jettison_cargo(&ship.id, cargo.good, cargo.quantity).await?;
+            ship.cargo.retain(|c| c.good != cargo.good);
+            ship.space_available += cargo.total_volume;
+        }
+    }
+
+    Ok(())
+}
+
+/// How much more fuel the ship needs to fly from origin to destination. The fuel model answers
+/// this once anything like the flight has been flown, topped up to the game's formula while it
+/// isn't sure. Until then the only way to find out is to ask for a flight plan, which the api
+/// refuses with the fuel that is missing. A ship that already has fuel might be given the flight
+/// plan instead so those go by the formula, or set off with what they have when the distance isn't
+/// known yet. The flight plan is refused with whatever is missing if that isn't enough.
+pub async fn get_additional_fuel_required_for_trip(storage: StorageClient, http_client: GameClient, ship_id: &str, ship_type: &str, current_fuel: i32, origin: &str, destination: &str) -> anyhow::Result<i32> {
+    let distance = storage.get_distance_between_locations(origin, destination).await?;
+
+    if let Some(distance) = &distance {
+        if let Some(prediction) = fuel_model::estimate(storage, ship_type, distance).await? {
+            let fuel = fuel_model::fuel_to_carry(&prediction, distance, ship_type);
+            log::debug!(
+                "{} -- Flying from {} to {} takes {} fuel and {} seconds (confidence {:.2}). Carrying {} fuel",
+                ship_id,
+                origin,
+                destination,
+                prediction.fuel,
+                prediction.flight_time,
+                prediction.confidence,
+                fuel,
+            );
+
+            return Ok(fuel - current_fuel);
+        }
+    }
+
+    if current_fuel > 0 {
+        return Ok(match &distance {
+            Some(distance) => fuel_model::baseline_fuel(distance, ship_type) - current_fuel,
+            None => 0,
+        });
+    }
+
+    match http_client.create_flight_plan(ship_id.to_string(), destination.to_string()).await {
+        Ok(_) => {
+            log::error!("This request should have failed. This method should only be called with ships that have zero fuel");
+
+            Err(anyhow!("Request shouldn't have succeeded. Ship is now in motion"))
+        },
+        Err(e) => match GameError::from_client_error(&e) {
+            Some(GameError::InsufficientFuel { required }) => Ok(required),
+            // Hand anything else back as is so that the machine can recover from it
+            _ => Err(e.into()),
+        }
+    }
 }
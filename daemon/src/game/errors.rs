@@ -0,0 +1,111 @@
+use regex::Regex;
+use spacetraders::errors::SpaceTradersClientError;
+use std::fmt;
+use std::sync::LazyLock;
+
+// The codes the live API documents for these situations. Everything else is recognized by its
+// message
+pub const ERROR_CODE_GOOD_NOT_LISTED: i32 = 2001;
+pub const ERROR_CODE_QUANTITY_UNAVAILABLE: i32 = 2006;
+
+static FUEL_REQUIRED: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"You require (\d+) more FUEL").unwrap());
+
+/// The api errors that the ship machines know how to recover from. Anything else is still a
+/// plain `SpaceTradersClientError`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GameError {
+    /// The ship doesn't have enough fuel for the flight plan. I.E. "You require 25 more FUEL"
+    InsufficientFuel { required: i32 },
+    /// The location's marketplace doesn't buy or sell the good
+    GoodNotListed,
+    /// The location doesn't have enough of the good for the purchase
+    QuantityUnavailable,
+    /// The user can't afford the purchase
+    InsufficientCredits,
+    /// The ship needs to be docked but it is flying somewhere
+    ShipInTransit,
+}
+
+impl GameError {
+    /// Work out which error the api returned. Only the live api's documented codes are trusted,
+    /// anything else has to be recognized by its message.
+    pub fn from_client_error(e: &SpaceTradersClientError) -> Option<GameError> {
+        let error = match e {
+            SpaceTradersClientError::ApiError(error_message) => &error_message.error,
+            _ => return None,
+        };
+
+        let message = error.message.to_lowercase();
+        // A number too big to be real falls through to the code like any other message
+        let fuel_required = FUEL_REQUIRED.captures(&error.message).and_then(|captures| captures[1].parse().ok());
+        if let Some(required) = fuel_required {
+            return Some(GameError::InsufficientFuel { required });
+        }
+
+        match error.code {
+            ERROR_CODE_GOOD_NOT_LISTED => Some(GameError::GoodNotListed),
+            ERROR_CODE_QUANTITY_UNAVAILABLE => Some(GameError::QuantityUnavailable),
+            _ if message.contains("not listed") => Some(GameError::GoodNotListed),
+            _ if message.contains("quantity is not available") => Some(GameError::QuantityUnavailable),
+            _ if message.contains("insufficient credits") => Some(GameError::InsufficientCredits),
+            _ if message.contains("in-transit") || message.contains("in transit") => Some(GameError::ShipInTransit),
+            _ => None,
+        }
+    }
+
+    pub fn from_error(e: &anyhow::Error) -> Option<GameError> {
+        e.downcast_ref::<SpaceTradersClientError>().and_then(GameError::from_client_error)
+    }
+}
+
+impl fmt::Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameError::InsufficientFuel { required } => write!(f, "insufficient fuel ({} more required)", required),
+            GameError::GoodNotListed => write!(f, "good is not listed"),
+            GameError::QuantityUnavailable => write!(f, "quantity is not available"),
+            GameError::InsufficientCredits => write!(f, "insufficient credits"),
+            GameError::ShipInTransit => write!(f, "ship is in transit"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::api_error;
+
+    #[test]
+    fn known_codes_and_messages_are_classified() {
+        let cases = vec![
+            (api_error(3001, "Ship has insufficient fuel for flight plan. You require 25 more FUEL"), Some(GameError::InsufficientFuel { required: 25 })),
+            // The fuel message wins no matter what the code is
+            (api_error(400, "You require 7 more FUEL"), Some(GameError::InsufficientFuel { required: 7 })),
+            (api_error(2001, "Good is not listed in planet marketplace."), Some(GameError::GoodNotListed)),
+            (api_error(2006, "Good quantity is not available on planet."), Some(GameError::QuantityUnavailable)),
+            (api_error(2004, "User has insufficient credits for transaction."), Some(GameError::InsufficientCredits)),
+            (api_error(400, "User has insufficient credits for transaction."), Some(GameError::InsufficientCredits)),
+            (api_error(3002, "Ship is currently in-transit. Ship must be docked to perform this action."), Some(GameError::ShipInTransit)),
+            // Undocumented codes alone don't mean anything
+            (api_error(2004, "Something went wrong."), None),
+            (api_error(3002, "Something went wrong."), None),
+            (api_error(3003, "Ship is already at the destination."), None),
+            // A fuel amount that doesn't fit doesn't take the daemon down with it
+            (api_error(3001, "You require 99999999999999999999 more FUEL"), None),
+            (api_error(2001, "Good is not listed. You require 99999999999999999999 more FUEL"), Some(GameError::GoodNotListed)),
+            (SpaceTradersClientError::ServiceUnavailable, None),
+        ];
+
+        for (e, expected) in cases {
+            assert_eq!(GameError::from_client_error(&e), expected, "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn errors_are_found_through_anyhow() {
+        let e: anyhow::Error = api_error(2001, "Good is not listed in planet marketplace.").into();
+        assert_eq!(GameError::from_error(&e), Some(GameError::GoodNotListed));
+
+        assert_eq!(GameError::from_error(&anyhow::anyhow!("something else")), None);
+    }
+}
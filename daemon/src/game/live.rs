@@ -0,0 +1,106 @@
+use crate::game::{GameApi, GameBackend, GameClient};
+use async_trait::async_trait;
+use spacetraders::client::{self, Client, HttpClient};
+use spacetraders::errors::SpaceTradersClientError;
+use spacetraders::{responses, shared};
+use std::sync::Arc;
+
+/// The real SpaceTraders API. All users share the same rate-limited http client.
+#[derive(Debug, Clone)]
+pub struct LiveBackend {
+    http_client: HttpClient,
+}
+
+impl LiveBackend {
+    pub fn new(http_proxy: Option<String>) -> LiveBackend {
+        LiveBackend {
+            http_client: client::get_http_client(http_proxy),
+        }
+    }
+
+    pub async fn get_my_ip_address(&self) -> Result<responses::MyIpAddress, SpaceTradersClientError> {
+        client::get_my_ip_address(self.http_client.clone()).await
+    }
+}
+
+#[async_trait]
+impl GameBackend for LiveBackend {
+    async fn get_game_status(&self) -> Result<responses::GameStatus, SpaceTradersClientError> {
+        client::get_game_status(self.http_client.clone()).await
+    }
+
+    async fn claim_username(&self, username: String) -> Result<responses::ClaimUsername, SpaceTradersClientError> {
+        client::claim_username(self.http_client.clone(), username).await
+    }
+
+    fn client(&self, username: String, token: String) -> GameClient {
+        Arc::new(Client::new(self.http_client.clone(), username, token))
+    }
+}
+
+#[async_trait]
+impl GameApi for Client {
+    async fn get_my_info(&self) -> Result<responses::UserInfo, SpaceTradersClientError> {
+        Client::get_my_info(self).await
+    }
+
+    async fn get_my_ships(&self) -> Result<responses::MyShips, SpaceTradersClientError> {
+        Client::get_my_ships(self).await
+    }
+
+    async fn get_my_ship(&self, ship_id: &str) -> Result<responses::MyShip, SpaceTradersClientError> {
+        Client::get_my_ship(self, ship_id).await
+    }
+
+    async fn get_my_loans(&self) -> Result<responses::LoanInfo, SpaceTradersClientError> {
+        Client::get_my_loans(self).await
+    }
+
+    async fn get_available_loans(&self) -> Result<responses::AvailableLoans, SpaceTradersClientError> {
+        Client::get_available_loans(self).await
+    }
+
+    async fn request_new_loan(&self, loan_type: shared::LoanType) -> Result<responses::RequestLoan, SpaceTradersClientError> {
+        Client::request_new_loan(self, loan_type).await
+    }
+
+    async fn pay_off_loan(&self, loan_id: &str) -> Result<responses::PayLoanResponse, SpaceTradersClientError> {
+        Client::pay_off_loan(self, loan_id).await
+    }
+
+    async fn get_ships_for_sale(&self) -> Result<responses::ShipsForSale, SpaceTradersClientError> {
+        Client::get_ships_for_sale(self).await
+    }
+
+    async fn purchase_ship(&self, location_symbol: String, ship_type: String) -> Result<responses::PurchaseShip, SpaceTradersClientError> {
+        Client::purchase_ship(self, location_symbol, ship_type).await
+    }
+
+    async fn get_systems_info(&self) -> Result<responses::SystemsInfo, SpaceTradersClientError> {
+        Client::get_systems_info(self).await
+    }
+
+    async fn get_location_marketplace(&self, location_symbol: &str) -> Result<responses::LocationMarketplace, SpaceTradersClientError> {
+        Client::get_location_marketplace(self, location_symbol).await
+    }
+
+    async fn create_flight_plan(&self, ship_id: String, destination: String) -> Result<responses::FlightPlan, SpaceTradersClientError> {
+        Client::create_flight_plan(self, ship_id, destination).await
+    }
+
+    async fn create_purchase_order(&self, ship_id: String, good: shared::Good, quantity: i32) -> Result<responses::PurchaseOrder, SpaceTradersClientError> {
+        Client::create_purchase_order(self, ship_id, good, quantity).await
+    }
+
+    async fn create_sell_order(&self, ship_id: String, good: shared::Good, quantity: i32) -> Result<responses::PurchaseOrder, SpaceTradersClientError> {
+        Client::create_sell_order(self, ship_id, good, quantity).await
+    }
+
+    async fn jettison_cargo(&self, ship_id: &str, good: shared::Good, quantity: i32) -> Result<responses::JettisonCargo, SpaceTradersClientError> {
+        Client::jettison_cargo(self, ship_id, good, quantity).await
+    }
+
+    async fn attempt_warp_jump(&self, ship_id: String) -> Result<responses::FlightPlan, SpaceTradersClientError> {
+        Client::attempt_warp_jump(self, ship_id).await
+    }
+}
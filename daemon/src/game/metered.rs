@@ -0,0 +1,152 @@
+use crate::game::{GameApi, GameBackend, GameClient};
+use crate::metrics::{self, Metrics};
+use async_trait::async_trait;
+use spacetraders::errors::SpaceTradersClientError;
+use spacetraders::{responses, shared};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Wraps another backend and records how long every call takes and which calls fail. See
+/// `metrics`
+#[derive(Debug)]
+pub struct MeteredBackend {
+    backend: Arc<dyn GameBackend>,
+    metrics: &'static Metrics,
+}
+
+impl MeteredBackend {
+    pub fn new(backend: Arc<dyn GameBackend>) -> MeteredBackend {
+        MeteredBackend {
+            backend,
+            metrics: metrics::global(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MeteredClient {
+    client: GameClient,
+    metrics: &'static Metrics,
+}
+
+async fn metered<T>(metrics: &Metrics, endpoint: &str, request: impl Future<Output = Result<T, SpaceTradersClientError>>) -> Result<T, SpaceTradersClientError> {
+    let started = Instant::now();
+    let result = request.await;
+    metrics.record_api_call(endpoint, started.elapsed(), result.as_ref().err());
+
+    result
+}
+
+#[async_trait]
+impl GameBackend for MeteredBackend {
+    async fn get_game_status(&self) -> Result<responses::GameStatus, SpaceTradersClientError> {
+        metered(self.metrics, "get_game_status", self.backend.get_game_status()).await
+    }
+
+    async fn claim_username(&self, username: String) -> Result<responses::ClaimUsername, SpaceTradersClientError> {
+        metered(self.metrics, "claim_username", self.backend.claim_username(username)).await
+    }
+
+    fn client(&self, username: String, token: String) -> GameClient {
+        Arc::new(MeteredClient {
+            client: self.backend.client(username, token),
+            metrics: self.metrics,
+        })
+    }
+}
+
+#[async_trait]
+impl GameApi for MeteredClient {
+    async fn get_my_info(&self) -> Result<responses::UserInfo, SpaceTradersClientError> {
+        metered(self.metrics, "get_my_info", self.client.get_my_info()).await
+    }
+
+    async fn get_my_ships(&self) -> Result<responses::MyShips, SpaceTradersClientError> {
+        metered(self.metrics, "get_my_ships", self.client.get_my_ships()).await
+    }
+
+    async fn get_my_ship(&self, ship_id: &str) -> Result<responses::MyShip, SpaceTradersClientError> {
+        metered(self.metrics, "get_my_ship", self.client.get_my_ship(ship_id)).await
+    }
+
+    async fn get_my_loans(&self) -> Result<responses::LoanInfo, SpaceTradersClientError> {
+        metered(self.metrics, "get_my_loans", self.client.get_my_loans()).await
+    }
+
+    async fn get_available_loans(&self) -> Result<responses::AvailableLoans, SpaceTradersClientError> {
+        metered(self.metrics, "get_available_loans", self.client.get_available_loans()).await
+    }
+
+    async fn request_new_loan(&self, loan_type: shared::LoanType) -> Result<responses::RequestLoan, SpaceTradersClientError> {
+        metered(self.metrics, "request_new_loan", self.client.request_new_loan(loan_type)).await
+    }
+
+    async fn pay_off_loan(&self, loan_id: &str) -> Result<responses::PayLoanResponse, SpaceTradersClientError> {
+        metered(self.metrics, "pay_off_loan", self.client.pay_off_loan(loan_id)).await
+    }
+
+    async fn get_ships_for_sale(&self) -> Result<responses::ShipsForSale, SpaceTradersClientError> {
+        metered(self.metrics, "get_ships_for_sale", self.client.get_ships_for_sale()).await
+    }
+
+    async fn purchase_ship(&self, location_symbol: String, ship_type: String) -> Result<responses::PurchaseShip, SpaceTradersClientError> {
+        metered(self.metrics, "purchase_ship", self.client.purchase_ship(location_symbol, ship_type)).await
+    }
+
+    async fn get_systems_info(&self) -> Result<responses::SystemsInfo, SpaceTradersClientError> {
+        metered(self.metrics, "get_systems_info", self.client.get_systems_info()).await
+    }
+
+    async fn get_location_marketplace(&self, location_symbol: &str) -> Result<responses::LocationMarketplace, SpaceTradersClientError> {
+        metered(self.metrics, "get_location_marketplace", self.client.get_location_marketplace(location_symbol)).await
+    }
+
+    async fn create_flight_plan(&self, ship_id: String, destination: String) -> Result<responses::FlightPlan, SpaceTradersClientError> {
+        metered(self.metrics, "create_flight_plan", self.client.create_flight_plan(ship_id, destination)).await
+    }
+
+    async fn create_purchase_order(&self, ship_id: String, good: shared::Good, quantity: i32) -> Result<responses::PurchaseOrder, SpaceTradersClientError> {
+        metered(self.metrics, "create_purchase_order", self.client.create_purchase_order(ship_id, good, quantity)).await
+    }
+
+    async fn create_sell_order(&self, ship_id: String, good: shared::Good, quantity: i32) -> Result<responses::PurchaseOrder, SpaceTradersClientError> {
+        metered(self.metrics, "create_sell_order", self.client.create_sell_order(ship_id, good, quantity)).await
+    }
+
+    async fn jettison_cargo(&self, ship_id: &str, good: shared::Good, quantity: i32) -> Result<responses::JettisonCargo, SpaceTradersClientError> {
+        metered(self.metrics, "jettison_cargo", self.client.jettison_cargo(ship_id, good, quantity)).await
+    }
+
+    async fn attempt_warp_jump(&self, ship_id: String) -> Result<responses::FlightPlan, SpaceTradersClientError> {
+        metered(self.metrics, "attempt_warp_jump", self.client.attempt_warp_jump(ship_id)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::mock::MockGameApi;
+    use crate::storage::StorageClient;
+    use crate::storage::memory::MemoryStorage;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn calls_are_timed_and_failures_counted_by_variant() {
+        let metrics: &'static Metrics = Box::leak(Box::default());
+        let mock = Arc::new(MockGameApi::default());
+        mock.get_my_ship.push(Err(SpaceTradersClientError::TooManyRetries));
+        mock.create_flight_plan.push(Ok(test_utils::flight_plan("OE-PM", "OE-UC", 60)));
+
+        let client = MeteredClient { client: mock.clone(), metrics };
+        assert!(client.get_my_ship("ship-1").await.is_err());
+        assert_eq!(client.create_flight_plan("ship-1".to_string(), "OE-UC".to_string()).await.unwrap().flight_plan.destination, "OE-UC");
+        assert_eq!(mock.calls(), vec!["get_my_ship(ship-1)", "create_flight_plan(ship-1, OE-UC)"]);
+
+        let out = metrics.render(&(Arc::new(MemoryStorage::new()) as StorageClient)).await.unwrap();
+        assert!(out.contains("spacemonger_api_request_duration_seconds_count{endpoint=\"get_my_ship\"} 1"));
+        assert!(out.contains("spacemonger_api_request_duration_seconds_count{endpoint=\"create_flight_plan\"} 1"));
+        assert!(out.contains("spacemonger_api_errors_total{endpoint=\"get_my_ship\",error=\"TooManyRetries\"} 1"));
+        assert!(!out.contains("spacemonger_api_errors_total{endpoint=\"create_flight_plan\""));
+    }
+}
@@ -0,0 +1,151 @@
+use crate::game::GameApi;
+use async_trait::async_trait;
+use spacetraders::errors::SpaceTradersClientError;
+use spacetraders::{responses, shared};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// The canned responses for a single `GameApi` method. Responses are handed out in the order that
+/// they were pushed and calling a method without a response left is a test failure.
+#[derive(Debug)]
+pub struct Script<T> {
+    responses: Mutex<VecDeque<Result<T, SpaceTradersClientError>>>,
+}
+
+impl<T> Default for Script<T> {
+    fn default() -> Self {
+        Script {
+            responses: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+impl<T> Script<T> {
+    pub fn push(&self, response: Result<T, SpaceTradersClientError>) -> &Self {
+        self.responses.lock().unwrap().push_back(response);
+        self
+    }
+
+    fn next(&self, method: &str) -> Result<T, SpaceTradersClientError> {
+        self.responses.lock().unwrap()
+            .pop_front()
+            .unwrap_or_else(|| panic!("Unexpected call to {}. No response was scripted", method))
+    }
+}
+
+/// A `GameApi` that replays scripted responses and records every call that was made to it so that
+/// a single ship machine transition can be tested without the network.
+#[derive(Debug, Default)]
+pub struct MockGameApi {
+    pub get_my_info: Script<responses::UserInfo>,
+    pub get_my_ships: Script<responses::MyShips>,
+    pub get_my_ship: Script<responses::MyShip>,
+    pub get_my_loans: Script<responses::LoanInfo>,
+    pub get_available_loans: Script<responses::AvailableLoans>,
+    pub request_new_loan: Script<responses::RequestLoan>,
+    pub pay_off_loan: Script<responses::PayLoanResponse>,
+    pub get_ships_for_sale: Script<responses::ShipsForSale>,
+    pub purchase_ship: Script<responses::PurchaseShip>,
+    pub get_systems_info: Script<responses::SystemsInfo>,
+    pub get_location_marketplace: Script<responses::LocationMarketplace>,
+    pub create_flight_plan: Script<responses::FlightPlan>,
+    pub create_purchase_order: Script<responses::PurchaseOrder>,
+    pub create_sell_order: Script<responses::PurchaseOrder>,
+    pub jettison_cargo: Script<responses::JettisonCargo>,
+    pub attempt_warp_jump: Script<responses::FlightPlan>,
+    calls: Mutex<Vec<String>>,
+}
+
+impl MockGameApi {
+    /// Every call made so far formatted as `method(arg, ...)`
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn record(&self, call: String) {
+        self.calls.lock().unwrap().push(call);
+    }
+}
+
+#[async_trait]
+impl GameApi for MockGameApi {
+    async fn get_my_info(&self) -> Result<responses::UserInfo, SpaceTradersClientError> {
+        self.record("get_my_info()".to_string());
+        self.get_my_info.next("get_my_info")
+    }
+
+    async fn get_my_ships(&self) -> Result<responses::MyShips, SpaceTradersClientError> {
+        self.record("get_my_ships()".to_string());
+        self.get_my_ships.next("get_my_ships")
+    }
+
+    async fn get_my_ship(&self, ship_id: &str) -> Result<responses::MyShip, SpaceTradersClientError> {
+        self.record(format!("get_my_ship({})", ship_id));
+        self.get_my_ship.next("get_my_ship")
+    }
+
+    async fn get_my_loans(&self) -> Result<responses::LoanInfo, SpaceTradersClientError> {
+        self.record("get_my_loans()".to_string());
+        self.get_my_loans.next("get_my_loans")
+    }
+
+    async fn get_available_loans(&self) -> Result<responses::AvailableLoans, SpaceTradersClientError> {
+        self.record("get_available_loans()".to_string());
+        self.get_available_loans.next("get_available_loans")
+    }
+
+    async fn request_new_loan(&self, loan_type: shared::LoanType) -> Result<responses::RequestLoan, SpaceTradersClientError> {
+        self.record(format!("request_new_loan({:?})", loan_type));
+        self.request_new_loan.next("request_new_loan")
+    }
+
+    async fn pay_off_loan(&self, loan_id: &str) -> Result<responses::PayLoanResponse, SpaceTradersClientError> {
+        self.record(format!("pay_off_loan({})", loan_id));
+        self.pay_off_loan.next("pay_off_loan")
+    }
+
+    async fn get_ships_for_sale(&self) -> Result<responses::ShipsForSale, SpaceTradersClientError> {
+        self.record("get_ships_for_sale()".to_string());
+        self.get_ships_for_sale.next("get_ships_for_sale")
+    }
+
+    async fn purchase_ship(&self, location_symbol: String, ship_type: String) -> Result<responses::PurchaseShip, SpaceTradersClientError> {
+        self.record(format!("purchase_ship({}, {})", location_symbol, ship_type));
+        self.purchase_ship.next("purchase_ship")
+    }
+
+    async fn get_systems_info(&self) -> Result<responses::SystemsInfo, SpaceTradersClientError> {
+        self.record("get_systems_info()".to_string());
+        self.get_systems_info.next("get_systems_info")
+    }
+
+    async fn get_location_marketplace(&self, location_symbol: &str) -> Result<responses::LocationMarketplace, SpaceTradersClientError> {
+        self.record(format!("get_location_marketplace({})", location_symbol));
+        self.get_location_marketplace.next("get_location_marketplace")
+    }
+
+    async fn create_flight_plan(&self, ship_id: String, destination: String) -> Result<responses::FlightPlan, SpaceTradersClientError> {
+        self.record(format!("create_flight_plan({}, {})", ship_id, destination));
+        self.create_flight_plan.next("create_flight_plan")
+    }
+
+    async fn create_purchase_order(&self, ship_id: String, good: shared::Good, quantity: i32) -> Result<responses::PurchaseOrder, SpaceTradersClientError> {
+        self.record(format!("create_purchase_order({}, {}, {})", ship_id, good, quantity));
+        self.create_purchase_order.next("create_purchase_order")
+    }
+
+    async fn create_sell_order(&self, ship_id: String, good: shared::Good, quantity: i32) -> Result<responses::PurchaseOrder, SpaceTradersClientError> {
+        self.record(format!("create_sell_order({}, {}, {})", ship_id, good, quantity));
+        self.create_sell_order.next("create_sell_order")
+    }
+
+    async fn jettison_cargo(&self, ship_id: &str, good: shared::Good, quantity: i32) -> Result<responses::JettisonCargo, SpaceTradersClientError> {
+        self.record(format!("jettison_cargo({}, {}, {})", ship_id, good, quantity));
+        self.jettison_cargo.next("jettison_cargo")
+    }
+
+    async fn attempt_warp_jump(&self, ship_id: String) -> Result<responses::FlightPlan, SpaceTradersClientError> {
+        self.record(format!("attempt_warp_jump({})", ship_id));
+        self.attempt_warp_jump.next("attempt_warp_jump")
+    }
+}
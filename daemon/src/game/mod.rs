@@ -0,0 +1,77 @@
+pub(crate) mod errors;
+pub(crate) mod live;
+pub(crate) mod metered;
+pub(crate) mod simulator;
+#[cfg(test)]
+pub(crate) mod mock;
+
+use async_trait::async_trait;
+use spacetraders::errors::SpaceTradersClientError;
+use spacetraders::{responses, shared};
+use std::env;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// A handle to a single user's view of the game. Every ship machine holds one of these rather
+/// than a concrete `spacetraders::client::Client` so that they can be pointed at the live API or
+/// the simulator without knowing which one they are talking to.
+pub type GameClient = Arc<dyn GameApi>;
+
+/// All the calls that the daemon makes on behalf of a specific user.
+#[async_trait]
+pub trait GameApi: Debug + Send + Sync {
+    async fn get_my_info(&self) -> Result<responses::UserInfo, SpaceTradersClientError>;
+    async fn get_my_ships(&self) -> Result<responses::MyShips, SpaceTradersClientError>;
+    async fn get_my_ship(&self, ship_id: &str) -> Result<responses::MyShip, SpaceTradersClientError>;
+    async fn get_my_loans(&self) -> Result<responses::LoanInfo, SpaceTradersClientError>;
+    async fn get_available_loans(&self) -> Result<responses::AvailableLoans, SpaceTradersClientError>;
+    async fn request_new_loan(&self, loan_type: shared::LoanType) -> Result<responses::RequestLoan, SpaceTradersClientError>;
+    async fn pay_off_loan(&self, loan_id: &str) -> Result<responses::PayLoanResponse, SpaceTradersClientError>;
+    async fn get_ships_for_sale(&self) -> Result<responses::ShipsForSale, SpaceTradersClientError>;
+    async fn purchase_ship(&self, location_symbol: String, ship_type: String) -> Result<responses::PurchaseShip, SpaceTradersClientError>;
+    async fn get_systems_info(&self) -> Result<responses::SystemsInfo, SpaceTradersClientError>;
+    async fn get_location_marketplace(&self, location_symbol: &str) -> Result<responses::LocationMarketplace, SpaceTradersClientError>;
+    async fn create_flight_plan(&self, ship_id: String, destination: String) -> Result<responses::FlightPlan, SpaceTradersClientError>;
+    async fn create_purchase_order(&self, ship_id: String, good: shared::Good, quantity: i32) -> Result<responses::PurchaseOrder, SpaceTradersClientError>;
+    async fn create_sell_order(&self, ship_id: String, good: shared::Good, quantity: i32) -> Result<responses::PurchaseOrder, SpaceTradersClientError>;
+    async fn jettison_cargo(&self, ship_id: &str, good: shared::Good, quantity: i32) -> Result<responses::JettisonCargo, SpaceTradersClientError>;
+    async fn attempt_warp_jump(&self, ship_id: String) -> Result<responses::FlightPlan, SpaceTradersClientError>;
+}
+
+/// The calls that aren't tied to a user (game status, claiming new usernames) and the factory for
+/// user specific clients.
+#[async_trait]
+pub trait GameBackend: Debug + Send + Sync {
+    async fn get_game_status(&self) -> Result<responses::GameStatus, SpaceTradersClientError>;
+    async fn claim_username(&self, username: String) -> Result<responses::ClaimUsername, SpaceTradersClientError>;
+    fn client(&self, username: String, token: String) -> GameClient;
+}
+
+/// Build the game backend selected by the GAME_BACKEND env var. Defaults to the live
+/// SpaceTraders API. Setting GAME_BACKEND=simulator runs the daemon against an in-process
+/// simulated universe instead (SIMULATOR_TIME_SCALE shrinks or stretches flight times). Either way
+/// every call is timed for the metrics endpoint.
+pub async fn get_backend_from_env(http_proxy: Option<String>) -> anyhow::Result<Arc<dyn GameBackend>> {
+    let game_backend = env::var("GAME_BACKEND").unwrap_or_else(|_| "live".to_string());
+
+    match game_backend.as_str() {
+        "live" => {
+            let backend = live::LiveBackend::new(http_proxy);
+
+            let my_ip_address = backend.get_my_ip_address().await?;
+            log::info!("Current IP address: {}", my_ip_address.ip);
+
+            Ok(Arc::new(metered::MeteredBackend::new(Arc::new(backend))))
+        }
+        "simulator" => {
+            let time_scale = env::var("SIMULATOR_TIME_SCALE")
+                .map(|s| s.parse::<f64>().expect("SIMULATOR_TIME_SCALE must be a number"))
+                .unwrap_or(1.0);
+
+            log::warn!("Running against the simulated SpaceTraders universe (time scale {})", time_scale);
+
+            Ok(Arc::new(metered::MeteredBackend::new(Arc::new(simulator::Simulator::new(time_scale)))))
+        }
+        other => Err(anyhow::anyhow!("Unknown GAME_BACKEND \"{}\". Expected \"live\" or \"simulator\"", other)),
+    }
+}
@@ -0,0 +1,1033 @@
+use crate::game::{GameApi, GameBackend, GameClient};
+use crate::game::errors::{ERROR_CODE_GOOD_NOT_LISTED, ERROR_CODE_QUANTITY_UNAVAILABLE};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use spacetraders::errors::SpaceTradersClientError;
+use spacetraders::shared::{Good, LoanType, LocationType};
+use spacetraders::{responses, shared};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+const STARTUP_LOAN_AMOUNT: i32 = 200_000;
+const STARTUP_LOAN_RATE: f64 = 40.0;
+const STARTUP_LOAN_TERM_IN_DAYS: i32 = 2;
+const WARP_JUMP_SECONDS: f64 = 180.0;
+
+// Codes the simulator returns for errors the live API doesn't document a code for. They are stable
+// so the simulator's own tests can match on them but the daemon only looks at the messages
+const ERROR_CODE_INSUFFICIENT_CREDITS: i32 = 2004;
+const ERROR_CODE_INSUFFICIENT_CARGO_SPACE: i32 = 2010;
+const ERROR_CODE_INSUFFICIENT_GOODS: i32 = 2011;
+const ERROR_CODE_RESTRICTED_GOOD: i32 = 2012;
+const ERROR_CODE_INSUFFICIENT_FUEL: i32 = 3001;
+const ERROR_CODE_SHIP_IN_TRANSIT: i32 = 3002;
+const ERROR_CODE_INVALID_DESTINATION: i32 = 3003;
+const ERROR_CODE_NOT_AT_WORMHOLE: i32 = 3004;
+const ERROR_CODE_NO_SHIP_AT_LOCATION: i32 = 3005;
+const ERROR_CODE_LOAN_UNAVAILABLE: i32 = 4001;
+const ERROR_CODE_LOAN_ALREADY_PAID: i32 = 4002;
+const ERROR_CODE_NOT_FOUND: i32 = 404;
+const ERROR_CODE_USERNAME_TAKEN: i32 = 409;
+// Markets recover this fraction of the distance to their base quantity every simulated minute
+const MARKET_RESTOCK_PER_MINUTE: f64 = 0.1;
+
+struct LocationDefinition {
+    system: &'static str,
+    symbol: &'static str,
+    name: &'static str,
+    location_type: LocationType,
+    x: i32,
+    y: i32,
+    // (good, base price per unit, base quantity available)
+    market: &'static [(Good, i32, i32)],
+}
+
+struct ShipDefinition {
+    ship_type: &'static str,
+    class: &'static str,
+    manufacturer: &'static str,
+    max_cargo: i32,
+    speed: i32,
+    plating: i32,
+    weapons: i32,
+    restricted_goods: &'static [Good],
+    // (location, price)
+    purchase_locations: &'static [(&'static str, i32)],
+}
+
+const SYSTEMS: &[(&str, &str)] = &[
+    ("OE", "Omicron Eridani"),
+    ("XV", "Xiav"),
+];
+
+// Each pair of wormholes is connected. Warping from one lands the ship at the other. Wormholes
+// only sell fuel so that ships can always leave them.
+const WORMHOLES: &[(&str, &str)] = &[
+    ("OE-W-XV", "XV-W-OE"),
+];
+
+const LOCATIONS: &[LocationDefinition] = &[
+    LocationDefinition { system: "OE", symbol: "OE-PM", name: "Prime", location_type: LocationType::Planet, x: -20, y: 5, market: &[
+        (Good::Fuel, 2, 5_000), (Good::Metals, 6, 8_000), (Good::Chemicals, 24, 3_000), (Good::Food, 12, 4_000),
+    ] },
+    LocationDefinition { system: "OE", symbol: "OE-PM-TR", name: "Tritus", location_type: LocationType::Moon, x: -18, y: 10, market: &[
+        (Good::Fuel, 2, 5_000), (Good::Machinery, 72, 1_500), (Good::Electronics, 140, 800), (Good::Metals, 9, 3_000),
+    ] },
+    LocationDefinition { system: "OE", symbol: "OE-CR", name: "Carth", location_type: LocationType::Planet, x: 12, y: -9, market: &[
+        (Good::Fuel, 3, 4_000), (Good::Food, 6, 9_000), (Good::Textiles, 20, 3_000), (Good::Chemicals, 30, 2_000),
+    ] },
+    LocationDefinition { system: "OE", symbol: "OE-KO", name: "Koria", location_type: LocationType::Planet, x: -30, y: -34, market: &[
+        (Good::Fuel, 2, 6_000), (Good::Chemicals, 16, 7_000), (Good::ConsumerGoods, 30, 2_500), (Good::Food, 15, 2_000),
+    ] },
+    LocationDefinition { system: "OE", symbol: "OE-UC", name: "Ucarro", location_type: LocationType::GasGiant, x: 74, y: -11, market: &[
+        (Good::Fuel, 1, 12_000), (Good::Chemicals, 20, 4_000),
+    ] },
+    LocationDefinition { system: "OE", symbol: "OE-UC-AD", name: "Ado", location_type: LocationType::Moon, x: 75, y: -15, market: &[
+        (Good::Fuel, 3, 3_000), (Good::Metals, 12, 2_000), (Good::Electronics, 160, 600), (Good::Machinery, 60, 2_000),
+    ] },
+    LocationDefinition { system: "OE", symbol: "OE-UC-OB", name: "Obo", location_type: LocationType::Moon, x: 79, y: -7, market: &[
+        (Good::Fuel, 3, 3_000), (Good::ConsumerGoods, 22, 5_000), (Good::Textiles, 28, 1_500),
+    ] },
+    LocationDefinition { system: "OE", symbol: "OE-NY", name: "Ten", location_type: LocationType::Asteroid, x: 11, y: 50, market: &[
+        (Good::Fuel, 4, 2_000), (Good::Metals, 4, 12_000), (Good::RareMetals, 40, 1_500),
+    ] },
+    LocationDefinition { system: "OE", symbol: "OE-BO", name: "Bo", location_type: LocationType::GasGiant, x: -58, y: -10, market: &[
+        (Good::Fuel, 1, 10_000), (Good::RareMetals, 55, 600),
+    ] },
+    LocationDefinition { system: "OE", symbol: "OE-W-XV", name: "Wormhole", location_type: LocationType::Wormhole, x: 120, y: 40, market: &[
+        (Good::Fuel, 5, 2_000),
+    ] },
+    LocationDefinition { system: "XV", symbol: "XV-BN", name: "Bann", location_type: LocationType::Planet, x: 0, y: 0, market: &[
+        (Good::Fuel, 2, 5_000), (Good::Food, 8, 6_000), (Good::Research, 90, 800), (Good::ShipParts, 200, 400),
+    ] },
+    LocationDefinition { system: "XV", symbol: "XV-CB-NM", name: "Nimbus", location_type: LocationType::Moon, x: 10, y: 12, market: &[
+        (Good::Fuel, 3, 3_000), (Good::Research, 70, 1_500), (Good::Food, 14, 2_000),
+    ] },
+    LocationDefinition { system: "XV", symbol: "XV-OS", name: "Oresh", location_type: LocationType::Asteroid, x: -40, y: 25, market: &[
+        (Good::Fuel, 4, 2_000), (Good::RareMetals, 30, 3_000), (Good::Metals, 5, 6_000),
+    ] },
+    LocationDefinition { system: "XV", symbol: "XV-TLF", name: "Tolfey", location_type: LocationType::Planet, x: 35, y: -20, market: &[
+        (Good::Fuel, 2, 4_000), (Good::Metals, 10, 3_000), (Good::ShipParts, 260, 300), (Good::RareMetals, 50, 1_000),
+    ] },
+    LocationDefinition { system: "XV", symbol: "XV-W-OE", name: "Wormhole", location_type: LocationType::Wormhole, x: -60, y: -60, market: &[
+        (Good::Fuel, 5, 2_000),
+    ] },
+];
+
+const SHIPS: &[ShipDefinition] = &[
+    ShipDefinition { ship_type: "JW-MK-I", class: "MK-I", manufacturer: "Jackshaw", max_cargo: 50, speed: 1, plating: 5, weapons: 5, restricted_goods: &[], purchase_locations: &[("OE-PM-TR", 21_125), ("XV-BN", 21_125)] },
+    ShipDefinition { ship_type: "EM-MK-I", class: "MK-I", manufacturer: "Electrum", max_cargo: 75, speed: 2, plating: 5, weapons: 10, restricted_goods: &[], purchase_locations: &[("OE-PM", 37_750), ("XV-BN", 37_750)] },
+    ShipDefinition { ship_type: "GR-MK-I", class: "MK-I", manufacturer: "Gravager", max_cargo: 100, speed: 1, plating: 10, weapons: 5, restricted_goods: &[], purchase_locations: &[("OE-PM-TR", 42_650), ("XV-TLF", 42_650)] },
+    ShipDefinition { ship_type: "GR-MK-II", class: "MK-II", manufacturer: "Gravager", max_cargo: 300, speed: 2, plating: 10, weapons: 5, restricted_goods: &[], purchase_locations: &[("OE-PM-TR", 248_500)] },
+    ShipDefinition { ship_type: "HM-MK-III", class: "MK-III", manufacturer: "Hermes", max_cargo: 250, speed: 3, plating: 20, weapons: 10, restricted_goods: &[], purchase_locations: &[("OE-UC-AD", 201_000)] },
+    ShipDefinition { ship_type: "TD-MK-I", class: "MK-I", manufacturer: "Tiddalik", max_cargo: 3_000, speed: 1, plating: 20, weapons: 0, restricted_goods: &[Good::Metals, Good::Machinery], purchase_locations: &[("OE-PM", 320_000)] },
+];
+
+fn api_error(code: i32, message: impl Into<String>) -> SpaceTradersClientError {
+    SpaceTradersClientError::ApiError(shared::ErrorMessage {
+        error: shared::ErrorMessageData {
+            code,
+            message: message.into(),
+        },
+    })
+}
+
+fn location_definition(symbol: &str) -> Option<&'static LocationDefinition> {
+    LOCATIONS.iter().find(|l| l.symbol == symbol)
+}
+
+fn distance_between(origin: &LocationDefinition, destination: &LocationDefinition) -> f64 {
+    (f64::from(origin.x - destination.x).powi(2) + f64::from(origin.y - destination.y).powi(2)).sqrt()
+}
+
+fn fuel_required(origin: &LocationDefinition, destination: &LocationDefinition, ship_type: &str) -> i32 {
+    let planet_penalty = if origin.location_type == LocationType::Planet { 2 } else { 0 };
+    let ship_penalty = match ship_type {
+        "GR-MK-II" => 1,
+        "GR-MK-III" => 2,
+        _ => 0,
+    };
+
+    (distance_between(origin, destination) / 4.0).round() as i32 + planet_penalty + 1 + ship_penalty
+}
+
+fn cargo_quantity(ship: &shared::Ship, good: Good) -> i32 {
+    ship.cargo.iter()
+        .filter(|c| c.good == good)
+        .fold(0, |acc, c| acc + c.quantity)
+}
+
+fn adjust_cargo(ship: &mut shared::Ship, good: Good, quantity: i32) {
+    match ship.cargo.iter_mut().find(|c| c.good == good) {
+        Some(cargo) => {
+            cargo.quantity += quantity;
+            cargo.total_volume = cargo.quantity * good.get_volume();
+        }
+        None => ship.cargo.push(shared::Cargo {
+            good,
+            quantity,
+            total_volume: quantity * good.get_volume(),
+        }),
+    }
+
+    ship.cargo.retain(|c| c.quantity > 0);
+    ship.space_available = ship.max_cargo - ship.cargo.iter().fold(0, |acc, c| acc + c.total_volume);
+}
+
+#[derive(Debug, Clone)]
+struct MarketGood {
+    good: Good,
+    base_price: i32,
+    base_quantity: i32,
+    quantity: i32,
+}
+
+impl MarketGood {
+    // Prices rise as a good is bought out of a market and fall as it is flooded. The effect is
+    // capped at half/double the base price.
+    fn price_per_unit(&self) -> i32 {
+        let ratio = f64::from(self.base_quantity) / f64::from(self.quantity.max(1));
+        (f64::from(self.base_price) * ratio.clamp(0.5, 2.0)).round().max(1.0) as i32
+    }
+
+    fn spread(&self) -> i32 {
+        (f64::from(self.price_per_unit()) * 0.05).ceil().max(1.0) as i32
+    }
+
+    fn purchase_price_per_unit(&self) -> i32 {
+        self.price_per_unit() + self.spread()
+    }
+
+    fn sell_price_per_unit(&self) -> i32 {
+        (self.price_per_unit() - self.spread()).max(1)
+    }
+
+    // Move `fraction` of the way back towards the base quantity
+    fn restock(&mut self, fraction: f64) {
+        self.quantity += (f64::from(self.base_quantity - self.quantity) * fraction).round() as i32;
+    }
+
+    fn to_marketplace_data(&self) -> shared::MarketplaceData {
+        shared::MarketplaceData {
+            symbol: self.good,
+            volume_per_unit: self.good.get_volume(),
+            price_per_unit: self.price_per_unit(),
+            purchase_price_per_unit: self.purchase_price_per_unit(),
+            sell_price_per_unit: self.sell_price_per_unit(),
+            quantity_available: self.quantity,
+            spread: self.spread(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SimUser {
+    token: String,
+    credits: i32,
+    joined_at: DateTime<Utc>,
+    loans: Vec<shared::Loan>,
+}
+
+#[derive(Debug, Clone)]
+struct SimShip {
+    owner: String,
+    ship: shared::Ship,
+    flight_plan: Option<shared::FlightPlanData>,
+}
+
+#[derive(Debug)]
+struct Universe {
+    time_scale: f64,
+    markets: BTreeMap<String, Vec<MarketGood>>,
+    users: BTreeMap<String, SimUser>,
+    ships: BTreeMap<String, SimShip>,
+    markets_restocked_at: DateTime<Utc>,
+    next_id: u64,
+}
+
+impl Universe {
+    fn new(time_scale: f64) -> Universe {
+        let markets = LOCATIONS.iter()
+            .map(|l| {
+                let goods = l.market.iter()
+                    .map(|(good, base_price, base_quantity)| MarketGood {
+                        good: *good,
+                        base_price: *base_price,
+                        base_quantity: *base_quantity,
+                        quantity: *base_quantity,
+                    })
+                    .collect();
+
+                (l.symbol.to_string(), goods)
+            })
+            .collect();
+
+        Universe {
+            time_scale,
+            markets,
+            users: BTreeMap::new(),
+            ships: BTreeMap::new(),
+            markets_restocked_at: Utc::now(),
+            next_id: 0,
+        }
+    }
+
+    fn next_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("sim-{}-{:06}", prefix, self.next_id)
+    }
+
+    fn scaled_duration(&self, seconds: f64) -> Duration {
+        Duration::milliseconds((seconds * self.time_scale * 1000.0).round() as i64)
+    }
+
+    /// Let every market recover for however much simulated time has passed since the last call.
+    /// A time scale of 0 means that markets are fully restocked between requests.
+    fn restock_markets(&mut self) {
+        let now = Utc::now();
+        let elapsed_seconds = (now - self.markets_restocked_at).num_milliseconds() as f64 / 1000.0;
+        let simulated_minutes = if self.time_scale > 0.0 { elapsed_seconds / self.time_scale / 60.0 } else { f64::INFINITY };
+        let fraction = 1.0 - (1.0 - MARKET_RESTOCK_PER_MINUTE).powf(simulated_minutes);
+
+        for goods in self.markets.values_mut() {
+            for good in goods.iter_mut() {
+                good.restock(fraction);
+            }
+        }
+
+        self.markets_restocked_at = now;
+    }
+
+    /// Dock every ship whose flight plan has completed
+    fn land_arrived_ships(&mut self) {
+        let now = Utc::now();
+
+        for sim_ship in self.ships.values_mut() {
+            if let Some(flight_plan) = &sim_ship.flight_plan {
+                if flight_plan.arrives_at <= now {
+                    let destination = location_definition(&flight_plan.destination)
+                        .expect("Flight plans are only created to known locations");
+
+                    sim_ship.ship.location = Some(destination.symbol.to_string());
+                    sim_ship.ship.x = Some(destination.x);
+                    sim_ship.ship.y = Some(destination.y);
+                    sim_ship.ship.flight_plan_id = None;
+                    sim_ship.flight_plan = None;
+                }
+            }
+        }
+    }
+
+    fn authenticate(&mut self, username: &str, token: &str) -> Result<(), SpaceTradersClientError> {
+        match self.users.get(username) {
+            Some(user) if user.token == token => {
+                self.land_arrived_ships();
+                self.restock_markets();
+                Ok(())
+            }
+            _ => Err(SpaceTradersClientError::Unauthorized),
+        }
+    }
+
+    fn user_mut(&mut self, username: &str) -> &mut SimUser {
+        self.users.get_mut(username).expect("User was authenticated")
+    }
+
+    fn user_ships(&self, username: &str) -> Vec<shared::Ship> {
+        self.ships.values()
+            .filter(|s| s.owner == username)
+            .map(|s| s.ship.clone())
+            .collect()
+    }
+
+    fn owned_ship_mut(&mut self, username: &str, ship_id: &str) -> Result<&mut SimShip, SpaceTradersClientError> {
+        match self.ships.get_mut(ship_id) {
+            Some(sim_ship) if sim_ship.owner == username => Ok(sim_ship),
+            _ => Err(api_error(ERROR_CODE_NOT_FOUND, format!("Ship {} not found.", ship_id))),
+        }
+    }
+
+    fn docked_location(&mut self, username: &str, ship_id: &str) -> Result<&'static LocationDefinition, SpaceTradersClientError> {
+        let sim_ship = self.owned_ship_mut(username, ship_id)?;
+
+        match &sim_ship.ship.location {
+            Some(location) => Ok(location_definition(location).expect("Ships are only ever docked at known locations")),
+            None => Err(api_error(ERROR_CODE_SHIP_IN_TRANSIT, "Ship is currently in-transit. Ship must be docked to perform this action.")),
+        }
+    }
+
+    fn market_good_mut(&mut self, location: &str, good: Good) -> Result<&mut MarketGood, SpaceTradersClientError> {
+        self.markets.get_mut(location)
+            .and_then(|goods| goods.iter_mut().find(|g| g.good == good))
+            .ok_or_else(|| api_error(ERROR_CODE_GOOD_NOT_LISTED, "Good is not listed in planet marketplace."))
+    }
+
+    fn user_info(&self, username: &str) -> responses::UserInfo {
+        let user = &self.users[username];
+
+        responses::UserInfo {
+            user: responses::UserInfoData {
+                username: username.to_string(),
+                credits: user.credits,
+                ship_count: self.user_ships(username).len() as i32,
+                structure_count: 0,
+                joined_at: user.joined_at,
+            },
+        }
+    }
+
+    fn systems_info(&self) -> responses::SystemsInfo {
+        responses::SystemsInfo {
+            systems: SYSTEMS.iter()
+                .map(|(symbol, name)| shared::SystemsInfoData {
+                    symbol: symbol.to_string(),
+                    name: name.to_string(),
+                    locations: LOCATIONS.iter()
+                        .filter(|l| l.system == *symbol)
+                        .map(|l| shared::SystemsInfoLocation {
+                            symbol: l.symbol.to_string(),
+                            systems_info_type: l.location_type,
+                            name: l.name.to_string(),
+                            x: l.x,
+                            y: l.y,
+                            ansible_progress: None,
+                            anomaly: None,
+                            structures: None,
+                            messages: None,
+                            allows_construction: false,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn ships_for_sale(&self) -> responses::ShipsForSale {
+        responses::ShipsForSale {
+            ships: SHIPS.iter()
+                .map(|s| shared::ShipForSale {
+                    ship_type: s.ship_type.to_string(),
+                    class: s.class.to_string(),
+                    max_cargo: s.max_cargo,
+                    speed: s.speed,
+                    manufacturer: s.manufacturer.to_string(),
+                    plating: s.plating,
+                    weapons: s.weapons,
+                    purchase_locations: s.purchase_locations.iter()
+                        .map(|(location, price)| shared::PurchaseLocation {
+                            system: location_definition(location).unwrap().system.to_string(),
+                            location: location.to_string(),
+                            price: *price,
+                        })
+                        .collect(),
+                    restricted_goods: if s.restricted_goods.is_empty() {
+                        None
+                    } else {
+                        Some(s.restricted_goods.to_vec())
+                    },
+                })
+                .collect(),
+        }
+    }
+
+    fn purchase_ship(&mut self, username: &str, location: &str, ship_type: &str) -> Result<responses::PurchaseShip, SpaceTradersClientError> {
+        let definition = SHIPS.iter()
+            .find(|s| s.ship_type == ship_type)
+            .ok_or_else(|| api_error(ERROR_CODE_NOT_FOUND, format!("Ship type {} not found.", ship_type)))?;
+
+        let (_, price) = definition.purchase_locations.iter()
+            .find(|(l, _)| *l == location)
+            .ok_or_else(|| api_error(ERROR_CODE_NOT_FOUND, format!("Ship type {} is not for sale at {}.", ship_type, location)))?;
+
+        if self.users[username].credits < *price {
+            return Err(api_error(ERROR_CODE_INSUFFICIENT_CREDITS, "User has insufficient credits for transaction."));
+        }
+
+        let location_definition = location_definition(location).unwrap();
+        let ship = shared::Ship {
+            id: self.next_id("ship"),
+            location: Some(location.to_string()),
+            cargo: Vec::new(),
+            space_available: definition.max_cargo,
+            ship_type: definition.ship_type.to_string(),
+            class: definition.class.to_string(),
+            max_cargo: definition.max_cargo,
+            speed: definition.speed,
+            manufacturer: definition.manufacturer.to_string(),
+            plating: definition.plating,
+            weapons: definition.weapons,
+            x: Some(location_definition.x),
+            y: Some(location_definition.y),
+            flight_plan_id: None,
+        };
+
+        self.ships.insert(ship.id.clone(), SimShip {
+            owner: username.to_string(),
+            ship: ship.clone(),
+            flight_plan: None,
+        });
+
+        let user = self.user_mut(username);
+        user.credits -= price;
+
+        Ok(responses::PurchaseShip {
+            credits: user.credits,
+            ship,
+        })
+    }
+
+    fn request_new_loan(&mut self, username: &str, loan_type: LoanType) -> Result<responses::RequestLoan, SpaceTradersClientError> {
+        if loan_type != LoanType::Startup {
+            return Err(api_error(ERROR_CODE_LOAN_UNAVAILABLE, format!("{:?} loans are not available.", loan_type)));
+        }
+
+        let loan_id = self.next_id("loan");
+        let user = self.user_mut(username);
+
+        if user.loans.iter().any(|l| l.status != "PAID") {
+            return Err(api_error(ERROR_CODE_LOAN_UNAVAILABLE, "User already has an outstanding loan."));
+        }
+
+        let loan = shared::Loan {
+            id: loan_id,
+            due: Utc::now() + Duration::days(i64::from(STARTUP_LOAN_TERM_IN_DAYS)),
+            repayment_amount: (f64::from(STARTUP_LOAN_AMOUNT) * (1.0 + STARTUP_LOAN_RATE / 100.0)).round() as i32,
+            status: "CURRENT".to_string(),
+            loan_type,
+        };
+
+        user.credits += STARTUP_LOAN_AMOUNT;
+        user.loans.push(loan.clone());
+
+        Ok(responses::RequestLoan {
+            credits: user.credits,
+            loan,
+        })
+    }
+
+    fn pay_off_loan(&mut self, username: &str, loan_id: &str) -> Result<responses::PayLoanResponse, SpaceTradersClientError> {
+        let user = self.user_mut(username);
+        let credits = user.credits;
+
+        let loan = user.loans.iter_mut()
+            .find(|l| l.id == loan_id)
+            .ok_or_else(|| api_error(ERROR_CODE_NOT_FOUND, format!("Loan {} not found.", loan_id)))?;
+
+        if loan.status == "PAID" {
+            return Err(api_error(ERROR_CODE_LOAN_ALREADY_PAID, "Loan has already been paid."));
+        }
+
+        if credits < loan.repayment_amount {
+            return Err(api_error(ERROR_CODE_INSUFFICIENT_CREDITS, "User has insufficient credits for transaction."));
+        }
+
+        loan.status = "PAID".to_string();
+        user.credits -= loan.repayment_amount;
+
+        Ok(responses::PayLoanResponse {
+            credits: user.credits,
+            loans: user.loans.clone(),
+        })
+    }
+
+    fn location_marketplace(&mut self, username: &str, location: &str) -> Result<responses::LocationMarketplace, SpaceTradersClientError> {
+        if location_definition(location).is_none() {
+            return Err(api_error(ERROR_CODE_NOT_FOUND, format!("Location {} not found.", location)));
+        }
+
+        if !self.user_ships(username).iter().any(|s| s.location.as_deref() == Some(location)) {
+            return Err(api_error(ERROR_CODE_NO_SHIP_AT_LOCATION, "A ship must be docked at the location to view its marketplace."));
+        }
+
+        Ok(responses::LocationMarketplace {
+            marketplace: self.markets[location].iter().map(|g| g.to_marketplace_data()).collect(),
+        })
+    }
+
+    fn create_flight_plan(&mut self, username: &str, ship_id: &str, destination: &str) -> Result<responses::FlightPlan, SpaceTradersClientError> {
+        let origin = self.docked_location(username, ship_id)?;
+        let destination = location_definition(destination)
+            .ok_or_else(|| api_error(ERROR_CODE_INVALID_DESTINATION, format!("Destination {} not found.", destination)))?;
+
+        if origin.symbol == destination.symbol {
+            return Err(api_error(ERROR_CODE_INVALID_DESTINATION, "Ship is already at the destination."));
+        }
+
+        if origin.system != destination.system {
+            return Err(api_error(ERROR_CODE_INVALID_DESTINATION, "Destination is in a different system. Use a wormhole to travel between systems."));
+        }
+
+        let distance = distance_between(origin, destination);
+        let speed = self.owned_ship_mut(username, ship_id)?.ship.speed;
+        let flight_duration = self.scaled_duration((distance * 2.0 / f64::from(speed)).round() + 60.0);
+        let flight_plan_id = self.next_id("flight-plan");
+        let sim_ship = self.owned_ship_mut(username, ship_id)?;
+
+        let fuel_required = fuel_required(origin, destination, &sim_ship.ship.ship_type);
+        let current_fuel = cargo_quantity(&sim_ship.ship, Good::Fuel);
+        if current_fuel < fuel_required {
+            return Err(api_error(
+                ERROR_CODE_INSUFFICIENT_FUEL,
+                format!("Ship has insufficient fuel for flight plan. You require {} more FUEL", fuel_required - current_fuel),
+            ));
+        }
+
+        let now = Utc::now();
+        let arrives_at = now + flight_duration;
+
+        adjust_cargo(&mut sim_ship.ship, Good::Fuel, -fuel_required);
+
+        let flight_plan = shared::FlightPlanData {
+            id: flight_plan_id,
+            ship_id: ship_id.to_string(),
+            fuel_consumed: fuel_required,
+            fuel_remaining: current_fuel - fuel_required,
+            time_remaining_in_seconds: flight_duration.num_seconds() as i32,
+            created_at: now,
+            arrives_at,
+            terminated_at: None,
+            destination: destination.symbol.to_string(),
+            departure: origin.symbol.to_string(),
+            distance: distance.round() as i32,
+        };
+
+        sim_ship.ship.location = None;
+        sim_ship.ship.flight_plan_id = Some(flight_plan.id.clone());
+        sim_ship.flight_plan = Some(flight_plan.clone());
+
+        Ok(responses::FlightPlan { flight_plan })
+    }
+
+    fn attempt_warp_jump(&mut self, username: &str, ship_id: &str) -> Result<responses::FlightPlan, SpaceTradersClientError> {
+        let origin = self.docked_location(username, ship_id)?;
+
+        let destination = WORMHOLES.iter()
+            .find_map(|(a, b)| {
+                if *a == origin.symbol {
+                    Some(*b)
+                } else if *b == origin.symbol {
+                    Some(*a)
+                } else {
+                    None
+                }
+            })
+            .and_then(location_definition)
+            .ok_or_else(|| api_error(ERROR_CODE_NOT_AT_WORMHOLE, "Ship must be docked at a wormhole to attempt a warp jump."))?;
+
+        let flight_plan_id = self.next_id("flight-plan");
+        let warp_duration = self.scaled_duration(WARP_JUMP_SECONDS);
+        let sim_ship = self.owned_ship_mut(username, ship_id)?;
+
+        let now = Utc::now();
+        let arrives_at = now + warp_duration;
+        let flight_plan = shared::FlightPlanData {
+            id: flight_plan_id,
+            ship_id: ship_id.to_string(),
+            fuel_consumed: 0,
+            fuel_remaining: cargo_quantity(&sim_ship.ship, Good::Fuel),
+            time_remaining_in_seconds: warp_duration.num_seconds() as i32,
+            created_at: now,
+            arrives_at,
+            terminated_at: None,
+            destination: destination.symbol.to_string(),
+            departure: origin.symbol.to_string(),
+            distance: 0,
+        };
+
+        sim_ship.ship.location = None;
+        sim_ship.ship.flight_plan_id = Some(flight_plan.id.clone());
+        sim_ship.flight_plan = Some(flight_plan.clone());
+
+        Ok(responses::FlightPlan { flight_plan })
+    }
+
+    fn create_purchase_order(&mut self, username: &str, ship_id: &str, good: Good, quantity: i32) -> Result<responses::PurchaseOrder, SpaceTradersClientError> {
+        let location = self.docked_location(username, ship_id)?;
+        let credits = self.users[username].credits;
+        let ship = &self.owned_ship_mut(username, ship_id)?.ship;
+        let space_available = ship.space_available;
+
+        // Restricted ships only carry the goods they were built for (and the fuel to fly them)
+        let restricted_goods = SHIPS.iter()
+            .find(|s| s.ship_type == ship.ship_type)
+            .map(|s| s.restricted_goods)
+            .unwrap_or(&[]);
+        if good != Good::Fuel && !restricted_goods.is_empty() && !restricted_goods.contains(&good) {
+            return Err(api_error(ERROR_CODE_RESTRICTED_GOOD, format!("Ship is restricted from carrying {}.", good)));
+        }
+
+        let market_good = self.market_good_mut(location.symbol, good)?;
+        if quantity <= 0 || quantity > market_good.quantity {
+            return Err(api_error(ERROR_CODE_QUANTITY_UNAVAILABLE, "Good quantity is not available on planet."));
+        }
+
+        if quantity * good.get_volume() > space_available {
+            return Err(api_error(ERROR_CODE_INSUFFICIENT_CARGO_SPACE, "Ship has insufficient cargo space for purchase."));
+        }
+
+        let price_per_unit = market_good.purchase_price_per_unit();
+        let total = price_per_unit * quantity;
+        if total > credits {
+            return Err(api_error(ERROR_CODE_INSUFFICIENT_CREDITS, "User has insufficient credits for transaction."));
+        }
+
+        market_good.quantity -= quantity;
+
+        let sim_ship = self.owned_ship_mut(username, ship_id)?;
+        adjust_cargo(&mut sim_ship.ship, good, quantity);
+        let ship = sim_ship.ship.clone();
+
+        let user = self.user_mut(username);
+        user.credits -= total;
+
+        Ok(responses::PurchaseOrder {
+            credits: user.credits,
+            order: shared::Order {
+                good,
+                quantity,
+                price_per_unit,
+                total,
+            },
+            ship,
+        })
+    }
+
+    fn create_sell_order(&mut self, username: &str, ship_id: &str, good: Good, quantity: i32) -> Result<responses::PurchaseOrder, SpaceTradersClientError> {
+        let location = self.docked_location(username, ship_id)?;
+        let on_board = cargo_quantity(&self.owned_ship_mut(username, ship_id)?.ship, good);
+
+        if quantity <= 0 || quantity > on_board {
+            return Err(api_error(ERROR_CODE_INSUFFICIENT_GOODS, "Ship does not have the quantity of goods requested to sell."));
+        }
+
+        let market_good = self.market_good_mut(location.symbol, good)?;
+        let price_per_unit = market_good.sell_price_per_unit();
+        let total = price_per_unit * quantity;
+        market_good.quantity += quantity;
+
+        let sim_ship = self.owned_ship_mut(username, ship_id)?;
+        adjust_cargo(&mut sim_ship.ship, good, -quantity);
+        let ship = sim_ship.ship.clone();
+
+        let user = self.user_mut(username);
+        user.credits += total;
+
+        Ok(responses::PurchaseOrder {
+            credits: user.credits,
+            order: shared::Order {
+                good,
+                quantity,
+                price_per_unit,
+                total,
+            },
+            ship,
+        })
+    }
+
+    fn jettison_cargo(&mut self, username: &str, ship_id: &str, good: Good, quantity: i32) -> Result<responses::JettisonCargo, SpaceTradersClientError> {
+        let sim_ship = self.owned_ship_mut(username, ship_id)?;
+        let on_board = cargo_quantity(&sim_ship.ship, good);
+
+        if quantity <= 0 || quantity > on_board {
+            return Err(api_error(ERROR_CODE_INSUFFICIENT_GOODS, "Ship does not have the quantity of goods requested to jettison."));
+        }
+
+        adjust_cargo(&mut sim_ship.ship, good, -quantity);
+
+        Ok(responses::JettisonCargo {
+            ship_id: ship_id.to_string(),
+            good,
+            quantity_remaining: on_board - quantity,
+        })
+    }
+}
+
+/// An in-process, deterministic stand-in for the SpaceTraders API. It models two systems joined
+/// by a wormhole, their marketplaces, the ships for sale, fuel consumption, flight plans, loans
+/// and warp jumps. Flight and warp durations are multiplied by `time_scale` so that tests can run
+/// whole trading loops without waiting on real flight times.
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    universe: Arc<Mutex<Universe>>,
+}
+
+impl Simulator {
+    pub fn new(time_scale: f64) -> Simulator {
+        Simulator {
+            universe: Arc::new(Mutex::new(Universe::new(time_scale))),
+        }
+    }
+}
+
+#[async_trait]
+impl GameBackend for Simulator {
+    async fn get_game_status(&self) -> Result<responses::GameStatus, SpaceTradersClientError> {
+        Ok(responses::GameStatus {
+            status: "spacetraders is currently online and available to play".to_string(),
+        })
+    }
+
+    async fn claim_username(&self, username: String) -> Result<responses::ClaimUsername, SpaceTradersClientError> {
+        let mut universe = self.universe.lock().unwrap();
+
+        if universe.users.contains_key(&username) {
+            return Err(api_error(ERROR_CODE_USERNAME_TAKEN, "Username has already been claimed."));
+        }
+
+        let token = format!("sim-token-{}", username);
+        universe.users.insert(username.clone(), SimUser {
+            token: token.clone(),
+            credits: 0,
+            joined_at: Utc::now(),
+            loans: Vec::new(),
+        });
+
+        Ok(responses::ClaimUsername {
+            token,
+            user: responses::ClaimUsernameUser {
+                username,
+                credits: 0,
+                ships: Vec::new(),
+                loans: Vec::new(),
+            },
+        })
+    }
+
+    fn client(&self, username: String, token: String) -> GameClient {
+        Arc::new(SimulatorClient {
+            universe: self.universe.clone(),
+            username,
+            token,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulatorClient {
+    universe: Arc<Mutex<Universe>>,
+    username: String,
+    token: String,
+}
+
+impl SimulatorClient {
+    fn with_universe<T>(&self, f: impl FnOnce(&mut Universe) -> Result<T, SpaceTradersClientError>) -> Result<T, SpaceTradersClientError> {
+        let mut universe = self.universe.lock().unwrap();
+        universe.authenticate(&self.username, &self.token)?;
+
+        f(&mut universe)
+    }
+}
+
+#[async_trait]
+impl GameApi for SimulatorClient {
+    async fn get_my_info(&self) -> Result<responses::UserInfo, SpaceTradersClientError> {
+        self.with_universe(|u| Ok(u.user_info(&self.username)))
+    }
+
+    async fn get_my_ships(&self) -> Result<responses::MyShips, SpaceTradersClientError> {
+        self.with_universe(|u| Ok(responses::MyShips { ships: u.user_ships(&self.username) }))
+    }
+
+    async fn get_my_ship(&self, ship_id: &str) -> Result<responses::MyShip, SpaceTradersClientError> {
+        self.with_universe(|u| Ok(responses::MyShip { ship: u.owned_ship_mut(&self.username, ship_id)?.ship.clone() }))
+    }
+
+    async fn get_my_loans(&self) -> Result<responses::LoanInfo, SpaceTradersClientError> {
+        self.with_universe(|u| Ok(responses::LoanInfo { loans: u.users[&self.username].loans.clone() }))
+    }
+
+    async fn get_available_loans(&self) -> Result<responses::AvailableLoans, SpaceTradersClientError> {
+        Ok(responses::AvailableLoans {
+            loans: vec![responses::AvailableLoan {
+                loan_type: LoanType::Startup,
+                amount: STARTUP_LOAN_AMOUNT,
+                rate: STARTUP_LOAN_RATE,
+                term_in_days: STARTUP_LOAN_TERM_IN_DAYS,
+                collateral_required: false,
+            }],
+        })
+    }
+
+    async fn request_new_loan(&self, loan_type: LoanType) -> Result<responses::RequestLoan, SpaceTradersClientError> {
+        self.with_universe(|u| u.request_new_loan(&self.username, loan_type))
+    }
+
+    async fn pay_off_loan(&self, loan_id: &str) -> Result<responses::PayLoanResponse, SpaceTradersClientError> {
+        self.with_universe(|u| u.pay_off_loan(&self.username, loan_id))
+    }
+
+    async fn get_ships_for_sale(&self) -> Result<responses::ShipsForSale, SpaceTradersClientError> {
+        self.with_universe(|u| Ok(u.ships_for_sale()))
+    }
+
+    async fn purchase_ship(&self, location_symbol: String, ship_type: String) -> Result<responses::PurchaseShip, SpaceTradersClientError> {
+        self.with_universe(|u| u.purchase_ship(&self.username, &location_symbol, &ship_type))
+    }
+
+    async fn get_systems_info(&self) -> Result<responses::SystemsInfo, SpaceTradersClientError> {
+        self.with_universe(|u| Ok(u.systems_info()))
+    }
+
+    async fn get_location_marketplace(&self, location_symbol: &str) -> Result<responses::LocationMarketplace, SpaceTradersClientError> {
+        self.with_universe(|u| u.location_marketplace(&self.username, location_symbol))
+    }
+
+    async fn create_flight_plan(&self, ship_id: String, destination: String) -> Result<responses::FlightPlan, SpaceTradersClientError> {
+        self.with_universe(|u| u.create_flight_plan(&self.username, &ship_id, &destination))
+    }
+
+    async fn create_purchase_order(&self, ship_id: String, good: Good, quantity: i32) -> Result<responses::PurchaseOrder, SpaceTradersClientError> {
+        self.with_universe(|u| u.create_purchase_order(&self.username, &ship_id, good, quantity))
+    }
+
+    async fn create_sell_order(&self, ship_id: String, good: Good, quantity: i32) -> Result<responses::PurchaseOrder, SpaceTradersClientError> {
+        self.with_universe(|u| u.create_sell_order(&self.username, &ship_id, good, quantity))
+    }
+
+    async fn jettison_cargo(&self, ship_id: &str, good: Good, quantity: i32) -> Result<responses::JettisonCargo, SpaceTradersClientError> {
+        self.with_universe(|u| u.jettison_cargo(&self.username, ship_id, good, quantity))
+    }
+
+    async fn attempt_warp_jump(&self, ship_id: String) -> Result<responses::FlightPlan, SpaceTradersClientError> {
+        self.with_universe(|u| u.attempt_warp_jump(&self.username, &ship_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn new_client(simulator: &Simulator, username: &str) -> GameClient {
+        let claim = simulator.claim_username(username.to_string()).await.unwrap();
+        simulator.client(username.to_string(), claim.token)
+    }
+
+    fn api_error_code(error: SpaceTradersClientError) -> i32 {
+        match error {
+            SpaceTradersClientError::ApiError(e) => e.error.code,
+            other => panic!("Expected an api error but got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_tokens() {
+        let simulator = Simulator::new(0.0);
+        new_client(&simulator, "trader").await;
+
+        let client = simulator.client("trader".to_string(), "not-the-token".to_string());
+
+        assert!(matches!(client.get_my_info().await, Err(SpaceTradersClientError::Unauthorized)));
+    }
+
+    #[tokio::test]
+    async fn startup_loan_can_only_be_taken_once() {
+        let simulator = Simulator::new(0.0);
+        let client = new_client(&simulator, "trader").await;
+
+        let loan = client.request_new_loan(LoanType::Startup).await.unwrap();
+        assert_eq!(loan.credits, STARTUP_LOAN_AMOUNT);
+
+        let second_loan = client.request_new_loan(LoanType::Startup).await.unwrap_err();
+        assert_eq!(api_error_code(second_loan), ERROR_CODE_LOAN_UNAVAILABLE);
+
+        // The repayment includes interest so the loaned credits alone can't cover it
+        let repayment = client.pay_off_loan(&loan.loan.id).await.unwrap_err();
+        assert_eq!(api_error_code(repayment), ERROR_CODE_INSUFFICIENT_CREDITS);
+    }
+
+    #[tokio::test]
+    async fn trading_round_trip() {
+        let simulator = Simulator::new(0.0);
+        let client = new_client(&simulator, "trader").await;
+        client.request_new_loan(LoanType::Startup).await.unwrap();
+
+        let ship = client.purchase_ship("OE-PM-TR".to_string(), "JW-MK-I".to_string()).await.unwrap().ship;
+        client.create_purchase_order(ship.id.clone(), Good::Fuel, 30).await.unwrap();
+
+        let marketplace = client.get_location_marketplace("OE-PM-TR").await.unwrap();
+        let machinery = marketplace.marketplace.iter().find(|m| m.symbol == Good::Machinery).unwrap();
+        let purchase = client.create_purchase_order(ship.id.clone(), Good::Machinery, 10).await.unwrap();
+        assert_eq!(purchase.order.price_per_unit, machinery.purchase_price_per_unit);
+
+        let flight_plan = client.create_flight_plan(ship.id.clone(), "OE-UC-AD".to_string()).await.unwrap();
+        assert_eq!(flight_plan.flight_plan.departure, "OE-PM-TR");
+
+        // With a time scale of 0 the ship has already landed by the next request
+        let ship = client.get_my_ship(&ship.id).await.unwrap().ship;
+        assert_eq!(ship.location, Some("OE-UC-AD".to_string()));
+
+        let sale = client.create_sell_order(ship.id.clone(), Good::Machinery, 10).await.unwrap();
+        assert_eq!(sale.credits, purchase.credits + sale.order.total);
+    }
+
+    #[tokio::test]
+    async fn restricted_ships_only_buy_the_goods_they_carry() {
+        let simulator = Simulator::new(0.0);
+        let client = new_client(&simulator, "trader").await;
+        simulator.universe.lock().unwrap().user_mut("trader").credits = 1_000_000;
+
+        let ship = client.purchase_ship("OE-PM".to_string(), "TD-MK-I".to_string()).await.unwrap().ship;
+        client.create_purchase_order(ship.id.clone(), Good::Fuel, 30).await.unwrap();
+        client.create_purchase_order(ship.id.clone(), Good::Metals, 10).await.unwrap();
+
+        let chemicals = client.create_purchase_order(ship.id.clone(), Good::Chemicals, 10).await.unwrap_err();
+        assert_eq!(api_error_code(chemicals), ERROR_CODE_RESTRICTED_GOOD);
+    }
+
+    #[tokio::test]
+    async fn buying_out_a_market_raises_the_price() {
+        // Real time so that the market doesn't recover between the two purchases
+        let simulator = Simulator::new(1.0);
+        let client = new_client(&simulator, "trader").await;
+        client.request_new_loan(LoanType::Startup).await.unwrap();
+
+        let ship = client.purchase_ship("OE-PM".to_string(), "TD-MK-I".to_string()).await.unwrap_err();
+        assert_eq!(api_error_code(ship), ERROR_CODE_INSUFFICIENT_CREDITS);
+
+        let ship = client.purchase_ship("OE-PM-TR".to_string(), "GR-MK-I".to_string()).await.unwrap().ship;
+        let first = client.create_purchase_order(ship.id.clone(), Good::Electronics, 90).await.unwrap();
+        client.jettison_cargo(&ship.id, Good::Electronics, 90).await.unwrap();
+        let second = client.create_purchase_order(ship.id.clone(), Good::Electronics, 90).await.unwrap();
+
+        assert!(second.order.price_per_unit > first.order.price_per_unit);
+    }
+
+    #[tokio::test]
+    async fn flight_without_fuel_reports_the_missing_amount() {
+        let simulator = Simulator::new(0.0);
+        let client = new_client(&simulator, "trader").await;
+        client.request_new_loan(LoanType::Startup).await.unwrap();
+
+        let ship = client.purchase_ship("OE-PM-TR".to_string(), "JW-MK-I".to_string()).await.unwrap().ship;
+        let error = client.create_flight_plan(ship.id.clone(), "OE-UC".to_string()).await.unwrap_err();
+
+        let origin = location_definition("OE-PM-TR").unwrap();
+        let destination = location_definition("OE-UC").unwrap();
+        let expected = format!("You require {} more FUEL", fuel_required(origin, destination, "JW-MK-I"));
+        match error {
+            SpaceTradersClientError::ApiError(e) => {
+                assert_eq!(e.error.code, ERROR_CODE_INSUFFICIENT_FUEL);
+                assert!(e.error.message.ends_with(&expected), "{}", e.error.message);
+            }
+            other => panic!("Expected an api error but got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn warp_jump_moves_ship_to_the_connected_system() {
+        let simulator = Simulator::new(0.0);
+        let client = new_client(&simulator, "trader").await;
+        client.request_new_loan(LoanType::Startup).await.unwrap();
+
+        let ship = client.purchase_ship("OE-PM-TR".to_string(), "JW-MK-I".to_string()).await.unwrap().ship;
+        let error = client.attempt_warp_jump(ship.id.clone()).await.unwrap_err();
+        assert_eq!(api_error_code(error), ERROR_CODE_NOT_AT_WORMHOLE);
+
+        client.create_purchase_order(ship.id.clone(), Good::Fuel, 40).await.unwrap();
+        client.create_flight_plan(ship.id.clone(), "OE-W-XV".to_string()).await.unwrap();
+        client.attempt_warp_jump(ship.id.clone()).await.unwrap();
+
+        let ship = client.get_my_ship(&ship.id).await.unwrap().ship;
+        assert_eq!(ship.location, Some("XV-W-OE".to_string()));
+    }
+}
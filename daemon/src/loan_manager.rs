@@ -0,0 +1,334 @@
+// Borrows and repays a user's loans. A loan is taken out when the user is short of credits and
+// nothing is outstanding, picking whichever loan on offer is cheapest. Loans are paid back early
+// once the user is comfortably in credit and always shortly before they are due. Everything that
+// happens is recorded to daemon_user_loan_event.
+use crate::config::LoanConfig;
+use crate::db::DbLoanEvent;
+use crate::game::GameClient;
+use crate::storage::StorageClient;
+use chrono::{DateTime, Duration, Utc};
+use spacetraders::responses::AvailableLoan;
+use spacetraders::shared::{self, LoanType};
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoanStatus {
+    Current,
+    Paid,
+    /// Anything the api comes up with that we don't know about. Treated as outstanding
+    Other(String),
+}
+
+impl From<&str> for LoanStatus {
+    fn from(status: &str) -> Self {
+        match status {
+            "CURRENT" => LoanStatus::Current,
+            status if status.contains("PAID") => LoanStatus::Paid,
+            status => LoanStatus::Other(status.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loan {
+    pub id: String,
+    pub loan_type: LoanType,
+    pub due: DateTime<Utc>,
+    pub repayment_amount: i32,
+    pub status: LoanStatus,
+}
+
+impl From<&shared::Loan> for Loan {
+    fn from(loan: &shared::Loan) -> Self {
+        Loan {
+            id: loan.id.clone(),
+            loan_type: loan.loan_type,
+            due: loan.due,
+            repayment_amount: loan.repayment_amount,
+            status: LoanStatus::from(loan.status.as_str()),
+        }
+    }
+}
+
+impl Loan {
+    pub fn is_outstanding(&self) -> bool {
+        self.status != LoanStatus::Paid
+    }
+}
+
+/// When to borrow and when to pay back
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoanPolicy {
+    /// Loans are paid off early once the user has more credits than this
+    pub pay_off_above: i32,
+    pub cash_reserve: i32,
+    pub repay_before_due: Duration,
+}
+
+impl LoanPolicy {
+    pub fn new(pay_off_above: i32, config: &LoanConfig) -> LoanPolicy {
+        LoanPolicy {
+            pay_off_above,
+            cash_reserve: config.cash_reserve,
+            repay_before_due: Duration::hours(config.repay_hours_before_due),
+        }
+    }
+
+    fn is_due(&self, loan: &Loan, now: DateTime<Utc>) -> bool {
+        now >= loan.due - self.repay_before_due
+    }
+}
+
+/// Which outstanding loans to pay off now, soonest due first. Loans that are about to be due are
+/// paid whenever there are enough credits. Anything else waits until the user has more than
+/// pay_off_above and would still have the reserve afterwards.
+pub fn repayments(loans: &[Loan], credits: i32, now: DateTime<Utc>, policy: &LoanPolicy) -> Vec<String> {
+    let mut outstanding: Vec<&Loan> = loans.iter().filter(|l| l.is_outstanding()).collect();
+    outstanding.sort_by_key(|l| l.due);
+
+    let pay_early = credits > policy.pay_off_above;
+    let mut credits = credits;
+    let mut repayments = Vec::new();
+    for loan in outstanding {
+        let early = pay_early && credits - loan.repayment_amount >= policy.cash_reserve;
+        let due = policy.is_due(loan, now) && credits >= loan.repayment_amount;
+
+        if early || due {
+            credits -= loan.repayment_amount;
+            repayments.push(loan.id.clone());
+        }
+    }
+
+    repayments
+}
+
+/// Whether the user should borrow. Only one loan is ever outstanding at a time
+pub fn needs_loan(loans: &[Loan], credits: i32, policy: &LoanPolicy) -> bool {
+    !loans.iter().any(|l| l.is_outstanding()) && (credits <= 0 || credits < policy.cash_reserve)
+}
+
+/// The cheapest loan that doesn't need collateral. Ties go to the loan that lends the most for the
+/// longest
+pub fn best_loan(available: &[AvailableLoan]) -> Option<LoanType> {
+    available.iter()
+        .filter(|l| !l.collateral_required && l.amount > 0)
+        .min_by(|a, b| {
+            a.rate.partial_cmp(&b.rate).unwrap_or(Ordering::Equal)
+                .then_with(|| b.amount.cmp(&a.amount))
+                .then_with(|| b.term_in_days.cmp(&a.term_in_days))
+        })
+        .map(|l| l.loan_type)
+}
+
+/// A user's loans along with everything needed to borrow and repay them
+#[derive(Debug, Clone)]
+pub struct LoanManager {
+    client: GameClient,
+    storage: StorageClient,
+    user_id: String,
+    username: String,
+    pub policy: LoanPolicy,
+    pub loans: Vec<Loan>,
+}
+
+impl LoanManager {
+    pub fn new(client: GameClient, storage: StorageClient, user_id: String, username: String, policy: LoanPolicy, loans: &[shared::Loan]) -> LoanManager {
+        LoanManager {
+            client,
+            storage,
+            user_id,
+            username,
+            policy,
+            loans: loans.iter().map(Loan::from).collect(),
+        }
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.loans.iter().filter(|l| l.is_outstanding()).count()
+    }
+
+    /// Credits that are spoken for by loans that are due soon
+    pub fn committed_credits(&self, now: DateTime<Utc>) -> i32 {
+        self.loans.iter()
+            .filter(|l| l.is_outstanding() && self.policy.is_due(l, now))
+            .map(|l| l.repayment_amount)
+            .sum()
+    }
+
+    /// Borrow or repay whatever the policy says to. Returns the user's new credits if anything
+    /// changed
+    pub async fn manage(&mut self, credits: i32) -> anyhow::Result<Option<i32>> {
+        let mut new_credits = None;
+
+        for loan_id in repayments(&self.loans, credits, Utc::now(), &self.policy) {
+            new_credits = Some(self.repay(&loan_id).await?);
+        }
+
+        let credits = new_credits.unwrap_or(credits);
+        if needs_loan(&self.loans, credits, &self.policy) {
+            let available = self.client.get_available_loans().await?;
+            match best_loan(&available.loans) {
+                Some(loan_type) => new_credits = Some(self.borrow(loan_type).await?),
+                None => log::warn!("{} -- Needs credits but there aren't any loans available", self.username),
+            }
+        }
+
+        Ok(new_credits)
+    }
+
+    /// Take out a loan. Returns the user's new credits
+    pub async fn borrow(&mut self, loan_type: LoanType) -> anyhow::Result<i32> {
+        log::info!("{} -- Requesting new {:?} loan", self.username, loan_type);
+        let response = self.client.request_new_loan(loan_type).await?;
+        let loan = Loan::from(&response.loan);
+
+        self.record("requested", &loan, response.credits).await?;
+        self.loans.push(loan);
+        log::info!("{} -- Now has {} outstanding loan(s)", self.username, self.outstanding());
+
+        Ok(response.credits)
+    }
+
+    /// Pay a loan off in full. Returns the user's new credits
+    pub async fn repay(&mut self, loan_id: &str) -> anyhow::Result<i32> {
+        let repaid = self.loans.iter().find(|l| l.id == loan_id).cloned();
+        let response = self.client.pay_off_loan(loan_id).await?;
+        log::info!("{} -- Paid off loan {}", self.username, loan_id);
+
+        self.loans = response.loans.iter().map(Loan::from).collect();
+        if let Some(loan) = repaid {
+            self.record("repaid", &loan, response.credits).await?;
+        }
+
+        Ok(response.credits)
+    }
+
+    async fn record(&self, event_type: &str, loan: &Loan, credits: i32) -> anyhow::Result<()> {
+        self.storage.persist_loan_event(&DbLoanEvent {
+            user_id: self.user_id.clone(),
+            loan_id: loan.id.clone(),
+            loan_type: format!("{:?}", loan.loan_type).to_uppercase(),
+            event_type: event_type.to_string(),
+            amount: loan.repayment_amount,
+            due_at: loan.due,
+            credits,
+            created_at: Utc::now(),
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::mock::MockGameApi;
+    use crate::storage::memory::MemoryStorage;
+    use spacetraders::responses;
+    use std::sync::Arc;
+
+    const USER_ID: &str = "00000000-0000-0000-0000-000000000001";
+
+    fn policy() -> LoanPolicy {
+        LoanPolicy {
+            pay_off_above: 1_000,
+            cash_reserve: 100,
+            repay_before_due: Duration::hours(12),
+        }
+    }
+
+    fn loan(id: &str, due_in_hours: i64, repayment_amount: i32, status: &str) -> shared::Loan {
+        shared::Loan {
+            id: id.to_string(),
+            due: Utc::now() + Duration::hours(due_in_hours),
+            repayment_amount,
+            status: status.to_string(),
+            loan_type: LoanType::Startup,
+        }
+    }
+
+    fn available(loan_type: LoanType, amount: i32, rate: f64, collateral_required: bool) -> AvailableLoan {
+        AvailableLoan { loan_type, amount, rate, term_in_days: 2, collateral_required }
+    }
+
+    #[test]
+    fn statuses_are_parsed() {
+        assert_eq!(LoanStatus::from("CURRENT"), LoanStatus::Current);
+        assert_eq!(LoanStatus::from("PAID"), LoanStatus::Paid);
+        assert_eq!(LoanStatus::from("PAID_LATE"), LoanStatus::Paid);
+        assert_eq!(LoanStatus::from("DEFAULTED"), LoanStatus::Other("DEFAULTED".to_string()));
+        assert!(Loan::from(&loan("loan-1", 48, 100, "DEFAULTED")).is_outstanding());
+    }
+
+    #[test]
+    fn loans_are_repaid_early_only_with_the_reserve_left() {
+        let now = Utc::now();
+        let loans = vec![Loan::from(&loan("late", 48, 800, "CURRENT")), Loan::from(&loan("soon", 24, 500, "CURRENT"))];
+
+        assert!(repayments(&loans, 1_000, now, &policy()).is_empty());
+        // Soonest due first and only as many as leave the reserve
+        assert_eq!(repayments(&loans, 1_001, now, &policy()), vec!["soon".to_string()]);
+        assert_eq!(repayments(&loans, 1_500, now, &policy()), vec!["soon".to_string(), "late".to_string()]);
+    }
+
+    #[test]
+    fn loans_that_are_nearly_due_are_repaid_out_of_the_reserve() {
+        let now = Utc::now();
+        let loans = vec![Loan::from(&loan("due", 6, 500, "CURRENT")), Loan::from(&loan("paid", 1, 500, "PAID"))];
+
+        assert_eq!(repayments(&loans, 500, now, &policy()), vec!["due".to_string()]);
+        assert!(repayments(&loans, 499, now, &policy()).is_empty());
+    }
+
+    #[test]
+    fn users_borrow_when_short_with_nothing_outstanding() {
+        let outstanding = vec![Loan::from(&loan("loan-1", 48, 500, "CURRENT"))];
+        let paid = vec![Loan::from(&loan("loan-1", 48, 500, "PAID"))];
+
+        assert!(needs_loan(&[], 0, &LoanPolicy { cash_reserve: 0, ..policy() }));
+        assert!(!needs_loan(&[], 1, &LoanPolicy { cash_reserve: 0, ..policy() }));
+        assert!(needs_loan(&paid, 99, &policy()));
+        assert!(!needs_loan(&outstanding, 0, &policy()));
+    }
+
+    #[test]
+    fn the_cheapest_loan_without_collateral_is_picked() {
+        let loans = vec![
+            available(LoanType::Enterprise, 1_000_000, 5.0, true),
+            available(LoanType::Startup, 200_000, 40.0, false),
+        ];
+        assert_eq!(best_loan(&loans), Some(LoanType::Startup));
+
+        let loans = vec![available(LoanType::Startup, 200_000, 40.0, false), available(LoanType::Enterprise, 500_000, 40.0, false)];
+        assert_eq!(best_loan(&loans), Some(LoanType::Enterprise));
+
+        assert_eq!(best_loan(&loans[..0]), None);
+    }
+
+    #[tokio::test]
+    async fn managing_loans_repays_then_borrows_and_records_both() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut repaid = loan("loan-1", 6, 280_000, "PAID");
+        mock.pay_off_loan.push(Ok(responses::PayLoanResponse { credits: 20, loans: vec![repaid.clone()] }));
+        mock.get_available_loans.push(Ok(responses::AvailableLoans { loans: vec![available(LoanType::Startup, 200_000, 40.0, false)] }));
+        mock.request_new_loan.push(Ok(responses::RequestLoan { credits: 200_020, loan: loan("loan-2", 48, 280_000, "CURRENT") }));
+        let storage = Arc::new(MemoryStorage::new());
+
+        repaid.status = "CURRENT".to_string();
+        let mut manager = LoanManager::new(mock.clone(), storage.clone(), USER_ID.to_string(), "user".to_string(), policy(), &[repaid]);
+
+        assert_eq!(manager.committed_credits(Utc::now()), 280_000);
+        assert_eq!(manager.manage(280_020).await.unwrap(), Some(200_020));
+        assert_eq!(mock.calls(), vec!["pay_off_loan(loan-1)", "get_available_loans()", "request_new_loan(Startup)"]);
+        assert_eq!(manager.outstanding(), 1);
+        assert_eq!(manager.committed_credits(Utc::now()), 0);
+
+        let events = storage.with_state(|state| state.loan_events.clone());
+        assert_eq!(events.iter().map(|e| (e.loan_id.as_str(), e.event_type.as_str(), e.credits)).collect::<Vec<_>>(), vec![
+            ("loan-1", "repaid", 20),
+            ("loan-2", "requested", 200_020),
+        ]);
+        assert_eq!(events[1].loan_type, "STARTUP");
+
+        // Nothing left to do
+        assert_eq!(manager.manage(200_020).await.unwrap(), None);
+    }
+}
@@ -1,87 +1,203 @@
+mod cli;
+mod config;
 mod funcs;
+mod fuel_model;
+mod loan_manager;
+mod metrics;
 mod db;
+mod game;
+mod route_reservations;
+mod slippage_model;
+mod survey_scheduler;
+mod ship_purchasing;
+mod ship_upgrades;
 mod user;
 mod ship_machines;
+mod storage;
+mod supervisor;
+
+#[cfg(test)]
+mod test_utils;
+#[cfg(test)]
+mod simulation_tests;
 
-use spacetraders::client;
 use std::env;
+use std::sync::Arc;
 use dotenv::dotenv;
+use crate::cli::{Command, Opt};
+use crate::config::{FleetConfig, ScoutMode, UserConfig};
+use crate::game::GameBackend;
+use crate::route_reservations::RouteReservations;
+use crate::ship_machines::ShipAssignment;
+use crate::storage::StorageClient;
+use crate::supervisor::SupervisorExit;
+use structopt::StructOpt;
+use tokio::sync::watch;
 use tokio::time::Duration;
-use spacetraders::shared::LoanType;
-use spacetraders::errors::SpaceTradersClientError;
-use crate::ship_machines::{ShipAssignment, PollResult};
-use tokio::sync::broadcast;
+
+/// How long to wait before trying to start the fleet again after it failed to start. Doubles each
+/// time it fails again up to `MAX_START_RETRY_DELAY`
+const START_RETRY_DELAY: Duration = Duration::from_secs(10);
+const MAX_START_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Why the fleet couldn't be started
+#[derive(Debug)]
+enum StartError {
+    /// The fleet config doesn't match the game. Trying again won't help
+    Config(anyhow::Error),
+    /// Anything else. I.E. a request that failed. Worth trying again in a while
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for StartError {
+    fn from(e: anyhow::Error) -> Self {
+        StartError::Other(e)
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv().ok();
     env_logger::init();
 
-    let username_base = env::var("USERNAME_BASE").unwrap();
-    let postgres_host = env::var("POSTGRES_HOST").unwrap();
-    let postgres_port = env::var("POSTGRES_PORT").unwrap().parse::<i32>().unwrap();
-    let postgres_username = env::var("POSTGRES_USERNAME").unwrap();
-    let postgres_password = env::var("POSTGRES_PASSWORD").unwrap();
-    let postgres_database = env::var("POSTGRES_DATABASE").unwrap();
-    let enable_scouts = env::var("ENABLE_SCOUTS").unwrap().parse::<bool>().unwrap();
-    let enable_trader = env::var("ENABLE_TRADER").unwrap().parse::<bool>().unwrap();
-    let enable_reset = env::var("ENABLE_RESET").unwrap().parse::<bool>().unwrap();
-    let http_proxy: Option<String> = env::var("HTTP_PROXY").map(Some).unwrap_or(None);
-
-    let pg_pool = db::get_db_pool(postgres_host, postgres_port, postgres_username, postgres_password, postgres_database).await?;
+    match Opt::from_args().command.unwrap_or(Command::Run) {
+        Command::Run => run().await,
+        command => cli::execute(command).await,
+    }
+}
 
-    db::run_migrations(pg_pool.clone()).await?;
+async fn run() -> anyhow::Result<()> {
+    // Listen for a shutdown before doing anything else so that one sent while the fleet is still
+    // starting isn't lost
+    let shutdown = supervisor::shutdown_signal()?;
+    let config = FleetConfig::from_env()?;
+    let http_proxy: Option<String> = env::var("HTTP_PROXY").map(Some).unwrap_or(None);
 
-    // Algorithm. Create the main user account (or get from db). Get the number of locations
-    // in the system. Create (or get from db) X scout accounts (where X is number of locations in
-    // the system). Send each scout account to the location they are assigned.
+    let mut storage = storage::get_storage_from_env().await?;
+    let backend = game::get_backend_from_env(http_proxy).await?;
 
-    let http_client = client::get_http_client(http_proxy);
+    // A postgres reset moves the tables into a backup and creates them again in the same database
+    // so the metrics can keep reading through the storage they started with
+    let metrics_address = env::var("METRICS_ADDRESS").unwrap_or_else(|_| "0.0.0.0:9100".to_string()).parse()?;
+    let metrics_storage = storage.clone();
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(metrics_address, metrics_storage).await {
+            log::error!("Metrics endpoint stopped. Error: {}", e);
+        }
+    });
+
+    // When an API reset occurs all the users will begin to fail making requests and the
+    // supervisor will stop the fleet. Before starting it again we check if the API is in
+    // maintenance mode (status code 503) and if it is then we wait for maintenance mode to end.
+    // After that ends if the main user is unable to make a requests we can assume that the API
+    // has been reset and we need to reset ourselves.
+    let mut retry_delay = START_RETRY_DELAY;
+    loop {
+        let mut terminated = shutdown.clone();
+        tokio::select! {
+            _ = supervisor::wait_out_maintenance(backend.clone()) => {},
+            _ = supervisor::terminated(&mut terminated) => return Ok(()),
+        }
 
-    let my_ip_address = client::get_my_ip_address(http_client.clone()).await?;
-    log::info!("Current IP address: {}", my_ip_address.ip);
+        let started = tokio::select! {
+            started = start_fleet(&config, backend.clone(), storage.clone()) => started,
+            _ = supervisor::terminated(&mut terminated) => return Ok(()),
+        };
 
-    if funcs::is_api_in_maintenance_mode(http_client.clone()).await {
-        loop {
-            log::warn!("Detected SpaceTraders API in maintenance mode (status code 503). Sleeping for 60 seconds and trying again");
-            tokio::time::sleep(Duration::from_secs(60)).await;
+        let users = match started {
+            Ok(Some(users)) => users,
+            Ok(None) => {
+                if !config.enable_reset {
+                    anyhow::bail!("Unable to connect using the main user and resets are disabled");
+                }
 
-            if !funcs::is_api_in_maintenance_mode(http_client.clone()).await {
-                break;
+                log::warn!("Unable to connect using the main user. Assuming an API reset. Backing up data and clearing the database");
+                // A reset isn't interrupted part way through. A shutdown sent meanwhile is picked up
+                // at the top of the loop
+                let reset = async {
+                    storage.reset().await?;
+                    // The tables were moved into the backup so they need to be created again
+                    storage::get_storage_from_env().await
+                };
+
+                match reset.await {
+                    Ok(new_storage) => storage = new_storage,
+                    Err(e) => {
+                        log::error!("Unable to reset the database. Trying again in {:?}. Error: {:?}", retry_delay, e);
+                        retry_delay = match back_off(retry_delay, &mut terminated).await {
+                            Some(delay) => delay,
+                            None => return Ok(()),
+                        };
+                    }
+                }
+                continue;
+            }
+            Err(StartError::Config(e)) => return Err(e),
+            Err(StartError::Other(e)) => {
+                log::error!("Unable to start the fleet. Trying again in {:?}. Error: {:?}", retry_delay, e);
+                retry_delay = match back_off(retry_delay, &mut terminated).await {
+                    Some(delay) => delay,
+                    None => return Ok(()),
+                };
+                continue;
+            }
+        };
+
+        retry_delay = START_RETRY_DELAY;
+        match supervisor::supervise(users, storage.clone(), shutdown.clone()).await? {
+            SupervisorExit::ApiUnavailable => log::warn!("The fleet was stopped. Starting it again once the api is available"),
+            SupervisorExit::Panicked => log::warn!("The fleet was stopped after a user panicked. Starting it again"),
+            SupervisorExit::Terminated => return Ok(()),
+            SupervisorExit::Finished => {
+                log::error!("Every user has stopped");
+                return Ok(());
             }
         }
     }
+}
 
-    // When an API reset occurs all the scouts will being to fail making requests.
-    // As soon as all the scouts fail this pod will restart. Upon restart we will check
-    // if the API is in maintenance mode (status code 503) if it is then we will wait for
-    // maintenance mode to end. After that ends if the main user is unable to make a requests
-    // we can assume that the API has been reset and we need to reset ourselves.
-    let user = user::User::new(
-        http_client.clone(),
-        pg_pool.clone(),
-        format!("{}-main", username_base),
-        ShipAssignment::Trader,
-        "OE".to_string(),
-        None
-    ).await;
-
-    if let Err(user_err) = user {
-        log::error!("Main user error: {}", user_err);
-        if enable_reset {
-            db::reset_db(pg_pool.clone()).await?;
-        }
-        // Now that the tables have been moved we will panic so that the pod will restart and the tables will be recreated
-        panic!("Unable to connect using the main user. Assuming an API reset. Backing up data and clearing the database");
+/// Wait before trying to start the fleet again. Returns how long to wait the next time, or None when
+/// we were asked to shut down in the meantime
+async fn back_off(delay: Duration, shutdown: &mut watch::Receiver<bool>) -> Option<Duration> {
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => Some((delay * 2).min(MAX_START_RETRY_DELAY)),
+        _ = supervisor::terminated(shutdown) => None,
     }
+}
 
-    let mut user = user.unwrap();
+/// Log in (or create) every user in the fleet and make sure that each of them has a ship.
+///
+/// Algorithm. Create the main user account (or get from db). Create every other user in the fleet
+/// config. Then create (or get from db) the scout accounts for each scouted system. Either a pool of
+/// roaming scouts or one scout parked at each location in the system.
+///
+/// Returns None when the main user can't log in.
+async fn start_fleet(config: &FleetConfig, backend: Arc<dyn GameBackend>, storage: StorageClient) -> Result<Option<Vec<user::User>>, StartError> {
+    // Shared by every trader in the fleet so that they don't all pick the same trade
+    let reservations = RouteReservations::new();
+
+    let main_user = config.main_user();
+    let user = match user::User::new(
+        backend.clone(),
+        storage.clone(),
+        config.username(&main_user.name),
+        main_user,
+        config.trading.clone(),
+        reservations.clone(),
+    ).await {
+        Ok(user) => user,
+        Err(user_err) => {
+            log::error!("Main user error: {}", user_err);
+            return Ok(None);
+        }
+    };
 
     let system_info = user.get_systems().await?;
+    config.validate_systems(&system_info).map_err(StartError::Config)?;
 
     for system in &system_info.systems {
         for location in &system.locations {
-            db::persist_system_location(pg_pool.clone(), system, location).await?;
+            storage.persist_system_location(system, location).await?;
         }
     }
 
@@ -98,185 +214,82 @@ async fn main() -> anyhow::Result<()> {
     log::info!("## End System Messages ------------------------------------------------------------");
 
     let mut users = Vec::new();
-    let mut user_handles = Vec::new();
 
-    if enable_scouts {
-        for system in &system_info.systems {
-            // Skip NA7 for now since this is a under-developed system with no resources
-            if system.symbol == "NA7" {
-                continue;
-            }
-
-            for location in &system.locations {
-                let mut scout_user = user::User::new(
-                    http_client.clone(),
-                    pg_pool.clone(),
-                    format!("{}-scout-{}", username_base, location.symbol),
-                    ShipAssignment::Scout,
-                    system.symbol.clone(),
-                    Some(location.symbol.clone()),
-                ).await?;
-
-                // 1. if the user doesn't have enough credits take out a startup loan
-                log::info!("Scout {} -- credits {}", scout_user.username, scout_user.credits);
-                if scout_user.credits == 0 {
-                    log::info!("Scout {} -- Requesting new {:?} loan", scout_user.username, LoanType::Startup);
-                    // assume that if the user has 0 credits that the user needs to take out a loan
-                    scout_user.request_new_loan(LoanType::Startup).await?;
-                }
-
-                // 2. if the user doesn't have any ships then buy the fastest one that the user can afford that is in the system assigned to the scout
-                if scout_user.ship_machines.is_empty() {
-                    scout_user.purchase_fastest_ship().await?;
-                }
+    if config.main_user().enabled {
+        users.push(setup_user(user).await?);
+    }
 
-                users.push(scout_user);
-            }
-        }
+    for user_config in config.users.iter().skip(1).filter(|u| u.enabled) {
+        let user = user::User::new(
+            backend.clone(),
+            storage.clone(),
+            config.username(&user_config.name),
+            user_config,
+            config.trading.clone(),
+            reservations.clone(),
+        ).await?;
+
+        users.push(setup_user(user).await?);
     }
 
-    // One task per user, each of those will create new tasks for each of it's ships
-    // The main task will handle upgrades by checking the users credits and ships periodically
-    // The main task will be able to create new ships and push them into the ship_handles array to
-    // be awaited upon later
-    // That's all that we need for creating new ships, but upgrading ships we need to be able to
-    // notify a ship task that it needs to be upgraded
-
-    if enable_trader {
-        // Setup our main user
-        // 1. if the user doesn't have enough credits take out a startup loan
-        if user.credits == 0 {
-            log::info!("User {} -- Requesting new {:?} loan", user.username, LoanType::Startup);
-            // assume that if the user has 0 credits that the user needs to take out a loan
-            user.request_new_loan(LoanType::Startup).await?;
+    for system in &system_info.systems {
+        if !config.scouts_system(&system.symbol) {
+            continue;
         }
 
-        // 2. if the user doesn't have any ships then buy the largest one that the user can afford
-        if user.ship_machines.is_empty() {
-            user.purchase_largest_ship().await?;
+        // Roaming scouts don't have a location so the survey scheduler sends them wherever they are
+        // needed
+        let scout_configs: Vec<UserConfig> = match config.scouts.mode {
+            ScoutMode::Parked => system.locations.iter()
+                .map(|location| UserConfig {
+                    name: format!("scout-{}", location.symbol),
+                    assignment: ShipAssignment::Scout,
+                    system: system.symbol.clone(),
+                    location: Some(location.symbol.clone()),
+                    enabled: true,
+                    purchasing: config.scouts.purchasing.clone(),
+                    loans: config.scouts.loans.clone(),
+                })
+                .collect(),
+            ScoutMode::Roaming => (1..=config.scouts.pool_size)
+                .map(|n| UserConfig {
+                    name: format!("scout-{}-{}", system.symbol, n),
+                    assignment: ShipAssignment::Scout,
+                    system: system.symbol.clone(),
+                    location: None,
+                    enabled: true,
+                    purchasing: config.scouts.purchasing.clone(),
+                    loans: config.scouts.loans.clone(),
+                })
+                .collect(),
+        };
+
+        for scout_config in scout_configs {
+            let scout_user = user::User::new(
+                backend.clone(),
+                storage.clone(),
+                config.username(&scout_config.name),
+                &scout_config,
+                config.trading.clone(),
+                reservations.clone(),
+            ).await?;
+
+            users.push(setup_user(scout_user).await?);
         }
-
-        users.push(user);
     }
 
-    let (kill_switch_tx, _) = broadcast::channel::<bool>(2);
-    for user in users {
-        let mut user = user.clone();
-        let pg_pool = pg_pool.clone();
-        let kill_switch_tx = kill_switch_tx.clone();
-        let mut kill_switch_rx = kill_switch_tx.subscribe();
-        user_handles.push(tokio::spawn(async move {
-            let mut prev_user_credits = 0;
-            loop {
-                if let Ok(value) = kill_switch_rx.try_recv() {
-                    log::error!("Received kill switch value {}", value);
-                    return;
-                }
-
-                if user.ship_machines.is_empty() {
-                    log::error!("User {} has no ships and therefore cannot make progress. Quitting this user", user.username);
-                    return;
-                }
-
-                for machine in &mut user.ship_machines {
-                    match machine.poll().await {
-                        Ok(poll_result) => {
-                            // TODO: Maybe there will be some signals that come back from the tick
-                            //       function that we should close and respawn the task... or handle errors
-                            //       or something like that
-                            if let Some(poll_result) = poll_result {
-                                match poll_result {
-                                    PollResult::UpdateCredits(credits) => user.credits = credits,
-                                    PollResult::ConvertToNewMachine(new_machine) => panic!("convert to a new machine"),
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            if let Some(e) = e.downcast_ref::<SpaceTradersClientError>() {
-                                match e {
-                                    // TODO: There is one thing that concerns me about this... if the service unavailable
-                                    //       message lasts for less than three minutes then the pod could end up in
-                                    //       a state where some of the tasks have panic'd and others have not which
-                                    //       would keep the pod running... consider how to fix this... later
-                                    SpaceTradersClientError::ServiceUnavailable => {
-                                        kill_switch_tx.send(true).expect("Unable to send kill switch");
-                                        panic!("Caught a service unavailable error. Sending kill switch and restarting the pod");
-                                    },
-                                    SpaceTradersClientError::Unauthorized => {
-                                        kill_switch_tx.send(true).expect("Unable to send kill switch");
-                                        panic!("Api returned Unauthorized response. Sending kill switch and restarting the pod");
-                                    },
-                                    SpaceTradersClientError::ApiError(e) => {
-                                        // If we received an Api error lets pessimistically reset
-                                        // the machine so we don't have to worry about machines
-                                        // getting stuck. Later we might check an error code before
-                                        // we do this. I.E. {"error":{"message":"Good is not listed in planet marketplace.","code":2001}}
-                                        // {"error":{"message":"Good quantity is not available on planet.","code":2006}}
-                                        // is the one I'm currently targeting
-                                        log::error!("{}:{} -- Caught Api error {}. Resetting machine", user.username, machine.get_ship_id(), e);
-                                        match machine.reset().await {
-                                            Ok(_) => log::info!("{}:{} -- Was reset", user.username, machine.get_ship_id()),
-                                            Err(e) => log::error!("{}:{} -- Was unable to be reset: {}", user.username, machine.get_ship_id(), e),
-                                        };
-                                    },
-                                    // NOTE: All other errors we are just going to skip because the state machine
-                                    //       will just try it again... which is fine
-                                    // SpaceTradersClientError::Http(_) => {}
-                                    // SpaceTradersClientError::ApiError(_) => {}
-                                    // SpaceTradersClientError::TooManyRetries => {}
-                                    // SpaceTradersClientError::JsonParse(_) => {}
-                                    // TODO: Try resetting the machine if we get an unexpected
-                                    // error
-                                    other_error => {
-                                        log::error!("Caught a space traders client. Error: {}", other_error);
-                                    }
-                                }
-                            } else {
-                                panic!("Caught unexpected error: {:?}", e);
-                            }
-                        }
-                    }
-                }
-
-                if prev_user_credits != user.credits {
-                    log::info!("{} -- Credits {}", user.username, user.credits);
-                    prev_user_credits = user.credits;
-
-                    let user_ships = user.get_my_ships().await.unwrap();
-                    db::persist_user_stats(pg_pool.clone(), &user.id, user.credits, &user_ships.ships)
-                        .await.unwrap();
-
-                    // We want to keep a base amount of 500k but as we get more ships it is more
-                    // costly to fill them with goods so we add 75k per ship to make sure we don't
-                    // go broke
-                    if user.credits > (user.ship_machines.len() as i32 * 1_000_000) && user.ship_machines.len() < 50 {
-                        match user.purchase_largest_ship().await {
-                            Ok(_) => {}
-                            Err(e) => log::error!("{} -- Error occurred while purchasing a ship. Error: {}", user.username, e)
-                        };
-                    }
+    Ok(Some(users))
+}
 
-                    // After we are millionaires we should probably pay off our loans
-                    if user.credits > 1_000_000 && user.outstanding_loans > 0 {
-                        let loan = user.loans.first().unwrap();
-                        match user.pay_off_loan(&loan.id).await {
-                            Ok(pay_loan_response) => {
-                                log::info!("{} -- User paid off loan id {}", user.username, loan.id);
-                                user.loans = pay_loan_response.loans.clone();
-                                user.outstanding_loans = pay_loan_response.loans.iter().filter(|l| { !l.status.contains("PAID") }).count();
-                                user.credits = pay_loan_response.credits;
-                            }
-                            Err(e) => log::error!("{} -- Unable to pay off loan id {}. Error: {}", user.username, loan.id, e)
-                        }
-                    }
-                }
+/// Make sure a user can start making progress. Take out a loan if they are broke (see
+/// loan_manager) and buy their first ship if they don't have one (see ship_purchasing).
+async fn setup_user(mut user: user::User) -> anyhow::Result<user::User> {
+    log::info!("User {} -- credits {}", user.username, user.credits);
+    user.manage_loans().await?;
 
-                tokio::time::sleep(Duration::from_secs(1)).await;
-            }
-        }));
+    if user.ship_machines.is_empty() {
+        user.purchase_best_ship().await?;
     }
 
-    futures::future::join_all(user_handles).await;
-
-    Ok(())
+    Ok(user)
 }
@@ -0,0 +1,339 @@
+// Prometheus metrics for the running daemon, served from METRICS_ADDRESS (default 0.0.0.0:9100)
+// at /metrics. Api calls, ship states and machine resets are recorded as they happen. Credits,
+// market freshness and trades are already in storage so they are read whenever the endpoint is
+// scraped.
+use crate::ship_machines::ShipAssignment;
+use crate::storage::StorageClient;
+use chrono::{Duration, Utc};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use spacetraders::errors::SpaceTradersClientError;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+
+/// Upper bounds in seconds of the api latency histogram buckets
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // Not cumulative. Anything above the last bucket is only in count
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|b| value <= *b) {
+            self.buckets[bucket] += 1;
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct MetricsState {
+    // By endpoint
+    api_latency: BTreeMap<String, Histogram>,
+    // By endpoint and error variant
+    api_errors: BTreeMap<(String, String), u64>,
+    // The machine and state of each ship by ship id
+    ship_states: BTreeMap<String, (String, String)>,
+    // By machine
+    machine_resets: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+}
+
+/// The metrics shared by the whole daemon
+pub fn global() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+    METRICS.get_or_init(Metrics::default)
+}
+
+/// The variant name so that errors can be counted without their messages
+fn error_variant(error: &SpaceTradersClientError) -> &'static str {
+    match error {
+        SpaceTradersClientError::Http(_) => "Http",
+        SpaceTradersClientError::ApiError(_) => "ApiError",
+        SpaceTradersClientError::TooManyRetries => "TooManyRetries",
+        SpaceTradersClientError::JsonParse(_) => "JsonParse",
+        SpaceTradersClientError::ServiceUnavailable => "ServiceUnavailable",
+        SpaceTradersClientError::Unauthorized => "Unauthorized",
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn describe(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+}
+
+impl Metrics {
+    pub fn record_api_call(&self, endpoint: &str, elapsed: std::time::Duration, error: Option<&SpaceTradersClientError>) {
+        let mut state = self.state.lock().unwrap();
+
+        state.api_latency.entry(endpoint.to_string()).or_default().observe(elapsed.as_secs_f64());
+        if let Some(error) = error {
+            *state.api_errors.entry((endpoint.to_string(), error_variant(error).to_string())).or_default() += 1;
+        }
+    }
+
+    pub fn record_ship_state(&self, ship_id: &str, machine: &ShipAssignment, state: &str) {
+        self.state.lock().unwrap().ship_states.insert(ship_id.to_string(), (machine.to_string(), state.to_string()));
+    }
+
+    /// Stop counting a ship that has left the fleet
+    pub fn forget_ship(&self, ship_id: &str) {
+        self.state.lock().unwrap().ship_states.remove(ship_id);
+    }
+
+    /// Stop counting every ship. Used once the fleet has stopped so ships that don't come back
+    /// after a restart or a reset aren't reported forever
+    pub fn forget_ships(&self) {
+        self.state.lock().unwrap().ship_states.clear();
+    }
+
+    pub fn record_reset(&self, machine: &ShipAssignment) {
+        *self.state.lock().unwrap().machine_resets.entry(machine.to_string()).or_default() += 1;
+    }
+
+    /// Everything in the prometheus text format
+    pub async fn render(&self, storage: &StorageClient) -> anyhow::Result<String> {
+        let mut out = self.render_recorded();
+        let now = Utc::now();
+
+        let users = storage.get_user_summaries().await?;
+        describe(&mut out, "spacemonger_user_credits", "gauge", "Credits each user had when their stats were last recorded");
+        for user in &users {
+            if let Some(credits) = user.credits {
+                let _ = writeln!(out, "spacemonger_user_credits{{user=\"{}\"}} {}", escape(&user.username), credits);
+            }
+        }
+
+        describe(&mut out, "spacemonger_trades_completed_last_hour", "gauge", "Trades each user completed in the last hour");
+        let mut profits = Vec::new();
+        for user in &users {
+            let trades = storage.get_trades(&user.id, now - Duration::hours(1)).await?;
+            let _ = writeln!(out, "spacemonger_trades_completed_last_hour{{user=\"{}\"}} {}", escape(&user.username), trades.len());
+            profits.push((&user.username, trades.iter().fold(0i64, |acc, t| acc + i64::from(t.profit))));
+        }
+
+        describe(&mut out, "spacemonger_trade_profit_last_hour", "gauge", "Profit in credits from the trades each user completed in the last hour");
+        for (username, profit) in profits {
+            let _ = writeln!(out, "spacemonger_trade_profit_last_hour{{user=\"{}\"}} {}", escape(username), profit);
+        }
+
+        describe(&mut out, "spacemonger_market_data_age_seconds", "gauge", "Seconds since the market at each location was last recorded");
+        for market in storage.get_market_freshness(now - Duration::hours(1)).await? {
+            if let Some(surveyed_at) = market.surveyed_at {
+                let _ = writeln!(out, "spacemonger_market_data_age_seconds{{location=\"{}\"}} {}", escape(&market.location), (now - surveyed_at).num_seconds());
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn render_recorded(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        describe(&mut out, "spacemonger_api_request_duration_seconds", "histogram", "How long calls to the game took by endpoint");
+        for (endpoint, histogram) in &state.api_latency {
+            let endpoint = escape(endpoint);
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(out, "spacemonger_api_request_duration_seconds_bucket{{endpoint=\"{}\",le=\"{}\"}} {}", endpoint, bound, cumulative);
+            }
+            let _ = writeln!(out, "spacemonger_api_request_duration_seconds_bucket{{endpoint=\"{}\",le=\"+Inf\"}} {}", endpoint, histogram.count);
+            let _ = writeln!(out, "spacemonger_api_request_duration_seconds_sum{{endpoint=\"{}\"}} {}", endpoint, histogram.sum);
+            let _ = writeln!(out, "spacemonger_api_request_duration_seconds_count{{endpoint=\"{}\"}} {}", endpoint, histogram.count);
+        }
+
+        describe(&mut out, "spacemonger_api_errors_total", "counter", "Calls to the game that failed by endpoint and SpaceTradersClientError variant");
+        for ((endpoint, error), count) in &state.api_errors {
+            let _ = writeln!(out, "spacemonger_api_errors_total{{endpoint=\"{}\",error=\"{}\"}} {}", escape(endpoint), error, count);
+        }
+
+        let mut ships: BTreeMap<&(String, String), u64> = BTreeMap::new();
+        for machine_state in state.ship_states.values() {
+            *ships.entry(machine_state).or_default() += 1;
+        }
+
+        describe(&mut out, "spacemonger_ships", "gauge", "Ships in each state of each machine");
+        for ((machine, machine_state), count) in ships {
+            let _ = writeln!(out, "spacemonger_ships{{machine=\"{}\",state=\"{}\"}} {}", machine, machine_state, count);
+        }
+
+        describe(&mut out, "spacemonger_machine_resets_total", "counter", "Ship machines that were reset after an error they couldn't recover from");
+        for (machine, count) in &state.machine_resets {
+            let _ = writeln!(out, "spacemonger_machine_resets_total{{machine=\"{}\"}} {}", machine, count);
+        }
+
+        out
+    }
+}
+
+async fn handle(request: Request<Body>, metrics: &Metrics, storage: StorageClient) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => match metrics.render(&storage).await {
+            Ok(body) => {
+                response.headers_mut().insert(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+                *response.body_mut() = Body::from(body);
+            }
+            Err(e) => {
+                log::error!("Unable to render metrics. Error: {}", e);
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        },
+        _ => *response.status_mut() = StatusCode::NOT_FOUND,
+    }
+
+    response
+}
+
+/// Serve the global metrics until the daemon exits
+pub async fn serve(address: SocketAddr, storage: StorageClient) -> anyhow::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let storage = storage.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let storage = storage.clone();
+
+                async move { Ok::<_, Infallible>(handle(request, global(), storage).await) }
+            }))
+        }
+    });
+
+    log::info!("Serving metrics at http://{}/metrics", address);
+    Server::try_bind(&address)?.serve(make_service).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DbUserTrade;
+    use crate::storage::Storage;
+    use crate::storage::memory::MemoryStorage;
+    use crate::test_utils::{market, system};
+    use spacetraders::shared::{Good, LocationType};
+    use std::sync::Arc;
+
+    fn trade(user_id: &str, trade_id: &str, profit: i32) -> DbUserTrade {
+        DbUserTrade {
+            trade_id: trade_id.to_string(),
+            user_id: user_id.to_string(),
+            ship_id: "ship-1".to_string(),
+            good: "Metals".to_string(),
+            purchase_location: "OE-PM".to_string(),
+            sell_location: "OE-UC".to_string(),
+            quantity: 10,
+            cost: 1_000,
+            revenue: 1_000 + profit,
+            fuel_cost: 0,
+            profit,
+            predicted_profit: profit,
+            duration_seconds: 300,
+            started_at: Utc::now() - Duration::seconds(300),
+            completed_at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn recorded_metrics_are_rendered_in_the_prometheus_format() {
+        let metrics = Metrics::default();
+        metrics.record_api_call("create_flight_plan", std::time::Duration::from_millis(200), None);
+        metrics.record_api_call("create_flight_plan", std::time::Duration::from_millis(700), Some(&SpaceTradersClientError::TooManyRetries));
+        metrics.record_ship_state("ship-1", &ShipAssignment::Trader, "WaitForArrival");
+        metrics.record_ship_state("ship-2", &ShipAssignment::Trader, "WaitForArrival");
+        metrics.record_ship_state("ship-3", &ShipAssignment::Scout, "Wait");
+        metrics.record_ship_state("ship-2", &ShipAssignment::Trader, "PickBestTrade");
+        metrics.forget_ship("ship-3");
+        metrics.record_reset(&ShipAssignment::Trader);
+
+        let out = metrics.render(&(Arc::new(MemoryStorage::new()) as StorageClient)).await.unwrap();
+
+        for line in [
+            "spacemonger_api_request_duration_seconds_bucket{endpoint=\"create_flight_plan\",le=\"0.25\"} 1",
+            "spacemonger_api_request_duration_seconds_bucket{endpoint=\"create_flight_plan\",le=\"1\"} 2",
+            "spacemonger_api_request_duration_seconds_bucket{endpoint=\"create_flight_plan\",le=\"+Inf\"} 2",
+            "spacemonger_api_request_duration_seconds_count{endpoint=\"create_flight_plan\"} 2",
+            "spacemonger_api_errors_total{endpoint=\"create_flight_plan\",error=\"TooManyRetries\"} 1",
+            "spacemonger_ships{machine=\"trader\",state=\"PickBestTrade\"} 1",
+            "spacemonger_ships{machine=\"trader\",state=\"WaitForArrival\"} 1",
+            "spacemonger_machine_resets_total{machine=\"trader\"} 1",
+        ] {
+            assert!(out.lines().any(|l| l == line), "Missing {} in\n{}", line, out);
+        }
+        assert!(!out.contains("machine=\"scout\""));
+    }
+
+    #[tokio::test]
+    async fn ships_are_forgotten_when_the_fleet_stops() {
+        let metrics = Metrics::default();
+        metrics.record_ship_state("ship-1", &ShipAssignment::Trader, "WaitForArrival");
+        metrics.record_ship_state("ship-2", &ShipAssignment::Scout, "Wait");
+        metrics.forget_ships();
+
+        let out = metrics.render(&(Arc::new(MemoryStorage::new()) as StorageClient)).await.unwrap();
+
+        assert!(!out.contains("spacemonger_ships{"), "{}", out);
+    }
+
+    #[tokio::test]
+    async fn credits_trades_and_market_freshness_are_read_from_storage() {
+        let storage = Arc::new(MemoryStorage::new());
+        let user = storage.persist_user("trader".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+        storage.persist_user_stats(&user.id, 25_000, &[]).await.unwrap();
+        let oe = system("OE", &[("OE-PM", LocationType::Planet, 20, -25), ("OE-UC", LocationType::GasGiant, -75, 80)]);
+        for location in &oe.locations {
+            storage.persist_system_location(&oe, location).await.unwrap();
+        }
+        storage.persist_market_data("OE-PM", "ship-1", &market(Good::Metals, 10, 1_000)).await.unwrap();
+        storage.persist_trade(&trade(&user.id, "trade-1", 300)).await.unwrap();
+        storage.persist_trade(&trade(&user.id, "trade-2", -50)).await.unwrap();
+
+        let out = Metrics::default().render(&(storage as StorageClient)).await.unwrap();
+
+        for line in [
+            "spacemonger_user_credits{user=\"trader\"} 25000",
+            "spacemonger_trades_completed_last_hour{user=\"trader\"} 2",
+            "spacemonger_trade_profit_last_hour{user=\"trader\"} 250",
+            "spacemonger_market_data_age_seconds{location=\"OE-PM\"} 0",
+        ] {
+            assert!(out.lines().any(|l| l == line), "Missing {} in\n{}", line, out);
+        }
+        // Never recorded so it has no age
+        assert!(!out.contains("location=\"OE-UC\""));
+    }
+
+    #[tokio::test]
+    async fn only_metrics_are_served() {
+        let metrics = Metrics::default();
+        let storage: StorageClient = Arc::new(MemoryStorage::new());
+
+        let response = handle(Request::get("/metrics").body(Body::empty()).unwrap(), &metrics, storage.clone()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = handle(Request::get("/").body(Body::empty()).unwrap(), &metrics, storage).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
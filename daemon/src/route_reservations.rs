@@ -0,0 +1,147 @@
+// Every trader picks its own trade so without some coordination they all see the same best route
+// and pile onto it, crashing the price for everyone. Traders claim the route they picked along
+// with how much they expect to move, and every other trader plans as if those units have already
+// been bought and sold. The book is shared by the whole fleet and only lives in the daemon.
+use spacemonger_core::planner::TradePlan;
+use chrono::{DateTime, Duration, Utc};
+use spacetraders::shared::Good;
+use std::sync::{Arc, Mutex};
+
+/// A claim is dropped this long after the trade should have finished even if the ship never
+/// released it. I.E. the daemon lost track of the ship
+const GRACE_PERIOD_MINUTES: i64 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reservation {
+    pub ship_id: String,
+    pub good: Good,
+    pub purchase_location: String,
+    pub sell_location: String,
+    pub units: i32,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RouteReservations {
+    reservations: Arc<Mutex<Vec<Reservation>>>,
+}
+
+impl RouteReservations {
+    pub fn new() -> RouteReservations {
+        RouteReservations::default()
+    }
+
+    /// Claim a route for a ship. A ship only ever has one claim so this replaces any earlier one
+    pub fn claim(&self, ship_id: &str, plan: &TradePlan, units: i32) {
+        let expires_at = Utc::now() + Duration::seconds(plan.flight_time.ceil() as i64) + Duration::minutes(GRACE_PERIOD_MINUTES);
+
+        let mut reservations = self.reservations.lock().unwrap();
+        reservations.retain(|r| r.ship_id != ship_id);
+        reservations.push(Reservation {
+            ship_id: ship_id.to_string(),
+            good: plan.good,
+            purchase_location: plan.purchase_location.clone(),
+            sell_location: plan.sell_location.clone(),
+            units,
+            expires_at,
+        });
+    }
+
+    pub fn release(&self, ship_id: &str) {
+        self.reservations.lock().unwrap().retain(|r| r.ship_id != ship_id);
+    }
+
+    /// Every claim that hasn't expired
+    pub fn active(&self) -> Vec<Reservation> {
+        let now = Utc::now();
+
+        let mut reservations = self.reservations.lock().unwrap();
+        reservations.retain(|r| r.expires_at > now);
+        reservations.clone()
+    }
+
+    /// What is left of a trade once everyone else's claims are taken out of it. The markets lose
+    /// the claimed units and the prices move against us by `impact` of the listed price for every
+    /// market's worth of claimed units, the same way `scoring::DepthSlippage` moves them.
+    pub fn remaining(&self, ship_id: &str, mut plan: TradePlan, impact: f64) -> TradePlan {
+        let others: Vec<Reservation> = self.active().into_iter()
+            .filter(|r| r.ship_id != ship_id && r.good == plan.good)
+            .collect();
+
+        let bought: i32 = others.iter().filter(|r| r.purchase_location == plan.purchase_location).map(|r| r.units).sum();
+        let sold: i32 = others.iter().filter(|r| r.sell_location == plan.sell_location).map(|r| r.units).sum();
+
+        if bought > 0 {
+            let moved = impact * f64::from(bought) / f64::from(plan.purchase_quantity.max(1));
+            plan.purchase_price_per_unit = (f64::from(plan.purchase_price_per_unit) * (1.0 + moved)).ceil() as i32;
+            plan.purchase_quantity = (plan.purchase_quantity - bought).max(0);
+        }
+
+        if sold > 0 {
+            let moved = impact * f64::from(sold) / f64::from(plan.sell_quantity.max(1));
+            plan.sell_price_per_unit = (f64::from(plan.sell_price_per_unit) * (1.0 - moved).max(0.0)).floor() as i32;
+            plan.sell_quantity = (plan.sell_quantity - sold).max(0);
+        }
+
+        plan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spacemonger_core::planner::LegType;
+    use crate::test_utils::{leg, trade_plan};
+
+    fn direct(good: Good, purchase_location: &str, sell_location: &str) -> TradePlan {
+        trade_plan(good, vec![leg(LegType::Flight, purchase_location, sell_location, 20.0)])
+    }
+
+    #[test]
+    fn other_ships_claims_eat_into_the_market() {
+        let reservations = RouteReservations::new();
+        reservations.claim("ship-2", &direct(Good::Metals, "OE-PM", "OE-UC"), 500);
+        // Same good, only the sell side overlaps
+        reservations.claim("ship-3", &direct(Good::Metals, "OE-PM-TR", "OE-UC"), 500);
+        // Different good
+        reservations.claim("ship-4", &direct(Good::Chemicals, "OE-PM", "OE-UC"), 500);
+
+        let remaining = reservations.remaining("ship-1", direct(Good::Metals, "OE-PM", "OE-UC"), 0.5);
+
+        assert_eq!(remaining.purchase_quantity, 500);
+        assert_eq!(remaining.purchase_price_per_unit, 125);
+        assert_eq!(remaining.sell_quantity, 1_000);
+        assert_eq!(remaining.sell_price_per_unit, 112);
+
+        // A ship's own claim doesn't count against it
+        let own = reservations.remaining("ship-2", direct(Good::Metals, "OE-PM", "OE-UC"), 0.5);
+        assert_eq!(own.purchase_quantity, 1_000);
+        assert_eq!(own.sell_quantity, 1_500);
+    }
+
+    #[test]
+    fn claims_are_replaced_released_and_expire() {
+        let reservations = RouteReservations::new();
+        reservations.claim("ship-1", &direct(Good::Metals, "OE-PM", "OE-UC"), 500);
+        reservations.claim("ship-1", &direct(Good::Chemicals, "OE-PM", "OE-UC"), 200);
+
+        let active = reservations.active();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].good, Good::Chemicals);
+
+        reservations.release("ship-1");
+        assert!(reservations.active().is_empty());
+
+        reservations.claim("ship-1", &direct(Good::Metals, "OE-PM", "OE-UC"), 500);
+        reservations.reservations.lock().unwrap()[0].expires_at = Utc::now() - Duration::seconds(1);
+        assert!(reservations.active().is_empty());
+    }
+
+    #[test]
+    fn clones_share_the_same_book() {
+        let reservations = RouteReservations::new();
+        reservations.clone().claim("ship-1", &direct(Good::Metals, "OE-PM", "OE-UC"), 500);
+
+        assert_eq!(reservations.active().len(), 1);
+    }
+}
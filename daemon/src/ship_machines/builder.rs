@@ -1,21 +1,22 @@
-use spacetraders::client::Client;
-use sqlx::PgPool;
-use crate::ship_machines::{ShipAssignment, ShipMachine};
+use crate::config::TradingConfig;
+use crate::route_reservations::RouteReservations;
+use crate::game::GameClient;
+use crate::storage::StorageClient;
+use crate::ship_machines::{MachineCheckpoint, ShipAssignment, ShipMachine};
 use crate::ship_machines::trader::Trader;
 use crate::ship_machines::scout::Scout;
 use crate::ship_machines::system_change::SystemChange;
 use spacetraders::shared;
+use tokio::sync::mpsc;
 
 #[derive(Debug, Clone)]
 pub struct ShipMachineBuilder {
-    client: Option<Client>,
-    pg_pool: Option<PgPool>,
+    client: Option<GameClient>,
+    storage: Option<StorageClient>,
     assignment: Option<ShipAssignment>,
-
-    // Only one of these should be present at any time
-    trader_machine: Option<Trader>,
-    scout_machine: Option<Scout>,
-    system_change_machine: Option<SystemChange>,
+    trading: TradingConfig,
+    reservations: RouteReservations,
+    roaming: bool,
 
     user_id: Option<String>,
     username: Option<String>,
@@ -28,11 +29,11 @@ impl ShipMachineBuilder {
     pub fn new() -> ShipMachineBuilder {
         ShipMachineBuilder {
             client: None,
-            pg_pool: None,
+            storage: None,
             assignment: None,
-            trader_machine: None,
-            scout_machine: None,
-            system_change_machine: None,
+            trading: TradingConfig::default(),
+            reservations: RouteReservations::new(),
+            roaming: false,
             user_id: None,
             username: None,
             system: None,
@@ -41,112 +42,249 @@ impl ShipMachineBuilder {
         }
     }
 
-    pub fn client(&mut self, client: Client) -> &mut ShipMachineBuilder {
+    pub fn client(&mut self, client: GameClient) -> &mut ShipMachineBuilder {
         self.client = Some(client);
         self
     }
 
-    pub fn pg_pool(&mut self, pg_pool: PgPool) -> &mut Self {
-        let mut new = self;
-        new.pg_pool = Some(pg_pool);
+    pub fn storage(&mut self, storage: StorageClient) -> &mut Self {
+        let new = self;
+        new.storage = Some(storage);
         new
     }
 
     pub fn user_id(&mut self, user_id: String) -> &mut Self {
-        let mut new = self;
+        let new = self;
         new.user_id = Some(user_id);
         new
     }
 
     pub fn username(&mut self, username: String) -> &mut Self {
-        let mut new = self;
+        let new = self;
         new.username = Some(username);
         new
     }
 
     pub fn system(&mut self, system: String) -> &mut Self {
-        let mut new = self;
+        let new = self;
         new.system = Some(system);
         new
     }
 
     pub fn location(&mut self, location: String) -> &mut Self {
-        let mut new = self;
+        let new = self;
         new.location = Some(location);
         new
     }
 
     pub fn assignment(&mut self, assignment: ShipAssignment) -> &mut Self {
-        let mut new = self;
+        let new = self;
         new.assignment = Some(assignment);
         new
     }
 
+    pub fn trading(&mut self, trading: TradingConfig) -> &mut Self {
+        let new = self;
+        new.trading = trading;
+        new
+    }
+
+    pub fn reservations(&mut self, reservations: RouteReservations) -> &mut Self {
+        let new = self;
+        new.reservations = reservations;
+        new
+    }
+
+    /// Scouts that roam their system rather than staying at location
+    pub fn roaming(&mut self, roaming: bool) -> &mut Self {
+        let new = self;
+        new.roaming = roaming;
+        new
+    }
+
     pub fn ship(&mut self, ship: shared::Ship) -> &mut Self {
-        let mut new = self;
+        let new = self;
         new.ship = Some(ship);
         new
     }
 
-    pub fn build(&self) -> anyhow::Result<ShipMachine> {
+    /// Build the machine for a ship. If the ship has a checkpoint from a previous run the machine
+    /// is restored from it, even when it was a different kind of machine than the assignment
+    /// (I.E. a trader that was part way through changing systems).
+    pub async fn build(&self) -> anyhow::Result<ShipMachine> {
         let mut trader_machine = None;
         let mut scout_machine = None;
         let mut system_change_machine = None;
 
         let client = self.client.as_ref().expect("client is required");
-        let pg_pool = self.pg_pool.as_ref().expect("pg_pool is required");
+        let storage = self.storage.as_ref().expect("storage is required");
         let ship = self.ship.as_ref().expect("ship is required");
         let user_id = self.user_id.as_ref().expect("user_id is required");
         let username = self.username.as_ref().expect("username is required");
         let system = self.system.as_ref().expect("system is required");
 
-        match self.assignment.as_ref().expect("a ship assignment is required when building a ship") {
-            ShipAssignment::Trader => {
-                trader_machine = Some(Trader::new(
-                    client.clone(),
-                    pg_pool.clone(),
-                    user_id.clone(),
-                    username.clone(),
-                    system.clone(),
-                    ship.clone(),
-                ));
+        let checkpoint = match storage.get_ship_machine_state(user_id, &ship.id).await? {
+            Some(db_state) => match MachineCheckpoint::from_db(&db_state) {
+                Ok(checkpoint) => {
+                    log::debug!("{}:{} -- Found {} checkpoint from {}", username, ship.id, db_state.machine_type, db_state.modified_at);
+                    Some(checkpoint)
+                },
+                Err(e) => {
+                    log::warn!("{}:{} -- Unable to read the ship's checkpoint. Starting from scratch. Error: {}", username, ship.id, e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        match checkpoint {
+            Some(MachineCheckpoint::Trader(checkpoint)) => {
+                log::info!("{}:{} -- Resuming trader from checkpoint", username, ship.id);
+                let mut trader = Trader::new(client.clone(), storage.clone(), user_id.clone(), username.clone(), system.clone(), ship.clone());
+                trader.trading = self.trading.clone();
+                trader.reservations = self.reservations.clone();
+                trader.restore(checkpoint);
+                trader_machine = Some(trader);
             }
-            ShipAssignment::Scout => {
-                scout_machine = Some(Scout::new(
-                    client.clone(),
-                    pg_pool.clone(),
-                    user_id.clone(),
-                    username.clone(),
-                    system.clone(),
-                    self.location.as_ref().expect("location is required when building a scout").clone(),
-                    ship.clone(),
-                ));
+            Some(MachineCheckpoint::Scout(checkpoint)) => {
+                log::info!("{}:{} -- Resuming scout from checkpoint", username, ship.id);
+                let mut scout = Scout::new(client.clone(), storage.clone(), user_id.clone(), username.clone(), system.clone(), checkpoint.location.clone(), ship.clone());
+                scout.roaming = self.roaming;
+                scout.restore(checkpoint);
+                scout_machine = Some(scout);
             }
-            ShipAssignment::SystemChange => {
-                system_change_machine = Some(SystemChange::new(
-                    client.clone(),
-                    pg_pool.clone(),
-                    user_id.clone(),
-                    username.clone(),
-                    system.clone(),
-                    ship.clone(),
-                ));
+            Some(MachineCheckpoint::SystemChange(checkpoint)) => {
+                log::info!("{}:{} -- Resuming system change from checkpoint", username, ship.id);
+                let mut system_change = SystemChange::new(client.clone(), storage.clone(), user_id.clone(), username.clone(), system.clone(), ship.clone());
+                system_change.restore(checkpoint);
+                system_change_machine = Some(system_change);
             }
+            None => match self.assignment.as_ref().expect("a ship assignment is required when building a ship") {
+                ShipAssignment::Trader => {
+                    let mut trader = Trader::new(
+                        client.clone(),
+                        storage.clone(),
+                        user_id.clone(),
+                        username.clone(),
+                        system.clone(),
+                        ship.clone(),
+                    );
+                    trader.trading = self.trading.clone();
+                    trader.reservations = self.reservations.clone();
+                    trader_machine = Some(trader);
+                }
+                ShipAssignment::Scout => {
+                    // Roaming scouts are told where to go once they start
+                    let mut scout = Scout::new(
+                        client.clone(),
+                        storage.clone(),
+                        user_id.clone(),
+                        username.clone(),
+                        system.clone(),
+                        self.location.clone().unwrap_or_default(),
+                        ship.clone(),
+                    );
+                    scout.roaming = self.roaming;
+                    scout_machine = Some(scout);
+                }
+                ShipAssignment::SystemChange => {
+                    system_change_machine = Some(SystemChange::new(
+                        client.clone(),
+                        storage.clone(),
+                        user_id.clone(),
+                        username.clone(),
+                        system.clone(),
+                        ship.clone(),
+                    ));
+                }
+            },
         }
 
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+
         Ok(
             ShipMachine {
-                client: client.clone(),
-                pg_pool: pg_pool.clone(),
+                storage: storage.clone(),
                 username: username.clone(),
                 user_id: user_id.clone(),
                 ship_id: ship.id.clone(),
+                ship_type: ship.ship_type.clone(),
                 system: system.clone(),
-                location: self.location.as_ref().unwrap_or(&"".to_string()).clone(),
                 trader_machine,
                 scout_machine,
                 system_change_machine,
+                trading: self.trading.clone(),
+                reservations: self.reservations.clone(),
+                last_checkpoint: None,
+                commands_tx,
+                commands_rx,
             }
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::mock::MockGameApi;
+    use crate::storage::{Storage, StorageClient};
+    use crate::storage::memory::MemoryStorage;
+    use crate::test_utils;
+    use std::sync::Arc;
+
+    const USER_ID: &str = "00000000-0000-0000-0000-000000000001";
+
+    async fn build(storage: StorageClient, assignment: ShipAssignment, ship: shared::Ship) -> ShipMachine {
+        ShipMachineBuilder::new()
+            .client(Arc::new(MockGameApi::default()))
+            .storage(storage)
+            .user_id(USER_ID.to_string())
+            .username("user".to_string())
+            .system("XV".to_string())
+            .location("OE-PM".to_string())
+            .assignment(assignment)
+            .ship(ship)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn build_without_a_checkpoint_uses_the_assignment() {
+        let storage = Arc::new(MemoryStorage::new());
+        let ship_machine = build(storage, ShipAssignment::Scout, test_utils::ship(Some("OE-PM"), &[])).await;
+
+        assert!(ship_machine.scout_machine.is_some());
+        assert!(ship_machine.trader_machine.is_none());
+        assert!(ship_machine.system_change_machine.is_none());
+    }
+
+    #[tokio::test]
+    async fn build_resumes_the_checkpointed_machine_over_the_assignment() {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut system_change = build(storage.clone(), ShipAssignment::SystemChange, test_utils::ship(Some("OE-PM"), &[])).await;
+        system_change.poll().await.unwrap();
+
+        let ship_machine = build(storage.clone(), ShipAssignment::Trader, test_utils::ship(Some("OE-PM"), &[])).await;
+
+        assert!(ship_machine.system_change_machine.is_some());
+        assert!(ship_machine.trader_machine.is_none());
+        match ship_machine.checkpoint() {
+            MachineCheckpoint::SystemChange(checkpoint) => {
+                assert_eq!(checkpoint.system, "XV");
+                assert!(serde_json::to_string(&checkpoint).unwrap().contains("MoveToWormhole"));
+            }
+            other => panic!("Expected a system change checkpoint but got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn build_ignores_an_unreadable_checkpoint() {
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship_machine_state(USER_ID, "ship-1", "trader", "{\"state\":\"Teleporting\"}").await.unwrap();
+
+        let ship_machine = build(storage, ShipAssignment::Scout, test_utils::ship(Some("OE-PM"), &[])).await;
+
+        assert!(ship_machine.scout_machine.is_some());
+    }
+}
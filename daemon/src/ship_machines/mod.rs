@@ -3,26 +3,82 @@ mod trader;
 mod scout;
 mod system_change;
 
-use spacetraders::client::Client;
-use sqlx::PgPool;
-use std::fmt::Debug;
-use crate::ship_machines::trader::Trader;
-use crate::ship_machines::scout::Scout;
-use crate::ship_machines::system_change::SystemChange;
+use crate::config::TradingConfig;
+use crate::route_reservations::RouteReservations;
+use crate::game::GameClient;
+use crate::game::errors::GameError;
+use crate::metrics;
+use crate::storage::StorageClient;
+use crate::ship_machines::trader::{Trader, TraderCheckpoint};
+use crate::ship_machines::scout::{Scout, ScoutCheckpoint};
+use crate::ship_machines::system_change::{SystemChange, SystemChangeCheckpoint};
+use crate::db::DbShipMachineState;
+use anyhow::anyhow;
+use futures::FutureExt;
+pub use spacemonger_core::models::ShipAssignment;
+use spacetraders::shared;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum PollResult {
     UpdateCredits(i32),
     ConvertToNewMachine(MachineType),
+    /// The ship has sold its cargo and is parked at the location. It won't do anything else
+    Retired(String),
 }
 
+/// Something the user needs a ship to do. Sent through the channel from `ShipMachine::commands`
+/// and picked up the next time the ship is polled
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShipCommand {
+    /// Finish the current trade, fly to the location, sell whatever is left and park there so the
+    /// ship can be replaced. Only traders can be retired
+    Retire(String),
+}
+
+/// Where an operator wants a ship to be and what it should be doing there. This lives in
+/// daemon_user_ship and is changed through the api. Machines check it whenever they are at a point
+/// where they can safely stop what they are doing.
 #[derive(Debug, Clone)]
-pub enum ShipAssignment {
-    Trader,
-    Scout,
-    SystemChange,
+pub struct DesiredState {
+    pub system: String,
+    pub assignment: ShipAssignment,
 }
 
+impl DesiredState {
+    pub async fn load(storage: &StorageClient, user_id: &str, ship_id: &str) -> anyhow::Result<DesiredState> {
+        let db_ship = storage.get_ship(user_id, ship_id).await?;
+
+        Ok(DesiredState {
+            system: db_ship.system,
+            assignment: db_ship.assignment.parse()?,
+        })
+    }
+}
+
+/// The flight plan a ship is flying, or None when it is docked at a location. A plan that wasn't
+/// stored (created outside the daemon or already finished) means the ship is fetched again since
+/// it has probably landed by now.
+pub(crate) async fn active_flight_plan(client: &GameClient, storage: &StorageClient, ship: &mut shared::Ship) -> anyhow::Result<Option<shared::FlightPlanData>> {
+    if ship.location.is_some() {
+        return Ok(None);
+    }
+
+    if let Some(flight_plan) = storage.get_active_flight_plan(&ship.id).await? {
+        return Ok(Some(flight_plan));
+    }
+
+    *ship = client.get_my_ship(&ship.id).await?.ship;
+
+    if ship.location.is_none() {
+        return Err(anyhow!("Ship {} is in motion but it doesn't have a stored flight plan", ship.id));
+    }
+
+    Ok(None)
+}
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum MachineType {
     Trader(Trader),
@@ -30,10 +86,45 @@ pub enum MachineType {
     SystemChange(SystemChange),
 }
 
+/// A snapshot of whichever machine is driving a ship. Persisted to daemon_ship_machine_state
+/// after every transition and used to pick up where the ship left off after a restart.
 #[derive(Debug, Clone)]
+pub enum MachineCheckpoint {
+    Trader(TraderCheckpoint),
+    Scout(ScoutCheckpoint),
+    SystemChange(SystemChangeCheckpoint),
+}
+
+impl MachineCheckpoint {
+    pub fn machine_type(&self) -> ShipAssignment {
+        match self {
+            MachineCheckpoint::Trader(_) => ShipAssignment::Trader,
+            MachineCheckpoint::Scout(_) => ShipAssignment::Scout,
+            MachineCheckpoint::SystemChange(_) => ShipAssignment::SystemChange,
+        }
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(match self {
+            MachineCheckpoint::Trader(checkpoint) => serde_json::to_string(checkpoint)?,
+            MachineCheckpoint::Scout(checkpoint) => serde_json::to_string(checkpoint)?,
+            MachineCheckpoint::SystemChange(checkpoint) => serde_json::to_string(checkpoint)?,
+        })
+    }
+
+    pub fn from_db(db_state: &DbShipMachineState) -> anyhow::Result<MachineCheckpoint> {
+        Ok(match db_state.machine_type.as_str() {
+            "trader" => MachineCheckpoint::Trader(serde_json::from_str(&db_state.state)?),
+            "scout" => MachineCheckpoint::Scout(serde_json::from_str(&db_state.state)?),
+            "system_change" => MachineCheckpoint::SystemChange(serde_json::from_str(&db_state.state)?),
+            other => return Err(anyhow!("Unknown machine type {}", other)),
+        })
+    }
+}
+
+#[derive(Debug)]
 pub struct ShipMachine {
-    client: Client,
-    pg_pool: PgPool,
+    storage: StorageClient,
     trader_machine: Option<Trader>,
     scout_machine: Option<Scout>,
     system_change_machine: Option<SystemChange>,
@@ -41,8 +132,18 @@ pub struct ShipMachine {
     user_id: String,
     username: String,
     ship_id: String,
+    ship_type: String,
     system: String,
-    location: String,
+
+    // Handed to every trader this ship becomes
+    trading: TradingConfig,
+    reservations: RouteReservations,
+
+    // The last checkpoint written so that we only write when something has changed
+    last_checkpoint: Option<String>,
+
+    commands_tx: UnboundedSender<ShipCommand>,
+    commands_rx: UnboundedReceiver<ShipCommand>,
 }
 
 // This should be a sort of operator pattern. It will maintain it's current state but also reload
@@ -57,60 +158,298 @@ impl ShipMachine {
         &self.ship_id
     }
 
-    pub async fn poll(&mut self) -> anyhow::Result<Option<PollResult>> {
+    pub fn get_ship_type(&self) -> &str {
+        &self.ship_type
+    }
+
+    /// Send the ship to a location to wait there once it is between trades. Only traders can be
+    /// sent anywhere. Returns whether the ship is on its way
+    pub fn send_to(&mut self, location: &str) -> bool {
+        match &mut self.trader_machine {
+            Some(trader_machine) => {
+                trader_machine.send_to(Some(location.to_string()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Where the ship has been sent. None once it has been released or is no longer a trader
+    pub fn destination(&self) -> Option<&str> {
+        self.trader_machine.as_ref().and_then(|t| t.destination())
+    }
+
+    /// Where the ship is waiting after being sent there
+    pub fn held_at(&self) -> Option<&str> {
+        self.trader_machine.as_ref().and_then(|t| t.held_at())
+    }
+
+    /// Let a ship that was sent somewhere get back to work
+    pub fn release(&mut self) {
         if let Some(trader_machine) = &mut self.trader_machine {
-            return match trader_machine.poll().await.unwrap().unwrap() {
-                PollResult::UpdateCredits(new_credits) => Ok(Some(PollResult::UpdateCredits(new_credits))),
-                PollResult::ConvertToNewMachine(new_machine) => match new_machine {
-                    MachineType::Trader(trader) => {
-                        self.scout_machine = None;
-                        self.system_change_machine = None;
-                        self.trader_machine = Some(trader);
-
-                        Ok(None)
-                    },
-                    MachineType::Scout(scout) => {
-                        self.scout_machine = Some(scout);
-                        self.system_change_machine = None;
-                        self.trader_machine = None;
-
-                        Ok(None)
-                    }
-                    MachineType::SystemChange(system_change) => {
-                        self.scout_machine = None;
-                        self.system_change_machine = Some(system_change);
-                        self.trader_machine = None;
-
-                        Ok(None)
-                    }
+            trader_machine.send_to(None);
+        }
+    }
+
+    pub fn can_be_sent(&self) -> bool {
+        self.trader_machine.as_ref().is_some_and(|t| !t.is_retiring())
+    }
+
+    /// A handle for sending the ship commands
+    pub fn commands(&self) -> UnboundedSender<ShipCommand> {
+        self.commands_tx.clone()
+    }
+
+    pub fn is_retiring(&self) -> bool {
+        self.trader_machine.as_ref().is_some_and(|t| t.is_retiring())
+    }
+
+    fn handle_commands(&mut self) {
+        // Only what has already been sent. Polling never waits on a command
+        while let Some(Some(command)) = self.commands_rx.recv().now_or_never() {
+            match (command, &mut self.trader_machine) {
+                (ShipCommand::Retire(location), Some(trader_machine)) => {
+                    log::info!("{}:{} -- Retiring at {}", self.username, self.ship_id, location);
+                    trader_machine.retire(location);
                 }
+                (command, None) => log::warn!("{}:{} -- Only traders can handle {:?}. Ignoring it", self.username, self.ship_id, command),
             }
         }
+    }
 
-        if let Some(scout_machine) = &mut self.scout_machine {
-            return scout_machine.poll().await;
-        }
+    pub async fn poll(&mut self) -> anyhow::Result<Option<PollResult>> {
+        self.handle_commands();
+        let poll_result = self.poll_machine().await?;
+        self.persist_checkpoint().await?;
+
+        Ok(poll_result)
+    }
+
+    async fn poll_machine(&mut self) -> anyhow::Result<Option<PollResult>> {
+        let poll_result = if let Some(trader_machine) = &mut self.trader_machine {
+            trader_machine.poll().await?
+        } else if let Some(scout_machine) = &mut self.scout_machine {
+            scout_machine.poll().await?
+        } else if let Some(system_change_machine) = &mut self.system_change_machine {
+            system_change_machine.poll().await?
+        } else {
+            unreachable!("Shouldn't have made it here. This means that a ship machine didn't have an underlying machine attached to it")
+        };
 
-        if let Some(system_change_machine) = &mut self.system_change_machine {
-            return system_change_machine.poll().await;
+        match poll_result {
+            Some(PollResult::ConvertToNewMachine(new_machine)) => {
+                self.convert_to(new_machine);
+
+                Ok(None)
+            },
+            poll_result => Ok(poll_result),
         }
+    }
 
-        unreachable!("Shouldn't have made it here. This means that a ship machine didn't have an underlying machine attached to it")
+    /// Swap the underlying machine for a new one. Only one machine is ever attached at a time.
+    fn convert_to(&mut self, new_machine: MachineType) {
+        self.trader_machine = None;
+        self.scout_machine = None;
+        self.system_change_machine = None;
+
+        match new_machine {
+            MachineType::Trader(mut trader) => {
+                log::info!("{}:{} -- Converting to a trader in {}", self.username, self.ship_id, trader.system);
+                trader.trading = self.trading.clone();
+                trader.reservations = self.reservations.clone();
+                self.system = trader.system.clone();
+                self.trader_machine = Some(trader);
+            },
+            MachineType::Scout(scout) => {
+                log::info!("{}:{} -- Converting to a scout in {}", self.username, self.ship_id, scout.system);
+                self.system = scout.system.clone();
+                self.scout_machine = Some(scout);
+            },
+            MachineType::SystemChange(system_change) => {
+                log::info!("{}:{} -- Converting to a system change to {}", self.username, self.ship_id, system_change.system);
+                self.system_change_machine = Some(system_change);
+            },
+        }
     }
 
     pub async fn reset(&mut self) -> anyhow::Result<()> {
+        metrics::global().record_reset(&self.checkpoint().machine_type());
+
         if let Some(trader_machine) = &mut self.trader_machine {
-            return trader_machine.reset().await;
+            trader_machine.reset().await?;
+        } else if let Some(scout_machine) = &mut self.scout_machine {
+            scout_machine.reset().await?;
+        } else if let Some(system_change_machine) = &mut self.system_change_machine {
+            system_change_machine.reset().await?;
+        } else {
+            unreachable!("Shouldn't have made it here. This means that a ship machine didn't have an underlying machine attached to it")
+        }
+
+        self.persist_checkpoint().await
+    }
+
+    /// Give the machine a chance to work around an api error that it knows about. Unlike reset the
+    /// ship keeps its cargo.
+    pub async fn recover(&mut self, error: &GameError) -> anyhow::Result<Option<PollResult>> {
+        let poll_result = if let Some(trader_machine) = &mut self.trader_machine {
+            trader_machine.recover(error).await?
+        } else if let Some(scout_machine) = &mut self.scout_machine {
+            scout_machine.recover(error).await?
+        } else if let Some(system_change_machine) = &mut self.system_change_machine {
+            system_change_machine.recover(error).await?
+        } else {
+            unreachable!("Shouldn't have made it here. This means that a ship machine didn't have an underlying machine attached to it")
+        };
+
+        self.persist_checkpoint().await?;
+
+        Ok(poll_result)
+    }
+
+    pub fn checkpoint(&self) -> MachineCheckpoint {
+        if let Some(trader_machine) = &self.trader_machine {
+            return MachineCheckpoint::Trader(trader_machine.checkpoint());
+        }
+
+        if let Some(scout_machine) = &self.scout_machine {
+            return MachineCheckpoint::Scout(scout_machine.checkpoint());
+        }
+
+        if let Some(system_change_machine) = &self.system_change_machine {
+            return MachineCheckpoint::SystemChange(system_change_machine.checkpoint());
+        }
+
+        unreachable!("Shouldn't have made it here. This means that a ship machine didn't have an underlying machine attached to it")
+    }
+
+    pub fn state_name(&self) -> String {
+        if let Some(trader_machine) = &self.trader_machine {
+            return trader_machine.state_name();
         }
 
-        if let Some(scout_machine) = &mut self.scout_machine {
-            return scout_machine.reset().await;
+        if let Some(scout_machine) = &self.scout_machine {
+            return scout_machine.state_name();
         }
 
-        if let Some(system_change_machine) = &mut self.system_change_machine {
-            return system_change_machine.reset().await;
+        if let Some(system_change_machine) = &self.system_change_machine {
+            return system_change_machine.state_name();
         }
 
         unreachable!("Shouldn't have made it here. This means that a ship machine didn't have an underlying machine attached to it")
     }
+
+    /// Save the machine's state so that it can be resumed after a restart. Nothing is written when
+    /// the state hasn't changed since the last checkpoint.
+    pub async fn persist_checkpoint(&mut self) -> anyhow::Result<()> {
+        let checkpoint = self.checkpoint();
+        let state = checkpoint.to_json()?;
+
+        if self.last_checkpoint.as_ref() != Some(&state) {
+            metrics::global().record_ship_state(&self.ship_id, &checkpoint.machine_type(), &self.state_name());
+            self.storage.persist_ship_machine_state(&self.user_id, &self.ship_id, &checkpoint.machine_type().to_string(), &state).await?;
+            self.last_checkpoint = Some(state);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::mock::MockGameApi;
+    use crate::ship_machines::builder::ShipMachineBuilder;
+    use crate::storage::Storage;
+    use crate::storage::memory::MemoryStorage;
+    use crate::test_utils;
+    use std::sync::Arc;
+
+    const USER_ID: &str = "00000000-0000-0000-0000-000000000001";
+
+    #[tokio::test]
+    async fn poll_checkpoints_only_when_the_machine_changes() {
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_flight_plan(USER_ID, "ship-1", &test_utils::flight_plan("OE-PM", "OE-UC", 60), None).await.unwrap();
+
+        let mut ship_machine = ShipMachineBuilder::new()
+            .client(Arc::new(MockGameApi::default()))
+            .storage(storage.clone())
+            .user_id(USER_ID.to_string())
+            .username("user".to_string())
+            .system("OE".to_string())
+            .assignment(ShipAssignment::Trader)
+            .ship(test_utils::ship(None, &[]))
+            .build()
+            .await
+            .unwrap();
+
+        ship_machine.poll().await.unwrap();
+        let written = storage.with_state(|state| state.ship_machine_states.drain(..).collect::<Vec<DbShipMachineState>>());
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].machine_type, "trader");
+        assert!(written[0].state.contains("WaitForArrival"));
+
+        // Still waiting for the ship to arrive so there is nothing new to write
+        ship_machine.poll().await.unwrap();
+        assert!(storage.with_state(|state| state.ship_machine_states.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn poll_converts_a_finished_system_change_into_a_trader() {
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &test_utils::ship(None, &[])).await.unwrap();
+        let checkpoint = serde_json::json!({
+            "system": "XV",
+            "state": "WaitForWarp",
+            "arrival_time": chrono::Utc::now(),
+            "flight_plan": test_utils::flight_plan("OE-W-XV", "XV-W-OE", 0).flight_plan,
+        });
+        storage.persist_ship_machine_state(USER_ID, "ship-1", "system_change", &checkpoint.to_string()).await.unwrap();
+
+        let mut ship_machine = ShipMachineBuilder::new()
+            .client(Arc::new(MockGameApi::default()))
+            .storage(storage.clone())
+            .user_id(USER_ID.to_string())
+            .username("user".to_string())
+            .system("OE".to_string())
+            .assignment(ShipAssignment::Trader)
+            .ship(test_utils::ship(None, &[]))
+            .build()
+            .await
+            .unwrap();
+        assert!(ship_machine.system_change_machine.is_some());
+
+        assert!(ship_machine.poll().await.unwrap().is_none());
+
+        assert!(ship_machine.system_change_machine.is_none());
+        assert_eq!(ship_machine.trader_machine.as_ref().unwrap().system, "XV");
+        assert_eq!(ship_machine.system, "XV");
+        assert_eq!(storage.get_ship_machine_state(USER_ID, "ship-1").await.unwrap().unwrap().machine_type, "trader");
+    }
+
+    #[tokio::test]
+    async fn retire_commands_park_traders_for_good() {
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+
+        let mut ship_machine = ShipMachineBuilder::new()
+            .client(Arc::new(MockGameApi::default()))
+            .storage(storage.clone())
+            .user_id(USER_ID.to_string())
+            .username("user".to_string())
+            .system("OE".to_string())
+            .assignment(ShipAssignment::Trader)
+            .ship(test_utils::ship(Some("OE-PM"), &[]))
+            .build()
+            .await
+            .unwrap();
+        assert!(ship_machine.can_be_sent());
+
+        ship_machine.commands().send(ShipCommand::Retire("OE-PM".to_string())).unwrap();
+        assert!(ship_machine.poll().await.unwrap().is_none());
+        assert!(ship_machine.is_retiring());
+        assert!(!ship_machine.can_be_sent());
+
+        assert!(matches!(ship_machine.poll().await.unwrap(), Some(PollResult::Retired(location)) if location == "OE-PM"));
+    }
 }
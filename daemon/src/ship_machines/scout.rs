@@ -1,30 +1,38 @@
-use crate::ship_machines::PollResult;
-use spacetraders::client::Client;
-use sqlx::PgPool;
+use crate::ship_machines::{active_flight_plan, DesiredState, MachineType, PollResult, ShipAssignment};
+use crate::ship_machines::system_change::SystemChange;
+use crate::ship_machines::trader::Trader;
+use crate::game::GameClient;
+use crate::game::errors::GameError;
+use crate::storage::StorageClient;
 use chrono::{DateTime, Utc, Duration};
-use crate::{db, funcs};
+use crate::funcs;
+use crate::survey_scheduler;
 use spacetraders::shared::Good;
 use std::cmp::min;
 use spacetraders::shared;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum ScoutState {
     InitializeShip,
     WaitForArrival,
     MoveToLocation,
     CheckForCorrectLocation,
     HarvestMarketData,
+    PickLocation,
     Wait,
 }
 
 #[derive(Debug, Clone)]
 pub struct Scout {
-    client: Client,
-    pg_pool: PgPool,
-    user_id: String,
-    username: String,
-    ship: shared::Ship,
-    system: String,
+    pub client: GameClient,
+    pub storage: StorageClient,
+    pub user_id: String,
+    pub username: String,
+    pub ship: shared::Ship,
+    pub system: String,
+    /// Roaming scouts go wherever `survey_scheduler` sends them instead of staying at location
+    pub roaming: bool,
     location: String,
     state: ScoutState,
     arrival_time: DateTime<Utc>,
@@ -32,15 +40,27 @@ pub struct Scout {
     flight_plan: Option<shared::FlightPlanData>,
 }
 
+/// The parts of a scout that need to survive a restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoutCheckpoint {
+    pub system: String,
+    pub location: String,
+    state: ScoutState,
+    arrival_time: DateTime<Utc>,
+    next_harvest_time: DateTime<Utc>,
+    flight_plan: Option<shared::FlightPlanData>,
+}
+
 impl Scout {
-    pub fn new(client: Client, pg_pool: PgPool, user_id: String, username: String, system: String, location: String, ship: shared::Ship) -> Scout {
+    pub fn new(client: GameClient, storage: StorageClient, user_id: String, username: String, system: String, location: String, ship: shared::Ship) -> Scout {
         Scout {
             client,
-            pg_pool,
+            storage,
             user_id,
             username,
             ship,
             system,
+            roaming: false,
             location,
             state: ScoutState::InitializeShip,
             arrival_time: Utc::now(),
@@ -49,6 +69,37 @@ impl Scout {
         }
     }
 
+    /// The state the machine is in. I.E. WaitForArrival
+    pub fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    pub fn checkpoint(&self) -> ScoutCheckpoint {
+        ScoutCheckpoint {
+            system: self.system.clone(),
+            location: self.location.clone(),
+            state: self.state.clone(),
+            arrival_time: self.arrival_time,
+            next_harvest_time: self.next_harvest_time,
+            flight_plan: self.flight_plan.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, checkpoint: ScoutCheckpoint) {
+        self.system = checkpoint.system;
+        self.location = checkpoint.location;
+        self.state = checkpoint.state;
+        self.arrival_time = checkpoint.arrival_time;
+        self.next_harvest_time = checkpoint.next_harvest_time;
+        self.flight_plan = checkpoint.flight_plan;
+
+        // A ship in motion can only be waiting to arrive
+        if self.ship.location.is_none() && !matches!(self.state, ScoutState::WaitForArrival) {
+            log::warn!("{}:{} -- Checkpoint {:?} doesn't match a ship in motion. Reinitializing", self.username, self.ship.id, self.state);
+            self.state = ScoutState::InitializeShip;
+        }
+    }
+
     pub async fn reset(&mut self) -> anyhow::Result<()> {
         log::info!("{}:{} -- Ship is being reset", self.username, self.ship.id);
 
@@ -69,28 +120,51 @@ impl Scout {
         Ok(())
     }
 
+    /// Recover from an api error without throwing away the cargo
+    pub async fn recover(&mut self, error: &GameError) -> anyhow::Result<Option<PollResult>> {
+        log::info!("{}:{} -- Recovering from {} while in {:?}", self.username, self.ship.id, error, self.state);
+
+        match error {
+            GameError::InsufficientFuel { required } => {
+                // The flight plan is created again on the next poll
+                let new_user_credits = funcs::buy_missing_fuel(self.client.clone(), self.storage.clone(), &self.user_id, None, *required, &mut self.ship).await?;
+
+                return Ok(new_user_credits.map(PollResult::UpdateCredits));
+            }
+            GameError::GoodNotListed => funcs::jettison_unlisted_cargo(self.client.clone(), &mut self.ship).await?,
+            GameError::QuantityUnavailable | GameError::InsufficientCredits => {
+                log::warn!("{}:{} -- Unable to buy fuel at {:?}. Trying again later", self.username, self.ship.id, self.ship.location);
+            }
+            GameError::ShipInTransit => {
+                self.ship = self.client.get_my_ship(&self.ship.id).await?.ship;
+                self.state = ScoutState::InitializeShip;
+            }
+        }
+
+        Ok(None)
+    }
+
     pub async fn poll(&mut self) -> anyhow::Result<Option<PollResult>> {
         match self.state {
             ScoutState::InitializeShip => {
                 log::trace!("{}:{} -- ScoutState::InitializeShip", self.username, self.ship.id);
 
-                if self.ship.location == None {
-                    // search for any stored flight plans that are valid for this scout.
-                    let flight_plan = db::get_active_flight_plan(self.pg_pool.clone(), &self.ship.id)
-                        .await.expect("Unable to find flight plan for a ship that is in motion").unwrap();
-
+                if let Some(flight_plan) = active_flight_plan(&self.client, &self.storage, &mut self.ship).await? {
                     log::info!("{}:{} -- Ship is moving to {}. Waiting for arrival", self.username, self.ship.id, flight_plan.destination);
+                    if self.roaming {
+                        self.location = flight_plan.destination.clone();
+                    }
                     self.arrival_time = flight_plan.arrives_at;
                     self.state = ScoutState::WaitForArrival;
                 } else {
                     let mut new_user_credits = 0;
                     for cargo in self.ship.cargo.clone() {
                         log::info!("{}:{} -- Selling {} goods {} at {}", self.username, self.ship.id, cargo.quantity, cargo.good, self.ship.location.clone().unwrap());
-                        let sell_order = funcs::create_sell_order(self.client.clone(), self.pg_pool.clone(), &self.user_id, cargo.good, cargo.quantity, &mut self.ship).await?;
+                        let sell_order = funcs::create_sell_order(self.client.clone(), self.storage.clone(), &self.user_id, None, cargo.good, cargo.quantity, &mut self.ship).await?;
                         new_user_credits = sell_order.credits;
                     }
 
-                    self.state = ScoutState::CheckForCorrectLocation;
+                    self.state = if self.roaming { ScoutState::PickLocation } else { ScoutState::CheckForCorrectLocation };
 
                     if new_user_credits > 0 {
                         return Ok(Some(PollResult::UpdateCredits(new_user_credits)));
@@ -125,7 +199,7 @@ impl Scout {
                     .fold(0, |acc, c| acc + c.quantity);
 
                 let additional_fuel_required = funcs::get_additional_fuel_required_for_trip(
-                    self.pg_pool.clone(),
+                    self.storage.clone(),
                     self.client.clone(),
                     &self.ship.id,
                     &self.ship.ship_type,
@@ -139,8 +213,9 @@ impl Scout {
                     log::info!("{}:{} -- Ship destined to {} is filling up with {} additional fuel", self.username, self.ship.id, self.location, additional_fuel_required);
                     let purchase_order = funcs::create_purchase_order(
                         self.client.clone(),
-                        self.pg_pool.clone(),
+                        self.storage.clone(),
                         &self.user_id,
+                        None,
                         Good::Fuel,
                         // Don't ever try and buy more fuel than the ship can hold
                         min(additional_fuel_required, self.ship.space_available),
@@ -154,8 +229,9 @@ impl Scout {
                 log::info!("{}:{} -- Ship destined to {} is creating a flight plan", self.username, self.ship.id, self.location);
                 let flight_plan = funcs::create_flight_plan(
                     self.client.clone(),
-                    self.pg_pool.clone(),
+                    self.storage.clone(),
                     &self.user_id,
+                    None,
                     &self.location,
                     &mut self.ship,
                 ).await?;
@@ -170,32 +246,324 @@ impl Scout {
             },
             ScoutState::HarvestMarketData => {
                 log::trace!("{}:{} -- ScoutState::HarvestMarketData", self.username, self.ship.id);
-                let marketplace_data = self.client.get_location_marketplace(&self.location).await?;
+                funcs::harvest_market_data(self.client.clone(), self.storage.clone(), &self.ship.id, &self.location).await?;
 
                 log::trace!("{}:{} -- Ship assigned to {} has received marketplace data", self.username, self.ship.id, self.location);
 
-                for datum in marketplace_data.marketplace {
-                    db::persist_market_data(
-                        self.pg_pool.clone(),
-                        &self.location,
-                        &datum,
-                    )
-                        .await.expect("Unable to save market data");
+                if self.roaming {
+                    self.state = ScoutState::PickLocation;
+                } else {
+                    self.state = ScoutState::Wait;
+                    self.next_harvest_time = Utc::now() + Duration::minutes(3);
+                    log::trace!("{}:{} -- Ship assigned to {} will check market data again at {}", self.username, self.ship.id, self.location, self.next_harvest_time);
                 }
+            },
+            ScoutState::PickLocation => {
+                log::trace!("{}:{} -- ScoutState::PickLocation", self.username, self.ship.id);
 
-                self.state = ScoutState::Wait;
-                self.next_harvest_time = Utc::now() + Duration::minutes(3);
-                log::trace!("{}:{} -- Ship assigned to {} will check market data again at {}", self.username, self.ship.id, self.location, self.next_harvest_time);
+                if let Some(poll_result) = self.check_desired_state().await? {
+                    return Ok(Some(poll_result));
+                }
+
+                match survey_scheduler::next_location(self.storage.clone(), &self.system, self.ship.location.as_deref()).await? {
+                    Some(location) => {
+                        log::info!("{}:{} -- Market data at {} is the most out of date. Surveying it next", self.username, self.ship.id, location);
+                        self.location = location;
+                        self.state = ScoutState::CheckForCorrectLocation;
+                    }
+                    None => {
+                        self.next_harvest_time = Utc::now() + Duration::minutes(1);
+                        self.state = ScoutState::Wait;
+                        log::trace!("{}:{} -- Every market in {} is fresh enough. Checking again at {}", self.username, self.ship.id, self.system, self.next_harvest_time);
+                    }
+                }
             },
             ScoutState::Wait => {
                 log::trace!("{}:{} -- ScoutState::Wait", self.username, self.ship.id);
                 if Utc::now().ge(&self.next_harvest_time) {
                     log::trace!("{}:{} -- Ship assigned to {} to harvest market data has finished waiting for next harvest time", self.username, self.ship.id, self.location);
-                    self.state = ScoutState::HarvestMarketData;
+
+                    if let Some(poll_result) = self.check_desired_state().await? {
+                        return Ok(Some(poll_result));
+                    }
+
+                    self.state = if self.roaming { ScoutState::PickLocation } else { ScoutState::HarvestMarketData };
                 }
             },
         }
 
         Ok(None)
     }
+
+    /// Scouts sit still between harvests so this is where they pick up any changes an operator has
+    /// made to the ship
+    async fn check_desired_state(&mut self) -> anyhow::Result<Option<PollResult>> {
+        let desired_state = DesiredState::load(&self.storage, &self.user_id, &self.ship.id).await?;
+        if desired_state.system != self.system {
+            log::info!("{}:{} -- Ship has been reassigned to {}", self.username, self.ship.id, desired_state.system);
+            let mut system_change: SystemChange = self.into();
+            system_change.system = desired_state.system;
+
+            return Ok(Some(PollResult::ConvertToNewMachine(MachineType::SystemChange(system_change))));
+        }
+
+        if desired_state.assignment != ShipAssignment::Scout {
+            log::info!("{}:{} -- Ship has been reassigned as a trader", self.username, self.ship.id);
+            return Ok(Some(PollResult::ConvertToNewMachine(MachineType::Trader(self.into()))));
+        }
+
+        Ok(None)
+    }
+}
+
+/// Ships that become scouts roam their system starting from wherever they happen to be
+impl From<&mut Trader> for Scout {
+    fn from(trader: &mut Trader) -> Self {
+        let location = trader.ship.location.clone().unwrap_or_default();
+        let mut scout = Scout::new(trader.client.clone(), trader.storage.clone(), trader.user_id.clone(), trader.username.clone(), trader.system.clone(), location, trader.ship.clone());
+        scout.roaming = true;
+        scout
+    }
+}
+
+impl From<&mut SystemChange> for Scout {
+    fn from(system_change: &mut SystemChange) -> Self {
+        let location = system_change.ship.location.clone().unwrap_or_default();
+        let mut scout = Scout::new(system_change.client.clone(), system_change.storage.clone(), system_change.user_id.clone(), system_change.username.clone(), system_change.system.clone(), location, system_change.ship.clone());
+        scout.roaming = true;
+        scout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::mock::MockGameApi;
+    use crate::storage::Storage;
+    use crate::storage::memory::MemoryStorage;
+    use crate::test_utils;
+    use spacetraders::responses;
+    use std::sync::Arc;
+
+    const USER_ID: &str = "00000000-0000-0000-0000-000000000001";
+
+    fn scout(mock: &Arc<MockGameApi>, storage: StorageClient, ship: shared::Ship) -> Scout {
+        Scout::new(mock.clone(), storage, USER_ID.to_string(), "scout".to_string(), "OE".to_string(), "OE-UC".to_string(), ship)
+    }
+
+    /// A roaming scout docked at OE-PM in a system where OE-PM was just surveyed
+    async fn roaming_scout(mock: &Arc<MockGameApi>, storage: Arc<MemoryStorage>) -> Scout {
+        let oe = test_utils::system("OE", &[("OE-PM", shared::LocationType::Planet, 0, 0), ("OE-UC", shared::LocationType::Planet, 10, 0)]);
+        for location in &oe.locations {
+            storage.persist_system_location(&oe, location).await.unwrap();
+        }
+        storage.persist_market_data("OE-PM", "ship-1", &test_utils::market(Good::Metals, 10, 1_000)).await.unwrap();
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Scout, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+
+        let mut scout = Scout::new(mock.clone(), storage, USER_ID.to_string(), "scout".to_string(), "OE".to_string(), "".to_string(), test_utils::ship(Some("OE-PM"), &[]));
+        scout.roaming = true;
+        scout
+    }
+
+    #[tokio::test]
+    async fn roaming_scouts_go_to_the_market_that_is_most_out_of_date() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut scout = roaming_scout(&mock, Arc::new(MemoryStorage::new())).await;
+        scout.state = ScoutState::PickLocation;
+
+        scout.poll().await.unwrap();
+        assert!(matches!(scout.state, ScoutState::CheckForCorrectLocation));
+        assert_eq!(scout.location, "OE-UC");
+
+        scout.poll().await.unwrap();
+        assert!(matches!(scout.state, ScoutState::MoveToLocation));
+    }
+
+    #[tokio::test]
+    async fn roaming_scouts_wait_when_every_market_is_fresh() {
+        let mock = Arc::new(MockGameApi::default());
+        let storage = Arc::new(MemoryStorage::new());
+        let mut scout = roaming_scout(&mock, storage.clone()).await;
+        storage.persist_market_data("OE-UC", "ship-1", &test_utils::market(Good::Metals, 10, 1_000)).await.unwrap();
+        scout.state = ScoutState::PickLocation;
+
+        scout.poll().await.unwrap();
+        assert!(matches!(scout.state, ScoutState::Wait));
+        assert!(scout.next_harvest_time > Utc::now());
+
+        scout.next_harvest_time = Utc::now();
+        scout.poll().await.unwrap();
+        assert!(matches!(scout.state, ScoutState::PickLocation));
+    }
+
+    #[tokio::test]
+    async fn roaming_scouts_pick_somewhere_new_after_harvesting() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.get_location_marketplace.push(Ok(responses::LocationMarketplace { marketplace: vec![test_utils::market(Good::Metals, 10, 1_000)] }));
+        let mut scout = roaming_scout(&mock, Arc::new(MemoryStorage::new())).await;
+        scout.location = "OE-PM".to_string();
+        scout.state = ScoutState::HarvestMarketData;
+
+        scout.poll().await.unwrap();
+        assert!(matches!(scout.state, ScoutState::PickLocation));
+    }
+
+    #[tokio::test]
+    async fn roaming_scouts_in_motion_survey_wherever_they_are_headed() {
+        let mock = Arc::new(MockGameApi::default());
+        let storage = Arc::new(MemoryStorage::new());
+        let mut scout = roaming_scout(&mock, storage.clone()).await;
+        storage.persist_flight_plan(USER_ID, "ship-1", &test_utils::flight_plan("OE-PM", "OE-UC", 60), None).await.unwrap();
+        scout.ship.location = None;
+
+        scout.poll().await.unwrap();
+        assert!(matches!(scout.state, ScoutState::WaitForArrival));
+        assert_eq!(scout.location, "OE-UC");
+    }
+
+    #[tokio::test]
+    async fn check_for_correct_location_harvests_when_at_the_assigned_location() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut scout = scout(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(Some("OE-UC"), &[]));
+        scout.state = ScoutState::CheckForCorrectLocation;
+
+        scout.poll().await.unwrap();
+        assert!(matches!(scout.state, ScoutState::HarvestMarketData));
+    }
+
+    #[tokio::test]
+    async fn check_for_correct_location_moves_when_somewhere_else() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut scout = scout(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(Some("OE-PM"), &[]));
+        scout.state = ScoutState::CheckForCorrectLocation;
+
+        scout.poll().await.unwrap();
+        assert!(matches!(scout.state, ScoutState::MoveToLocation));
+    }
+
+    #[tokio::test]
+    async fn wait_harvests_again_after_the_next_harvest_time() {
+        let mock = Arc::new(MockGameApi::default());
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Scout, &test_utils::ship(Some("OE-UC"), &[])).await.unwrap();
+        let mut scout = scout(&mock, storage, test_utils::ship(Some("OE-UC"), &[]));
+        scout.state = ScoutState::Wait;
+        scout.next_harvest_time = Utc::now() + Duration::minutes(1);
+
+        scout.poll().await.unwrap();
+        assert!(matches!(scout.state, ScoutState::Wait));
+
+        scout.next_harvest_time = Utc::now();
+        scout.poll().await.unwrap();
+        assert!(matches!(scout.state, ScoutState::HarvestMarketData));
+    }
+
+    #[tokio::test]
+    async fn harvest_market_data_persists_the_marketplace() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.get_location_marketplace.push(Ok(responses::LocationMarketplace {
+            marketplace: vec![shared::MarketplaceData {
+                symbol: Good::Fuel,
+                volume_per_unit: 1,
+                price_per_unit: 2,
+                purchase_price_per_unit: 3,
+                sell_price_per_unit: 1,
+                quantity_available: 5_000,
+                spread: 1,
+            }],
+        }));
+
+        let storage = Arc::new(MemoryStorage::new());
+        let mut scout = scout(&mock, storage.clone(), test_utils::ship(Some("OE-UC"), &[]));
+        scout.state = ScoutState::HarvestMarketData;
+
+        scout.poll().await.unwrap();
+        assert!(matches!(scout.state, ScoutState::Wait));
+        assert!(scout.next_harvest_time > Utc::now());
+        assert_eq!(mock.calls(), vec!["get_location_marketplace(OE-UC)"]);
+        storage.with_state(|state| {
+            assert_eq!(state.market_data.len(), 1);
+            assert_eq!(state.market_data[0].location, "OE-UC");
+        });
+    }
+
+    #[tokio::test]
+    async fn move_to_location_fuels_up_and_departs() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.create_flight_plan.push(Err(test_utils::api_error(3001, "Ship has insufficient fuel for flight plan. You require 30 more FUEL")));
+        mock.create_purchase_order.push(Ok(test_utils::order(1_000, Good::Fuel, 30, 2, test_utils::ship(Some("OE-PM"), &[(Good::Fuel, 30)]))));
+        mock.create_flight_plan.push(Ok(test_utils::flight_plan("OE-PM", "OE-UC", 180)));
+
+        let storage = Arc::new(MemoryStorage::new());
+        let mut scout = scout(&mock, storage.clone(), test_utils::ship(Some("OE-PM"), &[]));
+        scout.state = ScoutState::MoveToLocation;
+
+        assert!(matches!(scout.poll().await.unwrap(), Some(PollResult::UpdateCredits(1_000))));
+        assert!(matches!(scout.state, ScoutState::WaitForArrival));
+        storage.with_state(|state| {
+            assert_eq!(state.transactions.len(), 1);
+            assert_eq!(state.flight_plans.len(), 1);
+        });
+    }
+
+    #[tokio::test]
+    async fn move_to_location_with_fuel_and_no_known_distance_departs_without_probing() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.create_flight_plan.push(Ok(test_utils::flight_plan("OE-PM", "OE-UC", 180)));
+
+        let storage = Arc::new(MemoryStorage::new());
+        let mut scout = scout(&mock, storage.clone(), test_utils::ship(Some("OE-PM"), &[(Good::Fuel, 10)]));
+        scout.state = ScoutState::MoveToLocation;
+
+        scout.poll().await.unwrap();
+        assert!(matches!(scout.state, ScoutState::WaitForArrival));
+        assert_eq!(mock.calls(), vec!["create_flight_plan(ship-1, OE-UC)"]);
+    }
+
+    #[tokio::test]
+    async fn recovering_from_an_unlisted_good_jettisons_only_unlisted_cargo() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.get_location_marketplace.push(Ok(responses::LocationMarketplace {
+            marketplace: vec![test_utils::market(Good::Metals, 10, 1_000)],
+        }));
+        mock.jettison_cargo.push(Ok(responses::JettisonCargo { ship_id: "ship-1".to_string(), good: Good::Electronics, quantity_remaining: 0 }));
+
+        let mut scout = scout(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(Some("OE-UC"), &[(Good::Fuel, 10), (Good::Metals, 5), (Good::Electronics, 3)]));
+        scout.state = ScoutState::HarvestMarketData;
+
+        scout.recover(&GameError::GoodNotListed).await.unwrap();
+
+        assert!(matches!(scout.state, ScoutState::HarvestMarketData));
+        assert_eq!(mock.calls(), vec!["get_location_marketplace(OE-UC)", "jettison_cargo(ship-1, Electronics, 3)"]);
+    }
+
+    #[tokio::test]
+    async fn wait_converts_to_a_trader_when_reassigned() {
+        let mock = Arc::new(MockGameApi::default());
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-UC"), &[])).await.unwrap();
+        let mut scout = scout(&mock, storage, test_utils::ship(Some("OE-UC"), &[]));
+        scout.state = ScoutState::Wait;
+        scout.next_harvest_time = Utc::now();
+
+        match scout.poll().await.unwrap() {
+            Some(PollResult::ConvertToNewMachine(MachineType::Trader(trader))) => assert_eq!(trader.system, "OE"),
+            other => panic!("Expected to convert to a trader but got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn wait_changes_systems_when_reassigned_to_another_system() {
+        let mock = Arc::new(MockGameApi::default());
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "XV", &ShipAssignment::Scout, &test_utils::ship(Some("OE-UC"), &[])).await.unwrap();
+        let mut scout = scout(&mock, storage, test_utils::ship(Some("OE-UC"), &[]));
+        scout.state = ScoutState::Wait;
+        scout.next_harvest_time = Utc::now();
+
+        match scout.poll().await.unwrap() {
+            Some(PollResult::ConvertToNewMachine(MachineType::SystemChange(system_change))) => assert_eq!(system_change.system, "XV"),
+            other => panic!("Expected to convert to a system change but got {:?}", other),
+        }
+    }
 }
@@ -1,14 +1,17 @@
-use spacetraders::client::Client;
-use sqlx::PgPool;
+use crate::game::GameClient;
+use crate::game::errors::GameError;
+use crate::storage::StorageClient;
 use spacetraders::shared;
 use chrono::{DateTime, Utc};
 use spacetraders::shared::Good;
-use crate::ship_machines::PollResult;
-use crate::{db, funcs};
+use crate::ship_machines::{active_flight_plan, DesiredState, MachineType, PollResult, ShipAssignment};
+use crate::funcs;
 use std::cmp::min;
+use crate::ship_machines::scout::Scout;
 use crate::ship_machines::trader::Trader;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum SystemChangeState {
     InitializeShip,
     WaitForArrival,
@@ -21,11 +24,21 @@ enum SystemChangeState {
 
 #[derive(Debug, Clone)]
 pub struct SystemChange {
-    pub client: Client,
-    pub pg_pool: PgPool,
+    pub client: GameClient,
+    pub storage: StorageClient,
     pub user_id: String,
     pub username: String,
     pub ship: shared::Ship,
+    // The system that the ship is moving to
+    pub system: String,
+    state: SystemChangeState,
+    arrival_time: DateTime<Utc>,
+    flight_plan: Option<shared::FlightPlanData>,
+}
+
+/// The parts of a system change that need to survive a restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemChangeCheckpoint {
     pub system: String,
     state: SystemChangeState,
     arrival_time: DateTime<Utc>,
@@ -33,10 +46,10 @@ pub struct SystemChange {
 }
 
 impl SystemChange {
-    pub fn new(client: Client, pg_pool: PgPool, user_id: String, username: String, system: String, ship: shared::Ship) -> SystemChange {
+    pub fn new(client: GameClient, storage: StorageClient, user_id: String, username: String, system: String, ship: shared::Ship) -> SystemChange {
         SystemChange {
             client,
-            pg_pool,
+            storage,
             user_id,
             username,
             ship,
@@ -47,6 +60,38 @@ impl SystemChange {
         }
     }
 
+    /// The state the machine is in. I.E. WaitForArrival
+    pub fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    pub fn checkpoint(&self) -> SystemChangeCheckpoint {
+        SystemChangeCheckpoint {
+            system: self.system.clone(),
+            state: self.state.clone(),
+            arrival_time: self.arrival_time,
+            flight_plan: self.flight_plan.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, checkpoint: SystemChangeCheckpoint) {
+        self.system = checkpoint.system;
+        self.state = checkpoint.state;
+        self.arrival_time = checkpoint.arrival_time;
+        self.flight_plan = checkpoint.flight_plan;
+
+        let waiting = matches!(
+            self.state,
+            SystemChangeState::WaitForArrival | SystemChangeState::WaitForArrivalAtWormhole | SystemChangeState::WaitForWarp
+        );
+
+        // A ship in motion can only be waiting to arrive
+        if (self.ship.location.is_none() && !waiting) || (waiting && self.flight_plan.is_none()) {
+            log::warn!("{}:{} -- Checkpoint {:?} doesn't match the ship's location {:?}. Reinitializing", self.username, self.ship.id, self.state, self.ship.location);
+            self.state = SystemChangeState::InitializeShip;
+        }
+    }
+
     pub async fn reset(&mut self) -> anyhow::Result<()> {
         log::info!("{}:{} -- Ship is being reset", self.username, self.ship.id);
 
@@ -67,16 +112,50 @@ impl SystemChange {
         Ok(())
     }
 
+    /// Recover from an api error without throwing away the cargo
+    pub async fn recover(&mut self, error: &GameError) -> anyhow::Result<Option<PollResult>> {
+        log::info!("{}:{} -- Recovering from {} while in {:?}", self.username, self.ship.id, error, self.state);
+
+        match error {
+            GameError::InsufficientFuel { required } => {
+                // The flight plan is created again on the next poll
+                let new_user_credits = funcs::buy_missing_fuel(self.client.clone(), self.storage.clone(), &self.user_id, None, *required, &mut self.ship).await?;
+
+                return Ok(new_user_credits.map(PollResult::UpdateCredits));
+            }
+            GameError::GoodNotListed => funcs::jettison_unlisted_cargo(self.client.clone(), &mut self.ship).await?,
+            GameError::QuantityUnavailable | GameError::InsufficientCredits => {
+                log::warn!("{}:{} -- Unable to buy fuel at {:?}. Trying again later", self.username, self.ship.id, self.ship.location);
+            }
+            GameError::ShipInTransit => {
+                self.ship = self.client.get_my_ship(&self.ship.id).await?.ship;
+                self.state = SystemChangeState::InitializeShip;
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The ship has made it to the new system. Hand it over to whatever it has been assigned to do
+    /// there. Ships that were only assigned to change systems start trading.
+    async fn settle(&mut self) -> anyhow::Result<Option<PollResult>> {
+        let desired_state = DesiredState::load(&self.storage, &self.user_id, &self.ship.id).await?;
+
+        if desired_state.assignment == ShipAssignment::Scout {
+            log::info!("{}:{} -- Ship will start scouting in {}", self.username, self.ship.id, self.system);
+            return Ok(Some(PollResult::ConvertToNewMachine(MachineType::Scout(self.into()))));
+        }
+
+        log::info!("{}:{} -- Ship will start trading in {}", self.username, self.ship.id, self.system);
+        Ok(Some(PollResult::ConvertToNewMachine(MachineType::Trader(self.into()))))
+    }
+
     pub async fn poll(&mut self) -> anyhow::Result<Option<PollResult>> {
         match self.state {
             SystemChangeState::InitializeShip => {
                 log::trace!("{}:{} -- SystemChangeState::InitializeShip", self.username, self.ship.id);
 
-                if self.ship.location == None {
-                    // search for any stored flight plans that are valid for this scout.
-                    let flight_plan = db::get_active_flight_plan(self.pg_pool.clone(), &self.ship.id)
-                        .await.expect("Unable to find flight plan for a ship that is in motion").unwrap();
-
+                if let Some(flight_plan) = active_flight_plan(&self.client, &self.storage, &mut self.ship).await? {
                     log::info!("{}:{} -- Ship is moving to {}. Waiting for arrival", self.username, self.ship.id, flight_plan.destination);
                     self.arrival_time = flight_plan.arrives_at;
                     self.flight_plan = Some(flight_plan);
@@ -98,7 +177,7 @@ impl SystemChange {
             },
             SystemChangeState::MoveToWormhole => {
                 log::trace!("{}:{} -- SystemChangeState::MoveToWormhole", self.username, self.ship.id);
-                if self.ship.location == None {
+                if self.ship.location.is_none() {
                     // We shouldn't be here without our ship having a location.
                     // Let's just restart and wait for the ship to stop somewhere
                     self.state = SystemChangeState::InitializeShip;
@@ -109,8 +188,9 @@ impl SystemChange {
                 for c in &self.ship.cargo.clone() {
                     let sell_order = funcs::create_sell_order(
                         self.client.clone(),
-                        self.pg_pool.clone(),
+                        self.storage.clone(),
                         &self.user_id,
+                        None,
                         c.good,
                         c.quantity,
                         &mut self.ship,
@@ -121,17 +201,22 @@ impl SystemChange {
                 }
 
                 if let Some(location) = &self.ship.location {
-                    let wormhole = db::get_wormhole_from_location_to_system(self.pg_pool.clone(), location, &self.system).await?;
+                    if funcs::get_system_from_location(location) == self.system {
+                        log::info!("{}:{} -- Ship is already in {}", self.username, self.ship.id, self.system);
+                        return self.settle().await;
+                    }
+
+                    let wormhole = self.storage.get_wormhole_from_location_to_system(location, &self.system).await?;
 
                     let current_fuel = self.ship.cargo.iter().filter(|c| c.good == Good::Fuel).fold(0, |acc, c| acc + c.quantity);
 
                     let additional_fuel_required = funcs::get_additional_fuel_required_for_trip(
-                        self.pg_pool.clone(),
+                        self.storage.clone(),
                         self.client.clone(),
                         &self.ship.id,
                         &self.ship.ship_type,
                         current_fuel,
-                        &location,
+                        location,
                         &wormhole,
                     ).await?;
 
@@ -139,8 +224,9 @@ impl SystemChange {
                         log::info!("{}:{} -- Ship destined to {} is filling up with {} additional fuel", self.username, self.ship.id, wormhole, additional_fuel_required);
                         let purchase_order = funcs::create_purchase_order(
                             self.client.clone(),
-                            self.pg_pool.clone(),
+                            self.storage.clone(),
                             &self.user_id,
+                            None,
                             Good::Fuel,
                             // Don't ever try and buy more fuel than the ship can hold
                             min(additional_fuel_required, self.ship.space_available),
@@ -151,7 +237,7 @@ impl SystemChange {
                         self.ship = purchase_order.ship;
                     }
 
-                    let flight_plan = funcs::create_flight_plan(self.client.clone(), self.pg_pool.clone(), &self.user_id, &wormhole, &mut self.ship).await?;
+                    let flight_plan = funcs::create_flight_plan(self.client.clone(), self.storage.clone(), &self.user_id, None, &wormhole, &mut self.ship).await?;
                     self.arrival_time = flight_plan.flight_plan.arrives_at;
                     self.flight_plan = Some(flight_plan.flight_plan);
                     self.state = SystemChangeState::WaitForArrivalAtWormhole;
@@ -167,14 +253,15 @@ impl SystemChange {
                 if Utc::now().ge(&self.arrival_time) {
                     if let Some(flight_plan) = self.flight_plan.clone() {
                         log::info!("{}:{} -- Ship traveling to {} has arrived", self.username, self.ship.id, flight_plan.destination);
-                        self.state = SystemChangeState::MoveToWormhole;
+                        self.state = SystemChangeState::Warp;
                         self.ship.location = Some(flight_plan.destination);
                     }
                 }
             },
             SystemChangeState::Warp => {
                 log::trace!("{}:{} -- SystemChangeState::Warp", self.username, self.ship.id);
-                let flight_plan = self.client.attempt_warp_jump(self.ship.id.to_string()).await?;
+                log::info!("{}:{} -- Ship is attempting a warp jump to {}", self.username, self.ship.id, self.system);
+                let flight_plan = funcs::attempt_warp_jump(self.client.clone(), self.storage.clone(), &self.user_id, None, &mut self.ship).await?;
                 self.arrival_time = flight_plan.flight_plan.arrives_at;
                 self.flight_plan = Some(flight_plan.flight_plan);
                 self.state = SystemChangeState::WaitForWarp;
@@ -184,9 +271,11 @@ impl SystemChange {
                 // We have arrived
                 if Utc::now().ge(&self.arrival_time) {
                     if let Some(flight_plan) = self.flight_plan.clone() {
-                        log::info!("{}:{} -- Ship traveling to {} has arrived", self.username, self.ship.id, flight_plan.destination);
-                        self.state = SystemChangeState::MoveToWormhole;
+                        log::info!("{}:{} -- Ship has warped to {}", self.username, self.ship.id, flight_plan.destination);
                         self.ship.location = Some(flight_plan.destination);
+                        self.storage.update_ship_system(&self.user_id, &self.ship.id, &self.system).await?;
+
+                        return self.settle().await;
                     }
                 }
             }
@@ -196,11 +285,17 @@ impl SystemChange {
     }
 }
 
+impl From<&mut Scout> for SystemChange {
+    fn from(scout: &mut Scout) -> Self {
+        SystemChange::new(scout.client.clone(), scout.storage.clone(), scout.user_id.clone(), scout.username.clone(), scout.system.clone(), scout.ship.clone())
+    }
+}
+
 impl From<&mut Trader> for SystemChange {
     fn from(trader: &mut Trader) -> Self {
         SystemChange {
             client: trader.client.clone(),
-            pg_pool: trader.pg_pool.clone(),
+            storage: trader.storage.clone(),
             user_id: trader.user_id.clone(),
             username: trader.username.clone(),
             ship: trader.ship.clone(),
@@ -211,3 +306,125 @@ impl From<&mut Trader> for SystemChange {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::mock::MockGameApi;
+    use crate::storage::Storage;
+    use crate::storage::memory::MemoryStorage;
+    use crate::test_utils;
+    use std::sync::Arc;
+
+    const USER_ID: &str = "00000000-0000-0000-0000-000000000001";
+
+    fn system_change(mock: &Arc<MockGameApi>, ship: shared::Ship) -> SystemChange {
+        SystemChange::new(mock.clone(), Arc::new(MemoryStorage::new()), USER_ID.to_string(), "trader".to_string(), "XV".to_string(), ship)
+    }
+
+    #[tokio::test]
+    async fn move_to_wormhole_converts_to_a_trader_when_already_in_the_system() {
+        let mock = Arc::new(MockGameApi::default());
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "XV", &ShipAssignment::SystemChange, &test_utils::ship(Some("XV-BN"), &[])).await.unwrap();
+        let mut system_change = SystemChange::new(mock.clone(), storage, USER_ID.to_string(), "trader".to_string(), "XV".to_string(), test_utils::ship(Some("XV-BN"), &[]));
+        system_change.state = SystemChangeState::MoveToWormhole;
+
+        match system_change.poll().await.unwrap() {
+            Some(PollResult::ConvertToNewMachine(MachineType::Trader(trader))) => assert_eq!(trader.system, "XV"),
+            other => panic!("Expected to convert to a trader but got {:?}", other),
+        }
+        assert!(mock.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn arriving_at_the_wormhole_warps() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut system_change = system_change(&mock, test_utils::ship(None, &[]));
+        system_change.state = SystemChangeState::WaitForArrivalAtWormhole;
+        system_change.flight_plan = Some(test_utils::flight_plan("OE-PM", "OE-W-XV", 0).flight_plan);
+        system_change.arrival_time = Utc::now();
+
+        system_change.poll().await.unwrap();
+        assert!(matches!(system_change.state, SystemChangeState::Warp));
+        assert_eq!(system_change.ship.location, Some("OE-W-XV".to_string()));
+    }
+
+    #[tokio::test]
+    async fn initialize_docked_ship_moves_to_the_wormhole() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut system_change = system_change(&mock, test_utils::ship(Some("OE-PM"), &[]));
+
+        system_change.poll().await.unwrap();
+        assert!(matches!(system_change.state, SystemChangeState::MoveToWormhole));
+    }
+
+    #[tokio::test]
+    async fn move_to_wormhole_without_a_location_reinitializes() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut system_change = system_change(&mock, test_utils::ship(None, &[]));
+        system_change.state = SystemChangeState::MoveToWormhole;
+
+        system_change.poll().await.unwrap();
+        assert!(matches!(system_change.state, SystemChangeState::InitializeShip));
+        assert!(mock.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn warp_jumps_and_waits_for_the_warp() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.attempt_warp_jump.push(Ok(test_utils::flight_plan("OE-W-XV", "XV-W-OE", 180)));
+
+        let storage = Arc::new(MemoryStorage::new());
+        let mut system_change = SystemChange::new(mock.clone(), storage.clone(), USER_ID.to_string(), "trader".to_string(), "XV".to_string(), test_utils::ship(Some("OE-W-XV"), &[]));
+        system_change.state = SystemChangeState::Warp;
+
+        system_change.poll().await.unwrap();
+        assert!(matches!(system_change.state, SystemChangeState::WaitForWarp));
+        assert_eq!(system_change.ship.location, None);
+        assert_eq!(system_change.flight_plan.unwrap().destination, "XV-W-OE");
+        assert_eq!(mock.calls(), vec!["attempt_warp_jump(ship-1)"]);
+        storage.with_state(|state| assert_eq!(state.flight_plans.len(), 1));
+    }
+
+    #[tokio::test]
+    async fn landing_moves_the_ship_to_the_new_system_and_converts_to_a_trader() {
+        let mock = Arc::new(MockGameApi::default());
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &test_utils::ship(None, &[])).await.unwrap();
+
+        let mut system_change = SystemChange::new(mock.clone(), storage.clone(), USER_ID.to_string(), "trader".to_string(), "XV".to_string(), test_utils::ship(None, &[]));
+        system_change.state = SystemChangeState::WaitForWarp;
+        system_change.flight_plan = Some(test_utils::flight_plan("OE-W-XV", "XV-W-OE", 0).flight_plan);
+        system_change.arrival_time = Utc::now();
+
+        match system_change.poll().await.unwrap() {
+            Some(PollResult::ConvertToNewMachine(MachineType::Trader(trader))) => {
+                assert_eq!(trader.system, "XV");
+                assert_eq!(trader.ship.location, Some("XV-W-OE".to_string()));
+            },
+            other => panic!("Expected to convert to a trader but got {:?}", other),
+        }
+        assert_eq!(storage.get_ship(USER_ID, "ship-1").await.unwrap().system, "XV");
+    }
+
+    #[tokio::test]
+    async fn landing_converts_to_a_scout_when_assigned_to_scout() {
+        let mock = Arc::new(MockGameApi::default());
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "XV", &ShipAssignment::Scout, &test_utils::ship(None, &[])).await.unwrap();
+
+        let mut system_change = SystemChange::new(mock.clone(), storage.clone(), USER_ID.to_string(), "trader".to_string(), "XV".to_string(), test_utils::ship(None, &[]));
+        system_change.state = SystemChangeState::WaitForWarp;
+        system_change.flight_plan = Some(test_utils::flight_plan("OE-W-XV", "XV-W-OE", 0).flight_plan);
+        system_change.arrival_time = Utc::now();
+
+        match system_change.poll().await.unwrap() {
+            Some(PollResult::ConvertToNewMachine(MachineType::Scout(scout))) => {
+                assert_eq!(scout.system, "XV");
+                assert_eq!(scout.ship.location, Some("XV-W-OE".to_string()));
+            },
+            other => panic!("Expected to convert to a scout but got {:?}", other),
+        }
+    }
+}
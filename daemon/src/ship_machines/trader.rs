@@ -1,57 +1,288 @@
-use crate::ship_machines::{PollResult, MachineType};
-use spacetraders::client::Client;
-use sqlx::PgPool;
+use crate::ship_machines::{active_flight_plan, DesiredState, MachineType, PollResult, ShipAssignment};
+use crate::game::GameClient;
+use crate::game::errors::GameError;
+use crate::db::{DbUserTrade, DbUserTransaction};
+use crate::storage::StorageClient;
 use chrono::{DateTime, Utc};
-use crate::{db, funcs};
-use crate::db::DbRoute;
+use crate::config::TradingConfig;
+use crate::funcs;
+use spacemonger_core::planner::{Leg, LegType, TradePlan};
+use crate::route_reservations::RouteReservations;
+use spacemonger_core::scoring::{self, ShipProfile};
+use crate::slippage_model;
 use spacetraders::shared;
 use spacetraders::shared::Good;
 use std::cmp::min;
 use rand::seq::SliceRandom;
-use crate::ship_machines::{system_change::SystemChange, ShipMachine};
+use crate::ship_machines::scout::Scout;
+use crate::ship_machines::system_change::SystemChange;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum TraderState {
     InitializeShip,
     WaitForArrival,
     PickBestTrade,
-    // MoveToLocation,
+    MoveToLocation,
     ExecuteTrade,
+    ContinueTrade,
     // PurchaseMaxGoodForTrading,
     MoveToRandomLocation,
     // PickRandomLocation,
 }
 
+/// The trade the ship is part way through. It is opened when a plan is picked and every order and
+/// flight plan made until the goods are sold carries its id
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct OpenTrade {
+    id: String,
+    good: Good,
+    // What the scorer expected the trade to make when it was picked
+    predicted_profit: f64,
+    started_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Trader {
-    pub client: Client,
-    pub pg_pool: PgPool,
+    pub client: GameClient,
+    pub storage: StorageClient,
     pub user_id: String,
     pub username: String,
     pub system: String,
     pub ship: shared::Ship,
     state: TraderState,
     arrival_time: DateTime<Utc>,
-    route: Option<DbRoute>,
+    pub trading: TradingConfig,
+    pub reservations: RouteReservations,
+    plan: Option<TradePlan>,
+    // The index of the leg of the plan that the ship is currently on
+    leg: usize,
+    // How many units the scorer thought were worth buying
+    order_size: Option<i32>,
+    // Outlives the plan until whatever was bought for it has been sold
+    trade: Option<OpenTrade>,
+    flight_plan: Option<shared::FlightPlanData>,
+    // Where the ship has been asked to go and wait between trades. I.E. a shipyard where a new
+    // ship is being bought. Only lives in memory like the route claims
+    destination: Option<String>,
+    // Whether the ship parks at destination for good. See ShipCommand::Retire
+    retiring: bool,
+}
+
+/// The parts of a trader that need to survive a restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraderCheckpoint {
+    pub system: String,
+    state: TraderState,
+    arrival_time: DateTime<Utc>,
+    plan: Option<TradePlan>,
+    leg: usize,
+    #[serde(default)]
+    order_size: Option<i32>,
+    #[serde(default)]
+    trade: Option<OpenTrade>,
     flight_plan: Option<shared::FlightPlanData>,
 }
 
 impl Trader {
-    pub fn new(client: Client, pg_pool: PgPool, user_id: String, username: String, system: String, ship: shared::Ship) -> Trader {
+    pub fn new(client: GameClient, storage: StorageClient, user_id: String, username: String, system: String, ship: shared::Ship) -> Trader {
         Trader {
             client,
-            pg_pool,
+            storage,
             user_id,
             username,
             system,
             ship,
             state: TraderState::InitializeShip,
             arrival_time: Utc::now(),
-            route: None,
+            trading: TradingConfig::default(),
+            reservations: RouteReservations::new(),
+            plan: None,
+            leg: 0,
+            order_size: None,
+            trade: None,
             flight_plan: None,
+            destination: None,
+            retiring: false,
         }
     }
 
+    /// The state the machine is in. I.E. WaitForArrival
+    pub fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    pub fn checkpoint(&self) -> TraderCheckpoint {
+        TraderCheckpoint {
+            system: self.system.clone(),
+            state: self.state.clone(),
+            arrival_time: self.arrival_time,
+            plan: self.plan.clone(),
+            leg: self.leg,
+            order_size: self.order_size,
+            trade: self.trade.clone(),
+            flight_plan: self.flight_plan.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, checkpoint: TraderCheckpoint) {
+        self.system = checkpoint.system;
+        self.state = checkpoint.state;
+        self.arrival_time = checkpoint.arrival_time;
+        self.plan = checkpoint.plan;
+        self.leg = checkpoint.leg;
+        self.order_size = checkpoint.order_size;
+        self.trade = checkpoint.trade;
+        self.flight_plan = checkpoint.flight_plan;
+
+        // The checkpoint only holds up if the ship is still where the trader thinks it is.
+        // Otherwise start over and let InitializeShip work out where the ship is
+        let consistent = match (&self.state, &self.ship.location) {
+            (TraderState::WaitForArrival, _) => self.flight_plan.is_some(),
+            (TraderState::ExecuteTrade, Some(location)) => self.plan.as_ref().map(|p| &p.purchase_location) == Some(location),
+            (TraderState::ContinueTrade, Some(location)) => self.plan.as_ref().and_then(|p| p.legs.get(self.leg)).map(|l| &l.origin) == Some(location),
+            (_, location) => location.is_some(),
+        };
+
+        if !consistent {
+            log::warn!("{}:{} -- Checkpoint {:?} doesn't match the ship's location {:?}. Reinitializing", self.username, self.ship.id, self.state, self.ship.location);
+            self.state = TraderState::InitializeShip;
+        }
+
+        // Claims only live in memory so take the trade back that the ship was part way through
+        if let (Some(plan), Some(order_size)) = (&self.plan, self.order_size) {
+            self.reservations.claim(&self.ship.id, plan, order_size);
+        }
+    }
+
+    /// Send the ship to a location once its current trade is done and hold it there until it is
+    /// sent somewhere else. None lets the ship go back to trading
+    pub fn send_to(&mut self, destination: Option<String>) {
+        self.destination = destination;
+    }
+
+    /// Send the ship to a location to sell whatever it has left and park there for good
+    pub fn retire(&mut self, location: String) {
+        self.destination = Some(location);
+        self.retiring = true;
+    }
+
+    pub fn is_retiring(&self) -> bool {
+        self.retiring
+    }
+
+    pub fn destination(&self) -> Option<&str> {
+        self.destination.as_deref()
+    }
+
+    /// Where the ship is waiting for whoever sent it there
+    pub fn held_at(&self) -> Option<&str> {
+        match (&self.state, &self.destination, &self.ship.location) {
+            (TraderState::PickBestTrade, Some(destination), Some(location)) if destination == location => Some(destination),
+            _ => None,
+        }
+    }
+
+    fn trade_id(&self) -> Option<String> {
+        self.trade.as_ref().map(|t| t.id.clone())
+    }
+
+    /// Record the trade the ship was on once its hold has been sold. A trade that can't be
+    /// recorded is dropped rather than holding up the ship
+    async fn complete_trade(&mut self) -> anyhow::Result<()> {
+        let trade = match self.trade.take() {
+            Some(trade) => trade,
+            None => return Ok(()),
+        };
+
+        let transactions = self.storage.get_trade_transactions(&self.user_id, &trade.id).await?;
+        match settle_trade(&self.user_id, &self.ship.id, &trade, &transactions, Utc::now()) {
+            Some(completed) => {
+                log::info!(
+                    "{}:{} -- Trade of {} {} from {} to {} made {} credits ({} fuel) in {} seconds. Expected {}",
+                    self.username,
+                    self.ship.id,
+                    completed.quantity,
+                    completed.good,
+                    completed.purchase_location,
+                    completed.sell_location,
+                    completed.profit,
+                    completed.fuel_cost,
+                    completed.duration_seconds,
+                    completed.predicted_profit,
+                );
+
+                self.storage.persist_trade(&completed).await?;
+            }
+            None => log::debug!("{}:{} -- Trade {} never bought and sold any {}. Nothing to record", self.username, self.ship.id, trade.id, trade.good),
+        }
+
+        Ok(())
+    }
+
+    /// Buy whatever fuel the leg needs. Returns the user's new credits if any fuel was bought
+    async fn refuel_for(&mut self, leg: &Leg) -> anyhow::Result<Option<i32>> {
+        if leg.leg_type == LegType::Warp {
+            return Ok(None);
+        }
+
+        let current_fuel = self.ship.cargo.iter().filter(|c| c.good == Good::Fuel).fold(0, |acc, c| acc + c.quantity);
+
+        let additional_fuel_required = funcs::get_additional_fuel_required_for_trip(
+            self.storage.clone(),
+            self.client.clone(),
+            &self.ship.id,
+            &self.ship.ship_type,
+            current_fuel,
+            &leg.origin,
+            &leg.destination,
+        ).await?;
+
+        if additional_fuel_required <= 0 {
+            return Ok(None);
+        }
+
+        log::info!("{}:{} -- Ship destined to {} is filling up with {} additional fuel", self.username, self.ship.id, leg.destination, additional_fuel_required);
+        let trade_id = self.trade_id();
+        let purchase_order = funcs::create_purchase_order(
+            self.client.clone(),
+            self.storage.clone(),
+            &self.user_id,
+            trade_id.as_deref(),
+            Good::Fuel,
+            // Don't ever try and buy more fuel than the ship can hold
+            min(additional_fuel_required, self.ship.space_available),
+            &mut self.ship,
+        ).await?;
+
+        self.ship = purchase_order.ship;
+
+        Ok(Some(purchase_order.credits))
+    }
+
+    /// Fly or warp to the end of the leg and wait for the ship to arrive
+    async fn set_off(&mut self, leg: &Leg) -> anyhow::Result<()> {
+        let trade_id = self.trade_id();
+        let flight_plan = match leg.leg_type {
+            LegType::Flight => {
+                log::info!("{}:{} -- Ship destined to {} is creating a flight plan", self.username, self.ship.id, leg.destination);
+                funcs::create_flight_plan(self.client.clone(), self.storage.clone(), &self.user_id, trade_id.as_deref(), &leg.destination, &mut self.ship).await?
+            }
+            LegType::Warp => {
+                log::info!("{}:{} -- Ship destined to {} is attempting a warp jump", self.username, self.ship.id, leg.destination);
+                funcs::attempt_warp_jump(self.client.clone(), self.storage.clone(), &self.user_id, trade_id.as_deref(), &mut self.ship).await?
+            }
+        };
+
+        log::info!("{}:{} -- Ship destined to {} is scheduled for arrival at {}", self.username, self.ship.id, leg.destination, flight_plan.flight_plan.arrives_at);
+        self.arrival_time = flight_plan.flight_plan.arrives_at;
+        self.flight_plan = Some(flight_plan.flight_plan);
+        self.state = TraderState::WaitForArrival;
+
+        Ok(())
+    }
+
     pub async fn reset(&mut self) -> anyhow::Result<()> {
         log::info!("{}:{} -- Ship is being reset", self.username, self.ship.id);
 
@@ -64,6 +295,9 @@ impl Trader {
         }
 
         self.ship.cargo.clear();
+        self.reservations.release(&self.ship.id);
+        // The goods are gone so there is nothing left of the trade to record
+        self.trade = None;
 
         // Next we will re-initialize the ship which will wait for the ship to arrive and restart
         // it's loop
@@ -72,26 +306,57 @@ impl Trader {
         Ok(())
     }
 
+    /// Recover from an api error without throwing away the cargo
+    pub async fn recover(&mut self, error: &GameError) -> anyhow::Result<Option<PollResult>> {
+        log::info!("{}:{} -- Recovering from {} while in {:?}", self.username, self.ship.id, error, self.state);
+
+        match error {
+            GameError::InsufficientFuel { required } => {
+                // The flight plan is created again on the next poll
+                let trade_id = self.trade_id();
+                let new_user_credits = funcs::buy_missing_fuel(self.client.clone(), self.storage.clone(), &self.user_id, trade_id.as_deref(), *required, &mut self.ship).await?;
+
+                return Ok(new_user_credits.map(PollResult::UpdateCredits));
+            }
+            GameError::GoodNotListed => {
+                // Whatever is in the hold can't be sold here. Take it somewhere else
+                self.reservations.release(&self.ship.id);
+                self.plan = None;
+                self.leg = 0;
+                self.state = TraderState::MoveToRandomLocation;
+            }
+            GameError::QuantityUnavailable | GameError::InsufficientCredits => {
+                // This trade can't go ahead. Sell whatever we have and pick another one
+                self.plan = None;
+                self.leg = 0;
+                self.state = TraderState::PickBestTrade;
+            }
+            GameError::ShipInTransit => {
+                self.ship = self.client.get_my_ship(&self.ship.id).await?.ship;
+                self.state = TraderState::InitializeShip;
+            }
+        }
+
+        Ok(None)
+    }
+
     pub async fn poll(&mut self) -> anyhow::Result<Option<PollResult>> {
         match self.state {
             TraderState::InitializeShip => {
                 log::trace!("{}:{} -- TraderState::InitializeShip", self.username, self.ship.id);
 
-                if self.ship.location == None {
-                    // search for any stored flight plans that are valid for this scout.
-                    let flight_plan = db::get_active_flight_plan(self.pg_pool.clone(), &self.ship.id)
-                        .await.expect("Unable to find flight plan for a ship that is in motion").unwrap();
-
+                if let Some(flight_plan) = active_flight_plan(&self.client, &self.storage, &mut self.ship).await? {
                     log::info!("{}:{} -- Ship is moving to {}. Waiting for arrival", self.username, self.ship.id, flight_plan.destination);
                     self.arrival_time = flight_plan.arrives_at;
                     self.flight_plan = Some(flight_plan);
                     self.state = TraderState::WaitForArrival;
                 } else {
+                    let trade_id = self.trade_id();
                     let mut new_user_credits = 0;
                     for cargo in self.ship.cargo.clone() {
                         if cargo.quantity > 0 {
                             log::info!("{}:{} -- Selling {} goods {} at {}", self.username, self.ship.id, cargo.quantity, cargo.good, self.ship.location.clone().unwrap());
-                            let sell_order = funcs::create_sell_order(self.client.clone(), self.pg_pool.clone(), &self.user_id, cargo.good, cargo.quantity, &mut self.ship).await?;
+                            let sell_order = funcs::create_sell_order(self.client.clone(), self.storage.clone(), &self.user_id, trade_id.as_deref(), cargo.good, cargo.quantity, &mut self.ship).await?;
                             new_user_credits = sell_order.credits;
                         }
                     }
@@ -107,9 +372,37 @@ impl Trader {
                 log::trace!("{}:{} -- TraderState::WaitForArrival", self.username, self.ship.id);
                 // We have arrived
                 if Utc::now().ge(&self.arrival_time) {
-                    log::info!("{}:{} -- Ship traveling to {} has arrived", self.username, self.ship.id, self.flight_plan.clone().unwrap().destination);
-                    self.ship.location = Some(self.flight_plan.clone().unwrap().destination);
-                    self.state = TraderState::PickBestTrade;
+                    let destination = self.flight_plan.clone().unwrap().destination;
+                    log::info!("{}:{} -- Ship traveling to {} has arrived", self.username, self.ship.id, destination);
+                    self.ship.location = Some(destination.clone());
+
+                    // Warping part way through a trade leaves the ship in another system. That's
+                    // where it trades from now on. Only the machine keeps track of it, the system
+                    // stored for the ship is the one it has been assigned to
+                    let system = funcs::get_system_from_location(&destination);
+                    if system != self.system {
+                        log::info!("{}:{} -- Ship has warped from {} to {}", self.username, self.ship.id, self.system, system);
+                        self.system = system.to_string();
+                    }
+
+                    // Traders dock at markets all day so they keep the market data fresh for
+                    // everyone else. A trade shouldn't stop because of it though
+                    if let Err(e) = funcs::harvest_market_data(self.client.clone(), self.storage.clone(), &self.ship.id, &destination).await {
+                        log::warn!("{}:{} -- Unable to record the market at {}. Error: {}", self.username, self.ship.id, destination, e);
+                    }
+
+                    match &self.plan {
+                        // Part way through a trade that spans multiple legs
+                        Some(plan) if self.leg + 1 < plan.legs.len() => {
+                            self.leg += 1;
+                            self.state = TraderState::ContinueTrade;
+                        }
+                        _ => {
+                            self.plan = None;
+                            self.leg = 0;
+                            self.state = TraderState::PickBestTrade;
+                        }
+                    }
                 }
             },
             TraderState::MoveToRandomLocation => {
@@ -122,7 +415,7 @@ impl Trader {
 
                 let current_ship_location = self.ship.location.clone().unwrap();
 
-                let locations = db::get_system_locations_from_location(self.pg_pool.clone(), &current_ship_location).await?;
+                let locations = self.storage.get_system_locations_from_location(&current_ship_location).await?;
 
                 log::debug!("{}:{} -- Found locations in system to randomly pick from {:?}", self.username, self.ship.id, locations);
 
@@ -143,7 +436,7 @@ impl Trader {
                             .fold(0, |acc, c| acc + c.quantity);
 
                         let additional_fuel_required = funcs::get_additional_fuel_required_for_trip(
-                            self.pg_pool.clone(),
+                            self.storage.clone(),
                             self.client.clone(),
                             &self.ship.id,
                             &self.ship.ship_type,
@@ -152,13 +445,16 @@ impl Trader {
                             location,
                         ).await?;
 
+                        // Cargo that couldn't be sold is still part of the trade it was bought for
+                        let trade_id = self.trade_id();
                         let mut new_user_credits = 0;
                         if additional_fuel_required > 0 {
                             log::info!("{}:{} -- Ship destined to {} is filling up with {} additional fuel", self.username, self.ship.id, location, additional_fuel_required);
                             let purchase_order = funcs::create_purchase_order(
                                 self.client.clone(),
-                                self.pg_pool.clone(),
+                                self.storage.clone(),
                                 &self.user_id,
+                                trade_id.as_deref(),
                                 Good::Fuel,
                                 // Don't ever try and buy more fuel than the ship can hold
                                 min(additional_fuel_required, self.ship.space_available),
@@ -171,9 +467,10 @@ impl Trader {
                         log::info!("{}:{} -- Ship destined to {} is creating a flight plan", self.username, self.ship.id, location);
                         let flight_plan = funcs::create_flight_plan(
                             self.client.clone(),
-                            self.pg_pool.clone(),
+                            self.storage.clone(),
                             &self.user_id,
-                            &location,
+                            trade_id.as_deref(),
+                            location,
                             &mut self.ship
                         ).await?;
 
@@ -196,44 +493,112 @@ impl Trader {
             TraderState::PickBestTrade => {
                 log::trace!("{}:{} -- TraderState::PickBestTrade", self.username, self.ship.id);
 
+                let trade_id = self.trade_id();
                 let mut new_user_credits = 0;
                 for cargo in self.ship.cargo.clone() {
                     if cargo.quantity > 0 {
                         log::info!("{}:{} -- Selling {} goods {} at {}", self.username, self.ship.id, cargo.quantity, cargo.good, self.ship.location.clone().unwrap());
-                        let sell_order = funcs::create_sell_order(self.client.clone(), self.pg_pool.clone(), &self.user_id, cargo.good, cargo.quantity, &mut self.ship).await?;
+                        let sell_order = funcs::create_sell_order(self.client.clone(), self.storage.clone(), &self.user_id, trade_id.as_deref(), cargo.good, cargo.quantity, &mut self.ship).await?;
                         new_user_credits = sell_order.credits;
                     }
                 }
 
-                // TODO: This is the optimal place for making changes to the current ship state
+                // Whatever trade the ship was on is over
+                self.reservations.release(&self.ship.id);
+                if let Err(e) = self.complete_trade().await {
+                    log::warn!("{}:{} -- Unable to record the trade. Error: {}", self.username, self.ship.id, e);
+                }
+
+                // Between trades with an empty hold is the optimal place for making changes to
+                // the current ship state
                 // I.E. if a ship is in system OE but the DB says it should be in XV then
-                //      we should convert the ship here and return
-                let db_ship = db::get_ship(self.pg_pool.clone(), &self.user_id, &self.ship.id).await?;
-                if db_ship.system != self.system {
+                //      we convert the ship here and return
+                let desired_state = DesiredState::load(&self.storage, &self.user_id, &self.ship.id).await?;
+                if desired_state.system != self.system {
                     log::trace!("{}:{} -- TraderState::ConvertToNewMachine", self.username, self.ship.id);
-                    log::trace!("{}:{} -- Detected that the ship is in {} and needs to move to {}", self.username, self.ship.id, self.system, db_ship.system);
-                    return Ok(Some(PollResult::ConvertToNewMachine(MachineType::SystemChange(self.into()))))
+                    log::trace!("{}:{} -- Detected that the ship is in {} and needs to move to {}", self.username, self.ship.id, self.system, desired_state.system);
+                    let mut system_change: SystemChange = self.into();
+                    system_change.system = desired_state.system;
+
+                    return Ok(Some(PollResult::ConvertToNewMachine(MachineType::SystemChange(system_change))))
+                }
+
+                if desired_state.assignment == ShipAssignment::Scout {
+                    log::info!("{}:{} -- Ship has been reassigned as a scout", self.username, self.ship.id);
+                    return Ok(Some(PollResult::ConvertToNewMachine(MachineType::Scout(self.into()))))
                 }
 
                 let origin = self.ship.location.clone().unwrap();
 
-                let routes = funcs::get_routes_for_ship(
-                    self.pg_pool.clone(),
+                // The ship has been asked to go somewhere else instead of trading
+                if let Some(destination) = &self.destination {
+                    if *destination == origin {
+                        // Anything that was sold has to be counted before the ship is gone
+                        if self.retiring && new_user_credits == 0 {
+                            log::info!("{}:{} -- Ship is parked at {} for good", self.username, self.ship.id, origin);
+                            return Ok(Some(PollResult::Retired(origin)));
+                        }
+
+                        log::trace!("{}:{} -- Holding at {}", self.username, self.ship.id, origin);
+                    } else {
+                        log::info!("{}:{} -- Ship has been sent to {}", self.username, self.ship.id, destination);
+                        self.state = TraderState::MoveToLocation;
+                    }
+
+                    return Ok((new_user_credits > 0).then(|| PollResult::UpdateCredits(new_user_credits)));
+                }
+
+                let plans = funcs::plan_trades_from_location(
+                    self.storage.clone(),
                     &origin,
-                        self.ship.speed
+                    self.ship.speed,
+                    &self.ship.ship_type,
                 ).await?;
 
-                log::debug!("{}:{} -- Routes: {:?}", self.username, self.ship.id, routes);
+                log::debug!("{}:{} -- Trade plans: {:?}", self.username, self.ship.id, plans);
 
-                for route in routes {
-                    if route.sell_location != "OE-XV-91-2" && route.purchase_quantity > 500 && route.profit_speed_volume_distance > 0.0 {
-                        log::info!("{}:{} -- Trading {} from {} to {} (purchase quantity {}, sell quantity {})", self.username, self.ship.id, route.good, route.purchase_location, route.sell_location, route.purchase_quantity, route.sell_quantity);
+                // Whatever was just sold here is something more to learn from
+                if self.trading.scoring.learn_slippage {
+                    if let Err(e) = slippage_model::learn(self.storage.clone(), &origin).await {
+                        log::warn!("{}:{} -- Unable to learn slippage at {}. Error: {}", self.username, self.ship.id, origin, e);
+                    }
+                }
 
-                        self.route = Some(route);
-                        self.state = TraderState::ExecuteTrade;
+                // Plan around what the rest of the fleet has already claimed
+                let plans = plans.into_iter()
+                    .filter(|p| self.trading.allows(p.good, &p.sell_location))
+                    .map(|p| self.reservations.remaining(&self.ship.id, p, self.trading.scoring.slippage_impact))
+                    .collect();
+                let scorer = scoring::scorer(&self.trading.scoring, self.storage.get_slippage_models().await?);
+                let ranked = scoring::rank_plans(plans, &ShipProfile::from_ship(&self.ship), scorer.as_ref());
 
-                        return Ok(None);
-                    }
+                if let Some((plan, score)) = ranked.into_iter().next().filter(|(_, score)| score.credits_per_second > 0.0) {
+                    log::info!(
+                        "{}:{} -- Trading {} {} from {} to {} over {} legs. Expecting {:.0} credits ({:.2} credits per second)",
+                        self.username,
+                        self.ship.id,
+                        score.units,
+                        plan.good,
+                        plan.purchase_location,
+                        plan.sell_location,
+                        plan.legs.len(),
+                        score.expected_profit,
+                        score.credits_per_second,
+                    );
+
+                    self.reservations.claim(&self.ship.id, &plan, score.units);
+                    self.trade = Some(OpenTrade {
+                        id: new_trade_id(),
+                        good: plan.good,
+                        predicted_profit: score.expected_profit,
+                        started_at: Utc::now(),
+                    });
+                    self.plan = Some(plan);
+                    self.leg = 0;
+                    self.order_size = Some(score.units);
+                    self.state = TraderState::ExecuteTrade;
+
+                    return Ok(None);
                 }
 
                 log::warn!("{}:{} -- Found no available routes from {}. Randomly picking a new location to move to in this system", self.username, self.ship.id, origin);
@@ -243,97 +608,120 @@ impl Trader {
                     return Ok(Some(PollResult::UpdateCredits(new_user_credits)));
                 }
             },
+            TraderState::MoveToLocation => {
+                log::trace!("{}:{} -- TraderState::MoveToLocation", self.username, self.ship.id);
+
+                let (origin, destination) = match (&self.ship.location, &self.destination) {
+                    (Some(origin), Some(destination)) => (origin.clone(), destination.clone()),
+                    // Nowhere to go anymore
+                    _ => {
+                        self.state = TraderState::PickBestTrade;
+                        return Ok(None);
+                    }
+                };
+
+                let leg = Leg {
+                    leg_type: LegType::Flight,
+                    origin,
+                    destination,
+                    distance: 0.0,
+                    fuel_required: 0.0,
+                    flight_time: 0.0,
+                };
+
+                let new_user_credits = self.refuel_for(&leg).await?;
+                self.set_off(&leg).await?;
+
+                return Ok(new_user_credits.map(PollResult::UpdateCredits));
+            },
             TraderState::ExecuteTrade => {
                 log::trace!("{}:{} -- TraderState::Execute", self.username, self.ship.id);
 
-                if self.route.is_none() {
-                    log::warn!("{}:{} -- Tried to execute a trade without a route. Picking a new route", self.username, self.ship.id);
-                    // Somehow we ended up here without a route... go back and pick a route
-                    self.state = TraderState::PickBestTrade;
-                }
+                let plan = match self.plan.clone() {
+                    Some(plan) => plan,
+                    None => {
+                        log::warn!("{}:{} -- Tried to execute a trade without a plan. Picking a new trade", self.username, self.ship.id);
+                        // Somehow we ended up here without a plan... go back and pick a trade
+                        self.state = TraderState::PickBestTrade;
+                        return Ok(None);
+                    }
+                };
 
                 // After we arrive at a location
-                let route = self.route.clone().unwrap();
-
-                let current_fuel = self.ship.cargo.iter().filter(|c| c.good == Good::Fuel).fold(0, |acc, c| acc + c.quantity);
+                let first_leg = plan.legs[0].clone();
+                let fuel_credits = self.refuel_for(&first_leg).await?;
 
-                let additional_fuel_required = funcs::get_additional_fuel_required_for_trip(
-                    self.pg_pool.clone(),
-                    self.client.clone(),
-                    &self.ship.id,
-                    &self.ship.ship_type,
-                    current_fuel,
-                    &route.purchase_location,
-                    &route.sell_location,
-                ).await?;
-
-                let mut new_user_credits = 0;
-                if additional_fuel_required > 0 {
-                    log::info!("{}:{} -- Ship destined to {} is filling up with {} additional fuel", self.username, self.ship.id, route.sell_location, additional_fuel_required);
-                    let purchase_order = funcs::create_purchase_order(
-                        self.client.clone(),
-                        self.pg_pool.clone(),
-                        &self.user_id,
-                        Good::Fuel,
-                        // Don't ever try and buy more fuel than the ship can hold
-                        min(additional_fuel_required, self.ship.space_available),
-                        &mut self.ship,
-                    ).await?;
-
-                    new_user_credits = purchase_order.credits;
-                    self.ship = purchase_order.ship;
-                }
+                // Leave room in the hold for the fuel needed on the later legs of the trip
+                let fuel_reserve = plan.fuel_required_after_first_leg();
+                let quantity = (self.ship.space_available - fuel_reserve).max(0) / plan.good.get_volume();
+                // Past a point buying more moves the price so far that it costs profit
+                let quantity = min(quantity, self.order_size.unwrap_or(quantity));
 
-                log::debug!("{}:{} -- Current space available {}", self.username, self.ship.id, self.ship.space_available);
+                log::debug!("{}:{} -- Current space available {} (keeping {} for fuel)", self.username, self.ship.id, self.ship.space_available, fuel_reserve);
 
                 log::info!(
                     "{}:{} -- Purchasing {} {} for trading (volume per unit {}). Purchase price at {} is {}. Sell price at {} is {}",
                     self.username,
                     self.ship.id,
-                    self.ship.space_available / route.good.get_volume(),
-                    route.good,
-                    route.good.get_volume(),
-                    route.purchase_location,
-                    route.purchase_price_per_unit,
-                    route.sell_location,
-                    route.sell_price_per_unit
+                    quantity,
+                    plan.good,
+                    plan.good.get_volume(),
+                    plan.purchase_location,
+                    plan.purchase_price_per_unit,
+                    plan.sell_location,
+                    plan.sell_price_per_unit
                 );
 
+                let trade_id = self.trade_id();
                 match funcs::create_purchase_order(
                     self.client.clone(),
-                    self.pg_pool.clone(),
+                    self.storage.clone(),
                     &self.user_id,
-                    route.good,
-                    self.ship.space_available / route.good.get_volume(),
+                    trade_id.as_deref(),
+                    plan.good,
+                    quantity,
                     &mut self.ship,
                 ).await {
                     Ok(purchase_order) => {
                         self.ship = purchase_order.ship;
-
-                        log::info!("{}:{} -- Ship destined to {} is creating a flight plan", self.username, self.ship.id, route.sell_location);
-                        let flight_plan = funcs::create_flight_plan(
-                            self.client.clone(),
-                            self.pg_pool.clone(),
-                            &self.user_id,
-                            &route.sell_location,
-                            &mut self.ship,
-                        ).await?;
-
-                        log::info!("{}:{} -- Ship destined to {} is scheduled for arrival at {}", self.username, self.ship.id, route.sell_location, flight_plan.flight_plan.arrives_at);
-                        self.arrival_time = flight_plan.flight_plan.arrives_at;
-                        self.flight_plan = Some(flight_plan.flight_plan);
-                        self.state = TraderState::WaitForArrival;
+                        // The goods are in the hold. If we can't leave right away then the first
+                        // leg is picked up again like any other leg of the trade
+                        self.state = TraderState::ContinueTrade;
+                        self.set_off(&first_leg).await?;
 
                         return Ok(Some(PollResult::UpdateCredits(purchase_order.credits)));
                     },
                     Err(e) => {
                         log::error!("{}:{} -- Unable to create purchase order. Picking a new trade. Error: {}", self.username, self.ship.id, e);
-                        // If there is any error then pick another trade
+                        // If there is any error then pick another trade. Nothing was bought so there
+                        // is no trade to record
+                        self.plan = None;
+                        self.trade = None;
                         self.state = TraderState::PickBestTrade;
                     }
                 }
 
-                if new_user_credits > 0 {
+                if let Some(new_user_credits) = fuel_credits {
+                    return Ok(Some(PollResult::UpdateCredits(new_user_credits)));
+                }
+            },
+            TraderState::ContinueTrade => {
+                log::trace!("{}:{} -- TraderState::ContinueTrade", self.username, self.ship.id);
+
+                let leg = match self.plan.as_ref().and_then(|p| p.legs.get(self.leg)) {
+                    Some(leg) => leg.clone(),
+                    None => {
+                        log::warn!("{}:{} -- Tried to continue a trade without another leg. Picking a new trade", self.username, self.ship.id);
+                        self.plan = None;
+                        self.state = TraderState::PickBestTrade;
+                        return Ok(None);
+                    }
+                };
+
+                let new_user_credits = self.refuel_for(&leg).await?;
+                self.set_off(&leg).await?;
+
+                if let Some(new_user_credits) = new_user_credits {
                     return Ok(Some(PollResult::UpdateCredits(new_user_credits)));
                 }
             },
@@ -343,19 +731,681 @@ impl Trader {
     }
 }
 
+/// A random uuid to tie a trade's orders and flight plans together
+fn new_trade_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Add up everything that was bought and sold for a trade. Fuel sold back once the goods are sold
+/// comes off the fuel cost. None when the goods were never both bought and sold
+fn settle_trade(user_id: &str, ship_id: &str, trade: &OpenTrade, transactions: &[DbUserTransaction], completed_at: DateTime<Utc>) -> Option<DbUserTrade> {
+    let good = trade.good.to_string();
+    let fuel = Good::Fuel.to_string();
+    let is = |t: &&DbUserTransaction, transaction_type: &str, good: &str| t.transaction_type == transaction_type && t.good == good;
+
+    let purchases: Vec<&DbUserTransaction> = transactions.iter().filter(|t| is(t, "purchase", &good)).collect();
+    let sells: Vec<&DbUserTransaction> = transactions.iter().filter(|t| is(t, "sell", &good)).collect();
+    let (first_purchase, last_sell) = (purchases.first()?, sells.last()?);
+
+    let cost = purchases.iter().fold(0, |acc, t| acc + t.total);
+    let revenue = sells.iter().fold(0, |acc, t| acc + t.total);
+    let fuel_cost = transactions.iter().fold(0, |acc, t| match t.transaction_type.as_str() {
+        "purchase" if t.good == fuel => acc + t.total,
+        "sell" if t.good == fuel => acc - t.total,
+        _ => acc,
+    });
+
+    Some(DbUserTrade {
+        trade_id: trade.id.clone(),
+        user_id: user_id.to_string(),
+        ship_id: ship_id.to_string(),
+        good,
+        purchase_location: first_purchase.location.clone(),
+        sell_location: last_sell.location.clone(),
+        quantity: sells.iter().fold(0, |acc, t| acc + t.quantity),
+        cost,
+        revenue,
+        fuel_cost,
+        profit: revenue - cost - fuel_cost,
+        predicted_profit: trade.predicted_profit.round() as i32,
+        duration_seconds: (completed_at - trade.started_at).num_seconds(),
+        started_at: trade.started_at,
+        completed_at,
+    })
+}
+
+impl From<&mut Scout> for Trader {
+    fn from(scout: &mut Scout) -> Self {
+        Trader::new(scout.client.clone(), scout.storage.clone(), scout.user_id.clone(), scout.username.clone(), scout.system.clone(), scout.ship.clone())
+    }
+}
+
 impl From<&mut SystemChange> for Trader {
     fn from(system_change: &mut SystemChange) -> Self {
         Trader {
             client: system_change.client.clone(),
-            pg_pool: system_change.pg_pool.clone(),
+            storage: system_change.storage.clone(),
             user_id: system_change.user_id.clone(),
             username: system_change.username.clone(),
             system: system_change.system.clone(),
             ship: system_change.ship.clone(),
             state: TraderState::InitializeShip,
             arrival_time: Utc::now(),
-            route: None,
-            flight_plan: None
+            trading: TradingConfig::default(),
+            reservations: RouteReservations::new(),
+            plan: None,
+            leg: 0,
+            order_size: None,
+            trade: None,
+            flight_plan: None,
+            destination: None,
+            retiring: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::mock::MockGameApi;
+    use crate::storage::Storage;
+    use crate::storage::memory::MemoryStorage;
+    use crate::test_utils;
+    use crate::test_utils::{leg, trade_plan};
+    use spacetraders::responses;
+    use std::sync::Arc;
+
+    const USER_ID: &str = "00000000-0000-0000-0000-000000000001";
+
+    fn trader(mock: &Arc<MockGameApi>, storage: StorageClient, ship: shared::Ship) -> Trader {
+        Trader::new(mock.clone(), storage, USER_ID.to_string(), "trader".to_string(), "OE".to_string(), ship)
+    }
+
+    fn single_system_plan() -> TradePlan {
+        trade_plan(Good::Electronics, vec![leg(LegType::Flight, "OE-PM-TR", "OE-UC-AD", 25.0)])
+    }
+
+    /// Buy in OE, fly to the wormhole, warp to XV and then fly on to sell
+    fn cross_system_plan() -> TradePlan {
+        trade_plan(Good::Electronics, vec![
+            leg(LegType::Flight, "OE-PM-TR", "OE-W-XV", 25.0),
+            leg(LegType::Warp, "OE-W-XV", "XV-W-OE", 0.0),
+            leg(LegType::Flight, "XV-W-OE", "XV-CB", 12.0),
+        ])
+    }
+
+    #[tokio::test]
+    async fn initialize_docked_ship_without_cargo_picks_a_trade() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(Some("OE-PM-TR"), &[]));
+
+        assert!(trader.poll().await.unwrap().is_none());
+        assert!(matches!(trader.state, TraderState::PickBestTrade));
+        assert!(mock.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn initialize_moving_ship_without_a_stored_flight_plan_fetches_the_ship() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.get_my_ship.push(Ok(responses::MyShip { ship: test_utils::ship(Some("OE-UC-AD"), &[]) }));
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(None, &[]));
+
+        assert!(trader.poll().await.unwrap().is_none());
+        assert!(matches!(trader.state, TraderState::PickBestTrade));
+        assert_eq!(trader.ship.location, Some("OE-UC-AD".to_string()));
+    }
+
+    #[tokio::test]
+    async fn initialize_moving_ship_without_any_flight_plan_is_an_error() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.get_my_ship.push(Ok(responses::MyShip { ship: test_utils::ship(None, &[]) }));
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(None, &[]));
+
+        assert!(trader.poll().await.is_err());
+        assert!(matches!(trader.state, TraderState::InitializeShip));
+    }
+
+    #[tokio::test]
+    async fn wait_for_arrival_docks_the_ship_and_records_the_market() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.get_location_marketplace.push(Ok(responses::LocationMarketplace {
+            marketplace: vec![test_utils::market(Good::Metals, 10, 1_000), test_utils::market(Good::Fuel, 2, 5_000)],
+        }));
+
+        let storage = Arc::new(MemoryStorage::new());
+        let mut trader = trader(&mock, storage.clone(), test_utils::ship(None, &[]));
+        trader.state = TraderState::WaitForArrival;
+        trader.flight_plan = Some(test_utils::flight_plan("OE-PM-TR", "OE-UC-AD", 60).flight_plan);
+        trader.arrival_time = Utc::now() + chrono::Duration::seconds(60);
+
+        trader.poll().await.unwrap();
+        assert!(matches!(trader.state, TraderState::WaitForArrival));
+        assert!(mock.calls().is_empty());
+
+        trader.arrival_time = Utc::now();
+        trader.poll().await.unwrap();
+        assert!(matches!(trader.state, TraderState::PickBestTrade));
+        assert_eq!(trader.ship.location, Some("OE-UC-AD".to_string()));
+        assert_eq!(mock.calls(), vec!["get_location_marketplace(OE-UC-AD)"]);
+        storage.with_state(|state| {
+            assert_eq!(state.market_data.len(), 2);
+            assert!(state.market_data.iter().all(|m| m.location == "OE-UC-AD" && m.ship_id.as_deref() == Some("ship-1")));
+        });
+    }
+
+    #[tokio::test]
+    async fn arriving_still_docks_when_the_market_cant_be_recorded() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.get_location_marketplace.push(Err(test_utils::api_error(500, "Something went wrong")));
+
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(None, &[]));
+        trader.state = TraderState::WaitForArrival;
+        trader.flight_plan = Some(test_utils::flight_plan("OE-PM-TR", "OE-UC-AD", 0).flight_plan);
+        trader.arrival_time = Utc::now();
+
+        trader.poll().await.unwrap();
+        assert!(matches!(trader.state, TraderState::PickBestTrade));
+        assert_eq!(trader.ship.location, Some("OE-UC-AD".to_string()));
+    }
+
+    #[tokio::test]
+    async fn move_to_random_location_waits_while_in_motion() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(None, &[]));
+        trader.state = TraderState::MoveToRandomLocation;
+
+        assert!(trader.poll().await.unwrap().is_none());
+        assert!(matches!(trader.state, TraderState::MoveToRandomLocation));
+    }
+
+    #[tokio::test]
+    async fn reset_jettisons_cargo_and_reinitializes() {
+        let mock = Arc::new(MockGameApi::default());
+        let ship = test_utils::ship(Some("OE-PM-TR"), &[(Good::Metals, 20)]);
+        mock.get_my_ship.push(Ok(responses::MyShip { ship: ship.clone() }));
+        mock.jettison_cargo.push(Ok(responses::JettisonCargo { ship_id: ship.id.clone(), good: Good::Metals, quantity_remaining: 0 }));
+
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), ship);
+        trader.state = TraderState::ExecuteTrade;
+        trader.reset().await.unwrap();
+
+        assert!(matches!(trader.state, TraderState::InitializeShip));
+        assert!(trader.ship.cargo.is_empty());
+        assert_eq!(mock.calls(), vec!["get_my_ship(ship-1)", "jettison_cargo(ship-1, Metals, 20)"]);
+    }
+
+    #[tokio::test]
+    async fn initialize_docked_ship_sells_its_cargo() {
+        let mock = Arc::new(MockGameApi::default());
+        let ship = test_utils::ship(Some("OE-UC-AD"), &[(Good::Electronics, 50)]);
+        mock.create_sell_order.push(Ok(test_utils::order(12_000, Good::Electronics, 50, 150, test_utils::ship(Some("OE-UC-AD"), &[]))));
+
+        let storage = Arc::new(MemoryStorage::new());
+        let mut trader = trader(&mock, storage.clone(), ship);
+
+        assert!(matches!(trader.poll().await.unwrap(), Some(PollResult::UpdateCredits(12_000))));
+        assert!(matches!(trader.state, TraderState::PickBestTrade));
+        assert!(trader.ship.cargo.is_empty());
+        storage.with_state(|state| {
+            assert_eq!(state.transactions.len(), 1);
+            assert_eq!(state.transactions[0].transaction_type, "sell");
+        });
+    }
+
+    #[tokio::test]
+    async fn execute_trade_fuels_up_buys_goods_and_departs() {
+        let mock = Arc::new(MockGameApi::default());
+        // Nothing has flown this route yet so the trader asks the api how much fuel it needs
+        mock.create_flight_plan.push(Err(test_utils::api_error(3001, "Ship has insufficient fuel for flight plan. You require 25 more FUEL")));
+        mock.create_purchase_order.push(Ok(test_utils::order(150_000, Good::Fuel, 25, 2, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 25)]))));
+        mock.create_purchase_order.push(Ok(test_utils::order(139_000, Good::Electronics, 75, 147, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 25), (Good::Electronics, 75)]))));
+        mock.create_flight_plan.push(Ok(test_utils::flight_plan("OE-PM-TR", "OE-UC-AD", 250)));
+
+        let storage = Arc::new(MemoryStorage::new());
+        let mut trader = trader(&mock, storage.clone(), test_utils::ship(Some("OE-PM-TR"), &[]));
+        trader.state = TraderState::ExecuteTrade;
+        trader.plan = Some(single_system_plan());
+
+        assert!(matches!(trader.poll().await.unwrap(), Some(PollResult::UpdateCredits(139_000))));
+        assert!(matches!(trader.state, TraderState::WaitForArrival));
+        assert_eq!(trader.ship.location, None);
+        assert_eq!(mock.calls(), vec![
+            "create_flight_plan(ship-1, OE-UC-AD)",
+            "create_purchase_order(ship-1, Fuel, 25)",
+            "create_purchase_order(ship-1, Electronics, 75)",
+            "create_flight_plan(ship-1, OE-UC-AD)",
+        ]);
+        storage.with_state(|state| {
+            assert_eq!(state.transactions.iter().map(|t| t.order.good).collect::<Vec<Good>>(), vec![Good::Fuel, Good::Electronics]);
+            assert_eq!(state.flight_plans.len(), 1);
+        });
+    }
+
+    #[tokio::test]
+    async fn execute_trade_buys_no_more_than_the_scored_order_size() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.create_flight_plan.push(Err(test_utils::api_error(3001, "Ship has insufficient fuel for flight plan. You require 25 more FUEL")));
+        mock.create_purchase_order.push(Ok(test_utils::order(150_000, Good::Fuel, 25, 2, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 25)]))));
+        mock.create_purchase_order.push(Ok(test_utils::order(145_000, Good::Electronics, 40, 147, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 25), (Good::Electronics, 40)]))));
+        mock.create_flight_plan.push(Ok(test_utils::flight_plan("OE-PM-TR", "OE-UC-AD", 250)));
+
+        let storage = Arc::new(MemoryStorage::new());
+        let mut trader = trader(&mock, storage.clone(), test_utils::ship(Some("OE-PM-TR"), &[]));
+        trader.state = TraderState::ExecuteTrade;
+        trader.plan = Some(single_system_plan());
+        trader.order_size = Some(40);
+
+        trader.poll().await.unwrap();
+
+        assert_eq!(mock.calls()[2], "create_purchase_order(ship-1, Electronics, 40)");
+    }
+
+    #[tokio::test]
+    async fn execute_trade_picks_a_new_trade_when_the_purchase_fails() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.create_flight_plan.push(Err(test_utils::api_error(3001, "Ship has insufficient fuel for flight plan. You require 25 more FUEL")));
+        mock.create_purchase_order.push(Ok(test_utils::order(150_000, Good::Fuel, 25, 2, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 25)]))));
+        mock.create_purchase_order.push(Err(test_utils::api_error(2006, "Good quantity is not available on planet.")));
+
+        let storage = Arc::new(MemoryStorage::new());
+        let mut trader = trader(&mock, storage.clone(), test_utils::ship(Some("OE-PM-TR"), &[]));
+        trader.state = TraderState::ExecuteTrade;
+        trader.plan = Some(single_system_plan());
+
+        assert!(matches!(trader.poll().await.unwrap(), Some(PollResult::UpdateCredits(150_000))));
+        assert!(matches!(trader.state, TraderState::PickBestTrade));
+    }
+
+    #[tokio::test]
+    async fn execute_trade_continues_the_first_leg_when_the_ship_cant_leave() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.create_flight_plan.push(Err(test_utils::api_error(3001, "Ship has insufficient fuel for flight plan. You require 25 more FUEL")));
+        mock.create_purchase_order.push(Ok(test_utils::order(150_000, Good::Fuel, 25, 2, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 25)]))));
+        mock.create_purchase_order.push(Ok(test_utils::order(139_000, Good::Electronics, 75, 147, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 25), (Good::Electronics, 75)]))));
+        mock.create_flight_plan.push(Err(test_utils::api_error(3001, "Ship has insufficient fuel for flight plan. You require 5 more FUEL")));
+
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(Some("OE-PM-TR"), &[]));
+        trader.state = TraderState::ExecuteTrade;
+        trader.plan = Some(single_system_plan());
+
+        assert!(trader.poll().await.is_err());
+        assert!(matches!(trader.state, TraderState::ContinueTrade));
+        assert_eq!(trader.leg, 0);
+        assert_eq!(trader.ship.cargo.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn recovering_from_insufficient_fuel_buys_what_is_missing() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.create_purchase_order.push(Ok(test_utils::order(150_000, Good::Fuel, 5, 2, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 5), (Good::Electronics, 75)]))));
+
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(Some("OE-PM-TR"), &[(Good::Electronics, 75)]));
+        trader.state = TraderState::ContinueTrade;
+        trader.plan = Some(single_system_plan());
+
+        let poll_result = trader.recover(&GameError::InsufficientFuel { required: 5 }).await.unwrap();
+
+        assert!(matches!(poll_result, Some(PollResult::UpdateCredits(150_000))));
+        assert!(matches!(trader.state, TraderState::ContinueTrade));
+        assert!(trader.plan.is_some());
+        assert_eq!(mock.calls(), vec!["create_purchase_order(ship-1, Fuel, 5)"]);
+    }
+
+    #[tokio::test]
+    async fn recovering_from_insufficient_fuel_with_a_full_hold_sells_cargo_to_make_room() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.create_sell_order.push(Ok(test_utils::order(140_000, Good::Electronics, 5, 140, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 2), (Good::Electronics, 93)]))));
+        mock.create_purchase_order.push(Ok(test_utils::order(139_990, Good::Fuel, 5, 2, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 7), (Good::Electronics, 93)]))));
+
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 2), (Good::Electronics, 98)]));
+        trader.state = TraderState::ContinueTrade;
+        trader.plan = Some(single_system_plan());
+
+        let poll_result = trader.recover(&GameError::InsufficientFuel { required: 5 }).await.unwrap();
+
+        assert!(matches!(poll_result, Some(PollResult::UpdateCredits(139_990))));
+        assert_eq!(trader.ship.space_available, 0);
+        assert_eq!(mock.calls(), vec!["create_sell_order(ship-1, Electronics, 5)", "create_purchase_order(ship-1, Fuel, 5)"]);
+    }
+
+    #[tokio::test]
+    async fn recovering_from_an_unlisted_good_takes_the_cargo_elsewhere() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(Some("OE-UC-AD"), &[(Good::Electronics, 75)]));
+        trader.state = TraderState::PickBestTrade;
+
+        trader.recover(&GameError::GoodNotListed).await.unwrap();
+
+        assert!(matches!(trader.state, TraderState::MoveToRandomLocation));
+        assert_eq!(trader.ship.cargo.len(), 1);
+        assert!(mock.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn recovering_from_an_unavailable_quantity_picks_another_trade() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(Some("OE-PM-TR"), &[]));
+        trader.state = TraderState::ExecuteTrade;
+        trader.plan = Some(single_system_plan());
+
+        trader.recover(&GameError::QuantityUnavailable).await.unwrap();
+
+        assert!(matches!(trader.state, TraderState::PickBestTrade));
+        assert!(trader.plan.is_none());
+    }
+
+    #[tokio::test]
+    async fn checkpoint_restores_a_trade_in_progress() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(None, &[(Good::Electronics, 75)]));
+        trader.state = TraderState::WaitForArrival;
+        trader.plan = Some(single_system_plan());
+        trader.trade = Some(OpenTrade { id: new_trade_id(), good: Good::Electronics, predicted_profit: 375.0, started_at: Utc::now() });
+        trader.flight_plan = Some(test_utils::flight_plan("OE-PM-TR", "OE-UC-AD", 60).flight_plan);
+
+        let json = serde_json::to_string(&trader.checkpoint()).unwrap();
+
+        let mut restored = self::trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(None, &[(Good::Electronics, 75)]));
+        restored.restore(serde_json::from_str(&json).unwrap());
+
+        assert!(matches!(restored.state, TraderState::WaitForArrival));
+        assert_eq!(restored.plan.unwrap().sell_location, "OE-UC-AD");
+        assert_eq!(restored.arrival_time, trader.arrival_time);
+        assert_eq!(restored.trade, trader.trade);
+        assert_eq!(restored.flight_plan.unwrap().destination, "OE-UC-AD");
+    }
+
+    #[tokio::test]
+    async fn trades_are_recorded_once_the_goods_are_sold() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.create_flight_plan.push(Err(test_utils::api_error(3001, "Ship has insufficient fuel for flight plan. You require 25 more FUEL")));
+        mock.create_purchase_order.push(Ok(test_utils::order(150_000, Good::Fuel, 25, 2, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 25)]))));
+        mock.create_purchase_order.push(Ok(test_utils::order(139_000, Good::Electronics, 75, 147, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 25), (Good::Electronics, 75)]))));
+        mock.create_flight_plan.push(Ok(test_utils::flight_plan("OE-PM-TR", "OE-UC-AD", 250)));
+        // The goods and whatever fuel is left over are sold at the end of the trade
+        mock.create_sell_order.push(Ok(test_utils::order(150_400, Good::Electronics, 75, 152, test_utils::ship(Some("OE-UC-AD"), &[(Good::Fuel, 5)]))));
+        mock.create_sell_order.push(Ok(test_utils::order(150_405, Good::Fuel, 5, 1, test_utils::ship(Some("OE-UC-AD"), &[]))));
+
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM-TR"), &[])).await.unwrap();
+
+        let mut trader = trader(&mock, storage.clone(), test_utils::ship(Some("OE-PM-TR"), &[]));
+        let trade_id = new_trade_id();
+        trader.state = TraderState::ExecuteTrade;
+        trader.plan = Some(single_system_plan());
+        trader.trade = Some(OpenTrade { id: trade_id.clone(), good: Good::Electronics, predicted_profit: 299.6, started_at: Utc::now() - chrono::Duration::seconds(300) });
+        trader.poll().await.unwrap();
+
+        trader.ship = test_utils::ship(Some("OE-UC-AD"), &[(Good::Electronics, 75), (Good::Fuel, 5)]);
+        trader.plan = None;
+        trader.state = TraderState::PickBestTrade;
+        trader.poll().await.unwrap();
+
+        assert!(trader.trade.is_none());
+        storage.with_state(|state| {
+            assert!(state.transactions.iter().all(|t| t.trade_id.as_ref() == Some(&trade_id)));
+        });
+
+        let trades = storage.get_trades(USER_ID, Utc::now() - chrono::Duration::hours(1)).await.unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].trade_id, trade_id);
+        assert_eq!((trades[0].purchase_location.as_str(), trades[0].sell_location.as_str()), ("OE-PM-TR", "OE-UC-AD"));
+        assert_eq!(trades[0].quantity, 75);
+        assert_eq!((trades[0].cost, trades[0].revenue, trades[0].fuel_cost), (11_025, 11_400, 45));
+        assert_eq!((trades[0].profit, trades[0].predicted_profit), (330, 300));
+        assert!(trades[0].duration_seconds >= 300);
+    }
+
+    #[tokio::test]
+    async fn trades_that_never_bought_anything_are_not_recorded() {
+        let mock = Arc::new(MockGameApi::default());
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM-TR"), &[])).await.unwrap();
+
+        let mut trader = trader(&mock, storage.clone(), test_utils::ship(Some("OE-PM-TR"), &[]));
+        trader.trade = Some(OpenTrade { id: new_trade_id(), good: Good::Electronics, predicted_profit: 300.0, started_at: Utc::now() });
+        trader.state = TraderState::PickBestTrade;
+        trader.poll().await.unwrap();
+
+        assert!(trader.trade.is_none());
+        assert!(storage.get_trades(USER_ID, Utc::now() - chrono::Duration::hours(1)).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn restore_reinitializes_when_the_ship_is_not_where_the_checkpoint_expects() {
+        let mock = Arc::new(MockGameApi::default());
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(Some("OE-PM-TR"), &[]));
+        trader.state = TraderState::ExecuteTrade;
+        trader.plan = Some(single_system_plan());
+        let checkpoint = trader.checkpoint();
+
+        let mut moved = self::trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(Some("OE-UC-AD"), &[]));
+        moved.restore(checkpoint.clone());
+        assert!(matches!(moved.state, TraderState::InitializeShip));
+
+        let mut in_motion = self::trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(None, &[]));
+        in_motion.restore(checkpoint);
+        assert!(matches!(in_motion.state, TraderState::InitializeShip));
+    }
+
+    #[tokio::test]
+    async fn execute_trade_leaves_room_for_fuel_needed_on_later_legs() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.create_flight_plan.push(Err(test_utils::api_error(3001, "Ship has insufficient fuel for flight plan. You require 25 more FUEL")));
+        mock.create_purchase_order.push(Ok(test_utils::order(150_000, Good::Fuel, 25, 2, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 25)]))));
+        mock.create_purchase_order.push(Ok(test_utils::order(141_000, Good::Electronics, 63, 147, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 25), (Good::Electronics, 63)]))));
+        mock.create_flight_plan.push(Ok(test_utils::flight_plan("OE-PM-TR", "OE-W-XV", 250)));
+
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(Some("OE-PM-TR"), &[]));
+        trader.state = TraderState::ExecuteTrade;
+        trader.plan = Some(cross_system_plan());
+
+        assert!(matches!(trader.poll().await.unwrap(), Some(PollResult::UpdateCredits(141_000))));
+        assert!(matches!(trader.state, TraderState::WaitForArrival));
+        assert_eq!(mock.calls(), vec![
+            "create_flight_plan(ship-1, OE-W-XV)",
+            "create_purchase_order(ship-1, Fuel, 25)",
+            "create_purchase_order(ship-1, Electronics, 63)",
+            "create_flight_plan(ship-1, OE-W-XV)",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn warping_part_way_through_a_trade_moves_the_machine_to_the_new_system() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.attempt_warp_jump.push(Ok(test_utils::flight_plan("OE-W-XV", "XV-W-OE", 0)));
+        mock.get_location_marketplace.push(Ok(responses::LocationMarketplace { marketplace: vec![] }));
+
+        let storage = Arc::new(MemoryStorage::new());
+        let ship = test_utils::ship(Some("OE-W-XV"), &[(Good::Electronics, 63)]);
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &ship).await.unwrap();
+
+        let mut trader = trader(&mock, storage.clone(), ship);
+        trader.state = TraderState::ContinueTrade;
+        trader.plan = Some(cross_system_plan());
+        trader.leg = 1;
+
+        trader.poll().await.unwrap();
+        assert!(matches!(trader.state, TraderState::WaitForArrival));
+        trader.poll().await.unwrap();
+
+        assert!(matches!(trader.state, TraderState::ContinueTrade));
+        assert_eq!(trader.leg, 2);
+        assert_eq!(trader.system, "XV");
+        assert_eq!(trader.checkpoint().system, "XV");
+        // The ship is still assigned to the system it warped out of
+        assert_eq!(storage.get_ship(USER_ID, "ship-1").await.unwrap().system, "OE");
+    }
+
+    #[tokio::test]
+    async fn arriving_part_way_through_a_trade_continues_to_the_next_leg() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.get_location_marketplace.push(Ok(responses::LocationMarketplace { marketplace: vec![] }));
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(None, &[(Good::Electronics, 63)]));
+        trader.state = TraderState::WaitForArrival;
+        trader.plan = Some(cross_system_plan());
+        trader.flight_plan = Some(test_utils::flight_plan("OE-PM-TR", "OE-W-XV", 0).flight_plan);
+        trader.arrival_time = Utc::now();
+
+        trader.poll().await.unwrap();
+
+        assert!(matches!(trader.state, TraderState::ContinueTrade));
+        assert_eq!(trader.leg, 1);
+        assert_eq!(trader.ship.location, Some("OE-W-XV".to_string()));
+        assert!(trader.plan.is_some());
+        assert_eq!(mock.calls(), vec!["get_location_marketplace(OE-W-XV)"]);
+    }
+
+    #[tokio::test]
+    async fn continue_trade_warps_through_a_wormhole_without_refueling() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.attempt_warp_jump.push(Ok(test_utils::flight_plan("OE-W-XV", "XV-W-OE", 180)));
+
+        let storage = Arc::new(MemoryStorage::new());
+        let mut trader = trader(&mock, storage.clone(), test_utils::ship(Some("OE-W-XV"), &[(Good::Electronics, 63)]));
+        trader.state = TraderState::ContinueTrade;
+        trader.plan = Some(cross_system_plan());
+        trader.leg = 1;
+
+        assert!(trader.poll().await.unwrap().is_none());
+        assert!(matches!(trader.state, TraderState::WaitForArrival));
+        assert_eq!(trader.ship.location, None);
+        assert_eq!(trader.flight_plan.as_ref().unwrap().destination, "XV-W-OE");
+        assert_eq!(mock.calls(), vec!["attempt_warp_jump(ship-1)"]);
+        storage.with_state(|state| assert_eq!(state.flight_plans.len(), 1));
+    }
+
+    #[tokio::test]
+    async fn arriving_at_the_end_of_the_last_leg_picks_a_new_trade() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.get_location_marketplace.push(Ok(responses::LocationMarketplace { marketplace: vec![] }));
+        let mut trader = trader(&mock, Arc::new(MemoryStorage::new()), test_utils::ship(None, &[(Good::Electronics, 63)]));
+        trader.state = TraderState::WaitForArrival;
+        trader.plan = Some(cross_system_plan());
+        trader.leg = 2;
+        trader.flight_plan = Some(test_utils::flight_plan("XV-W-OE", "XV-CB", 0).flight_plan);
+        trader.arrival_time = Utc::now();
+
+        trader.poll().await.unwrap();
+
+        assert!(matches!(trader.state, TraderState::PickBestTrade));
+        assert!(trader.plan.is_none());
+        assert_eq!(trader.leg, 0);
+        assert_eq!(trader.ship.location, Some("XV-CB".to_string()));
+    }
+
+    #[tokio::test]
+    async fn pick_best_trade_changes_systems_when_the_ship_belongs_elsewhere() {
+        let mock = Arc::new(MockGameApi::default());
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "XV", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM-TR"), &[])).await.unwrap();
+
+        let mut trader = trader(&mock, storage, test_utils::ship(Some("OE-PM-TR"), &[]));
+        trader.state = TraderState::PickBestTrade;
+
+        match trader.poll().await.unwrap() {
+            Some(PollResult::ConvertToNewMachine(MachineType::SystemChange(system_change))) => assert_eq!(system_change.system, "XV"),
+            other => panic!("Expected to convert to a system change but got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn ships_sent_somewhere_fly_there_and_hold_until_released() {
+        let mock = Arc::new(MockGameApi::default());
+        mock.create_flight_plan.push(Err(test_utils::api_error(3001, "Ship has insufficient fuel for flight plan. You require 10 more FUEL")));
+        mock.create_purchase_order.push(Ok(test_utils::order(149_980, Good::Fuel, 10, 2, test_utils::ship(Some("OE-PM-TR"), &[(Good::Fuel, 10)]))));
+        mock.create_flight_plan.push(Ok(test_utils::flight_plan("OE-PM-TR", "OE-PM", -1)));
+        mock.get_location_marketplace.push(Ok(responses::LocationMarketplace { marketplace: Vec::new() }));
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM-TR"), &[])).await.unwrap();
+
+        let mut trader = trader(&mock, storage, test_utils::ship(Some("OE-PM-TR"), &[]));
+        trader.state = TraderState::PickBestTrade;
+        trader.send_to(Some("OE-PM".to_string()));
+
+        trader.poll().await.unwrap();
+        assert!(matches!(trader.state, TraderState::MoveToLocation));
+        assert!(matches!(trader.poll().await.unwrap(), Some(PollResult::UpdateCredits(149_980))));
+        assert!(matches!(trader.state, TraderState::WaitForArrival));
+        assert_eq!(trader.held_at(), None);
+
+        // Arrive and then stay put rather than trading
+        trader.poll().await.unwrap();
+        trader.poll().await.unwrap();
+        trader.poll().await.unwrap();
+        assert_eq!(trader.held_at(), Some("OE-PM"));
+        assert_eq!(mock.calls(), vec![
+            "create_flight_plan(ship-1, OE-PM)",
+            "create_purchase_order(ship-1, Fuel, 10)",
+            "create_flight_plan(ship-1, OE-PM)",
+            "get_location_marketplace(OE-PM)",
+        ]);
+
+        trader.send_to(None);
+        assert_eq!(trader.held_at(), None);
+    }
+
+    #[tokio::test]
+    async fn pick_best_trade_converts_to_a_scout_when_reassigned() {
+        let mock = Arc::new(MockGameApi::default());
+        let storage = Arc::new(MemoryStorage::new());
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Scout, &test_utils::ship(Some("OE-PM-TR"), &[])).await.unwrap();
+
+        let mut trader = trader(&mock, storage, test_utils::ship(Some("OE-PM-TR"), &[]));
+        trader.state = TraderState::PickBestTrade;
+
+        match trader.poll().await.unwrap() {
+            Some(PollResult::ConvertToNewMachine(MachineType::Scout(scout))) => assert_eq!(scout.ship.location, Some("OE-PM-TR".to_string())),
+            other => panic!("Expected to convert to a scout but got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn traders_steer_clear_of_trades_the_fleet_has_claimed() {
+        let mock = Arc::new(MockGameApi::default());
+        let storage = Arc::new(MemoryStorage::new());
+        let oe = test_utils::system("OE", &[
+            ("OE-PM", shared::LocationType::Planet, 20, -25),
+            ("OE-UC", shared::LocationType::GasGiant, -75, 80),
+        ]);
+        for location in &oe.locations {
+            storage.persist_system_location(&oe, location).await.unwrap();
+        }
+        for (location, marketplace_data) in [
+            ("OE-PM", test_utils::market(Good::Fuel, 2, 5_000)),
+            ("OE-PM", test_utils::market(Good::Metals, 10, 200)),
+            ("OE-PM", test_utils::market(Good::Chemicals, 10, 5_000)),
+            ("OE-UC", test_utils::market(Good::Metals, 30, 200)),
+            ("OE-UC", test_utils::market(Good::Chemicals, 25, 5_000)),
+        ] {
+            storage.persist_market_data(location, "ship-1", &marketplace_data).await.unwrap();
+        }
+
+        let reservations = RouteReservations::new();
+        let mut traders = Vec::new();
+        for ship_id in &["ship-1", "ship-2"] {
+            let mut ship = test_utils::ship(Some("OE-PM"), &[]);
+            ship.id = ship_id.to_string();
+            storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &ship).await.unwrap();
+
+            let mut trader = trader(&mock, storage.clone(), ship);
+            trader.reservations = reservations.clone();
+            trader.state = TraderState::PickBestTrade;
+            traders.push(trader);
         }
+
+        for trader in &mut traders {
+            trader.poll().await.unwrap();
+        }
+
+        assert_eq!(traders[0].plan.as_ref().unwrap().good, Good::Metals);
+        assert_eq!(traders[1].plan.as_ref().unwrap().good, Good::Chemicals);
+        assert_eq!(reservations.active().len(), 2);
+        assert_ne!(traders[0].trade.as_ref().unwrap().id, traders[1].trade.as_ref().unwrap().id);
+
+        // Once the first trade is over the route is free again
+        traders[0].reservations.release("ship-1");
+        let remaining = reservations.remaining("ship-2", traders[0].plan.clone().unwrap(), 0.5);
+        assert_eq!(remaining.purchase_quantity, 200);
     }
 }
@@ -0,0 +1,339 @@
+// Decides which ship a user buys next and where. Every listing at every shipyard in the user's
+// system is valued by what it is expected to do for its role for each credit it costs. Traders are
+// valued by what the best routes in our market data would earn a ship with its hold and speed.
+// Scouts only need to get around so they are valued by their speed. Ships can only be bought where
+// one of our ships is docked so a shipyard without one needs a ship sent there first.
+use crate::config::{PurchasingConfig, TradingConfig};
+use spacemonger_core::scoring::{self, RouteScorer, ShipProfile};
+use crate::ship_machines::ShipAssignment;
+use crate::storage::StorageClient;
+use spacetraders::responses::ShipsForSale;
+use spacetraders::shared::Good;
+use std::cmp::Ordering;
+
+/// How many of the best routes a trader's earnings are averaged over. Other traders are already
+/// flying the very best one so a new ship can't count on it
+const ROUTES_AVERAGED: usize = 5;
+
+/// A ship for sale at one shipyard
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub ship_type: String,
+    pub location: String,
+    pub price: i32,
+    pub max_cargo: i32,
+    pub speed: i32,
+    /// The only goods the ship can carry. None when it can carry anything
+    pub restricted_goods: Option<Vec<Good>>,
+}
+
+impl Listing {
+    fn can_carry(&self, good: Good) -> bool {
+        self.restricted_goods.as_ref().is_none_or(|goods| goods.contains(&good))
+    }
+
+    fn profile(&self) -> ShipProfile {
+        ShipProfile {
+            ship_type: self.ship_type.clone(),
+            speed: self.speed,
+            space_available: self.max_cargo,
+            fuel: 0,
+        }
+    }
+}
+
+/// What a listing is worth to the role it would be bought for
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub listing: Listing,
+    /// Credits per hour the ship is expected to earn. Zero for ships that don't trade or when
+    /// there isn't any market data yet
+    pub credits_per_hour: f64,
+    /// What the ship is worth to its role for every credit it costs. Only comparable between
+    /// candidates for the same role
+    pub roi: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PurchasePlan {
+    /// One of our ships is docked at the shipyard (or we don't have any ships yet)
+    Buy(Candidate),
+    /// A ship has to be sent to the shipyard before buying
+    MoveThenBuy(Candidate),
+}
+
+/// A purchase that is waiting on one of our ships to reach the shipyard
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingPurchase {
+    /// The ship that was sent
+    pub ship_id: String,
+    pub candidate: Candidate,
+}
+
+/// Every ship for sale in a system, once for each shipyard selling it
+pub fn listings(ships_for_sale: &ShipsForSale, system: &str) -> Vec<Listing> {
+    ships_for_sale.ships.iter()
+        .flat_map(|ship| {
+            ship.purchase_locations.iter()
+                .filter(|l| l.system == system)
+                .map(move |l| Listing {
+                    ship_type: ship.ship_type.clone(),
+                    location: l.location.clone(),
+                    price: l.price,
+                    max_cargo: ship.max_cargo,
+                    speed: ship.speed,
+                    restricted_goods: ship.restricted_goods.clone(),
+                })
+        })
+        .collect()
+}
+
+/// What a ship like the listing is expected to earn an hour trading from any location in the
+/// system. The average of the best few routes it could fly
+pub async fn expected_credits_per_hour(storage: StorageClient, locations: &[String], listing: &Listing, trading: &TradingConfig, scorer: &dyn RouteScorer) -> anyhow::Result<f64> {
+    let profile = listing.profile();
+
+    let mut routes = Vec::new();
+    for location in locations {
+        routes.extend(
+            storage.get_routes_from_location(location, &profile).await?
+                .into_iter()
+                .filter(|r| {
+                    let good = Good::from(r.good.clone());
+                    listing.can_carry(good) && trading.allows(good, &r.purchase_location) && trading.allows(good, &r.sell_location)
+                })
+        );
+    }
+
+    let best: Vec<f64> = scoring::rank_routes(routes, &profile, scorer).into_iter()
+        .map(|r| r.credits_per_second)
+        .filter(|credits_per_second| *credits_per_second > 0.0)
+        .take(ROUTES_AVERAGED)
+        .collect();
+
+    if best.is_empty() {
+        return Ok(0.0);
+    }
+
+    Ok(best.iter().sum::<f64>() / best.len() as f64 * 3600.0)
+}
+
+/// Every location in the system that a trader could start a route from
+pub async fn system_locations(storage: StorageClient, system: &str) -> anyhow::Result<Vec<String>> {
+    Ok(storage.get_system_locations().await?
+        .into_iter()
+        .filter(|l| l.system == system)
+        .map(|l| l.location)
+        .collect())
+}
+
+/// Value every listing for the role the ship would be bought for. The best are first and anything
+/// that isn't worth anything to the role is dropped
+pub fn rank(listings: Vec<Listing>, assignment: &ShipAssignment, credits_per_hour: &[f64]) -> Vec<Candidate> {
+    let have_market_data = credits_per_hour.iter().any(|c| *c > 0.0);
+
+    let mut candidates: Vec<Candidate> = listings.into_iter()
+        .zip(credits_per_hour.iter().copied().chain(std::iter::repeat(0.0)))
+        .map(|(listing, credits_per_hour)| {
+            let price = f64::from(listing.price.max(1));
+            let roi = match assignment {
+                ShipAssignment::Scout => f64::from(listing.speed) / price,
+                _ if have_market_data => credits_per_hour / price,
+                // Without any market data the best we can do is how much a ship can carry how
+                // fast. Ships that can only carry a few goods are left alone until we know those
+                // goods are worth carrying
+                _ if listing.restricted_goods.is_some() => 0.0,
+                _ => f64::from(listing.max_cargo * listing.speed) / price,
+            };
+
+            Candidate { listing, credits_per_hour, roi }
+        })
+        .filter(|c| c.roi > 0.0)
+        .collect();
+
+    candidates.sort_by(|a, b| {
+        b.roi.partial_cmp(&a.roi).unwrap_or(Ordering::Equal)
+            .then_with(|| a.listing.price.cmp(&b.listing.price))
+            .then_with(|| a.listing.location.cmp(&b.listing.location))
+    });
+
+    candidates
+}
+
+/// Value every ship for sale in the system for the role it would be bought for
+pub async fn evaluate(storage: StorageClient, ships_for_sale: &ShipsForSale, system: &str, assignment: &ShipAssignment, trading: &TradingConfig, scorer: &dyn RouteScorer) -> anyhow::Result<Vec<Candidate>> {
+    let listings = listings(ships_for_sale, system);
+
+    let mut credits_per_hour = Vec::new();
+    if *assignment != ShipAssignment::Scout {
+        let locations = system_locations(storage.clone(), system).await?;
+        for listing in &listings {
+            credits_per_hour.push(expected_credits_per_hour(storage.clone(), &locations, listing, trading, scorer).await?);
+        }
+    }
+
+    Ok(rank(listings, assignment, &credits_per_hour))
+}
+
+/// The best candidate the user can afford. Buying right away where one of our ships is docked,
+/// otherwise sending a ship to the shipyard if there is one that can go. A user without any ships
+/// can buy anywhere
+pub fn plan(candidates: &[Candidate], credits: i32, purchasing: &PurchasingConfig, docked_at: &[String], ship_count: usize, can_move: bool) -> Option<PurchasePlan> {
+    candidates.iter()
+        .filter(|c| purchasing.can_afford(credits, c.listing.price))
+        .find_map(|c| {
+            if ship_count == 0 || docked_at.contains(&c.listing.location) {
+                Some(PurchasePlan::Buy(c.clone()))
+            } else if can_move {
+                Some(PurchasePlan::MoveThenBuy(c.clone()))
+            } else {
+                None
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spacemonger_core::scoring::ScoringConfig;
+    use crate::storage::Storage;
+    use crate::storage::memory::{MemoryMarketData, MemoryStorage};
+    use crate::test_utils::{market, system};
+    use chrono::Utc;
+    use spacetraders::shared::{LocationType, PurchaseLocation, ShipForSale};
+    use std::sync::Arc;
+
+    fn ship_for_sale(ship_type: &str, max_cargo: i32, speed: i32, restricted_goods: Option<Vec<Good>>, locations: &[(&str, i32)]) -> ShipForSale {
+        ShipForSale {
+            ship_type: ship_type.to_string(),
+            class: "MK-I".to_string(),
+            max_cargo,
+            speed,
+            manufacturer: "Jackshaw".to_string(),
+            plating: 5,
+            weapons: 5,
+            purchase_locations: locations.iter()
+                .map(|(location, price)| PurchaseLocation {
+                    system: location.split('-').next().unwrap().to_string(),
+                    location: location.to_string(),
+                    price: *price,
+                })
+                .collect(),
+            restricted_goods,
+        }
+    }
+
+    fn ships_for_sale() -> ShipsForSale {
+        ShipsForSale {
+            ships: vec![
+                ship_for_sale("JW-MK-I", 50, 1, None, &[("OE-PM-TR", 20_000), ("XV-BN", 20_000)]),
+                ship_for_sale("EM-MK-I", 75, 2, None, &[("OE-PM", 37_500)]),
+                ship_for_sale("TD-MK-I", 3_000, 1, Some(vec![Good::Metals]), &[("OE-PM", 300_000)]),
+            ],
+        }
+    }
+
+    fn candidate(ship_type: &str, location: &str, price: i32) -> Candidate {
+        Candidate {
+            listing: Listing {
+                ship_type: ship_type.to_string(),
+                location: location.to_string(),
+                price,
+                max_cargo: 50,
+                speed: 1,
+                restricted_goods: None,
+            },
+            credits_per_hour: 0.0,
+            roi: 1.0,
+        }
+    }
+
+    #[test]
+    fn listings_are_only_for_the_system() {
+        let listings = listings(&ships_for_sale(), "OE");
+
+        assert_eq!(listings.len(), 3);
+        assert!(listings.iter().all(|l| l.location.starts_with("OE")));
+    }
+
+    #[test]
+    fn scouts_get_the_most_speed_for_their_credits() {
+        let candidates = rank(listings(&ships_for_sale(), "OE"), &ShipAssignment::Scout, &[]);
+
+        assert_eq!(candidates[0].listing.ship_type, "EM-MK-I");
+        // Scouts don't carry anything so restricted ships are fine
+        assert_eq!(candidates.len(), 3);
+    }
+
+    #[test]
+    fn traders_are_ranked_by_earnings_for_their_price() {
+        let listings = listings(&ships_for_sale(), "OE");
+
+        // The restricted ship is huge and the metals route is lucrative enough to pay for it
+        let candidates = rank(listings.clone(), &ShipAssignment::Trader, &[1_000.0, 1_500.0, 30_000.0]);
+        assert_eq!(candidates[0].listing.ship_type, "TD-MK-I");
+
+        // Without market data cargo times speed decides and restricted ships are skipped
+        let candidates = rank(listings, &ShipAssignment::Trader, &[0.0, 0.0, 0.0]);
+        assert_eq!(candidates.iter().map(|c| c.listing.ship_type.as_str()).collect::<Vec<&str>>(), vec!["EM-MK-I", "JW-MK-I"]);
+    }
+
+    #[test]
+    fn plans_stay_within_budget_and_prefer_docked_shipyards() {
+        let purchasing = PurchasingConfig { reserve_credits: 10_000, max_ship_price: Some(50_000), ..PurchasingConfig::default() };
+        let candidates = vec![candidate("GR-MK-II", "OE-PM-TR", 60_000), candidate("EM-MK-I", "OE-PM", 40_000), candidate("JW-MK-I", "OE-UC", 20_000)];
+        let docked_at = vec!["OE-UC".to_string()];
+
+        // Too expensive for the config, then the reserve, then needs a ship to move
+        assert_eq!(plan(&candidates, 100_000, &purchasing, &docked_at, 1, true), Some(PurchasePlan::MoveThenBuy(candidates[1].clone())));
+        assert_eq!(plan(&candidates, 100_000, &purchasing, &docked_at, 1, false), Some(PurchasePlan::Buy(candidates[2].clone())));
+        assert_eq!(plan(&candidates, 45_000, &purchasing, &docked_at, 1, true), Some(PurchasePlan::Buy(candidates[2].clone())));
+        assert_eq!(plan(&candidates, 100_000, &purchasing, &[], 0, false), Some(PurchasePlan::Buy(candidates[1].clone())));
+        assert_eq!(plan(&candidates, 25_000, &purchasing, &docked_at, 1, true), None);
+    }
+
+    #[tokio::test]
+    async fn traders_are_valued_by_the_routes_they_could_fly() {
+        let storage = Arc::new(MemoryStorage::new());
+        let oe = system("OE", &[("OE-PM", LocationType::Planet, 0, 0), ("OE-PM-TR", LocationType::Moon, 10, 0)]);
+        for location in &oe.locations {
+            storage.persist_system_location(&oe, location).await.unwrap();
+        }
+
+        let now = Utc::now();
+        storage.with_state(|state| {
+            let snapshot = |location: &str, good: Good, price_per_unit: i32| MemoryMarketData {
+                location: location.to_string(),
+                ship_id: None,
+                marketplace_data: market(good, price_per_unit, 1_000),
+                created_at: now,
+            };
+
+            state.market_data = vec![
+                snapshot("OE-PM", Good::Metals, 10),
+                snapshot("OE-PM-TR", Good::Metals, 20),
+                snapshot("OE-PM", Good::Fuel, 1),
+                snapshot("OE-PM-TR", Good::Fuel, 1),
+            ];
+        });
+
+        let scorer = scoring::scorer(&ScoringConfig::default(), Vec::new());
+        let locations = vec!["OE-PM".to_string(), "OE-PM-TR".to_string()];
+        let listings = listings(&ships_for_sale(), "OE");
+
+        let small = expected_credits_per_hour(storage.clone(), &locations, &listings[0], &TradingConfig::default(), scorer.as_ref()).await.unwrap();
+        let fast = expected_credits_per_hour(storage.clone(), &locations, &listings[1], &TradingConfig::default(), scorer.as_ref()).await.unwrap();
+        assert!(small > 0.0);
+        assert!(fast > small, "{} {}", fast, small);
+
+        let blacklisted = TradingConfig { blacklisted_goods: vec![Good::Metals], ..TradingConfig::default() };
+        assert_eq!(expected_credits_per_hour(storage.clone(), &locations, &listings[1], &blacklisted, scorer.as_ref()).await.unwrap(), 0.0);
+
+        let candidates = evaluate(storage, &ships_for_sale(), "OE", &ShipAssignment::Trader, &TradingConfig::default(), scorer.as_ref()).await.unwrap();
+        // The bigger ships earn more but the small one pays for itself the quickest. The restricted
+        // ship can carry metals but our orders move the price too much for it to pay off
+        assert_eq!(candidates.iter().map(|c| c.listing.ship_type.as_str()).collect::<Vec<&str>>(), vec!["JW-MK-I", "EM-MK-I", "TD-MK-I"]);
+        assert!(candidates[1].credits_per_hour > candidates[0].credits_per_hour);
+        assert!(candidates.iter().all(|c| c.credits_per_hour > 0.0));
+    }
+}
@@ -0,0 +1,200 @@
+// Decides when one of a user's traders should make way for a better hull. Our own ships are valued
+// the same way as the ships for sale (see ship_purchasing) so that the two can be compared. Once a
+// ship for sale is expected to earn upgrade_ratio times what our worst trader does that trader is
+// retired. It finishes its trade, flies to the shipyard, sells whatever is left and parks there.
+// The replacement is bought once it has parked. The api doesn't let us scrap ships so retired
+// ships stay parked for good and are left out whenever the fleet starts.
+use crate::config::PurchasingConfig;
+use crate::db::DbShipEvent;
+use crate::ship_purchasing::{Candidate, Listing};
+use spacetraders::responses::ShipsForSale;
+use spacetraders::shared;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+pub const PURCHASED: &str = "purchased";
+pub const RETIRING: &str = "retiring";
+pub const RETIRED: &str = "retired";
+
+/// One of our traders and what it is expected to earn
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShipValue {
+    pub ship_id: String,
+    pub ship_type: String,
+    pub credits_per_hour: f64,
+}
+
+/// A trader that is worth retiring and the ship to buy in its place
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upgrade {
+    pub retiree: ShipValue,
+    pub replacement: Candidate,
+}
+
+/// Where a ship is in being retired according to its history
+#[derive(Debug, Clone, PartialEq)]
+pub enum Retirement {
+    /// On its way to park at the location
+    Retiring(String),
+    Retired,
+}
+
+/// One of our ships as if it were for sale so that it can be valued like one. Ships don't say what
+/// they are restricted to carrying so that comes from the same kind of ship for sale
+pub fn listing_for(ship: &shared::Ship, ships_for_sale: &ShipsForSale) -> Listing {
+    let restricted_goods = ships_for_sale.ships.iter()
+        .find(|s| s.ship_type == ship.ship_type)
+        .and_then(|s| s.restricted_goods.clone());
+
+    Listing {
+        ship_type: ship.ship_type.clone(),
+        location: ship.location.clone().unwrap_or_default(),
+        price: 0,
+        max_cargo: ship.max_cargo,
+        speed: ship.speed,
+        restricted_goods,
+    }
+}
+
+/// The trader to retire and what to replace it with. The replacement is the best candidate the user
+/// can afford and it has to be expected to earn at least upgrade_ratio times what our worst trader
+/// does. Nothing is ever replaced without market data to compare them by
+pub fn pick(owned: &[ShipValue], candidates: &[Candidate], credits: i32, purchasing: &PurchasingConfig) -> Option<Upgrade> {
+    let upgrade_ratio = purchasing.upgrade_ratio?;
+
+    let replacement = candidates.iter()
+        .find(|c| c.credits_per_hour > 0.0 && purchasing.can_afford(credits, c.listing.price))?;
+
+    owned.iter()
+        .min_by(|a, b| a.credits_per_hour.partial_cmp(&b.credits_per_hour).unwrap_or(Ordering::Equal))
+        .filter(|retiree| replacement.credits_per_hour >= retiree.credits_per_hour * upgrade_ratio)
+        .map(|retiree| Upgrade {
+            retiree: retiree.clone(),
+            replacement: replacement.clone(),
+        })
+}
+
+/// Every ship that is retiring or has been retired going by the latest event for each ship
+pub fn retirements(history: &[DbShipEvent]) -> HashMap<String, Retirement> {
+    let mut latest: HashMap<&str, &DbShipEvent> = HashMap::new();
+    for event in history {
+        match latest.get(event.ship_id.as_str()) {
+            Some(previous) if previous.created_at > event.created_at => {}
+            _ => {
+                latest.insert(&event.ship_id, event);
+            }
+        }
+    }
+
+    latest.into_iter()
+        .filter_map(|(ship_id, event)| {
+            let retirement = match event.event_type.as_str() {
+                RETIRING => Retirement::Retiring(event.location.clone()),
+                RETIRED => Retirement::Retired,
+                _ => return None,
+            };
+
+            Some((ship_id.to_string(), retirement))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use chrono::{Duration, Utc};
+    use spacetraders::shared::{Good, PurchaseLocation, ShipForSale};
+
+    fn value(ship_id: &str, credits_per_hour: f64) -> ShipValue {
+        ShipValue {
+            ship_id: ship_id.to_string(),
+            ship_type: "JW-MK-I".to_string(),
+            credits_per_hour,
+        }
+    }
+
+    fn candidate(ship_type: &str, price: i32, credits_per_hour: f64) -> Candidate {
+        Candidate {
+            listing: Listing {
+                ship_type: ship_type.to_string(),
+                location: "OE-PM".to_string(),
+                price,
+                max_cargo: 100,
+                speed: 2,
+                restricted_goods: None,
+            },
+            credits_per_hour,
+            roi: credits_per_hour / f64::from(price),
+        }
+    }
+
+    fn event(ship_id: &str, event_type: &str, minutes_ago: i64) -> DbShipEvent {
+        DbShipEvent {
+            user_id: "user".to_string(),
+            ship_id: ship_id.to_string(),
+            ship_type: "JW-MK-I".to_string(),
+            event_type: event_type.to_string(),
+            location: "OE-PM".to_string(),
+            created_at: Utc::now() - Duration::minutes(minutes_ago),
+        }
+    }
+
+    #[test]
+    fn the_worst_trader_is_replaced_once_something_earns_enough_more() {
+        let purchasing = PurchasingConfig { upgrade_ratio: Some(2.0), reserve_credits: 10_000, ..PurchasingConfig::default() };
+        let owned = vec![value("ship-1", 4_000.0), value("ship-2", 2_000.0)];
+        let candidates = vec![candidate("GR-MK-II", 90_000, 9_000.0), candidate("EM-MK-I", 40_000, 4_000.0)];
+
+        let upgrade = pick(&owned, &candidates, 100_000, &purchasing).unwrap();
+        assert_eq!(upgrade.retiree.ship_id, "ship-2");
+        assert_eq!(upgrade.replacement.listing.ship_type, "GR-MK-II");
+
+        // The best ship we can afford only earns twice as much as the worst trader
+        assert_eq!(pick(&owned, &candidates, 60_000, &purchasing).unwrap().replacement.listing.ship_type, "EM-MK-I");
+        assert_eq!(pick(&[value("ship-1", 4_000.0)], &candidates, 60_000, &purchasing), None);
+        // Can't afford anything
+        assert_eq!(pick(&owned, &candidates, 40_000, &purchasing), None);
+        // Upgrades are off
+        assert_eq!(pick(&owned, &candidates, 100_000, &PurchasingConfig::default()), None);
+        // No market data
+        assert_eq!(pick(&owned, &[candidate("GR-MK-II", 90_000, 0.0)], 100_000, &purchasing), None);
+    }
+
+    #[test]
+    fn our_ships_are_valued_like_the_same_ship_for_sale() {
+        let ships_for_sale = ShipsForSale {
+            ships: vec![ShipForSale {
+                ship_type: "GR-MK-I".to_string(),
+                class: "MK-I".to_string(),
+                max_cargo: 100,
+                speed: 1,
+                manufacturer: "Jackshaw".to_string(),
+                plating: 5,
+                weapons: 5,
+                purchase_locations: vec![PurchaseLocation { system: "OE".to_string(), location: "OE-PM-TR".to_string(), price: 20_000 }],
+                restricted_goods: Some(vec![Good::Metals]),
+            }],
+        };
+
+        let listing = listing_for(&test_utils::ship(Some("OE-UC"), &[]), &ships_for_sale);
+        assert_eq!(listing.location, "OE-UC");
+        assert_eq!(listing.restricted_goods, Some(vec![Good::Metals]));
+    }
+
+    #[test]
+    fn retirements_go_by_the_latest_event_for_each_ship() {
+        let history = vec![
+            event("ship-1", PURCHASED, 60),
+            event("ship-1", RETIRING, 30),
+            event("ship-2", RETIRED, 20),
+            event("ship-2", RETIRING, 50),
+            event("ship-3", PURCHASED, 10),
+        ];
+
+        let retirements = retirements(&history);
+        assert_eq!(retirements.len(), 2);
+        assert_eq!(retirements["ship-1"], Retirement::Retiring("OE-PM".to_string()));
+        assert_eq!(retirements["ship-2"], Retirement::Retired);
+    }
+}
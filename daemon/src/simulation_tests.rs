@@ -0,0 +1,105 @@
+// These tests run the daemon's ship machines end to end against the simulated universe. They
+// need a postgres database (the one from docker-compose works) so they are ignored by default.
+use crate::config::{LoanConfig, PurchasingConfig, TradingConfig, UserConfig};
+use crate::game::GameBackend;
+use crate::game::simulator::Simulator;
+use crate::route_reservations::RouteReservations;
+use crate::ship_machines::ShipAssignment;
+use crate::storage::StorageClient;
+use crate::storage::postgres::PgStorage;
+use crate::supervisor;
+use crate::user::User;
+use crate::test_utils;
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+use std::sync::Arc;
+
+fn user_config(name: &str, assignment: ShipAssignment, system: &str, location: Option<&str>) -> UserConfig {
+    UserConfig {
+        name: name.to_string(),
+        assignment,
+        system: system.to_string(),
+        location: location.map(|l| l.to_string()),
+        enabled: true,
+        purchasing: PurchasingConfig::default(),
+        loans: LoanConfig::default(),
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn trader_turns_a_profit_in_the_simulator() {
+    let test_db = test_utils::get_test_db().await;
+    let pg_pool = test_db.pg_pool.clone();
+    let storage: StorageClient = Arc::new(PgStorage::new(pg_pool.clone()));
+    let backend: Arc<dyn GameBackend> = Arc::new(Simulator::new(0.0));
+
+    let mut trader = User::new(
+        backend.clone(),
+        storage.clone(),
+        "sim-main".to_string(),
+        &user_config("main", ShipAssignment::Trader, "OE", None),
+        TradingConfig::default(),
+        RouteReservations::new(),
+    ).await.unwrap();
+
+    let system_info = trader.get_systems().await.unwrap();
+    for system in &system_info.systems {
+        for location in &system.locations {
+            storage.persist_system_location(system, location).await.unwrap();
+        }
+    }
+
+    let mut users = Vec::new();
+    let oe = system_info.systems.iter().find(|s| s.symbol == "OE").unwrap();
+    for location in &oe.locations {
+        let mut scout = User::new(
+            backend.clone(),
+            storage.clone(),
+            format!("sim-scout-{}", location.symbol),
+            &user_config("scout", ShipAssignment::Scout, &oe.symbol, Some(&location.symbol)),
+            TradingConfig::default(),
+            RouteReservations::new(),
+        ).await.unwrap();
+
+        scout.manage_loans().await.unwrap();
+        scout.purchase_best_ship().await.unwrap();
+        users.push(scout);
+    }
+
+    trader.manage_loans().await.unwrap();
+    trader.purchase_best_ship().await.unwrap();
+    users.push(trader);
+
+    for _ in 0..200 {
+        for user in &mut users {
+            // Same recovery as the daemon's supervisor
+            if let Some(exit) = supervisor::poll_ships(user).await {
+                panic!("{} -- Unexpectedly stopped {:?}", user.username, exit);
+            }
+        }
+    }
+
+    // Anything bought after the last sale is still in the hold so only count completed trades
+    let trader = users.last().unwrap();
+    let (sales, realized_profit): (i64, i64) = sqlx::query("
+        WITH last_sale AS (
+            SELECT MAX(created_at) AS created_at
+            FROM daemon_user_transaction
+            WHERE user_id = $1::uuid AND type = 'sell'
+        )
+        SELECT
+             COUNT(*) FILTER (WHERE type = 'sell') AS sales
+            ,COALESCE(SUM(CASE WHEN type = 'sell' THEN total ELSE -total END), 0) AS realized_profit
+        FROM daemon_user_transaction
+        WHERE user_id = $1::uuid AND created_at <= (SELECT created_at FROM last_sale);
+    ")
+        .bind(&trader.id)
+        .map(|row: PgRow| (row.get("sales"), row.get("realized_profit")))
+        .fetch_one(&pg_pool)
+        .await
+        .unwrap();
+
+    assert!(sales > 0, "The trader never sold anything");
+    assert!(realized_profit > 0, "The trader lost {} credits over {} sales", -realized_profit, sales);
+}
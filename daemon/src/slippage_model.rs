@@ -0,0 +1,176 @@
+// Learns how much our own orders move prices. Every purchase and sell order is recorded along
+// with the price we were charged, and the scouts and traders keep snapshotting the markets, so
+// comparing each order with the snapshots taken just before and just after it shows how far the
+// price moved because of us. The route scorer uses the learned impact per good per location in
+// place of the configured guess to decide how big an order is worth placing, see
+// `scoring::LearnedSlippage`.
+use crate::db::{DbSlippageModel, DbTradeSample};
+use crate::storage::StorageClient;
+use spacetraders::shared::Good;
+
+/// Fit the purchase and sell impact of one good at one location. Returns None when there is
+/// nothing to learn from
+pub fn fit(location: &str, good: Good, samples: &[DbTradeSample]) -> Option<DbSlippageModel> {
+    let samples: Vec<&DbTradeSample> = samples.iter()
+        .filter(|s| s.location == location && s.good == good)
+        .filter(|s| s.quoted_price_per_unit > 0 && s.quantity_available > 0 && s.quantity > 0)
+        .collect();
+
+    if samples.is_empty() {
+        return None;
+    }
+
+    let purchases: Vec<&DbTradeSample> = samples.iter().copied().filter(|s| s.transaction_type == "purchase").collect();
+    let sells: Vec<&DbTradeSample> = samples.iter().copied().filter(|s| s.transaction_type != "purchase").collect();
+
+    Some(DbSlippageModel {
+        location: location.to_string(),
+        good,
+        purchase_impact: impact(&purchases, 1.0),
+        sell_impact: impact(&sells, -1.0),
+        purchase_samples: purchases.len() as i32,
+        sell_samples: sells.len() as i32,
+    })
+}
+
+/// DepthSlippage says an order of `quantity` moves the average price paid by
+/// impact * quantity / (2 * depth) and leaves the market impact * quantity / depth away from where
+/// it started. So the price we were charged and the next snapshot each give a point on a line
+/// through the origin, and the impact is its slope. `direction` is 1 when our orders push the
+/// price up and -1 when they push it down.
+fn impact(samples: &[&DbTradeSample], direction: f64) -> f64 {
+    let mut points: Vec<(f64, f64)> = Vec::new();
+
+    for sample in samples {
+        let quoted = f64::from(sample.quoted_price_per_unit);
+        let depth = f64::from(sample.quantity_available);
+        let quantity = f64::from(sample.quantity);
+
+        points.push((quantity / (2.0 * depth), direction * (f64::from(sample.price_per_unit) - quoted) / quoted));
+
+        if let Some(after) = sample.quoted_price_per_unit_after {
+            points.push((quantity / depth, direction * (f64::from(after) - quoted) / quoted));
+        }
+    }
+
+    let sxx: f64 = points.iter().map(|(x, _)| x * x).sum();
+    let sxy: f64 = points.iter().map(|(x, y)| x * y).sum();
+
+    if sxx > f64::EPSILON { (sxy / sxx).max(0.0) } else { 0.0 }
+}
+
+/// Refit the models for every good we have traded at a location and store them
+pub async fn learn(storage: StorageClient, location: &str) -> anyhow::Result<Vec<DbSlippageModel>> {
+    let samples = storage.get_trade_samples(location).await?;
+
+    let mut goods: Vec<Good> = samples.iter().map(|s| s.good).collect();
+    goods.sort_by_key(|g| g.to_string());
+    goods.dedup();
+
+    let mut slippage_models = Vec::new();
+    for good in goods {
+        if let Some(slippage_model) = fit(location, good, &samples) {
+            log::debug!(
+                "Learned slippage for {} at {} from {} purchases and {} sells. purchase impact = {:.3}, sell impact = {:.3}",
+                good,
+                location,
+                slippage_model.purchase_samples,
+                slippage_model.sell_samples,
+                slippage_model.purchase_impact,
+                slippage_model.sell_impact,
+            );
+            storage.persist_slippage_model(&slippage_model).await?;
+            slippage_models.push(slippage_model);
+        }
+    }
+
+    Ok(slippage_models)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::storage::memory::{MemoryMarketData, MemoryStorage, MemoryTransaction};
+    use crate::test_utils::market;
+    use chrono::{Duration, Utc};
+    use spacetraders::shared;
+    use std::sync::Arc;
+
+    fn sample(transaction_type: &str, quantity: i32, price_per_unit: i32, after: Option<i32>) -> DbTradeSample {
+        DbTradeSample {
+            location: "OE-PM".to_string(),
+            good: Good::Metals,
+            transaction_type: transaction_type.to_string(),
+            quantity,
+            price_per_unit,
+            quoted_price_per_unit: 100,
+            quantity_available: 1_000,
+            quoted_price_per_unit_after: after,
+        }
+    }
+
+    #[test]
+    fn fit_learns_the_impact_on_each_side() {
+        // An impact of 0.4: buying 500 of 1000 costs 10% more on average and leaves the price 20% up
+        let samples = vec![
+            sample("purchase", 500, 110, Some(120)),
+            sample("purchase", 250, 105, None),
+            sample("sell", 500, 95, Some(90)),
+            sample("sell", 100, 99, None),
+        ];
+
+        let slippage_model = fit("OE-PM", Good::Metals, &samples).unwrap();
+
+        assert!((slippage_model.purchase_impact - 0.4).abs() < 1e-9, "{:?}", slippage_model);
+        assert!((slippage_model.sell_impact - 0.2).abs() < 1e-9, "{:?}", slippage_model);
+        assert_eq!(slippage_model.purchase_samples, 2);
+        assert_eq!(slippage_model.sell_samples, 2);
+        assert!(fit("OE-PM", Good::Fuel, &samples).is_none());
+    }
+
+    #[test]
+    fn prices_moving_our_way_dont_make_a_negative_impact() {
+        let slippage_model = fit("OE-PM", Good::Metals, &[sample("purchase", 500, 90, Some(80))]).unwrap();
+
+        assert!(slippage_model.purchase_impact.abs() < f64::EPSILON);
+    }
+
+    #[tokio::test]
+    async fn learn_compares_orders_with_the_surrounding_snapshots() {
+        let storage = Arc::new(MemoryStorage::new());
+        let now = Utc::now();
+
+        storage.with_state(|state| {
+            let snapshot = |good: Good, price_per_unit: i32, minutes_ago: i64| MemoryMarketData {
+                location: "OE-PM".to_string(),
+                ship_id: None,
+                marketplace_data: market(good, price_per_unit, 1_000),
+                created_at: now - Duration::minutes(minutes_ago),
+            };
+            let order = |good: Good, quantity: i32, price_per_unit: i32, minutes_ago: i64| MemoryTransaction {
+                user_id: "00000000-0000-0000-0000-000000000001".to_string(),
+                ship_id: "ship-1".to_string(),
+                transaction_type: "purchase".to_string(),
+                order: shared::Order { good, quantity, price_per_unit, total: quantity * price_per_unit },
+                location: "OE-PM".to_string(),
+                trade_id: None,
+                created_at: now - Duration::minutes(minutes_ago),
+            };
+
+            state.market_data = vec![
+                snapshot(Good::Metals, 100, 12),
+                snapshot(Good::Metals, 120, 8),
+                // Too long before the order to say anything about it
+                snapshot(Good::Chemicals, 50, 40),
+            ];
+            state.transactions = vec![order(Good::Metals, 500, 110, 10), order(Good::Chemicals, 500, 60, 10)];
+        });
+
+        let slippage_models = learn(storage.clone(), "OE-PM").await.unwrap();
+
+        assert_eq!(slippage_models.len(), 1);
+        assert!((slippage_models[0].purchase_impact - 0.4).abs() < 1e-9, "{:?}", slippage_models);
+        assert_eq!(storage.get_slippage_models().await.unwrap(), slippage_models);
+    }
+}
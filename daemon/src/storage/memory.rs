@@ -0,0 +1,778 @@
+use crate::db::{DbDistanceBetweenLocations, DbFlightSample, DbFuelModel, DbLoanEvent, DbLocationSurvey, DbMarketData, DbMarketFreshness, DbRoute, DbShip, DbShipEvent, DbShipMachineState, DbSlippageModel, DbSystemLocation, DbTradeSample, DbUser, DbUserSummary, DbUserTrade, DbUserTransaction};
+use spacemonger_core::scoring::ShipProfile;
+use crate::ship_machines::ShipAssignment;
+use crate::storage::Storage;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use spacemonger_core::routes::{self, LocationGood};
+use spacetraders::{responses, shared};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone)]
+pub struct MemoryMarketData {
+    pub location: String,
+    pub ship_id: Option<String>,
+    pub marketplace_data: shared::MarketplaceData,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryTransaction {
+    pub user_id: String,
+    pub ship_id: String,
+    pub transaction_type: String,
+    pub order: shared::Order,
+    pub location: String,
+    pub trade_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryUserStats {
+    pub user_id: String,
+    pub credits: i32,
+    pub ship_count: i32,
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryState {
+    pub users: Vec<DbUser>,
+    pub user_stats: Vec<MemoryUserStats>,
+    pub ships: Vec<DbShip>,
+    pub ship_machine_states: Vec<DbShipMachineState>,
+    pub system_locations: Vec<DbSystemLocation>,
+    pub flight_plans: Vec<shared::FlightPlanData>,
+    pub market_data: Vec<MemoryMarketData>,
+    pub transactions: Vec<MemoryTransaction>,
+    pub trades: Vec<DbUserTrade>,
+    pub fuel_models: Vec<DbFuelModel>,
+    pub slippage_models: Vec<DbSlippageModel>,
+    pub loan_events: Vec<DbLoanEvent>,
+    pub ship_history: Vec<DbShipEvent>,
+}
+
+/// Keeps everything in process. Used by tests and by dry runs where nothing should outlive the
+/// daemon. Queries behave the same as their postgres counterparts in `db`.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// Inspect or seed the stored state directly
+    pub fn with_state<T>(&self, f: impl FnOnce(&mut MemoryState) -> T) -> T {
+        f(&mut self.state.lock().unwrap())
+    }
+}
+
+impl MemoryState {
+    fn system_location(&self, location: &str) -> Option<&DbSystemLocation> {
+        self.system_locations.iter().find(|l| l.location == location)
+    }
+
+    /// The latest market data for each good in each location, ignoring anything too old to trade on
+    fn latest_market_data(&self) -> Vec<&MemoryMarketData> {
+        let freshness_cutoff = Utc::now() - Duration::minutes(routes::MARKET_DATA_MAX_AGE_MINUTES);
+
+        let mut latest: Vec<&MemoryMarketData> = Vec::new();
+        for market_data in &self.market_data {
+            match latest.iter_mut().find(|m| {
+                m.location == market_data.location && m.marketplace_data.symbol == market_data.marketplace_data.symbol
+            }) {
+                Some(existing) => if existing.created_at <= market_data.created_at {
+                    *existing = market_data;
+                },
+                None => latest.push(market_data),
+            }
+        }
+
+        latest.retain(|m| m.created_at > freshness_cutoff);
+        latest
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn reset(&self) -> anyhow::Result<()> {
+        self.with_state(|state| *state = MemoryState::default());
+
+        Ok(())
+    }
+
+    async fn get_user(&self, username: String) -> anyhow::Result<Option<DbUser>> {
+        self.with_state(|state| Ok(state.users.iter().find(|u| u.username == username).cloned()))
+    }
+
+    async fn persist_user(&self, username: String, token: String, new_ship_assignment: &ShipAssignment, new_ship_system: &str) -> anyhow::Result<DbUser> {
+        self.with_state(|state| {
+            if state.users.iter().any(|u| u.username == username) {
+                return Err(anyhow!("User {} already exists", username));
+            }
+
+            let user = DbUser {
+                id: format!("00000000-0000-0000-0000-{:012}", state.users.len() + 1),
+                username,
+                token,
+                new_ship_assignment: new_ship_assignment.to_string(),
+                new_ship_system: new_ship_system.to_string(),
+            };
+
+            state.users.push(user.clone());
+
+            Ok(user)
+        })
+    }
+
+    async fn persist_user_stats(&self, user_id: &str, credits: i32, ships: &[shared::Ship]) -> anyhow::Result<()> {
+        self.with_state(|state| {
+            state.user_stats.push(MemoryUserStats {
+                user_id: user_id.to_string(),
+                credits,
+                ship_count: ships.len() as i32,
+            });
+
+            Ok(())
+        })
+    }
+
+    async fn get_user_summaries(&self) -> anyhow::Result<Vec<DbUserSummary>> {
+        self.with_state(|state| {
+            let mut summaries: Vec<DbUserSummary> = state.users.iter()
+                .map(|user| {
+                    let stats = state.user_stats.iter().rev().find(|s| s.user_id == user.id);
+
+                    DbUserSummary {
+                        id: user.id.clone(),
+                        username: user.username.clone(),
+                        new_ship_assignment: user.new_ship_assignment.clone(),
+                        new_ship_system: user.new_ship_system.clone(),
+                        credits: stats.map(|s| s.credits),
+                        ship_count: stats.map(|s| s.ship_count),
+                    }
+                })
+                .collect();
+
+            summaries.sort_by(|a, b| a.username.cmp(&b.username));
+
+            Ok(summaries)
+        })
+    }
+
+    async fn persist_ship(&self, user_id: &str, system: &str, assignment: &ShipAssignment, ship: &shared::Ship) -> anyhow::Result<()> {
+        self.with_state(|state| {
+            let mut db_ship = DbShip {
+                user_id: user_id.to_string(),
+                ship_id: ship.id.clone(),
+                ship_type: ship.ship_type.clone(),
+                system: system.to_string(),
+                assignment: assignment.to_string(),
+            };
+
+            match state.ships.iter_mut().find(|s| s.user_id == user_id && s.ship_id == ship.id) {
+                Some(existing) => {
+                    // Operators own the system and assignment once the ship exists
+                    db_ship.system = existing.system.clone();
+                    db_ship.assignment = existing.assignment.clone();
+                    *existing = db_ship;
+                },
+                None => state.ships.push(db_ship),
+            }
+
+            Ok(())
+        })
+    }
+
+    async fn update_ship_system(&self, user_id: &str, ship_id: &str, system: &str) -> anyhow::Result<()> {
+        self.with_state(|state| {
+            if let Some(ship) = state.ships.iter_mut().find(|s| s.user_id == user_id && s.ship_id == ship_id) {
+                ship.system = system.to_string();
+            }
+
+            Ok(())
+        })
+    }
+
+    async fn get_ship(&self, user_id: &str, ship_id: &str) -> anyhow::Result<DbShip> {
+        self.with_state(|state| {
+            state.ships.iter()
+                .find(|s| s.user_id == user_id && s.ship_id == ship_id)
+                .cloned()
+                .ok_or_else(|| anyhow!("Ship {} not found for user {}", ship_id, user_id))
+        })
+    }
+
+    async fn get_ship_owner(&self, ship_id: &str) -> anyhow::Result<Option<DbUser>> {
+        self.with_state(|state| {
+            Ok(
+                state.ships.iter()
+                    .find(|s| s.ship_id == ship_id)
+                    .and_then(|ship| state.users.iter().find(|u| u.id == ship.user_id))
+                    .cloned()
+            )
+        })
+    }
+
+    async fn persist_ship_machine_state(&self, user_id: &str, ship_id: &str, machine_type: &str, state: &str) -> anyhow::Result<()> {
+        self.with_state(|memory_state| {
+            let ship_machine_state = DbShipMachineState {
+                user_id: user_id.to_string(),
+                ship_id: ship_id.to_string(),
+                machine_type: machine_type.to_string(),
+                state: state.to_string(),
+                modified_at: Utc::now(),
+            };
+
+            match memory_state.ship_machine_states.iter_mut().find(|s| s.user_id == user_id && s.ship_id == ship_id) {
+                Some(existing) => *existing = ship_machine_state,
+                None => memory_state.ship_machine_states.push(ship_machine_state),
+            }
+
+            Ok(())
+        })
+    }
+
+    async fn get_ship_machine_state(&self, user_id: &str, ship_id: &str) -> anyhow::Result<Option<DbShipMachineState>> {
+        self.with_state(|state| {
+            Ok(
+                state.ship_machine_states.iter()
+                    .find(|s| s.user_id == user_id && s.ship_id == ship_id)
+                    .cloned()
+            )
+        })
+    }
+
+    async fn persist_system_location(&self, system: &shared::SystemsInfoData, location: &shared::SystemsInfoLocation) -> anyhow::Result<()> {
+        self.with_state(|state| {
+            let system_location = DbSystemLocation {
+                system: system.symbol.clone(),
+                system_name: system.name.clone(),
+                location: location.symbol.clone(),
+                location_name: location.name.clone(),
+                location_type: location.systems_info_type.to_string(),
+                x: location.x,
+                y: location.y,
+                created_at: Utc::now(),
+            };
+
+            match state.system_locations.iter_mut().find(|l| l.system == system.symbol && l.location == location.symbol) {
+                Some(existing) => *existing = system_location,
+                None => state.system_locations.push(system_location),
+            }
+
+            Ok(())
+        })
+    }
+
+    async fn get_system_locations(&self) -> anyhow::Result<Vec<DbSystemLocation>> {
+        self.with_state(|state| {
+            let mut system_locations = state.system_locations.clone();
+            system_locations.sort_by(|a, b| (&a.system, &a.location).cmp(&(&b.system, &b.location)));
+
+            Ok(system_locations)
+        })
+    }
+
+    async fn get_system_locations_from_location(&self, location: &str) -> anyhow::Result<Vec<String>> {
+        self.with_state(|state| {
+            let system = match state.system_location(location) {
+                Some(system_location) => system_location.system.clone(),
+                None => return Ok(Vec::new()),
+            };
+
+            Ok(
+                state.system_locations.iter()
+                    .filter(|l| l.system == system)
+                    .map(|l| l.location.clone())
+                    .collect()
+            )
+        })
+    }
+
+    async fn get_wormhole_from_location_to_system(&self, location: &str, system: &str) -> anyhow::Result<String> {
+        self.with_state(|state| {
+            let origin = state.system_location(location)
+                .ok_or_else(|| anyhow!("Location {} not found", location))?;
+
+            state.system_locations.iter()
+                .find(|l| l.system == origin.system && l.location_type == "Wormhole" && l.location.contains(system))
+                .map(|l| l.location.clone())
+                .ok_or_else(|| anyhow!("No wormhole from {} to {}", location, system))
+        })
+    }
+
+    async fn get_distance_between_locations(&self, origin: &str, destination: &str) -> anyhow::Result<Option<DbDistanceBetweenLocations>> {
+        self.with_state(|state| {
+            let (from, to) = match (state.system_location(origin), state.system_location(destination)) {
+                (Some(from), Some(to)) if from.system == to.system => (from, to),
+                _ => return Ok(None),
+            };
+
+            Ok(Some(DbDistanceBetweenLocations {
+                origin_location_type: from.location_type.clone(),
+                distance: (((from.x - to.x).pow(2) + (from.y - to.y).pow(2)) as f64).sqrt(),
+            }))
+        })
+    }
+
+    async fn persist_flight_plan(&self, _user_id: &str, ship_id: &str, flight_plan: &responses::FlightPlan, _trade_id: Option<&str>) -> anyhow::Result<()> {
+        self.with_state(|state| {
+            let mut flight_plan = flight_plan.flight_plan.clone();
+            flight_plan.ship_id = ship_id.to_string();
+
+            state.flight_plans.push(flight_plan);
+
+            Ok(())
+        })
+    }
+
+    async fn get_active_flight_plan(&self, ship_id: &str) -> anyhow::Result<Option<shared::FlightPlanData>> {
+        let now = Utc::now();
+
+        self.with_state(|state| {
+            Ok(
+                state.flight_plans.iter()
+                    .find(|f| f.ship_id == ship_id && f.arrives_at > now)
+                    .cloned()
+            )
+        })
+    }
+
+    async fn get_flight_samples(&self, ship_type: &str, location_type: &str) -> anyhow::Result<Vec<DbFlightSample>> {
+        self.with_state(|state| {
+            Ok(
+                state.flight_plans.iter()
+                    .filter(|f| state.ships.iter().any(|s| s.ship_id == f.ship_id && s.ship_type == ship_type))
+                    .filter(|f| match (state.system_location(&f.departure), state.system_location(&f.destination)) {
+                        // warp jumps are flights between systems and don't use any fuel
+                        (Some(from), Some(to)) => from.location_type == location_type && from.system == to.system,
+                        _ => false,
+                    })
+                    .map(|f| DbFlightSample {
+                        distance: f.distance,
+                        fuel_consumed: f.fuel_consumed,
+                        flight_time: f.time_remaining_in_seconds,
+                    })
+                    .collect()
+            )
+        })
+    }
+
+    async fn persist_fuel_model(&self, fuel_model: &DbFuelModel) -> anyhow::Result<()> {
+        self.with_state(|state| {
+            state.fuel_models.retain(|m| m.ship_type != fuel_model.ship_type || m.location_type != fuel_model.location_type);
+            state.fuel_models.push(fuel_model.clone());
+
+            Ok(())
+        })
+    }
+
+    async fn get_fuel_model(&self, ship_type: &str, location_type: &str) -> anyhow::Result<Option<DbFuelModel>> {
+        self.with_state(|state| {
+            Ok(
+                state.fuel_models.iter()
+                    .find(|m| m.ship_type == ship_type && m.location_type == location_type)
+                    .cloned()
+            )
+        })
+    }
+
+    async fn persist_market_data(&self, location: &str, ship_id: &str, marketplace_data: &shared::MarketplaceData) -> anyhow::Result<()> {
+        self.with_state(|state| {
+            state.market_data.push(MemoryMarketData {
+                location: location.to_string(),
+                ship_id: Some(ship_id.to_string()),
+                marketplace_data: *marketplace_data,
+                created_at: Utc::now(),
+            });
+
+            Ok(())
+        })
+    }
+
+    async fn get_latest_market_data(&self) -> anyhow::Result<Vec<DbMarketData>> {
+        self.with_state(|state| {
+            Ok(
+                state.latest_market_data().into_iter()
+                    .filter(|m| state.system_location(&m.location).is_some())
+                    .map(|m| DbMarketData {
+                        location: m.location.clone(),
+                        good: m.marketplace_data.symbol,
+                        price_per_unit: m.marketplace_data.price_per_unit,
+                        volume_per_unit: m.marketplace_data.volume_per_unit,
+                        quantity_available: m.marketplace_data.quantity_available,
+                        created_at: m.created_at,
+                    })
+                    .collect()
+            )
+        })
+    }
+
+    async fn get_routes_from_location(&self, location: &str, ship: &ShipProfile) -> anyhow::Result<Vec<DbRoute>> {
+        self.with_state(|state| {
+            let location_goods: Vec<LocationGood> = state.latest_market_data().into_iter()
+                .filter_map(|m| {
+                    let system_location = state.system_location(&m.location)?;
+
+                    Some(LocationGood {
+                        system: system_location.system.clone(),
+                        location: m.location.clone(),
+                        location_type: system_location.location_type.clone(),
+                        x: system_location.x,
+                        y: system_location.y,
+                        good: m.marketplace_data.symbol.to_string(),
+                        quantity_available: m.marketplace_data.quantity_available,
+                        price_per_unit: m.marketplace_data.price_per_unit,
+                        volume_per_unit: m.marketplace_data.volume_per_unit,
+                        created_at: m.created_at,
+                    })
+                })
+                .collect();
+
+            Ok(routes::routes_from_location(&location_goods, location, &ship.ship_type, ship.speed))
+        })
+    }
+
+    async fn persist_transaction(&self, transaction_type: &str, user_id: &str, order: &responses::PurchaseOrder, trade_id: Option<&str>) -> anyhow::Result<()> {
+        self.with_state(|state| {
+            state.transactions.push(MemoryTransaction {
+                user_id: user_id.to_string(),
+                ship_id: order.ship.id.clone(),
+                transaction_type: transaction_type.to_string(),
+                order: order.order,
+                location: order.ship.location.clone().unwrap_or_else(|| "UNKNOWN".to_string()),
+                trade_id: trade_id.map(str::to_string),
+                created_at: Utc::now(),
+            });
+
+            Ok(())
+        })
+    }
+
+    async fn get_trade_samples(&self, location: &str) -> anyhow::Result<Vec<DbTradeSample>> {
+        self.with_state(|state| {
+            let window = Duration::minutes(15);
+            let is_purchase = |t: &MemoryTransaction| t.transaction_type == "purchase";
+            let quoted = |t: &MemoryTransaction, m: &MemoryMarketData| if is_purchase(t) {
+                m.marketplace_data.purchase_price_per_unit
+            } else {
+                m.marketplace_data.sell_price_per_unit
+            };
+
+            Ok(
+                state.transactions.iter()
+                    .filter(|t| t.location == location)
+                    .filter_map(|t| {
+                        let snapshots = state.market_data.iter()
+                            .filter(|m| m.location == t.location && m.marketplace_data.symbol == t.order.good);
+
+                        let before = snapshots.clone()
+                            .filter(|m| m.created_at <= t.created_at && m.created_at > t.created_at - window)
+                            .max_by_key(|m| m.created_at)?;
+                        let after = snapshots
+                            .filter(|m| m.created_at > t.created_at && m.created_at < t.created_at + window)
+                            .min_by_key(|m| m.created_at);
+
+                        Some(DbTradeSample {
+                            location: t.location.clone(),
+                            good: t.order.good,
+                            transaction_type: t.transaction_type.clone(),
+                            quantity: t.order.quantity,
+                            price_per_unit: t.order.price_per_unit,
+                            quoted_price_per_unit: quoted(t, before),
+                            quantity_available: before.marketplace_data.quantity_available,
+                            quoted_price_per_unit_after: after.map(|m| quoted(t, m)),
+                        })
+                    })
+                    .collect()
+            )
+        })
+    }
+
+    async fn get_location_surveys(&self, system: &str, since: DateTime<Utc>) -> anyhow::Result<Vec<DbLocationSurvey>> {
+        let now = Utc::now();
+
+        self.with_state(|state| {
+            let mut locations: Vec<&DbSystemLocation> = state.system_locations.iter().filter(|l| l.system == system).collect();
+            locations.sort_by(|a, b| a.location.cmp(&b.location));
+
+            Ok(
+                locations.into_iter()
+                    .map(|l| {
+                        let mut snapshots: Vec<&MemoryMarketData> = state.market_data.iter().filter(|m| m.location == l.location).collect();
+                        snapshots.sort_by_key(|m| m.created_at);
+
+                        let mut price_changes = Vec::new();
+                        for (i, snapshot) in snapshots.iter().enumerate().filter(|(_, m)| m.created_at > since) {
+                            let previous = snapshots[..i].iter().rev()
+                                .find(|m| m.created_at > since && m.marketplace_data.symbol == snapshot.marketplace_data.symbol);
+
+                            if let Some(previous) = previous {
+                                if previous.marketplace_data.price_per_unit != 0 {
+                                    let change = snapshot.marketplace_data.price_per_unit - previous.marketplace_data.price_per_unit;
+                                    price_changes.push(f64::from(change.abs()) / f64::from(previous.marketplace_data.price_per_unit));
+                                }
+                            }
+                        }
+
+                        let arrivals = state.flight_plans.iter().filter(|f| f.destination == l.location);
+
+                        DbLocationSurvey {
+                            location: l.location.clone(),
+                            surveyed_at: snapshots.last().map(|m| m.created_at),
+                            visited_at: arrivals.clone().filter(|f| f.arrives_at <= now).map(|f| f.arrives_at).max(),
+                            volatility: if price_changes.is_empty() { 0.0 } else { price_changes.iter().sum::<f64>() / price_changes.len() as f64 },
+                            trades: state.transactions.iter().filter(|t| t.location == l.location && t.created_at > since).count() as i32,
+                            ships_arriving: arrivals.filter(|f| f.arrives_at > now).count() as i32,
+                        }
+                    })
+                    .collect()
+            )
+        })
+    }
+
+    async fn get_market_freshness(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<DbMarketFreshness>> {
+        self.with_state(|state| {
+            let mut locations: Vec<&DbSystemLocation> = state.system_locations.iter().collect();
+            locations.sort_by(|a, b| a.location.cmp(&b.location));
+
+            let assignment = |m: &MemoryMarketData| state.ships.iter()
+                .find(|s| Some(&s.ship_id) == m.ship_id.as_ref())
+                .map(|s| s.assignment.clone());
+
+            Ok(
+                locations.into_iter()
+                    .map(|l| {
+                        let snapshots: Vec<&MemoryMarketData> = state.market_data.iter().filter(|m| m.location == l.location).collect();
+                        let latest = snapshots.iter().max_by_key(|m| m.created_at);
+                        let recent: Vec<Option<String>> = snapshots.iter().filter(|m| m.created_at > since).map(|m| assignment(m)).collect();
+
+                        DbMarketFreshness {
+                            location: l.location.clone(),
+                            surveyed_at: latest.map(|m| m.created_at),
+                            surveyed_by: latest.and_then(|m| m.ship_id.clone()),
+                            scout_records: recent.iter().filter(|a| a.as_deref() == Some("scout")).count() as i32,
+                            trader_records: recent.iter().filter(|a| a.as_deref() == Some("trader")).count() as i32,
+                        }
+                    })
+                    .collect()
+            )
+        })
+    }
+
+    async fn persist_slippage_model(&self, slippage_model: &DbSlippageModel) -> anyhow::Result<()> {
+        self.with_state(|state| {
+            state.slippage_models.retain(|m| m.location != slippage_model.location || m.good != slippage_model.good);
+            state.slippage_models.push(slippage_model.clone());
+
+            Ok(())
+        })
+    }
+
+    async fn get_slippage_models(&self) -> anyhow::Result<Vec<DbSlippageModel>> {
+        self.with_state(|state| Ok(state.slippage_models.clone()))
+    }
+
+    async fn get_trade_transactions(&self, user_id: &str, trade_id: &str) -> anyhow::Result<Vec<DbUserTransaction>> {
+        self.with_state(|state| {
+            Ok(state.transactions.iter()
+                .enumerate()
+                .filter(|(_, t)| t.user_id == user_id && t.trade_id.as_deref() == Some(trade_id))
+                .map(|(i, t)| DbUserTransaction {
+                    id: i as i64 + 1,
+                    user_id: t.user_id.clone(),
+                    ship_id: t.ship_id.clone(),
+                    transaction_type: t.transaction_type.clone(),
+                    good: t.order.good.to_string(),
+                    price_per_unit: t.order.price_per_unit,
+                    quantity: t.order.quantity,
+                    total: t.order.total,
+                    location: t.location.clone(),
+                    trade_id: t.trade_id.clone(),
+                    created_at: t.created_at,
+                })
+                .collect())
+        })
+    }
+
+    async fn persist_trade(&self, trade: &DbUserTrade) -> anyhow::Result<()> {
+        self.with_state(|state| {
+            state.trades.push(trade.clone());
+
+            Ok(())
+        })
+    }
+
+    async fn get_trades(&self, user_id: &str, since: DateTime<Utc>) -> anyhow::Result<Vec<DbUserTrade>> {
+        self.with_state(|state| Ok(state.trades.iter().filter(|t| t.user_id == user_id && t.completed_at >= since).cloned().collect()))
+    }
+
+    async fn persist_loan_event(&self, loan_event: &DbLoanEvent) -> anyhow::Result<()> {
+        self.with_state(|state| {
+            state.loan_events.push(DbLoanEvent { created_at: Utc::now(), ..loan_event.clone() });
+
+            Ok(())
+        })
+    }
+
+    async fn persist_ship_event(&self, ship_event: &DbShipEvent) -> anyhow::Result<()> {
+        self.with_state(|state| {
+            state.ship_history.push(DbShipEvent { created_at: Utc::now(), ..ship_event.clone() });
+
+            Ok(())
+        })
+    }
+
+    async fn get_ship_history(&self, user_id: &str) -> anyhow::Result<Vec<DbShipEvent>> {
+        self.with_state(|state| Ok(state.ship_history.iter().filter(|e| e.user_id == user_id).cloned().collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use spacetraders::shared::{Good, LocationType};
+    use test_utils::{market, system};
+
+    const USER_ID: &str = "00000000-0000-0000-0000-000000000001";
+
+    async fn storage_with_systems() -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        let systems = vec![
+            system("OE", &[
+                ("OE-PM", LocationType::Planet, 20, -25),
+                ("OE-UC", LocationType::GasGiant, -75, 80),
+                ("OE-W-XV", LocationType::Wormhole, 120, 120),
+            ]),
+            system("XV", &[
+                ("XV-BN", LocationType::Planet, 20, -25),
+            ]),
+        ];
+
+        for system in &systems {
+            for location in &system.locations {
+                storage.persist_system_location(system, location).await.unwrap();
+            }
+        }
+
+        storage
+    }
+
+    #[tokio::test]
+    async fn routes_only_join_goods_within_the_same_system() {
+        let storage = storage_with_systems().await;
+        storage.persist_market_data("OE-PM", "ship-1", &market(Good::Metals, 10, 500)).await.unwrap();
+        storage.persist_market_data("OE-PM", "ship-1", &market(Good::Fuel, 2, 500)).await.unwrap();
+        storage.persist_market_data("OE-UC", "ship-1", &market(Good::Metals, 25, 300)).await.unwrap();
+        storage.persist_market_data("XV-BN", "ship-1", &market(Good::Metals, 90, 300)).await.unwrap();
+
+        let routes = storage.get_routes_from_location("OE-PM", &test_utils::ship_profile()).await.unwrap();
+
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].sell_location, "OE-UC");
+        assert_eq!(routes[0].good, Good::Metals.to_string());
+        assert_eq!(routes[0].purchase_price_per_unit, 10);
+        assert_eq!(routes[0].sell_price_per_unit, 25);
+        assert!((routes[0].distance - (95.0f64.powi(2) + 105.0f64.powi(2)).sqrt()).abs() < f64::EPSILON);
+        assert_eq!(routes[0].fuel_price_per_unit, Some(2));
+        assert!(routes[0].fuel_required > 0.0 && routes[0].flight_time > 0.0);
+    }
+
+    #[tokio::test]
+    async fn routes_use_the_latest_and_only_fresh_market_data() {
+        let storage = storage_with_systems().await;
+        storage.persist_market_data("OE-PM", "ship-1", &market(Good::Metals, 10, 500)).await.unwrap();
+        storage.persist_market_data("OE-UC", "ship-1", &market(Good::Metals, 25, 300)).await.unwrap();
+        storage.persist_market_data("OE-UC", "ship-1", &market(Good::Metals, 30, 300)).await.unwrap();
+        storage.persist_market_data("OE-UC", "ship-1", &market(Good::Chemicals, 30, 300)).await.unwrap();
+        storage.persist_market_data("OE-PM", "ship-1", &market(Good::Chemicals, 5, 300)).await.unwrap();
+        storage.with_state(|state| {
+            let stale = state.market_data.iter_mut()
+                .find(|m| m.location == "OE-PM" && m.marketplace_data.symbol == Good::Chemicals)
+                .unwrap();
+            stale.created_at = Utc::now() - Duration::hours(1);
+        });
+
+        let routes = storage.get_routes_from_location("OE-PM", &test_utils::ship_profile()).await.unwrap();
+
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].sell_price_per_unit, 30);
+    }
+
+    #[tokio::test]
+    async fn wormholes_are_found_by_their_destination_system() {
+        let storage = storage_with_systems().await;
+
+        assert_eq!(storage.get_wormhole_from_location_to_system("OE-PM", "XV").await.unwrap(), "OE-W-XV");
+        assert!(storage.get_wormhole_from_location_to_system("XV-BN", "OE").await.is_err());
+        assert_eq!(storage.get_system_locations_from_location("OE-UC").await.unwrap(), vec!["OE-PM", "OE-UC", "OE-W-XV"]);
+    }
+
+    #[tokio::test]
+    async fn flight_samples_are_grouped_by_ship_type_and_origin() {
+        let storage = storage_with_systems().await;
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+        storage.persist_flight_plan(USER_ID, "ship-1", &test_utils::flight_plan("OE-PM", "OE-UC", 60), None).await.unwrap();
+        storage.persist_flight_plan(USER_ID, "ship-1", &test_utils::flight_plan("OE-W-XV", "XV-BN", 180), None).await.unwrap();
+
+        assert_eq!(storage.get_flight_samples("GR-MK-I", "Planet").await.unwrap(), vec![DbFlightSample { distance: 40, fuel_consumed: 10, flight_time: 60 }]);
+        assert!(storage.get_flight_samples("GR-MK-II", "Planet").await.unwrap().is_empty());
+        assert!(storage.get_flight_samples("GR-MK-I", "GasGiant").await.unwrap().is_empty());
+        assert!(storage.get_flight_samples("GR-MK-I", "Wormhole").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn distances_are_only_known_within_a_system() {
+        let storage = storage_with_systems().await;
+
+        let distance = storage.get_distance_between_locations("OE-PM", "OE-UC").await.unwrap().unwrap();
+        assert_eq!(distance.origin_location_type, "Planet");
+        assert!((distance.distance - 141.6).abs() < 0.1);
+        assert!(storage.get_distance_between_locations("OE-PM", "XV-BN").await.unwrap().is_none());
+        assert!(storage.get_distance_between_locations("OE-PM", "ZZ-ZZ").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn persisting_a_known_ship_keeps_its_assignment() {
+        let storage = MemoryStorage::new();
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+        storage.update_ship_system(USER_ID, "ship-1", "XV").await.unwrap();
+        storage.with_state(|state| state.ships[0].assignment = "scout".to_string());
+
+        storage.persist_ship(USER_ID, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+
+        let db_ship = storage.get_ship(USER_ID, "ship-1").await.unwrap();
+        assert_eq!(db_ship.system, "XV");
+        assert_eq!(db_ship.assignment, "scout");
+    }
+
+    #[tokio::test]
+    async fn only_flight_plans_still_in_progress_are_active() {
+        let storage = MemoryStorage::new();
+        storage.persist_flight_plan(USER_ID, "ship-1", &test_utils::flight_plan("OE-PM", "OE-UC", -60), None).await.unwrap();
+        assert!(storage.get_active_flight_plan("ship-1").await.unwrap().is_none());
+
+        storage.persist_flight_plan(USER_ID, "ship-1", &test_utils::flight_plan("OE-UC", "OE-PM", 60), None).await.unwrap();
+        let flight_plan = storage.get_active_flight_plan("ship-1").await.unwrap().unwrap();
+        assert_eq!(flight_plan.destination, "OE-PM");
+    }
+
+    #[tokio::test]
+    async fn users_are_unique_by_username() {
+        let storage = MemoryStorage::new();
+        let user = storage.persist_user("main".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+
+        assert_eq!(user.new_ship_assignment, "trader");
+        assert_eq!(storage.get_user("main".to_string()).await.unwrap().unwrap().id, user.id);
+        assert!(storage.get_user("someone-else".to_string()).await.unwrap().is_none());
+        assert!(storage.persist_user("main".to_string(), "token".to_string(), &ShipAssignment::Scout, "OE").await.is_err());
+
+        storage.reset().await.unwrap();
+        assert!(storage.get_user("main".to_string()).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,128 @@
+pub(crate) mod postgres;
+pub(crate) mod memory;
+
+use crate::db::{self, DbDistanceBetweenLocations, DbFlightSample, DbFuelModel, DbLoanEvent, DbLocationSurvey, DbMarketData, DbMarketFreshness, DbRoute, DbShip, DbShipEvent, DbShipMachineState, DbSlippageModel, DbSystemLocation, DbTradeSample, DbUser, DbUserSummary, DbUserTrade, DbUserTransaction};
+use spacemonger_core::scoring::ShipProfile;
+use crate::ship_machines::ShipAssignment;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use spacetraders::{responses, shared};
+use std::env;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// A handle to wherever the daemon keeps its state. Ship machines and users hold one of these
+/// rather than a `PgPool` so that they can run against postgres or entirely in memory.
+pub type StorageClient = Arc<dyn Storage>;
+
+/// Everything the daemon reads and writes while it runs
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// Throw away everything after an API reset. The postgres implementation keeps a backup
+    async fn reset(&self) -> anyhow::Result<()>;
+
+    // users
+    async fn get_user(&self, username: String) -> anyhow::Result<Option<DbUser>>;
+    async fn persist_user(&self, username: String, token: String, new_ship_assignment: &ShipAssignment, new_ship_system: &str) -> anyhow::Result<DbUser>;
+    async fn persist_user_stats(&self, user_id: &str, credits: i32, ships: &[shared::Ship]) -> anyhow::Result<()>;
+    async fn get_user_summaries(&self) -> anyhow::Result<Vec<DbUserSummary>>;
+
+    // ships
+    async fn persist_ship(&self, user_id: &str, system: &str, assignment: &ShipAssignment, ship: &shared::Ship) -> anyhow::Result<()>;
+    async fn update_ship_system(&self, user_id: &str, ship_id: &str, system: &str) -> anyhow::Result<()>;
+    async fn get_ship(&self, user_id: &str, ship_id: &str) -> anyhow::Result<DbShip>;
+    async fn get_ship_owner(&self, ship_id: &str) -> anyhow::Result<Option<DbUser>>;
+
+    // ship machines
+    async fn persist_ship_machine_state(&self, user_id: &str, ship_id: &str, machine_type: &str, state: &str) -> anyhow::Result<()>;
+    async fn get_ship_machine_state(&self, user_id: &str, ship_id: &str) -> anyhow::Result<Option<DbShipMachineState>>;
+
+    // systems
+    async fn persist_system_location(&self, system: &shared::SystemsInfoData, location: &shared::SystemsInfoLocation) -> anyhow::Result<()>;
+    async fn get_system_locations(&self) -> anyhow::Result<Vec<DbSystemLocation>>;
+    async fn get_system_locations_from_location(&self, location: &str) -> anyhow::Result<Vec<String>>;
+    async fn get_wormhole_from_location_to_system(&self, location: &str, system: &str) -> anyhow::Result<String>;
+    async fn get_distance_between_locations(&self, origin: &str, destination: &str) -> anyhow::Result<Option<DbDistanceBetweenLocations>>;
+
+    // flight plans
+    /// trade_id is the trade the flight is part of. See `DbUserTrade`
+    async fn persist_flight_plan(&self, user_id: &str, ship_id: &str, flight_plan: &responses::FlightPlan, trade_id: Option<&str>) -> anyhow::Result<()>;
+    async fn get_active_flight_plan(&self, ship_id: &str) -> anyhow::Result<Option<shared::FlightPlanData>>;
+    async fn get_flight_samples(&self, ship_type: &str, location_type: &str) -> anyhow::Result<Vec<DbFlightSample>>;
+
+    // fuel model
+    async fn persist_fuel_model(&self, fuel_model: &DbFuelModel) -> anyhow::Result<()>;
+    async fn get_fuel_model(&self, ship_type: &str, location_type: &str) -> anyhow::Result<Option<DbFuelModel>>;
+
+    // markets and trading
+    /// Record a good at a location as seen by a ship docked there
+    async fn persist_market_data(&self, location: &str, ship_id: &str, marketplace_data: &shared::MarketplaceData) -> anyhow::Result<()>;
+    async fn get_latest_market_data(&self) -> anyhow::Result<Vec<DbMarketData>>;
+    /// Every single system route from a location with the fuel and flight time for the ship. See
+    /// `scoring::rank_routes` for which are worth flying
+    async fn get_routes_from_location(&self, location: &str, ship: &ShipProfile) -> anyhow::Result<Vec<DbRoute>>;
+    /// trade_id is the trade the order was made for. See `DbUserTrade`
+    async fn persist_transaction(&self, transaction_type: &str, user_id: &str, order: &responses::PurchaseOrder, trade_id: Option<&str>) -> anyhow::Result<()>;
+    /// Our orders at a location that have a market snapshot from just before them
+    async fn get_trade_samples(&self, location: &str) -> anyhow::Result<Vec<DbTradeSample>>;
+    /// Every location in a system with how its market has behaved since `since`. See
+    /// `survey_scheduler`
+    async fn get_location_surveys(&self, system: &str, since: DateTime<Utc>) -> anyhow::Result<Vec<DbLocationSurvey>>;
+    /// Every location with its latest snapshot and how much scouts and traders have recorded
+    /// there since `since`
+    async fn get_market_freshness(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<DbMarketFreshness>>;
+
+    // trades
+    /// Every order made for a trade, oldest first
+    async fn get_trade_transactions(&self, user_id: &str, trade_id: &str) -> anyhow::Result<Vec<DbUserTransaction>>;
+    async fn persist_trade(&self, trade: &DbUserTrade) -> anyhow::Result<()>;
+    /// Every trade completed since `since`, oldest first
+    async fn get_trades(&self, user_id: &str, since: DateTime<Utc>) -> anyhow::Result<Vec<DbUserTrade>>;
+
+    // slippage model
+    async fn persist_slippage_model(&self, slippage_model: &DbSlippageModel) -> anyhow::Result<()>;
+    async fn get_slippage_models(&self) -> anyhow::Result<Vec<DbSlippageModel>>;
+
+    // loans
+    async fn persist_loan_event(&self, loan_event: &DbLoanEvent) -> anyhow::Result<()>;
+
+    // ship history
+    async fn persist_ship_event(&self, ship_event: &DbShipEvent) -> anyhow::Result<()>;
+    async fn get_ship_history(&self, user_id: &str) -> anyhow::Result<Vec<DbShipEvent>>;
+}
+
+/// Build the storage selected by the STORAGE_BACKEND env var. Defaults to postgres (configured by
+/// the POSTGRES_* env vars) and runs any pending migrations. Setting STORAGE_BACKEND=memory keeps
+/// everything in process which is handy for dry runs against the simulator.
+pub async fn get_storage_from_env() -> anyhow::Result<StorageClient> {
+    let storage_backend = env::var("STORAGE_BACKEND").unwrap_or_else(|_| "postgres".to_string());
+
+    match storage_backend.as_str() {
+        "postgres" => {
+            let pg_pool = get_db_pool_from_env().await?;
+
+            db::run_migrations(pg_pool.clone()).await?;
+
+            Ok(Arc::new(postgres::PgStorage::new(pg_pool)))
+        }
+        "memory" => {
+            log::warn!("Using in-memory storage. Nothing will be persisted after the daemon exits");
+
+            Ok(Arc::new(memory::MemoryStorage::new()))
+        }
+        other => Err(anyhow::anyhow!("Unknown STORAGE_BACKEND \"{}\". Expected \"postgres\" or \"memory\"", other)),
+    }
+}
+
+/// Connect to the postgres database configured by the POSTGRES_* env vars without running any
+/// migrations
+pub async fn get_db_pool_from_env() -> anyhow::Result<PgPool> {
+    let postgres_host = env::var("POSTGRES_HOST").unwrap();
+    let postgres_port = env::var("POSTGRES_PORT").unwrap().parse::<i32>().unwrap();
+    let postgres_username = env::var("POSTGRES_USERNAME").unwrap();
+    let postgres_password = env::var("POSTGRES_PASSWORD").unwrap();
+    let postgres_database = env::var("POSTGRES_DATABASE").unwrap();
+
+    db::get_db_pool(postgres_host, postgres_port, postgres_username, postgres_password, postgres_database).await
+}
@@ -0,0 +1,638 @@
+use crate::db::{self, DbDistanceBetweenLocations, DbFlightSample, DbFuelModel, DbLoanEvent, DbLocationSurvey, DbMarketData, DbMarketFreshness, DbRoute, DbShip, DbShipEvent, DbShipMachineState, DbSlippageModel, DbSystemLocation, DbTradeSample, DbUser, DbUserSummary, DbUserTrade, DbUserTransaction};
+use spacemonger_core::scoring::ShipProfile;
+use crate::ship_machines::ShipAssignment;
+use crate::storage::Storage;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use spacetraders::{responses, shared};
+use sqlx::PgPool;
+
+/// The daemon's real storage. Every call is handed off to the queries in `db`.
+#[derive(Debug, Clone)]
+pub struct PgStorage {
+    pg_pool: PgPool,
+}
+
+impl PgStorage {
+    pub fn new(pg_pool: PgPool) -> PgStorage {
+        PgStorage { pg_pool }
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    async fn reset(&self) -> anyhow::Result<()> {
+        db::reset_db(self.pg_pool.clone()).await
+    }
+
+    async fn get_user(&self, username: String) -> anyhow::Result<Option<DbUser>> {
+        db::get_user(self.pg_pool.clone(), username).await
+    }
+
+    async fn persist_user(&self, username: String, token: String, new_ship_assignment: &ShipAssignment, new_ship_system: &str) -> anyhow::Result<DbUser> {
+        db::persist_user(self.pg_pool.clone(), username, token, new_ship_assignment, new_ship_system).await
+    }
+
+    async fn persist_user_stats(&self, user_id: &str, credits: i32, ships: &[shared::Ship]) -> anyhow::Result<()> {
+        db::persist_user_stats(self.pg_pool.clone(), user_id, credits, ships).await
+    }
+
+    async fn get_user_summaries(&self) -> anyhow::Result<Vec<DbUserSummary>> {
+        db::get_user_summaries(self.pg_pool.clone()).await
+    }
+
+    async fn persist_ship(&self, user_id: &str, system: &str, assignment: &ShipAssignment, ship: &shared::Ship) -> anyhow::Result<()> {
+        db::persist_ship(self.pg_pool.clone(), user_id, system, assignment, ship).await
+    }
+
+    async fn update_ship_system(&self, user_id: &str, ship_id: &str, system: &str) -> anyhow::Result<()> {
+        db::update_ship_system(self.pg_pool.clone(), user_id, ship_id, system).await
+    }
+
+    async fn get_ship(&self, user_id: &str, ship_id: &str) -> anyhow::Result<DbShip> {
+        db::get_ship(self.pg_pool.clone(), user_id, ship_id).await
+    }
+
+    async fn get_ship_owner(&self, ship_id: &str) -> anyhow::Result<Option<DbUser>> {
+        db::get_ship_owner(self.pg_pool.clone(), ship_id).await
+    }
+
+    async fn persist_ship_machine_state(&self, user_id: &str, ship_id: &str, machine_type: &str, state: &str) -> anyhow::Result<()> {
+        db::persist_ship_machine_state(self.pg_pool.clone(), user_id, ship_id, machine_type, state).await
+    }
+
+    async fn get_ship_machine_state(&self, user_id: &str, ship_id: &str) -> anyhow::Result<Option<DbShipMachineState>> {
+        db::get_ship_machine_state(self.pg_pool.clone(), user_id, ship_id).await
+    }
+
+    async fn persist_system_location(&self, system: &shared::SystemsInfoData, location: &shared::SystemsInfoLocation) -> anyhow::Result<()> {
+        db::persist_system_location(self.pg_pool.clone(), system, location).await
+    }
+
+    async fn get_system_locations(&self) -> anyhow::Result<Vec<DbSystemLocation>> {
+        db::get_system_locations(self.pg_pool.clone()).await
+    }
+
+    async fn get_system_locations_from_location(&self, location: &str) -> anyhow::Result<Vec<String>> {
+        db::get_system_locations_from_location(self.pg_pool.clone(), location).await
+    }
+
+    async fn get_wormhole_from_location_to_system(&self, location: &str, system: &str) -> anyhow::Result<String> {
+        db::get_wormhole_from_location_to_system(self.pg_pool.clone(), location, system).await
+    }
+
+    async fn get_distance_between_locations(&self, origin: &str, destination: &str) -> anyhow::Result<Option<DbDistanceBetweenLocations>> {
+        db::get_distance_between_locations(self.pg_pool.clone(), origin, destination).await
+    }
+
+    async fn persist_flight_plan(&self, user_id: &str, ship_id: &str, flight_plan: &responses::FlightPlan, trade_id: Option<&str>) -> anyhow::Result<()> {
+        db::persist_flight_plan(self.pg_pool.clone(), user_id, ship_id, flight_plan, trade_id).await
+    }
+
+    async fn get_active_flight_plan(&self, ship_id: &str) -> anyhow::Result<Option<shared::FlightPlanData>> {
+        db::get_active_flight_plan(self.pg_pool.clone(), ship_id).await
+    }
+
+    async fn get_flight_samples(&self, ship_type: &str, location_type: &str) -> anyhow::Result<Vec<DbFlightSample>> {
+        db::get_flight_samples(self.pg_pool.clone(), ship_type, location_type).await
+    }
+
+    async fn persist_fuel_model(&self, fuel_model: &DbFuelModel) -> anyhow::Result<()> {
+        db::persist_fuel_model(self.pg_pool.clone(), fuel_model).await
+    }
+
+    async fn get_fuel_model(&self, ship_type: &str, location_type: &str) -> anyhow::Result<Option<DbFuelModel>> {
+        db::get_fuel_model(self.pg_pool.clone(), ship_type, location_type).await
+    }
+
+    async fn persist_market_data(&self, location: &str, ship_id: &str, marketplace_data: &shared::MarketplaceData) -> anyhow::Result<()> {
+        db::persist_market_data(self.pg_pool.clone(), location, ship_id, marketplace_data).await
+    }
+
+    async fn get_latest_market_data(&self) -> anyhow::Result<Vec<DbMarketData>> {
+        db::get_latest_market_data(self.pg_pool.clone()).await
+    }
+
+    async fn get_routes_from_location(&self, location: &str, ship: &ShipProfile) -> anyhow::Result<Vec<DbRoute>> {
+        db::get_routes_from_location(self.pg_pool.clone(), location, ship).await
+    }
+
+    async fn persist_transaction(&self, transaction_type: &str, user_id: &str, order: &responses::PurchaseOrder, trade_id: Option<&str>) -> anyhow::Result<()> {
+        db::persist_transaction(self.pg_pool.clone(), transaction_type, user_id, order, trade_id).await
+    }
+
+    async fn get_trade_samples(&self, location: &str) -> anyhow::Result<Vec<DbTradeSample>> {
+        db::get_trade_samples(self.pg_pool.clone(), location).await
+    }
+
+    async fn get_location_surveys(&self, system: &str, since: DateTime<Utc>) -> anyhow::Result<Vec<DbLocationSurvey>> {
+        db::get_location_surveys(self.pg_pool.clone(), system, since).await
+    }
+
+    async fn get_market_freshness(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<DbMarketFreshness>> {
+        db::get_market_freshness(self.pg_pool.clone(), since).await
+    }
+
+    async fn get_trade_transactions(&self, user_id: &str, trade_id: &str) -> anyhow::Result<Vec<DbUserTransaction>> {
+        db::get_trade_transactions(self.pg_pool.clone(), user_id, trade_id).await
+    }
+
+    async fn persist_trade(&self, trade: &DbUserTrade) -> anyhow::Result<()> {
+        db::persist_trade(self.pg_pool.clone(), trade).await
+    }
+
+    async fn get_trades(&self, user_id: &str, since: DateTime<Utc>) -> anyhow::Result<Vec<DbUserTrade>> {
+        db::get_trades(self.pg_pool.clone(), user_id, since).await
+    }
+
+    async fn persist_slippage_model(&self, slippage_model: &DbSlippageModel) -> anyhow::Result<()> {
+        db::persist_slippage_model(self.pg_pool.clone(), slippage_model).await
+    }
+
+    async fn get_slippage_models(&self) -> anyhow::Result<Vec<DbSlippageModel>> {
+        db::get_slippage_models(self.pg_pool.clone()).await
+    }
+
+    async fn persist_loan_event(&self, loan_event: &DbLoanEvent) -> anyhow::Result<()> {
+        db::persist_loan_event(self.pg_pool.clone(), loan_event).await
+    }
+
+    async fn persist_ship_event(&self, ship_event: &DbShipEvent) -> anyhow::Result<()> {
+        db::persist_ship_event(self.pg_pool.clone(), ship_event).await
+    }
+
+    async fn get_ship_history(&self, user_id: &str) -> anyhow::Result<Vec<DbShipEvent>> {
+        db::get_ship_history(self.pg_pool.clone(), user_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuel_model;
+    use crate::slippage_model;
+    use crate::storage::StorageClient;
+    use crate::storage::memory::MemoryStorage;
+    use crate::test_utils::{self, market, system};
+    use chrono::{Duration, TimeZone};
+    use spacetraders::shared::{Good, LocationType};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    #[ignore]
+    async fn routes_match_the_in_memory_implementation() {
+        let test_db = test_utils::get_test_db().await;
+        let storages: Vec<Box<dyn Storage>> = vec![
+            Box::new(PgStorage::new(test_db.pg_pool.clone())),
+            Box::new(MemoryStorage::new()),
+        ];
+
+        let systems = vec![
+            system("OE", &[
+                ("OE-PM", LocationType::Planet, 20, -25),
+                ("OE-PM-TR", LocationType::Moon, 22, -28),
+                ("OE-UC", LocationType::GasGiant, -75, 80),
+                ("OE-W-XV", LocationType::Wormhole, 120, 120),
+            ]),
+            system("XV", &[
+                ("XV-BN", LocationType::Planet, 20, -25),
+            ]),
+        ];
+        let markets = vec![
+            ("OE-PM", market(Good::Metals, 10, 500)),
+            ("OE-PM", market(Good::Fuel, 2, 5_000)),
+            ("OE-PM", market(Good::Electronics, 140, 300)),
+            ("OE-PM-TR", market(Good::Metals, 12, 800)),
+            ("OE-PM-TR", market(Good::Electronics, 150, 100)),
+            ("OE-UC", market(Good::Metals, 25, 300)),
+            ("OE-UC", market(Good::Fuel, 3, 2_000)),
+            ("OE-W-XV", market(Good::Fuel, 5, 2_000)),
+            ("XV-BN", market(Good::Metals, 90, 300)),
+        ];
+
+        for storage in &storages {
+            for system in &systems {
+                for location in &system.locations {
+                    storage.persist_system_location(system, location).await.unwrap();
+                }
+            }
+
+            for (location, datum) in &markets {
+                storage.persist_market_data(location, "ship-1", datum).await.unwrap();
+            }
+        }
+
+        let summarize = |routes: Vec<DbRoute>| -> Vec<(String, String, i32, i32, Option<i32>)> {
+            routes.into_iter()
+                .map(|r| (r.sell_location, r.good, r.purchase_price_per_unit, r.sell_price_per_unit, r.fuel_price_per_unit))
+                .collect()
+        };
+
+        for location in ["OE-PM", "OE-PM-TR", "OE-UC", "OE-W-XV", "XV-BN"].iter() {
+            let pg_routes = storages[0].get_routes_from_location(location, &test_utils::ship_profile()).await.unwrap();
+            let memory_routes = storages[1].get_routes_from_location(location, &test_utils::ship_profile()).await.unwrap();
+
+            assert_eq!(summarize(pg_routes), summarize(memory_routes), "Routes from {} differ", location);
+        }
+
+        // The route planner builds its graph from these
+        let locations = |locations: Vec<DbSystemLocation>| -> Vec<(String, String)> {
+            locations.into_iter().map(|l| (l.system, l.location)).collect()
+        };
+        assert_eq!(
+            locations(storages[0].get_system_locations().await.unwrap()),
+            locations(storages[1].get_system_locations().await.unwrap()),
+        );
+
+        let market_data = |market_data: Vec<DbMarketData>| -> Vec<(String, Good, i32)> {
+            let mut market_data: Vec<(String, Good, i32)> = market_data.into_iter()
+                .map(|m| (m.location, m.good, m.price_per_unit))
+                .collect();
+            market_data.sort_by(|a, b| (&a.0, a.1.to_string()).cmp(&(&b.0, b.1.to_string())));
+            market_data
+        };
+        assert_eq!(
+            market_data(storages[0].get_latest_market_data().await.unwrap()),
+            market_data(storages[1].get_latest_market_data().await.unwrap()),
+        );
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn ship_machine_state_is_replaced_on_each_write() {
+        let test_db = test_utils::get_test_db().await;
+        let storage = PgStorage::new(test_db.pg_pool.clone());
+        let user_id = "00000000-0000-0000-0000-000000000001";
+
+        assert!(storage.get_ship_machine_state(user_id, "ship-1").await.unwrap().is_none());
+
+        storage.persist_ship_machine_state(user_id, "ship-1", "trader", "{\"state\":\"PickBestTrade\"}").await.unwrap();
+        storage.persist_ship_machine_state(user_id, "ship-1", "system_change", "{\"state\":\"Warp\"}").await.unwrap();
+
+        let db_state = storage.get_ship_machine_state(user_id, "ship-1").await.unwrap().unwrap();
+        assert_eq!(db_state.machine_type, "system_change");
+        assert_eq!(db_state.state, "{\"state\": \"Warp\"}");
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn persisting_a_known_ship_keeps_the_operator_assignment() {
+        let test_db = test_utils::get_test_db().await;
+        let storage = PgStorage::new(test_db.pg_pool.clone());
+        let user = storage.persist_user("user".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+
+        storage.persist_ship(&user.id, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+        assert_eq!(storage.get_ship(&user.id, "ship-1").await.unwrap().assignment, "trader");
+
+        // What the api does when an operator reassigns a ship
+        sqlx::query("UPDATE daemon_user_ship SET system = 'XV', assignment = 'scout' WHERE user_id = $1::uuid AND ship_id = 'ship-1';")
+            .bind(&user.id)
+            .execute(&test_db.pg_pool)
+            .await
+            .unwrap();
+
+        storage.persist_ship(&user.id, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+
+        let db_ship = storage.get_ship(&user.id, "ship-1").await.unwrap();
+        assert_eq!(db_ship.system, "XV");
+        assert_eq!(db_ship.assignment, "scout");
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn user_summaries_use_the_latest_stats() {
+        let test_db = test_utils::get_test_db().await;
+        let storage = PgStorage::new(test_db.pg_pool.clone());
+        let trader = storage.persist_user("trader".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+        storage.persist_user("scout".to_string(), "token".to_string(), &ShipAssignment::Scout, "OE").await.unwrap();
+
+        storage.persist_user_stats(&trader.id, 100, &[]).await.unwrap();
+        storage.persist_user_stats(&trader.id, 200, &[test_utils::ship(Some("OE-PM"), &[])]).await.unwrap();
+        storage.persist_ship(&trader.id, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+
+        let summaries = storage.get_user_summaries().await.unwrap();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].username, "scout");
+        assert_eq!(summaries[0].credits, None);
+        assert_eq!(summaries[1].credits, Some(200));
+        assert_eq!(summaries[1].ship_count, Some(1));
+
+        assert_eq!(storage.get_ship_owner("ship-1").await.unwrap().unwrap().id, trader.id);
+        assert!(storage.get_ship_owner("ship-2").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn fuel_models_learn_from_flights_within_a_system() {
+        let test_db = test_utils::get_test_db().await;
+        let storage: StorageClient = Arc::new(PgStorage::new(test_db.pg_pool.clone()));
+        let user = storage.persist_user("user".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+        let systems = vec![
+            system("OE", &[
+                ("OE-PM", LocationType::Planet, 20, -25),
+                ("OE-UC", LocationType::GasGiant, -75, 80),
+                ("OE-W-XV", LocationType::Wormhole, 120, 120),
+            ]),
+            system("XV", &[
+                ("XV-BN", LocationType::Planet, 20, -25),
+            ]),
+        ];
+        for system in &systems {
+            for location in &system.locations {
+                storage.persist_system_location(system, location).await.unwrap();
+            }
+        }
+        storage.persist_ship(&user.id, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+
+        let mut warp = test_utils::flight_plan("OE-W-XV", "XV-BN", 180);
+        warp.flight_plan.id = "warp".to_string();
+        storage.persist_flight_plan(&user.id, "ship-1", &test_utils::flight_plan("OE-PM", "OE-UC", 60), None).await.unwrap();
+        storage.persist_flight_plan(&user.id, "ship-1", &warp, None).await.unwrap();
+
+        assert_eq!(storage.get_flight_samples("GR-MK-I", "Planet").await.unwrap(), vec![DbFlightSample { distance: 40, fuel_consumed: 10, flight_time: 60 }]);
+        assert!(storage.get_flight_samples("GR-MK-I", "Wormhole").await.unwrap().is_empty());
+
+        let distance = storage.get_distance_between_locations("OE-PM", "OE-UC").await.unwrap().unwrap();
+        let prediction = fuel_model::estimate(storage.clone(), "GR-MK-I", &distance).await.unwrap().unwrap();
+        assert_eq!(prediction.fuel, 10);
+
+        let mut fuel_model = storage.get_fuel_model("GR-MK-I", "Planet").await.unwrap().unwrap();
+        assert_eq!(fuel_model.samples, 1);
+        fuel_model.samples = 2;
+        storage.persist_fuel_model(&fuel_model).await.unwrap();
+        assert_eq!(storage.get_fuel_model("GR-MK-I", "Planet").await.unwrap(), Some(fuel_model));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn trade_samples_use_the_snapshots_surrounding_each_order() {
+        let test_db = test_utils::get_test_db().await;
+        let storage: StorageClient = Arc::new(PgStorage::new(test_db.pg_pool.clone()));
+        let user = storage.persist_user("user".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+
+        storage.persist_market_data("OE-PM", "ship-1", &market(Good::Metals, 100, 1_000)).await.unwrap();
+        storage.persist_market_data("OE-PM", "ship-1", &market(Good::Metals, 120, 500)).await.unwrap();
+        storage.persist_market_data("OE-PM", "ship-1", &market(Good::Chemicals, 50, 1_000)).await.unwrap();
+        let ship = test_utils::ship(Some("OE-PM"), &[]);
+        storage.persist_transaction("purchase", &user.id, &test_utils::order(1_000, Good::Metals, 500, 110, ship.clone()), None).await.unwrap();
+        storage.persist_transaction("purchase", &user.id, &test_utils::order(1_000, Good::Chemicals, 500, 60, ship), None).await.unwrap();
+
+        // Spread everything out around the orders
+        for (good, price_per_unit, minutes_ago) in &[("Metals", 100, 12), ("Metals", 120, 8), ("Chemicals", 50, 40)] {
+            sqlx::query("UPDATE daemon_market_data SET created_at = NOW() - ($3 || ' MINUTES')::INTERVAL WHERE good = $1 AND purchase_price_per_unit = $2")
+                .bind(good)
+                .bind(price_per_unit)
+                .bind(minutes_ago.to_string())
+                .execute(&test_db.pg_pool)
+                .await
+                .unwrap();
+        }
+        sqlx::query("UPDATE daemon_user_transaction SET created_at = NOW() - INTERVAL '10 MINUTES'")
+            .execute(&test_db.pg_pool)
+            .await
+            .unwrap();
+
+        let samples = storage.get_trade_samples("OE-PM").await.unwrap();
+
+        assert_eq!(samples, vec![DbTradeSample {
+            location: "OE-PM".to_string(),
+            good: Good::Metals,
+            transaction_type: "purchase".to_string(),
+            quantity: 500,
+            price_per_unit: 110,
+            quoted_price_per_unit: 100,
+            quantity_available: 1_000,
+            quoted_price_per_unit_after: Some(120),
+        }]);
+
+        let slippage_models = slippage_model::learn(storage.clone(), "OE-PM").await.unwrap();
+        assert!((slippage_models[0].purchase_impact - 0.4).abs() < 1e-9);
+        assert_eq!(storage.get_slippage_models().await.unwrap(), slippage_models);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn location_surveys_match_the_in_memory_implementation() {
+        let test_db = test_utils::get_test_db().await;
+        let storages: Vec<Box<dyn Storage>> = vec![
+            Box::new(PgStorage::new(test_db.pg_pool.clone())),
+            Box::new(MemoryStorage::new()),
+        ];
+
+        let oe = system("OE", &[
+            ("OE-PM", LocationType::Planet, 20, -25),
+            ("OE-UC", LocationType::GasGiant, -75, 80),
+            ("OE-W-XV", LocationType::Wormhole, 120, 120),
+        ]);
+        let xv = system("XV", &[("XV-BN", LocationType::Planet, 20, -25)]);
+
+        let mut surveys = Vec::new();
+        for storage in &storages {
+            for system in &[&oe, &xv] {
+                for location in &system.locations {
+                    storage.persist_system_location(system, location).await.unwrap();
+                }
+            }
+
+            for (location, datum) in &[
+                ("OE-PM", market(Good::Metals, 100, 500)),
+                ("OE-PM", market(Good::Fuel, 2, 5_000)),
+                ("OE-PM", market(Good::Metals, 120, 500)),
+                ("OE-PM", market(Good::Fuel, 3, 5_000)),
+                ("OE-UC", market(Good::Metals, 100, 500)),
+                ("XV-BN", market(Good::Metals, 10, 500)),
+                ("XV-BN", market(Good::Metals, 20, 500)),
+            ] {
+                storage.persist_market_data(location, "ship-1", datum).await.unwrap();
+            }
+
+            let user = storage.persist_user("user".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+            storage.persist_transaction("purchase", &user.id, &test_utils::order(1_000, Good::Metals, 10, 100, test_utils::ship(Some("OE-PM"), &[])), None).await.unwrap();
+            storage.persist_flight_plan(&user.id, "ship-1", &test_utils::flight_plan("OE-PM", "OE-W-XV", -60), None).await.unwrap();
+            storage.persist_flight_plan(&user.id, "ship-2", &test_utils::flight_plan("OE-PM", "OE-UC", 60), None).await.unwrap();
+
+            let summary: Vec<(String, bool, bool, i64, i32, i32)> = storage.get_location_surveys("OE", Utc::now() - Duration::hours(6)).await.unwrap()
+                .into_iter()
+                .map(|s| (s.location, s.surveyed_at.is_some(), s.visited_at.is_some(), (s.volatility * 1_000.0).round() as i64, s.trades, s.ships_arriving))
+                .collect();
+            surveys.push(summary);
+        }
+
+        assert_eq!(surveys[0], vec![
+            // Metals moved 20% and fuel 50%
+            ("OE-PM".to_string(), true, false, 350, 1, 0),
+            ("OE-UC".to_string(), true, false, 0, 0, 1),
+            ("OE-W-XV".to_string(), false, true, 0, 0, 0),
+        ]);
+        assert_eq!(surveys[0], surveys[1]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn market_freshness_matches_the_in_memory_implementation() {
+        let test_db = test_utils::get_test_db().await;
+        let storages: Vec<Box<dyn Storage>> = vec![
+            Box::new(PgStorage::new(test_db.pg_pool.clone())),
+            Box::new(MemoryStorage::new()),
+        ];
+
+        let oe = system("OE", &[("OE-PM", LocationType::Planet, 20, -25), ("OE-UC", LocationType::GasGiant, -75, 80)]);
+
+        let mut freshness = Vec::new();
+        for storage in &storages {
+            for location in &oe.locations {
+                storage.persist_system_location(&oe, location).await.unwrap();
+            }
+
+            let user = storage.persist_user("user".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+            let mut scout = test_utils::ship(Some("OE-PM"), &[]);
+            scout.id = "scout-1".to_string();
+            storage.persist_ship(&user.id, "OE", &ShipAssignment::Scout, &scout).await.unwrap();
+            storage.persist_ship(&user.id, "OE", &ShipAssignment::Trader, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+
+            storage.persist_market_data("OE-PM", "scout-1", &market(Good::Metals, 10, 500)).await.unwrap();
+            storage.persist_market_data("OE-PM", "scout-1", &market(Good::Fuel, 2, 500)).await.unwrap();
+            storage.persist_market_data("OE-PM", "ship-1", &market(Good::Metals, 11, 500)).await.unwrap();
+            // Nobody we know took this one
+            storage.persist_market_data("OE-PM", "ship-9", &market(Good::Metals, 12, 500)).await.unwrap();
+
+            let summary: Vec<(String, bool, Option<String>, i32, i32)> = storage.get_market_freshness(Utc::now() - Duration::hours(1)).await.unwrap()
+                .into_iter()
+                .map(|m| (m.location, m.surveyed_at.is_some(), m.surveyed_by, m.scout_records, m.trader_records))
+                .collect();
+            freshness.push(summary);
+        }
+
+        assert_eq!(freshness[0], vec![
+            ("OE-PM".to_string(), true, Some("ship-9".to_string()), 2, 1),
+            ("OE-UC".to_string(), false, None, 0, 0),
+        ]);
+        assert_eq!(freshness[0], freshness[1]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn trades_match_the_in_memory_implementation() {
+        let test_db = test_utils::get_test_db().await;
+        let storages: Vec<Box<dyn Storage>> = vec![
+            Box::new(PgStorage::new(test_db.pg_pool.clone())),
+            Box::new(MemoryStorage::new()),
+        ];
+
+        let trade_id = "4f0c2d8e-5b7a-4c1e-9d3f-2a6b8c0e1f47";
+        let started_at = Utc.timestamp(1_625_000_000, 0);
+        let mut trades = Vec::new();
+        for storage in &storages {
+            let user = storage.persist_user("user".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+
+            storage.persist_transaction("purchase", &user.id, &test_utils::order(1_000, Good::Metals, 10, 100, test_utils::ship(Some("OE-PM"), &[])), Some(trade_id)).await.unwrap();
+            storage.persist_transaction("purchase", &user.id, &test_utils::order(1_000, Good::Fuel, 5, 2, test_utils::ship(Some("OE-PM"), &[])), None).await.unwrap();
+            storage.persist_transaction("sell", &user.id, &test_utils::order(1_000, Good::Metals, 10, 120, test_utils::ship(Some("OE-UC"), &[])), Some(trade_id)).await.unwrap();
+
+            let transactions: Vec<(String, String, i32, Option<String>)> = storage.get_trade_transactions(&user.id, trade_id).await.unwrap()
+                .into_iter()
+                .map(|t| (t.transaction_type, t.location, t.total, t.trade_id))
+                .collect();
+            assert_eq!(transactions, vec![
+                ("purchase".to_string(), "OE-PM".to_string(), 1_000, Some(trade_id.to_string())),
+                ("sell".to_string(), "OE-UC".to_string(), 1_200, Some(trade_id.to_string())),
+            ]);
+
+            storage.persist_trade(&DbUserTrade {
+                trade_id: trade_id.to_string(),
+                user_id: user.id.clone(),
+                ship_id: "ship-1".to_string(),
+                good: "Metals".to_string(),
+                purchase_location: "OE-PM".to_string(),
+                sell_location: "OE-UC".to_string(),
+                quantity: 10,
+                cost: 1_000,
+                revenue: 1_200,
+                fuel_cost: 10,
+                profit: 190,
+                predicted_profit: 180,
+                duration_seconds: 300,
+                started_at,
+                completed_at: started_at + Duration::seconds(300),
+            }).await.unwrap();
+
+            assert!(storage.get_trades(&user.id, started_at + Duration::seconds(301)).await.unwrap().is_empty());
+            trades.push(storage.get_trades(&user.id, started_at).await.unwrap().into_iter().map(|t| DbUserTrade { user_id: String::new(), ..t }).collect::<Vec<DbUserTrade>>());
+        }
+
+        assert_eq!(trades[0].len(), 1);
+        assert_eq!(trades[0][0].profit, 190);
+        assert_eq!(trades[0], trades[1]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn loan_events_are_read_back_by_the_api() {
+        let test_db = test_utils::get_test_db().await;
+        let storage = PgStorage::new(test_db.pg_pool.clone());
+
+        let due_at = Utc::now() + Duration::days(2);
+        let user = storage.persist_user("user".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+        let other = storage.persist_user("other".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+
+        for (user_id, event_type, credits) in [(&user.id, "requested", 200_000), (&other.id, "requested", 200_000), (&user.id, "repaid", 20_000)] {
+            storage.persist_loan_event(&DbLoanEvent {
+                user_id: user_id.clone(),
+                loan_id: "loan-1".to_string(),
+                loan_type: "STARTUP".to_string(),
+                event_type: event_type.to_string(),
+                amount: 280_000,
+                due_at,
+                credits,
+                created_at: Utc::now(),
+            }).await.unwrap();
+        }
+
+        let events: Vec<(String, i32)> = spacemonger_core::queries::loan_events(&test_db.pg_pool, &user.id).await.unwrap()
+            .into_iter()
+            .map(|e| (e.event_type, e.credits))
+            .collect();
+
+        assert_eq!(events, vec![("repaid".to_string(), 20_000), ("requested".to_string(), 200_000)]);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn ship_history_matches_the_in_memory_implementation() {
+        let test_db = test_utils::get_test_db().await;
+        let storages: Vec<Box<dyn Storage>> = vec![
+            Box::new(PgStorage::new(test_db.pg_pool.clone())),
+            Box::new(MemoryStorage::new()),
+        ];
+
+        let mut histories = Vec::new();
+        for storage in &storages {
+            let user = storage.persist_user("user".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+            let other = storage.persist_user("other".to_string(), "token".to_string(), &ShipAssignment::Trader, "OE").await.unwrap();
+
+            for (user_id, ship_id, event_type) in [(&user.id, "ship-1", "purchased"), (&other.id, "ship-2", "purchased"), (&user.id, "ship-1", "retiring"), (&user.id, "ship-1", "retired")] {
+                storage.persist_ship_event(&DbShipEvent {
+                    user_id: user_id.clone(),
+                    ship_id: ship_id.to_string(),
+                    ship_type: "JW-MK-I".to_string(),
+                    event_type: event_type.to_string(),
+                    location: "OE-PM-TR".to_string(),
+                    created_at: Utc::now(),
+                }).await.unwrap();
+            }
+
+            let summary: Vec<(String, String)> = storage.get_ship_history(&user.id).await.unwrap()
+                .into_iter()
+                .map(|e| (e.ship_id, e.event_type))
+                .collect();
+            histories.push(summary);
+        }
+
+        assert_eq!(histories[0], vec![
+            ("ship-1".to_string(), "purchased".to_string()),
+            ("ship-1".to_string(), "retiring".to_string()),
+            ("ship-1".to_string(), "retired".to_string()),
+        ]);
+        assert_eq!(histories[0], histories[1]);
+    }
+}
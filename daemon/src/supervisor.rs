@@ -0,0 +1,476 @@
+// Runs one task per user and decides what happens when one of them stops. A task that hits
+// something unexpected is restarted on its own. A task that panics takes its user with it so the
+// whole fleet is started again. When the api goes into maintenance or stops accepting our tokens
+// every task is asked to stop, they all checkpoint their ships and the supervisor hands control
+// back so that the fleet can be rebuilt once the api is available again.
+use crate::funcs;
+use crate::game::GameBackend;
+use crate::game::errors::GameError;
+use crate::metrics;
+use crate::ship_machines::PollResult;
+use crate::storage::StorageClient;
+use crate::user::User;
+use futures::stream::{FuturesUnordered, StreamExt};
+use spacetraders::errors::SpaceTradersClientError;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+/// How long a user that failed waits before it is started again
+const RESTART_DELAY: Duration = Duration::from_secs(10);
+
+/// Why the supervisor stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum SupervisorExit {
+    /// Every user stopped on their own. I.E. they ran out of ships
+    Finished,
+    /// We were asked to shut down (SIGTERM or ctrl-c)
+    Terminated,
+    /// The api went into maintenance or was reset. The fleet should be started again from scratch
+    ApiUnavailable,
+    /// A user task panicked and took its user with it. The fleet should be started again from
+    /// scratch so that the user's ships get going again
+    Panicked,
+}
+
+/// Why a single user task stopped
+#[derive(Debug)]
+pub enum UserExit {
+    /// The supervisor asked the task to stop
+    Cancelled,
+    /// The user has no ships so it can't make any progress
+    NoShips,
+    /// The api is in maintenance or didn't accept our token. Every task needs to stop
+    ApiUnavailable(SpaceTradersClientError),
+    /// Something we didn't expect. Only this user is restarted
+    Failed(anyhow::Error),
+}
+
+/// What a user task should do about an error from one of its ships
+#[derive(Debug, Clone, PartialEq)]
+enum ErrorAction {
+    /// The state machine will just try it again... which is fine
+    Retry,
+    /// The machine knows how to work around this one
+    Recover(GameError),
+    /// We don't know what went wrong. Pessimistically reset the machine so that it doesn't get stuck. I.E.
+    /// {"error":{"message":"Good is not listed in planet marketplace.","code":2001}}
+    ResetMachine,
+    /// Stop the whole fleet
+    StopFleet,
+    /// Restart this user
+    Fail,
+}
+
+fn classify(e: &anyhow::Error) -> ErrorAction {
+    if let Some(error) = GameError::from_error(e) {
+        return ErrorAction::Recover(error);
+    }
+
+    match e.downcast_ref::<SpaceTradersClientError>() {
+        Some(SpaceTradersClientError::ServiceUnavailable) | Some(SpaceTradersClientError::Unauthorized) => ErrorAction::StopFleet,
+        Some(SpaceTradersClientError::ApiError(_)) => ErrorAction::ResetMachine,
+        Some(_) => ErrorAction::Retry,
+        None => ErrorAction::Fail,
+    }
+}
+
+type UserHandle = JoinHandle<(User, UserExit)>;
+
+/// Run every user until they all stop, the api becomes unavailable or we are asked to shut down
+pub async fn supervise(users: Vec<User>, storage: StorageClient, mut shutdown: watch::Receiver<bool>) -> anyhow::Result<SupervisorExit> {
+    let terminated = terminated(&mut shutdown);
+    tokio::pin!(terminated);
+    let (cancel_tx, cancel_rx) = watch::channel(false);
+
+    let mut handles: FuturesUnordered<UserHandle> = users.into_iter()
+        .map(|user| spawn_user(user, storage.clone(), cancel_rx.clone(), Duration::from_secs(0)))
+        .collect();
+
+    let exit = loop {
+        tokio::select! {
+            finished = handles.next() => {
+                let (user, user_exit) = match finished {
+                    Some(Ok(finished)) => finished,
+                    Some(Err(e)) => {
+                        log::error!("A user task panicked. Restarting the fleet. Error: {}", e);
+                        break SupervisorExit::Panicked;
+                    }
+                    None => break SupervisorExit::Finished,
+                };
+
+                match user_exit {
+                    UserExit::Cancelled => {},
+                    UserExit::NoShips => {
+                        log::error!("User {} has no ships and therefore cannot make progress. Quitting this user", user.username);
+                    },
+                    UserExit::ApiUnavailable(e) => {
+                        log::warn!("{} -- Api is unavailable ({}). Stopping every user", user.username, e);
+                        break SupervisorExit::ApiUnavailable;
+                    },
+                    UserExit::Failed(e) => {
+                        log::error!("{} -- Caught unexpected error: {:?}. Restarting user in {:?}", user.username, e, RESTART_DELAY);
+                        handles.push(spawn_user(user, storage.clone(), cancel_rx.clone(), RESTART_DELAY));
+                    },
+                }
+            }
+            _ = &mut terminated => {
+                log::info!("Stopping every user");
+                break SupervisorExit::Terminated;
+            }
+        }
+    };
+
+    // Tell everyone that is still running to stop and wait for them to checkpoint their ships
+    cancel_tx.send(true).ok();
+    while let Some(finished) = handles.next().await {
+        if let Ok((user, UserExit::Failed(e))) = finished {
+            log::error!("{} -- Caught unexpected error while stopping: {:?}", user.username, e);
+        }
+    }
+
+    // Whatever ships the fleet has when it starts again will report their own states
+    metrics::global().forget_ships();
+
+    Ok(exit)
+}
+
+/// Start listening for SIGTERM and ctrl-c. This needs to happen once before anything else so that
+/// a signal is never missed. The receiver changes to true once we are asked to shut down, see
+/// `terminated`
+pub fn shutdown_signal() -> anyhow::Result<watch::Receiver<bool>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => log::info!("Received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => log::info!("Received ctrl-c"),
+        }
+
+        shutdown_tx.send(true).ok();
+    });
+
+    Ok(shutdown_rx)
+}
+
+/// Resolves once we are asked to shut down, even if that happened before this was called
+pub async fn terminated(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            // Nobody is listening for signals anymore so one can't come
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Block until the api is out of maintenance mode
+pub async fn wait_out_maintenance(backend: Arc<dyn GameBackend>) {
+    while funcs::is_api_in_maintenance_mode(backend.clone()).await {
+        log::warn!("Detected SpaceTraders API in maintenance mode (status code 503). Sleeping for 60 seconds and trying again");
+        tokio::time::sleep(Duration::from_secs(60)).await;
+    }
+}
+
+fn spawn_user(user: User, storage: StorageClient, cancel: watch::Receiver<bool>, delay: Duration) -> UserHandle {
+    tokio::spawn(async move {
+        let mut cancel = cancel;
+        if !delay.is_zero() {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {},
+                _ = cancel.changed() => {},
+            }
+        }
+
+        run_user(user, storage, cancel).await
+    })
+}
+
+/// Poll every one of the user's ships once a second until asked to stop. The user is handed back
+/// when the task stops so that it can be restarted.
+pub async fn run_user(mut user: User, storage: StorageClient, mut cancel: watch::Receiver<bool>) -> (User, UserExit) {
+    let mut prev_user_credits = 0;
+
+    let exit = loop {
+        if *cancel.borrow() {
+            break UserExit::Cancelled;
+        }
+
+        if user.ship_machines.is_empty() {
+            break UserExit::NoShips;
+        }
+
+        if let Some(exit) = poll_ships(&mut user).await {
+            break exit;
+        }
+
+        // A ship sent to a shipyard doesn't change the user's credits when it gets there
+        if user.pending_purchase.is_some() {
+            if let Err(e) = user.complete_pending_purchase().await {
+                log::error!("{} -- Error occurred while purchasing a ship. Error: {}", user.username, e);
+            }
+        }
+
+        if prev_user_credits != user.credits {
+            log::info!("{} -- Credits {}", user.username, user.credits);
+            prev_user_credits = user.credits;
+
+            if let Err(e) = manage_fleet(&mut user, &storage).await {
+                match classify(&e) {
+                    ErrorAction::StopFleet => break UserExit::ApiUnavailable(into_client_error(e)),
+                    ErrorAction::Fail => break UserExit::Failed(e),
+                    _ => log::error!("{} -- Unable to manage fleet. Error: {}", user.username, e),
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {},
+            _ = cancel.changed() => {},
+        }
+    };
+
+    if let Err(e) = user.checkpoint_ships().await {
+        log::error!("{} -- Unable to checkpoint ships while stopping. Error: {}", user.username, e);
+    }
+
+    (user, exit)
+}
+
+/// Poll each of the user's ships once, recovering from whatever errors we can. Returns why the
+/// user needs to stop if it does
+pub async fn poll_ships(user: &mut User) -> Option<UserExit> {
+    // Ships that parked for good. They are replaced once every ship has been polled
+    let mut retired = Vec::new();
+
+    for machine in &mut user.ship_machines {
+        let e = match machine.poll().await {
+            Ok(Some(PollResult::UpdateCredits(credits))) => {
+                user.credits = credits;
+                continue;
+            }
+            Ok(Some(PollResult::Retired(location))) => {
+                retired.push((machine.get_ship_id().to_string(), location));
+                continue;
+            }
+            Ok(_) => continue,
+            Err(e) => e,
+        };
+
+        match classify(&e) {
+            ErrorAction::Retry => log::error!("{}:{} -- Caught a space traders client error. Error: {}", user.username, machine.get_ship_id(), e),
+            ErrorAction::Recover(error) => match machine.recover(&error).await {
+                Ok(Some(PollResult::UpdateCredits(credits))) => user.credits = credits,
+                Ok(_) => {}
+                Err(e) => log::error!("{}:{} -- Was unable to recover from {}: {}", user.username, machine.get_ship_id(), error, e),
+            },
+            ErrorAction::ResetMachine => {
+                log::error!("{}:{} -- Caught Api error {}. Resetting machine", user.username, machine.get_ship_id(), e);
+                match machine.reset().await {
+                    Ok(_) => log::info!("{}:{} -- Was reset", user.username, machine.get_ship_id()),
+                    Err(e) => log::error!("{}:{} -- Was unable to be reset: {}", user.username, machine.get_ship_id(), e),
+                };
+            }
+            ErrorAction::StopFleet => return Some(UserExit::ApiUnavailable(into_client_error(e))),
+            ErrorAction::Fail => return Some(UserExit::Failed(e)),
+        }
+    }
+
+    for (ship_id, location) in retired {
+        if let Err(e) = user.replace_retired_ship(&ship_id, &location).await {
+            log::error!("{}:{} -- Unable to replace the retired ship. Error: {}", user.username, ship_id, e);
+        }
+    }
+
+    None
+}
+
+/// Record the user's stats, borrow or pay off loans and buy more ships (or replace the ones that
+/// earn the least) whenever the user's credits change
+async fn manage_fleet(user: &mut User, storage: &StorageClient) -> anyhow::Result<()> {
+    let user_ships = user.get_my_ships().await?;
+    storage.persist_user_stats(&user.id, user.credits, &user_ships.ships).await?;
+
+    // Loans come first so a repayment that's due isn't spent on a ship
+    if let Err(e) = user.manage_loans().await {
+        log::error!("{} -- Unable to manage loans. Error: {}", user.username, e);
+    }
+
+    if user.purchasing.should_purchase_ship(user.credits, user.ship_machines.len()) {
+        match user.purchase_best_ship().await {
+            Ok(_) => {}
+            Err(e) => log::error!("{} -- Error occurred while purchasing a ship. Error: {}", user.username, e)
+        };
+    } else if user.purchasing.upgrade_ratio.is_some() {
+        if let Err(e) = user.upgrade_ship().await {
+            log::error!("{} -- Error occurred while upgrading a ship. Error: {}", user.username, e);
+        }
+    }
+
+    Ok(())
+}
+
+fn into_client_error(e: anyhow::Error) -> SpaceTradersClientError {
+    e.downcast::<SpaceTradersClientError>().expect("only client errors stop the fleet")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{LoanConfig, PurchasingConfig, TradingConfig, UserConfig};
+    use crate::route_reservations::RouteReservations;
+    use crate::game::mock::MockGameApi;
+    use crate::game::simulator::Simulator;
+    use crate::ship_machines::{ShipAssignment, ShipCommand};
+    use crate::ship_upgrades::{self, Retirement};
+    use crate::ship_machines::builder::ShipMachineBuilder;
+    use crate::storage::memory::MemoryStorage;
+    use crate::test_utils;
+
+    async fn user_with_a_ship(storage: StorageClient, username: &str) -> User {
+        let backend: Arc<dyn GameBackend> = Arc::new(Simulator::new(0.0));
+        let config = UserConfig {
+            name: "main".to_string(),
+            assignment: ShipAssignment::Trader,
+            system: "OE".to_string(),
+            location: None,
+            enabled: true,
+            purchasing: PurchasingConfig::default(),
+            loans: LoanConfig::default(),
+        };
+
+        let mut user = User::new(backend, storage.clone(), username.to_string(), &config, TradingConfig::default(), RouteReservations::new()).await.unwrap();
+        for system in &user.get_systems().await.unwrap().systems {
+            for location in &system.locations {
+                storage.persist_system_location(system, location).await.unwrap();
+            }
+        }
+        user.manage_loans().await.unwrap();
+        user.purchase_best_ship().await.unwrap();
+
+        user
+    }
+
+    #[test]
+    fn errors_are_classified() {
+        assert_eq!(classify(&SpaceTradersClientError::ServiceUnavailable.into()), ErrorAction::StopFleet);
+        assert_eq!(classify(&SpaceTradersClientError::Unauthorized.into()), ErrorAction::StopFleet);
+        assert_eq!(classify(&SpaceTradersClientError::TooManyRetries.into()), ErrorAction::Retry);
+        assert_eq!(classify(&test_utils::api_error(2001, "Good is not listed in planet marketplace.").into()), ErrorAction::Recover(GameError::GoodNotListed));
+        assert_eq!(classify(&test_utils::api_error(3003, "Ship is already at the destination.").into()), ErrorAction::ResetMachine);
+        assert_eq!(classify(&anyhow::anyhow!("something else")), ErrorAction::Fail);
+    }
+
+    #[tokio::test]
+    async fn cancelled_users_checkpoint_their_ships() {
+        let storage: StorageClient = Arc::new(MemoryStorage::new());
+        let user = user_with_a_ship(storage.clone(), "cancelled").await;
+        let ship_id = user.ship_machines[0].get_ship_id().to_string();
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+
+        let handle = tokio::spawn(run_user(user, storage.clone(), cancel_rx));
+        cancel_tx.send(true).unwrap();
+        let (user, exit) = handle.await.unwrap();
+
+        assert!(matches!(exit, UserExit::Cancelled));
+        assert!(storage.get_ship_machine_state(&user.id, &ship_id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn an_unavailable_api_stops_every_user() {
+        let storage: StorageClient = Arc::new(MemoryStorage::new());
+        let mut unavailable = user_with_a_ship(storage.clone(), "unavailable").await;
+        let healthy = user_with_a_ship(storage.clone(), "healthy").await;
+
+        // Swap the first user's ship for one that talks to an api that is in maintenance
+        let client = Arc::new(MockGameApi::default());
+        client.get_location_marketplace.push(Err(SpaceTradersClientError::ServiceUnavailable));
+        client.create_flight_plan.push(Err(SpaceTradersClientError::ServiceUnavailable));
+        storage.persist_ship(&unavailable.id, "OE", &ShipAssignment::Scout, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+        unavailable.ship_machines = vec![
+            ShipMachineBuilder::new()
+                .client(client)
+                .storage(storage.clone())
+                .user_id(unavailable.id.clone())
+                .username(unavailable.username.clone())
+                .system("OE".to_string())
+                .location("OE-PM".to_string())
+                .assignment(ShipAssignment::Scout)
+                .ship(test_utils::ship(Some("OE-PM"), &[]))
+                .build()
+                .await
+                .unwrap()
+        ];
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let exit = supervise(vec![unavailable, healthy], storage, shutdown).await.unwrap();
+
+        assert_eq!(exit, SupervisorExit::ApiUnavailable);
+    }
+
+    #[tokio::test]
+    async fn a_panicked_user_restarts_the_fleet() {
+        let storage: StorageClient = Arc::new(MemoryStorage::new());
+        let mut panicking = user_with_a_ship(storage.clone(), "panicking").await;
+        let healthy = user_with_a_ship(storage.clone(), "healthy").await;
+
+        // Nothing is scripted so the first request the ship makes panics
+        storage.persist_ship(&panicking.id, "OE", &ShipAssignment::Scout, &test_utils::ship(Some("OE-PM"), &[])).await.unwrap();
+        panicking.ship_machines = vec![
+            ShipMachineBuilder::new()
+                .client(Arc::new(MockGameApi::default()))
+                .storage(storage.clone())
+                .user_id(panicking.id.clone())
+                .username(panicking.username.clone())
+                .system("OE".to_string())
+                .location("OE-PM".to_string())
+                .assignment(ShipAssignment::Scout)
+                .ship(test_utils::ship(Some("OE-PM"), &[]))
+                .build()
+                .await
+                .unwrap()
+        ];
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let exit = supervise(vec![panicking, healthy], storage, shutdown).await.unwrap();
+
+        assert_eq!(exit, SupervisorExit::Panicked);
+    }
+
+    #[tokio::test]
+    async fn a_shutdown_requested_before_supervising_is_not_missed() {
+        let storage: StorageClient = Arc::new(MemoryStorage::new());
+        let user = user_with_a_ship(storage.clone(), "stopping").await;
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        shutdown_tx.send(true).unwrap();
+
+        let exit = supervise(vec![user], storage, shutdown).await.unwrap();
+
+        assert_eq!(exit, SupervisorExit::Terminated);
+    }
+
+    #[tokio::test]
+    async fn retired_ships_are_replaced_and_stay_parked() {
+        let storage: StorageClient = Arc::new(MemoryStorage::new());
+        let mut user = user_with_a_ship(storage.clone(), "retiring").await;
+        let ship_id = user.ship_machines[0].get_ship_id().to_string();
+
+        user.ship_machines[0].commands().send(ShipCommand::Retire("OE-PM".to_string())).unwrap();
+        for _ in 0..10 {
+            assert!(poll_ships(&mut user).await.is_none());
+        }
+
+        assert_eq!(user.ship_machines.len(), 1);
+        assert_ne!(user.ship_machines[0].get_ship_id(), ship_id);
+        let history = storage.get_ship_history(&user.id).await.unwrap();
+        let events: Vec<(&str, &str)> = history.iter().map(|e| (e.event_type.as_str(), e.location.as_str())).collect();
+        assert_eq!(events[1..], [("retired", "OE-PM"), ("purchased", "OE-PM")]);
+
+        // The retired ship is still ours. Its history keeps it from getting a machine when the user
+        // starts again
+        assert!(user.get_my_ships().await.unwrap().ships.iter().any(|s| s.id == ship_id));
+        assert_eq!(ship_upgrades::retirements(&history).get(&ship_id), Some(&Retirement::Retired));
+    }
+}
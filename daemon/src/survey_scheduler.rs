@@ -0,0 +1,183 @@
+// Decides which market a scout should look at next. Every location gets its own refresh interval
+// which shrinks the more its prices move around and the more our traders buy and sell there. The
+// location that is furthest past its interval is surveyed first. Traders record the market at
+// every location they dock at so the busiest locations mostly look after themselves.
+use crate::db::DbLocationSurvey;
+use crate::storage::StorageClient;
+use chrono::{DateTime, Duration, Utc};
+use std::cmp::Ordering;
+
+/// How far back price changes and our orders are counted
+const WINDOW_HOURS: i64 = 6;
+/// How often a quiet market that we don't trade at is surveyed
+const REFRESH_MINUTES: f64 = 15.0;
+/// No market is surveyed more often than this
+const MIN_REFRESH_MINUTES: f64 = 3.0;
+/// Prices moving this much between snapshots on average shrinks the refresh interval as much as
+/// TRADES_STEP of our orders does
+const VOLATILITY_STEP: f64 = 0.01;
+const TRADES_STEP: f64 = 5.0;
+
+/// How long the market at a location can go without being surveyed
+pub fn refresh_interval(survey: &DbLocationSurvey) -> Duration {
+    let minutes = REFRESH_MINUTES / (1.0 + survey.volatility / VOLATILITY_STEP + f64::from(survey.trades) / TRADES_STEP);
+
+    Duration::seconds((minutes.max(MIN_REFRESH_MINUTES) * 60.0).round() as i64)
+}
+
+/// How far into its refresh interval a location is. Anything at 1.0 or above is due. None when
+/// there is no point sending a scout. I.E. a ship is already on its way or there is no market
+pub fn urgency(survey: &DbLocationSurvey, now: DateTime<Utc>) -> Option<f64> {
+    if survey.ships_arriving > 0 {
+        return None;
+    }
+
+    match survey.surveyed_at {
+        Some(surveyed_at) => Some((now - surveyed_at).num_seconds() as f64 / refresh_interval(survey).num_seconds() as f64),
+        None if survey.visited_at.is_some() => None,
+        None => Some(f64::INFINITY),
+    }
+}
+
+/// The most overdue location that is due. The ship's current location wins a tie since getting
+/// there is free
+pub fn pick(surveys: &[DbLocationSurvey], current_location: Option<&str>, now: DateTime<Utc>) -> Option<String> {
+    let is_current = |survey: &DbLocationSurvey| Some(survey.location.as_str()) == current_location;
+
+    surveys.iter()
+        .filter_map(|s| urgency(s, now).map(|u| (s, u)))
+        .filter(|(_, u)| *u >= 1.0)
+        .max_by(|(a, a_urgency), (b, b_urgency)| {
+            a_urgency.partial_cmp(b_urgency).unwrap_or(Ordering::Equal)
+                .then_with(|| is_current(a).cmp(&is_current(b)))
+                .then_with(|| b.location.cmp(&a.location))
+        })
+        .map(|(s, _)| s.location.clone())
+}
+
+/// Where a scout in a system should go next. None when every market is fresh enough
+pub async fn next_location(storage: StorageClient, system: &str, current_location: Option<&str>) -> anyhow::Result<Option<String>> {
+    let now = Utc::now();
+    let surveys = storage.get_location_surveys(system, now - Duration::hours(WINDOW_HOURS)).await?;
+
+    Ok(pick(&surveys, current_location, now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::storage::memory::{MemoryMarketData, MemoryStorage};
+    use crate::test_utils::{self, market, system};
+    use spacetraders::shared::{Good, LocationType};
+    use std::sync::Arc;
+
+    fn survey(location: &str, minutes_ago: Option<i64>, volatility: f64, trades: i32) -> DbLocationSurvey {
+        DbLocationSurvey {
+            location: location.to_string(),
+            surveyed_at: minutes_ago.map(|m| Utc::now() - Duration::minutes(m)),
+            visited_at: None,
+            volatility,
+            trades,
+            ships_arriving: 0,
+        }
+    }
+
+    #[test]
+    fn volatile_and_busy_markets_are_surveyed_more_often() {
+        assert_eq!(refresh_interval(&survey("OE-PM", None, 0.0, 0)), Duration::minutes(15));
+        assert_eq!(refresh_interval(&survey("OE-PM", None, 0.01, 0)), Duration::seconds(450));
+        assert_eq!(refresh_interval(&survey("OE-PM", None, 0.01, 5)), Duration::minutes(5));
+        assert_eq!(refresh_interval(&survey("OE-PM", None, 1.0, 500)), Duration::minutes(3));
+    }
+
+    #[test]
+    fn the_most_overdue_market_is_picked() {
+        let now = Utc::now();
+        let surveys = vec![
+            // Fresh enough
+            survey("OE-PM", Some(10), 0.0, 0),
+            // Twice its interval
+            survey("OE-PM-TR", Some(10), 0.01, 5),
+            // Due
+            survey("OE-UC", Some(16), 0.0, 0),
+        ];
+
+        assert_eq!(pick(&surveys, None, now), Some("OE-PM-TR".to_string()));
+        assert_eq!(pick(&surveys[..1], None, now), None);
+    }
+
+    #[test]
+    fn markets_that_were_never_surveyed_go_first_unless_there_is_nothing_there() {
+        let now = Utc::now();
+        let mut wormhole = survey("OE-W-XV", None, 0.0, 0);
+        wormhole.visited_at = Some(now - Duration::hours(1));
+        let surveys = vec![survey("OE-PM", Some(60), 0.0, 0), wormhole, survey("OE-UC", None, 0.0, 0)];
+
+        assert_eq!(pick(&surveys, None, now), Some("OE-UC".to_string()));
+        assert_eq!(pick(&surveys[..2], None, now), Some("OE-PM".to_string()));
+    }
+
+    #[test]
+    fn markets_a_ship_is_already_headed_to_are_skipped() {
+        let now = Utc::now();
+        let mut arriving = survey("OE-UC", None, 0.0, 0);
+        arriving.ships_arriving = 1;
+
+        assert_eq!(pick(&[arriving, survey("OE-PM", Some(20), 0.0, 0)], None, now), Some("OE-PM".to_string()));
+    }
+
+    #[test]
+    fn ties_go_to_where_the_ship_already_is() {
+        let now = Utc::now();
+        let surveys = vec![survey("OE-PM", None, 0.0, 0), survey("OE-UC", None, 0.0, 0)];
+
+        assert_eq!(pick(&surveys, None, now), Some("OE-PM".to_string()));
+        assert_eq!(pick(&surveys, Some("OE-UC"), now), Some("OE-UC".to_string()));
+    }
+
+    #[tokio::test]
+    async fn next_location_measures_the_markets_in_the_system() {
+        let storage = Arc::new(MemoryStorage::new());
+        let systems = [
+            system("OE", &[("OE-PM", LocationType::Planet, 0, 0), ("OE-UC", LocationType::Planet, 10, 0)]),
+            system("XV", &[("XV-BN", LocationType::Planet, 0, 0)]),
+        ];
+        for s in &systems {
+            for l in &s.locations {
+                storage.persist_system_location(s, l).await.unwrap();
+            }
+        }
+
+        let now = Utc::now();
+        storage.with_state(|state| {
+            let snapshot = |location: &str, price_per_unit: i32, minutes_ago: i64| MemoryMarketData {
+                location: location.to_string(),
+                ship_id: None,
+                marketplace_data: market(Good::Metals, price_per_unit, 1_000),
+                created_at: now - Duration::minutes(minutes_ago),
+            };
+
+            // OE-UC barely moves so it isn't due yet but OE-PM jumps around
+            state.market_data = vec![
+                snapshot("OE-PM", 100, 30),
+                snapshot("OE-PM", 110, 8),
+                snapshot("OE-UC", 100, 30),
+                snapshot("OE-UC", 100, 8),
+            ];
+        });
+
+        let surveys = storage.get_location_surveys("OE", now - Duration::hours(6)).await.unwrap();
+        assert_eq!(surveys.len(), 2);
+        assert!((surveys[0].volatility - 0.1).abs() < 1e-9, "{:?}", surveys);
+        assert!(surveys[1].volatility.abs() < f64::EPSILON, "{:?}", surveys);
+
+        assert_eq!(next_location(storage.clone(), "OE", None).await.unwrap(), Some("OE-PM".to_string()));
+
+        storage.persist_flight_plan("user", "ship-1", &test_utils::flight_plan("OE-UC", "OE-PM", 60), None).await.unwrap();
+        assert_eq!(next_location(storage.clone(), "OE", None).await.unwrap(), None);
+
+        // Nobody has looked at XV yet
+        assert_eq!(next_location(storage, "XV", None).await.unwrap(), Some("XV-BN".to_string()));
+    }
+}
@@ -0,0 +1,153 @@
+// Shared fixtures for the daemon's tests. The database harness lives in spacemonger-core so that
+// the api's tests use the same one.
+use spacemonger_core::planner::{Leg, LegType, TradePlan};
+use spacemonger_core::scoring::ShipProfile;
+use chrono::{Duration, Utc};
+use spacetraders::errors::SpaceTradersClientError;
+use spacetraders::shared::Good;
+use spacetraders::{responses, shared};
+
+pub use spacemonger_core::test_utils::get_test_db;
+
+pub fn ship(location: Option<&str>, cargo: &[(Good, i32)]) -> shared::Ship {
+    let cargo: Vec<shared::Cargo> = cargo.iter()
+        .map(|(good, quantity)| shared::Cargo {
+            good: *good,
+            quantity: *quantity,
+            total_volume: quantity * good.get_volume(),
+        })
+        .collect();
+
+    shared::Ship {
+        id: "ship-1".to_string(),
+        location: location.map(|l| l.to_string()),
+        space_available: 100 - cargo.iter().fold(0, |acc, c| acc + c.total_volume),
+        cargo,
+        ship_type: "GR-MK-I".to_string(),
+        class: "MK-I".to_string(),
+        max_cargo: 100,
+        speed: 1,
+        manufacturer: "Gravager".to_string(),
+        plating: 10,
+        weapons: 5,
+        x: None,
+        y: None,
+        flight_plan_id: None,
+    }
+}
+
+pub fn leg(leg_type: LegType, origin: &str, destination: &str, fuel_required: f64) -> Leg {
+    Leg {
+        leg_type,
+        origin: origin.to_string(),
+        destination: destination.to_string(),
+        distance: 95.0,
+        fuel_required,
+        flight_time: 250.0,
+    }
+}
+
+/// Buying the good at the start of the first leg and selling it at the end of the last one
+pub fn trade_plan(good: Good, legs: Vec<Leg>) -> TradePlan {
+    TradePlan {
+        good,
+        purchase_location: legs[0].origin.clone(),
+        sell_location: legs[legs.len() - 1].destination.clone(),
+        purchase_quantity: 1_000,
+        sell_quantity: 2_000,
+        purchase_price_per_unit: 100,
+        sell_price_per_unit: 150,
+        volume_per_unit: 1,
+        fuel_required: legs.iter().fold(0.0, |acc, l| acc + l.fuel_required),
+        flight_time: legs.iter().fold(0.0, |acc, l| acc + l.flight_time),
+        profit_volume_time: 0.05,
+        fuel_price_per_unit: Some(2),
+        fuel_cost_after_first_leg: 0.0,
+        legs,
+    }
+}
+
+/// An empty GR-MK-I as the route scorers see it
+pub fn ship_profile() -> ShipProfile {
+    ShipProfile {
+        ship_type: "GR-MK-I".to_string(),
+        speed: 2,
+        space_available: 100,
+        fuel: 0,
+    }
+}
+
+pub fn system(symbol: &str, locations: &[(&str, shared::LocationType, i32, i32)]) -> shared::SystemsInfoData {
+    shared::SystemsInfoData {
+        symbol: symbol.to_string(),
+        name: symbol.to_string(),
+        locations: locations.iter()
+            .map(|(location, location_type, x, y)| shared::SystemsInfoLocation {
+                symbol: location.to_string(),
+                systems_info_type: *location_type,
+                name: location.to_string(),
+                x: *x,
+                y: *y,
+                ansible_progress: None,
+                anomaly: None,
+                structures: None,
+                messages: None,
+                allows_construction: false,
+            })
+            .collect(),
+    }
+}
+
+pub fn market(good: Good, price_per_unit: i32, quantity_available: i32) -> shared::MarketplaceData {
+    shared::MarketplaceData {
+        symbol: good,
+        volume_per_unit: good.get_volume(),
+        price_per_unit,
+        purchase_price_per_unit: price_per_unit,
+        sell_price_per_unit: price_per_unit,
+        quantity_available,
+        spread: 0,
+    }
+}
+
+pub fn flight_plan(origin: &str, destination: &str, arrives_in_seconds: i64) -> responses::FlightPlan {
+    let now = Utc::now();
+
+    responses::FlightPlan {
+        flight_plan: shared::FlightPlanData {
+            id: format!("flight-plan-{}-{}", origin, destination),
+            ship_id: "ship-1".to_string(),
+            fuel_consumed: 10,
+            fuel_remaining: 0,
+            time_remaining_in_seconds: arrives_in_seconds as i32,
+            created_at: now,
+            arrives_at: now + Duration::seconds(arrives_in_seconds),
+            terminated_at: None,
+            destination: destination.to_string(),
+            departure: origin.to_string(),
+            distance: 40,
+        },
+    }
+}
+
+pub fn order(credits: i32, good: Good, quantity: i32, price_per_unit: i32, ship: shared::Ship) -> responses::PurchaseOrder {
+    responses::PurchaseOrder {
+        credits,
+        order: shared::Order {
+            good,
+            quantity,
+            price_per_unit,
+            total: quantity * price_per_unit,
+        },
+        ship,
+    }
+}
+
+pub fn api_error(code: i32, message: &str) -> SpaceTradersClientError {
+    SpaceTradersClientError::ApiError(shared::ErrorMessage {
+        error: shared::ErrorMessageData {
+            code,
+            message: message.to_string(),
+        },
+    })
+}
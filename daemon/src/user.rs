@@ -1,72 +1,90 @@
-use crate::db;
-
-use spacetraders::client::{Client, HttpClient};
-use sqlx::PgPool;
-use spacetraders::{client, responses, shared};
+use crate::storage::StorageClient;
+use spacetraders::{responses, shared};
 use spacetraders::responses::MyShips;
-use spacetraders::shared::LoanType;
 use spacetraders::errors::SpaceTradersClientError;
-use crate::ship_machines::{ShipMachine, ShipAssignment, builder::ShipMachineBuilder};
-
-#[derive(Debug, Clone)]
+use crate::ship_machines::{DesiredState, ShipCommand, ShipMachine, ShipAssignment, builder::ShipMachineBuilder};
+use crate::config::{PurchasingConfig, TradingConfig, UserConfig};
+use crate::route_reservations::RouteReservations;
+use spacemonger_core::scoring;
+use crate::ship_purchasing::{self, PendingPurchase, PurchasePlan};
+use crate::ship_upgrades::{self, Retirement, ShipValue};
+use crate::db::DbShipEvent;
+use crate::funcs;
+use crate::metrics;
+use crate::loan_manager::{LoanManager, LoanPolicy};
+use crate::game::{GameBackend, GameClient};
+use chrono::Utc;
+use std::sync::Arc;
+
+#[derive(Debug)]
 pub struct User {
     pub username: String,
-    pub token: String,
     pub id: String,
-    client: Client,
-    pg_pool: PgPool,
-    new_ship_assignment: ShipAssignment,
+    client: GameClient,
+    storage: StorageClient,
+    pub new_ship_assignment: ShipAssignment,
     pub new_ship_system: String,
     pub new_ship_location: Option<String>,
+    pub purchasing: PurchasingConfig,
+    /// A ship that will be bought once one of our ships reaches the shipyard
+    pub pending_purchase: Option<PendingPurchase>,
+    trading: TradingConfig,
+    reservations: RouteReservations,
     pub ship_machines: Vec<ShipMachine>,
-    pub loans: Vec<shared::Loan>,
-    pub outstanding_loans: usize,
+    pub loans: LoanManager,
     pub credits: i32,
 }
 
 impl User {
-    pub async fn new(http_client: HttpClient, pg_pool: PgPool, username: String, new_ship_assignment: ShipAssignment, new_ship_system: String, new_ship_location: Option<String>) -> anyhow::Result<User> {
-        let db_user = db::get_user(pg_pool.clone(), username.clone()).await?;
+    pub async fn new(backend: Arc<dyn GameBackend>, storage: StorageClient, username: String, config: &UserConfig, trading: TradingConfig, reservations: RouteReservations) -> anyhow::Result<User> {
+        let new_ship_assignment = config.assignment.clone();
+        let new_ship_system = config.system.clone();
+        let new_ship_location = config.location.clone();
+
+        let db_user = storage.get_user(username.clone()).await?;
 
         if let Some(user) = db_user {
             log::debug!("Found existing user {}", username);
-            let client = Client::new(http_client, user.username, user.token.clone());
+            let client = backend.client(user.username, user.token.clone());
             let info = client.get_my_info().await?;
             let ships = client.get_my_ships().await?;
             let loans = client.get_my_loans().await?;
 
             log::info!("User credits {}", info.user.credits);
+            let policy = LoanPolicy::new(config.purchasing.pay_off_loans_above, &config.loans);
+            let loans = LoanManager::new(client.clone(), storage.clone(), user.id.clone(), username.clone(), policy, &loans.loans);
 
             let mut user = User {
                 username,
-                token: user.token.clone(),
                 id: user.id,
                 client,
-                pg_pool: pg_pool.clone(),
+                storage: storage.clone(),
                 new_ship_assignment: new_ship_assignment.clone(),
                 new_ship_system: new_ship_system.clone(),
                 new_ship_location: new_ship_location.clone(),
+                purchasing: config.purchasing.clone(),
+                pending_purchase: None,
+                trading: trading.clone(),
+                reservations: reservations.clone(),
                 ship_machines: Vec::new(),
                 credits: info.user.credits,
-                outstanding_loans: loans.loans.iter().filter(|f| { !f.status.contains("PAID") }).count(),
-                loans: loans.loans,
+                loans,
             };
 
-            user.add_ship_machines_from_user_info(&ships, &new_ship_assignment);
-
             for ship in &ships.ships {
-                db::persist_ship(pg_pool.clone(), &user.id, &new_ship_system, ship).await?;
+                storage.persist_ship(&user.id, &new_ship_system, &new_ship_assignment, ship).await?;
             }
 
+            user.add_ship_machines_from_user_info(&ships).await?;
+
             Ok(user)
         } else {
             log::debug!("Creating new user {}", username);
-            let claimed_user = client::claim_username(http_client.clone(), username.clone()).await?;
+            let claimed_user = backend.claim_username(username.clone()).await?;
 
             log::info!("Claimed new user {:?}", claimed_user);
 
-            let db_user = db::persist_user(
-                pg_pool.clone(),
+            let db_user = storage.persist_user(
                 username.clone(),
                 claimed_user.token.clone(),
                 &new_ship_assignment,
@@ -75,181 +93,319 @@ impl User {
 
             log::debug!("New user persisted");
 
-            let client = Client::new(http_client, username.clone(), claimed_user.token.clone());
+            let client = backend.client(username.clone(), claimed_user.token.clone());
             let info = client.get_my_info().await?;
             let ships = client.get_my_ships().await?;
             let loans = client.get_my_loans().await?;
 
             log::info!("User credits {}", info.user.credits);
+            let policy = LoanPolicy::new(config.purchasing.pay_off_loans_above, &config.loans);
+            let loans = LoanManager::new(client.clone(), storage.clone(), db_user.id.clone(), username.clone(), policy, &loans.loans);
 
             let mut user = User {
                 username: username.clone(),
-                token: claimed_user.token.clone(),
                 id: db_user.id,
                 client,
-                pg_pool: pg_pool.clone(),
+                storage: storage.clone(),
                 new_ship_assignment: new_ship_assignment.clone(),
                 new_ship_system: new_ship_system.clone(),
                 new_ship_location: new_ship_location.clone(),
+                purchasing: config.purchasing.clone(),
+                pending_purchase: None,
+                trading: trading.clone(),
+                reservations: reservations.clone(),
                 ship_machines: Vec::new(),
                 credits: info.user.credits,
-                loans: loans.loans.clone(),
-                outstanding_loans: loans.loans.iter().filter(|f| { !f.status.contains("PAID") }).count()
+                loans,
             };
 
-            user.add_ship_machines_from_user_info(&ships, &new_ship_assignment);
-
             for ship in &ships.ships {
-                db::persist_ship(pg_pool.clone(), &user.id, &new_ship_system, ship).await?;
+                storage.persist_ship(&user.id, &new_ship_system, &new_ship_assignment, ship).await?;
             }
 
+            user.add_ship_machines_from_user_info(&ships).await?;
+
             Ok(user)
         }
     }
 
-    fn add_ship_machines_from_user_info(&mut self, ships: &MyShips, assignment: &ShipAssignment) {
-        self.ship_machines = ships.ships.iter().map(|ship| {
-            self.ship_to_machine(ship, &assignment)
-        }).collect()
+    async fn add_ship_machines_from_user_info(&mut self, ships: &MyShips) -> anyhow::Result<()> {
+        let retirements = ship_upgrades::retirements(&self.storage.get_ship_history(&self.id).await?);
+
+        let mut ship_machines = Vec::new();
+        for ship in &ships.ships {
+            match retirements.get(&ship.id) {
+                Some(Retirement::Retired) => log::debug!("{}:{} -- Ship is retired. Leaving it parked", self.username, ship.id),
+                Some(Retirement::Retiring(location)) => {
+                    let ship_machine = self.ship_to_machine(ship).await?;
+                    ship_machine.commands().send(ShipCommand::Retire(location.clone()))?;
+                    ship_machines.push(ship_machine);
+                }
+                None => ship_machines.push(self.ship_to_machine(ship).await?),
+            }
+        }
+
+        self.ship_machines = ship_machines;
+
+        Ok(())
     }
 
-    fn ship_to_machine(&self, ship: &shared::Ship, assignment: &ShipAssignment) -> ShipMachine {
+    /// Build a machine for a ship that has already been persisted. The ship starts out doing
+    /// whatever it has been assigned in daemon_user_ship and will move itself to the assigned
+    /// system if it isn't already there.
+    async fn ship_to_machine(&self, ship: &shared::Ship) -> anyhow::Result<ShipMachine> {
+        let desired_state = DesiredState::load(&self.storage, &self.id, &ship.id).await?;
+
+        // A ship in motion is assumed to be headed somewhere in the system it was assigned to
+        let current_system = match &ship.location {
+            Some(location) => funcs::get_system_from_location(location).to_string(),
+            None => desired_state.system.clone(),
+        };
+
         let mut ship_machine_builder = ShipMachineBuilder::new();
         ship_machine_builder.client(self.client.clone())
-            .pg_pool(self.pg_pool.clone())
+            .storage(self.storage.clone())
             .user_id(self.id.clone())
             .username(self.username.clone())
-            .system(self.new_ship_system.clone())
-            .assignment(assignment.clone())
+            .system(current_system)
+            .assignment(desired_state.assignment)
+            .trading(self.trading.clone())
+            .reservations(self.reservations.clone())
+            .roaming(self.new_ship_location.is_none())
             .ship(ship.clone());
 
-        if let Some(new_ship_location) = self.new_ship_location.clone() {
-            ship_machine_builder.location(new_ship_location);
+        if let Some(location) = self.new_ship_location.clone().or_else(|| ship.location.clone()) {
+            ship_machine_builder.location(location);
         }
 
-        ship_machine_builder.build().expect("Unable to build ship")
+        ship_machine_builder.build().await
     }
 
-    pub async fn request_new_loan(&mut self, loan_type: LoanType) -> anyhow::Result<()> {
-        let loan_response = self.client.request_new_loan(loan_type).await?;
-
-        // Update our info to contain the new data from the loan response
-        self.credits = loan_response.credits;
-
-        // Keep track of loans...
-        self.loans.push(loan_response.loan);
-        self.outstanding_loans = self.loans.iter().filter(|f| { !f.status.contains("PAID") }).count();
+    /// Borrow when short of credits and pay back whatever the loan policy says is due
+    pub async fn manage_loans(&mut self) -> anyhow::Result<()> {
+        if let Some(credits) = self.loans.manage(self.credits).await? {
+            self.credits = credits;
+        }
 
         Ok(())
     }
 
-    pub async fn purchase_ship(&mut self, fastest_ship_location: String, ship_type: String) -> anyhow::Result<()> {
-        let purchase_ship_response = self.client.purchase_ship(fastest_ship_location, ship_type).await?;
+    pub async fn purchase_ship(&mut self, location: String, ship_type: String) -> anyhow::Result<()> {
+        let purchase_ship_response = self.client.purchase_ship(location, ship_type).await?;
 
         // TODO: Record new ship
-        db::persist_ship(self.pg_pool.clone(), &self.id, &self.new_ship_system, &purchase_ship_response.ship).await?;
+        self.storage.persist_ship(&self.id, &self.new_ship_system, &self.new_ship_assignment, &purchase_ship_response.ship).await?;
+        let ship = &purchase_ship_response.ship;
+        self.record_ship_event(&ship.id, &ship.ship_type, ship_upgrades::PURCHASED, ship.location.as_deref().unwrap_or_default()).await?;
 
         self.credits = purchase_ship_response.credits;
-        self.ship_machines.push(self.ship_to_machine(&purchase_ship_response.ship, &self.new_ship_assignment));
+        let ship_machine = self.ship_to_machine(&purchase_ship_response.ship).await?;
+        self.ship_machines.push(ship_machine);
 
         Ok(())
     }
 
-    pub async fn purchase_fastest_ship(&mut self) -> anyhow::Result<()> {
-        let available_ships = self.client.get_ships_for_sale().await?;
-        let mut fastest_ship = None;
-        let mut fastest_ship_speed: i32 = 0;
-        let mut fastest_ship_location = "".to_string();
-        let mut fastest_ship_price: i32 = 0;
+    /// Buy whichever ship is expected to do the most for the user's assignment per credit. When the
+    /// best shipyard doesn't have one of our ships docked at it a trader is sent there and the
+    /// ship is bought once it arrives, see complete_pending_purchase
+    pub async fn purchase_best_ship(&mut self) -> anyhow::Result<()> {
+        if let Some(pending) = &self.pending_purchase {
+            log::debug!("{} -- Still waiting on {} to reach {}", self.username, pending.ship_id, pending.candidate.listing.location);
+            return Ok(());
+        }
 
+        let ships_for_sale = self.client.get_ships_for_sale().await?;
         let ships = self.client.get_my_ships().await?;
-        let ships_count = ships.ships.len();
-        let valid_locations: Vec<String> = ships.ships
-            .into_iter()
-            .filter(|s| s.location != None)
-            .map(|s| s.location.unwrap())
-            .collect();
+        let docked_at: Vec<String> = ships.ships.iter().filter_map(|s| s.location.clone()).collect();
+
+        let scorer = scoring::scorer(&self.trading.scoring, self.storage.get_slippage_models().await?);
+        let candidates = ship_purchasing::evaluate(
+            self.storage.clone(),
+            &ships_for_sale,
+            &self.new_ship_system,
+            &self.new_ship_assignment,
+            &self.trading,
+            scorer.as_ref(),
+        ).await?;
+        log::debug!("{} -- Ships worth buying {:?}", self.username, candidates);
+
+        let can_move = self.ship_machines.iter().any(|m| m.can_be_sent());
+        // Credits that are about to go on a loan repayment can't be spent on ships
+        let credits = self.credits - self.loans.committed_credits(Utc::now());
+        match ship_purchasing::plan(&candidates, credits, &self.purchasing, &docked_at, ships.ships.len(), can_move) {
+            Some(PurchasePlan::Buy(candidate)) => {
+                log::info!(
+                    "{} -- Buying {} for {} at {}. Expecting {:.0} credits per hour",
+                    self.username,
+                    candidate.listing.ship_type,
+                    candidate.listing.price,
+                    candidate.listing.location,
+                    candidate.credits_per_hour,
+                );
+                self.purchase_ship(candidate.listing.location, candidate.listing.ship_type).await?;
+            }
+            Some(PurchasePlan::MoveThenBuy(candidate)) => {
+                // Any trader will do. It finishes the trade it is on before leaving
+                let machine = self.ship_machines.iter_mut().find(|m| m.can_be_sent()).unwrap();
+                machine.send_to(&candidate.listing.location);
+                log::info!("{} -- Sending {} to {} to buy {}", self.username, machine.get_ship_id(), candidate.listing.location, candidate.listing.ship_type);
+
+                self.pending_purchase = Some(PendingPurchase {
+                    ship_id: machine.get_ship_id().to_string(),
+                    candidate,
+                });
+            }
+            None => log::warn!("{} -- Unable to find a ship for the user to purchase", self.username),
+        }
+
+        Ok(())
+    }
 
-        log::info!("{} -- Valid locations to purchase a ship from are {:?}", self.username, valid_locations.clone());
-        log::info!("{} -- User currently has {} ships", self.username, ships_count);
-        log::info!("{} -- Ships available for purchase {:?}", self.username, available_ships.clone());
+    /// Buy the ship that a trader was sent to the shipyard for once it is there. The purchase is
+    /// dropped if the trader stopped being a trader or the ship no longer fits the budget
+    pub async fn complete_pending_purchase(&mut self) -> anyhow::Result<()> {
+        let pending = match &self.pending_purchase {
+            Some(pending) => pending.clone(),
+            None => return Ok(()),
+        };
+        let location = &pending.candidate.listing.location;
+
+        let machine = match self.ship_machines.iter_mut().find(|m| m.get_ship_id() == pending.ship_id) {
+            Some(machine) if machine.destination() == Some(location.as_str()) => machine,
+            _ => {
+                log::warn!("{} -- {} is no longer headed to {}. Dropping the purchase", self.username, pending.ship_id, location);
+                self.pending_purchase = None;
+                return Ok(());
+            }
+        };
 
-        if ships_count > 0 && valid_locations.is_empty() {
-            log::warn!("{} -- No docked ships found to purchase ships with. Will retry later", self.username);
+        if machine.held_at() != Some(location.as_str()) {
             return Ok(());
         }
 
-        for available_ship in &available_ships.ships {
-            for purchase_location in &available_ship.purchase_locations {
-                if available_ship.speed > fastest_ship_speed
-                    && available_ship.restricted_goods == None
-                    && self.credits > purchase_location.price
-                    && (ships_count == 0 || valid_locations.contains(&purchase_location.location))
-                    && purchase_location.system == self.new_ship_system
-                {
-                    fastest_ship_speed = available_ship.speed;
-                    fastest_ship = Some(available_ship);
-                    fastest_ship_location = purchase_location.location.clone();
-                    fastest_ship_price = purchase_location.price;
-                }
-            }
-        }
+        machine.release();
+        self.pending_purchase = None;
 
-        if let Some(ship) = fastest_ship {
-            log::info!("Ship {} -- Buying {} for {} at location {}", self.username, ship.ship_type.clone(), fastest_ship_price, fastest_ship_location);
-            self.purchase_ship(fastest_ship_location, ship.ship_type.clone()).await?;
-        } else {
-            log::warn!("Unable to find a ship for the user to purchase");
+        if !self.purchasing.can_afford(self.credits - self.loans.committed_credits(Utc::now()), pending.candidate.listing.price) {
+            log::warn!("{} -- Can no longer afford {} at {}", self.username, pending.candidate.listing.ship_type, location);
+            return Ok(());
         }
 
-        Ok(())
+        log::info!("{} -- Buying {} for {} at {}", self.username, pending.candidate.listing.ship_type, pending.candidate.listing.price, location);
+        self.purchase_ship(location.clone(), pending.candidate.listing.ship_type).await
     }
 
-    pub async fn purchase_largest_ship(&mut self) -> anyhow::Result<()> {
-        let available_ships = self.client.get_ships_for_sale().await?;
-        let mut largest_ship = None;
-        let mut largest_ship_capacity: i32 = 0;
-        let mut largest_ship_location = "".to_string();
-        let mut largest_ship_price: i32 = 0;
+    /// Retire the trader that is earning the least once there is a ship for sale that is expected to
+    /// earn enough more. The trader is told to park at the shipyard and the replacement is bought
+    /// once it is there, see replace_retired_ship. Only one ship is retired at a time
+    pub async fn upgrade_ship(&mut self) -> anyhow::Result<()> {
+        // Scouts aren't valued by what they earn
+        if self.new_ship_assignment == ShipAssignment::Scout || self.pending_purchase.is_some() || self.ship_machines.iter().any(|m| m.is_retiring()) {
+            return Ok(());
+        }
 
+        let ships_for_sale = self.client.get_ships_for_sale().await?;
         let ships = self.client.get_my_ships().await?;
-        let ships_count = ships.ships.len();
-        let valid_locations: Vec<String> = ships.ships
-            .into_iter()
-            .filter(|s| s.location != None)
-            .map(|s| s.location.unwrap())
-            .collect();
 
-        log::info!("{} -- Valid locations to purchase a ship from are {:?}", self.username, valid_locations.clone());
-        log::info!("{} -- User currently has {} ships", self.username, ships_count);
-        log::info!("{} -- Ships available for purchase {:?}", self.username, available_ships.clone());
+        let scorer = scoring::scorer(&self.trading.scoring, self.storage.get_slippage_models().await?);
+        let candidates = ship_purchasing::evaluate(
+            self.storage.clone(),
+            &ships_for_sale,
+            &self.new_ship_system,
+            &self.new_ship_assignment,
+            &self.trading,
+            scorer.as_ref(),
+        ).await?;
+
+        let locations = ship_purchasing::system_locations(self.storage.clone(), &self.new_ship_system).await?;
+        let mut owned = Vec::new();
+        for ship in &ships.ships {
+            if !self.ship_machines.iter().any(|m| m.get_ship_id() == ship.id && m.can_be_sent()) {
+                continue;
+            }
 
-        if ships_count > 0 && valid_locations.is_empty() {
-            log::warn!("{} -- No docked ships found to purchase ships with. Will retry later", self.username);
-            return Ok(());
+            let listing = ship_upgrades::listing_for(ship, &ships_for_sale);
+            owned.push(ShipValue {
+                ship_id: ship.id.clone(),
+                ship_type: ship.ship_type.clone(),
+                credits_per_hour: ship_purchasing::expected_credits_per_hour(self.storage.clone(), &locations, &listing, &self.trading, scorer.as_ref()).await?,
+            });
         }
 
-        for available_ship in &available_ships.ships {
-            for purchase_location in &available_ship.purchase_locations {
-                if available_ship.max_cargo > largest_ship_capacity
-                    && available_ship.restricted_goods == None
-                    && self.credits > purchase_location.price
-                    && (ships_count == 0 || valid_locations.contains(&purchase_location.location))
-                    && purchase_location.system == self.new_ship_system
-                {
-                    largest_ship_capacity = available_ship.max_cargo;
-                    largest_ship = Some(available_ship);
-                    largest_ship_location = purchase_location.location.clone();
-                    largest_ship_price = purchase_location.price;
-                }
+        let credits = self.credits - self.loans.committed_credits(Utc::now());
+        let upgrade = match ship_upgrades::pick(&owned, &candidates, credits, &self.purchasing) {
+            Some(upgrade) => upgrade,
+            None => return Ok(()),
+        };
+
+        log::info!(
+            "{} -- Retiring {} ({:.0} credits per hour) to make way for {} at {} ({:.0} credits per hour)",
+            self.username,
+            upgrade.retiree.ship_id,
+            upgrade.retiree.credits_per_hour,
+            upgrade.replacement.listing.ship_type,
+            upgrade.replacement.listing.location,
+            upgrade.replacement.credits_per_hour,
+        );
+
+        let location = upgrade.replacement.listing.location.clone();
+        let machine = self.ship_machines.iter().find(|m| m.get_ship_id() == upgrade.retiree.ship_id).unwrap();
+        machine.commands().send(ShipCommand::Retire(location.clone()))?;
+        self.record_ship_event(&upgrade.retiree.ship_id, &upgrade.retiree.ship_type, ship_upgrades::RETIRING, &location).await
+    }
+
+    /// Take a ship that has parked for good out of the fleet and buy the best ship we can afford
+    /// at the shipyard it parked at
+    pub async fn replace_retired_ship(&mut self, ship_id: &str, location: &str) -> anyhow::Result<()> {
+        let machine = match self.ship_machines.iter().position(|m| m.get_ship_id() == ship_id) {
+            Some(index) => self.ship_machines.remove(index),
+            None => return Ok(()),
+        };
+        metrics::global().forget_ship(ship_id);
+        self.record_ship_event(ship_id, machine.get_ship_type(), ship_upgrades::RETIRED, location).await?;
+
+        let ships_for_sale = self.client.get_ships_for_sale().await?;
+        let scorer = scoring::scorer(&self.trading.scoring, self.storage.get_slippage_models().await?);
+        let candidates: Vec<_> = ship_purchasing::evaluate(
+            self.storage.clone(),
+            &ships_for_sale,
+            &self.new_ship_system,
+            &self.new_ship_assignment,
+            &self.trading,
+            scorer.as_ref(),
+        ).await?
+            .into_iter()
+            .filter(|c| c.listing.location == location)
+            .collect();
+
+        let credits = self.credits - self.loans.committed_credits(Utc::now());
+        match ship_purchasing::plan(&candidates, credits, &self.purchasing, &[location.to_string()], self.ship_machines.len(), false) {
+            Some(PurchasePlan::Buy(candidate)) | Some(PurchasePlan::MoveThenBuy(candidate)) => {
+                log::info!("{} -- Replacing {} with {} for {} at {}", self.username, ship_id, candidate.listing.ship_type, candidate.listing.price, location);
+                self.purchase_ship(candidate.listing.location, candidate.listing.ship_type).await
+            }
+            None => {
+                log::warn!("{} -- Unable to find a ship to replace {} with at {}", self.username, ship_id, location);
+                Ok(())
             }
         }
+    }
 
-        if let Some(ship) = largest_ship {
-            log::info!("Ship {} -- Buying {} for {} at location {}", self.username, ship.ship_type.clone(), largest_ship_price, largest_ship_location);
-            self.purchase_ship(largest_ship_location, ship.ship_type.clone()).await?;
-        } else {
-            log::warn!("Unable to find a ship for the user to purchase");
+    async fn record_ship_event(&self, ship_id: &str, ship_type: &str, event_type: &str, location: &str) -> anyhow::Result<()> {
+        self.storage.persist_ship_event(&DbShipEvent {
+            user_id: self.id.clone(),
+            ship_id: ship_id.to_string(),
+            ship_type: ship_type.to_string(),
+            event_type: event_type.to_string(),
+            location: location.to_string(),
+            created_at: Utc::now(),
+        }).await
+    }
+
+    /// Save the state of every ship machine. Used before the user's task stops
+    pub async fn checkpoint_ships(&mut self) -> anyhow::Result<()> {
+        for machine in &mut self.ship_machines {
+            machine.persist_checkpoint().await?;
         }
 
         Ok(())
@@ -261,7 +417,7 @@ impl User {
 
         for system in &systems_info.systems {
             for location in &system.locations {
-                db::persist_system_location(self.pg_pool.clone(), system, location).await?;
+                self.storage.persist_system_location(system, location).await?;
             }
         }
 
@@ -271,8 +427,4 @@ impl User {
     pub async fn get_my_ships(&self) -> Result<responses::MyShips, SpaceTradersClientError> {
         self.client.get_my_ships().await
     }
-
-    pub async fn pay_off_loan(&self, loan_id: &str) -> Result<responses::PayLoanResponse, SpaceTradersClientError> {
-        self.client.pay_off_loan(loan_id).await
-    }
 }